@@ -0,0 +1,221 @@
+//! Storage consumption over time, for showback and chargeback
+//!
+//! Consumption is computed from the size samples `clean` records in the
+//! `size_history` table.  Each sample is taken to hold until the next time
+//! `clean` sampled any workspace, so workspaces which were destroyed stop
+//! accruing consumption at the first run they are missing from.
+
+use crate::db::Connection;
+use crate::{cli, db, error::Error, table};
+use chrono::{DateTime, Local};
+use prettytable::{
+    format::{Alignment, FormatBuilder},
+    Attr, Cell, Row, Table,
+};
+use std::collections::BTreeMap;
+
+/// Storage consumed by a user's workspaces on a filesystem, per project
+#[derive(Debug)]
+pub struct Consumption {
+    pub user: String,
+    pub project: Option<String>,
+    pub filesystem_name: String,
+    /// GiB-days consumed within the accounting period
+    pub gib_days: f64,
+}
+
+/// Sums up the storage consumed between `from` and `to`
+pub fn consumption(
+    conn: &Connection,
+    from: &DateTime<Local>,
+    to: &DateTime<Local>,
+) -> Result<Vec<Consumption>, Error> {
+    let from = db::Timestamp::from(*from);
+    // the run in effect at the start of the period, if any
+    let start: Option<db::Timestamp> = conn.query_row(
+        "SELECT MAX(time) FROM size_history WHERE time <= ?1",
+        [from],
+        |row| row.get(0),
+    )?;
+    let start = start.unwrap_or(from);
+    let end = db::Timestamp::from(*to.min(&Local::now()));
+
+    let mut statement = conn.prepare(
+        "SELECT DISTINCT time FROM size_history
+            WHERE time >= ?1
+                AND time < ?2
+            ORDER BY time",
+    )?;
+    let runs = statement
+        .query_map((start, end), |row| row.get::<_, db::Timestamp>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut statement = conn.prepare(
+        "SELECT user, project, filesystem, time, bytes FROM size_history
+            WHERE time >= ?1
+                AND time < ?2",
+    )?;
+    let mut rows = statement.query((start, end))?;
+    let mut byte_seconds: BTreeMap<(String, Option<String>, String), f64> = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        let time: db::Timestamp = row.get(3)?;
+        let bytes: i64 = row.get(4)?;
+        let next_run = runs
+            .get(runs.partition_point(|run| *run <= time))
+            .unwrap_or(&end);
+        let seconds = (next_run.0.min(end.0) - time.0.max(from.0))
+            .num_seconds()
+            .max(0);
+        *byte_seconds
+            .entry((row.get(0)?, row.get(1)?, row.get(2)?))
+            .or_default() += bytes as f64 * seconds as f64;
+    }
+    Ok(byte_seconds
+        .into_iter()
+        .map(
+            |((user, project, filesystem_name), byte_seconds)| Consumption {
+                user,
+                project,
+                filesystem_name,
+                gib_days: byte_seconds / (1u64 << 30) as f64 / 86400.0,
+            },
+        )
+        .collect())
+}
+
+/// Quotes a CSV field if necessary
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Prints the consumption of each user and project
+pub fn print(consumptions: &[Consumption], format: cli::AccountingFormat) {
+    match format {
+        cli::AccountingFormat::Csv => {
+            println!("user,project,filesystem,gib_days");
+            for consumption in consumptions {
+                println!(
+                    "{},{},{},{:.3}",
+                    csv_field(&consumption.user),
+                    csv_field(consumption.project.as_deref().unwrap_or("")),
+                    csv_field(&consumption.filesystem_name),
+                    consumption.gib_days
+                );
+            }
+        }
+        cli::AccountingFormat::Table => {
+            let mut table = Table::new();
+            table.set_format(FormatBuilder::new().padding(0, 2).build());
+            table.set_titles(Row::new(
+                ["USER", "PROJECT", "FS", "GIB-DAYS"]
+                    .iter()
+                    .map(|h| Cell::new(h).with_style(Attr::Bold))
+                    .collect(),
+            ));
+            for consumption in consumptions {
+                table.add_row(Row::new(vec![
+                    Cell::new(&consumption.user),
+                    Cell::new(consumption.project.as_deref().unwrap_or("-")),
+                    Cell::new(&consumption.filesystem_name),
+                    Cell::new_align(&format!("{:.1}", consumption.gib_days), Alignment::RIGHT),
+                ]));
+            }
+            table::print(&table);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite::Sqlite;
+    use chrono::{TimeZone, Utc};
+    use std::{path::Path, time::Duration};
+
+    const GIB: i64 = 1 << 30;
+
+    fn day(day: u32, hour: u32) -> DateTime<Local> {
+        Utc.with_ymd_and_hms(2020, 1, day, hour, 0, 0)
+            .unwrap()
+            .with_timezone(&Local)
+    }
+
+    /// Samples of `clean` runs on days 1, 2 and 4: `alice` kept 1 GiB
+    /// throughout, while `bob`'s 2 GiB were only sampled on day 1
+    fn history() -> Connection {
+        let conn = Connection::new(Box::new(
+            Sqlite::open(Path::new(":memory:"), Duration::ZERO).unwrap(),
+        ));
+        conn.execute_batch(
+            "CREATE TABLE size_history (
+                filesystem TEXT NOT NULL,
+                user TEXT NOT NULL,
+                name TEXT NOT NULL,
+                time DATETIME NOT NULL,
+                bytes INTEGER NOT NULL,
+                project TEXT
+            )",
+        )
+        .unwrap();
+        let samples = [
+            ("alice", None, 1, GIB),
+            ("alice", None, 2, GIB),
+            ("alice", None, 4, GIB),
+            ("bob", Some("proj"), 1, 2 * GIB),
+        ];
+        for (user, project, sampled_on, bytes) in samples {
+            conn.execute(
+                "INSERT INTO size_history (filesystem, user, name, time, bytes, project)
+                    VALUES ('bulk', ?1, 'ws', ?2, ?3, ?4)",
+                (
+                    user,
+                    db::Timestamp::from(day(sampled_on, 0)),
+                    bytes,
+                    project,
+                ),
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    fn gib_days(consumptions: &[Consumption]) -> Vec<(&str, Option<&str>, f64)> {
+        consumptions
+            .iter()
+            .map(|c| (c.user.as_str(), c.project.as_deref(), c.gib_days))
+            .collect()
+    }
+
+    #[test]
+    fn samples_hold_until_the_next_run() {
+        let consumptions = consumption(&history(), &day(1, 0), &day(5, 0)).unwrap();
+        assert_eq!(
+            gib_days(&consumptions),
+            [("alice", None, 4.), ("bob", Some("proj"), 2.)]
+        );
+    }
+
+    #[test]
+    fn period_cuts_samples_short() {
+        let consumptions = consumption(&history(), &day(1, 12), &day(4, 12)).unwrap();
+        assert_eq!(
+            gib_days(&consumptions),
+            [("alice", None, 3.), ("bob", Some("proj"), 1.)]
+        );
+    }
+
+    #[test]
+    fn nothing_is_consumed_before_the_first_run() {
+        let consumptions = consumption(
+            &history(),
+            &(day(1, 0) - chrono::Duration::days(7)),
+            &day(1, 0),
+        )
+        .unwrap();
+        assert!(consumptions.is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+use crate::{error::Error, units};
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::SystemTime,
+};
+use walkdir::WalkDir;
+
+/// Directory depth below the mountpoint up to which directory sizes are tallied
+const MAX_DIR_DEPTH: usize = 3;
+
+/// Extensions of file formats which usually compress well
+const COMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "csv", "tsv", "txt", "log", "json", "xml", "fastq", "fq", "sam", "vcf", "fasta", "fa", "tar",
+];
+
+/// Bounds of a workspace scan
+pub struct Options {
+    /// Number of entries to report per category
+    pub top: usize,
+    /// Files not modified within this duration are considered old
+    pub older_than: chrono::Duration,
+    /// Files smaller than this number of bytes are ignored
+    pub min_size: u64,
+    /// Maximum number of files to look at
+    pub max_files: usize,
+    /// Number of threads scanning the workspace's top-level entries
+    pub workers: usize,
+}
+
+/// The findings of (part of) a workspace scan
+#[derive(Default)]
+struct Scan {
+    dir_sizes: HashMap<PathBuf, u64>,
+    old_large: Vec<(PathBuf, u64)>,
+    compressible: Vec<(PathBuf, u64)>,
+}
+
+impl Scan {
+    fn merge(&mut self, other: Scan) {
+        for (dir, size) in other.dir_sizes {
+            *self.dir_sizes.entry(dir).or_default() += size;
+        }
+        self.old_large.extend(other.old_large);
+        self.compressible.extend(other.compressible);
+    }
+}
+
+/// Scans a single subtree of the workspace
+///
+/// As this runs as root, neither symlinks, not even `subtree` itself, nor
+/// other filesystems mounted within the workspace are followed.
+fn scan(
+    mountpoint: &Path,
+    subtree: &Path,
+    options: &Options,
+    cutoff: SystemTime,
+    files_seen: &AtomicUsize,
+) -> Scan {
+    let mut result = Scan::default();
+    let walk = WalkDir::new(subtree)
+        .follow_root_links(false)
+        .same_file_system(true);
+    for entry in walk.into_iter().filter_map(Result::ok) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if files_seen.fetch_add(1, Ordering::Relaxed) >= options.max_files {
+            break;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let size = metadata.len();
+        let relative = entry.path().strip_prefix(mountpoint).unwrap();
+
+        for dir in relative
+            .ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .filter(|dir| dir.components().count() <= MAX_DIR_DEPTH)
+        {
+            *result.dir_sizes.entry(dir.to_path_buf()).or_default() += size;
+        }
+
+        if size < options.min_size {
+            continue;
+        }
+        if metadata.modified().is_ok_and(|modified| modified < cutoff) {
+            result.old_large.push((relative.to_path_buf(), size));
+        }
+        let is_compressible_format = relative
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| COMPRESSIBLE_EXTENSIONS.contains(&e.to_lowercase().as_str()));
+        // files taking up (almost) their full size on disk are not compressed yet
+        let is_uncompressed = metadata.blocks() * 512 >= size / 10 * 9;
+        if is_compressible_format && is_uncompressed {
+            result.compressible.push((relative.to_path_buf(), size));
+        }
+    }
+    result
+}
+
+/// Prints the largest entries of a category
+fn print_top(title: &str, entries: &mut [(PathBuf, u64)], top: usize, exact_sizes: bool) {
+    println!("{}:", title);
+    if entries.is_empty() {
+        println!("  [ none ]");
+    }
+    entries.sort_unstable_by(|(_, a), (_, b)| b.cmp(a));
+    for (path, size) in entries.iter().take(top) {
+        println!(
+            "  {:>9}  {}",
+            units::format_size(*size, exact_sizes),
+            path.display()
+        );
+    }
+    println!();
+}
+
+/// Scans a workspace and reports candidates for trimming it down
+///
+/// The top-level entries of the workspace are scanned by `workers` threads.
+/// The scan stops after `max_files` files.
+pub fn advise(mountpoint: &Path, options: &Options, exact_sizes: bool) -> Result<(), Error> {
+    let cutoff = SystemTime::now() - options.older_than.to_std().unwrap();
+    let files_seen = AtomicUsize::new(0);
+
+    let subtrees: Vec<PathBuf> = fs::read_dir(mountpoint)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .collect();
+    let queue = Mutex::new(subtrees.iter());
+    let mut result = Scan::default();
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..options.workers.clamp(1, subtrees.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut result = Scan::default();
+                    loop {
+                        let Some(subtree) = queue.lock().unwrap().next() else {
+                            break;
+                        };
+                        result.merge(scan(mountpoint, subtree, options, cutoff, &files_seen));
+                    }
+                    result
+                })
+            })
+            .collect();
+        for handle in handles {
+            result.merge(handle.join().unwrap());
+        }
+    });
+
+    let mut dir_sizes: Vec<(PathBuf, u64)> = result.dir_sizes.into_iter().collect();
+    print_top(
+        "Largest directories",
+        &mut dir_sizes,
+        options.top,
+        exact_sizes,
+    );
+    print_top(
+        &format!(
+            "Large files not modified in {} days",
+            options.older_than.num_days()
+        ),
+        &mut result.old_large,
+        options.top,
+        exact_sizes,
+    );
+    print_top(
+        "Large uncompressed files which may compress well",
+        &mut result.compressible,
+        options.top,
+        exact_sizes,
+    );
+
+    let files_seen = files_seen.into_inner();
+    if files_seen > options.max_files {
+        eprintln!(
+            "Stopped scanning after {} files; results may be incomplete.",
+            options.max_files
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+//! Archiving datasets to S3-compatible object storage before they are deleted
+//!
+//! Uploads are done with the `aws` command line tool, which has to be
+//! configured with credentials for the bucket.  Each dataset is streamed to
+//! its own key below the configured prefix, so the bucket's lifecycle rules
+//! can be scoped by filesystem or user.
+
+use crate::{cli::ArchiveFormat, config, dry_run, error::Error, hooks, tarball, zfs};
+use chrono::{SecondsFormat, Utc};
+use std::process::{Command, Stdio};
+
+/// Runs the `aws` command line tool against the archive's endpoint
+fn aws(archive: &config::Archive) -> Command {
+    let mut command = Command::new("aws");
+    if let Some(endpoint_url) = &archive.endpoint_url {
+        command.args(["--endpoint-url", endpoint_url]);
+    }
+    command
+}
+
+/// Uploads the data of `workspace` to `archive`, returning where it went
+///
+/// The object's metadata records the workspace it came from, when it expired
+/// and when it was archived.  If the upload fails, the partial object is
+/// removed again.
+pub fn upload(archive: &config::Archive, workspace: &hooks::Workspace) -> Result<String, Error> {
+    let now = Utc::now();
+    let url = format!(
+        "{}/{}/{}/{}/{}.{}",
+        archive.url.trim_end_matches('/'),
+        workspace.filesystem_name,
+        workspace.user,
+        workspace.name,
+        now.format("%Y%m%dT%H%M%SZ"),
+        match archive.format {
+            ArchiveFormat::Zfs => "zfs",
+            ArchiveFormat::Tar => "tar.zst",
+        }
+    );
+    if dry_run::skip(format_args!("upload of {} to {}", workspace.volume, url)) {
+        return Ok(url);
+    }
+    let metadata = format!(
+        "filesystem={},user={},name={},expiration-time={},archived-time={},format={}",
+        workspace.filesystem_name,
+        workspace.user,
+        workspace.name,
+        workspace
+            .expiration_time
+            .to_rfc3339_opts(SecondsFormat::Secs, true),
+        now.to_rfc3339_opts(SecondsFormat::Secs, true),
+        archive.format
+    );
+    // needed by `aws` to split streams of more than 50 GB into enough parts
+    let size: usize = zfs::get_property(workspace.volume, "referenced")?;
+
+    // expired datasets are read-only, but might still be in use
+    let snapshot = format!("archive-{}", now.timestamp());
+    let mountpoint = match archive.format {
+        ArchiveFormat::Zfs => {
+            zfs::snapshot(workspace.volume, &snapshot)?;
+            None
+        }
+        ArchiveFormat::Tar => {
+            // an unmounted dataset would be archived as an empty directory
+            if zfs::get_property::<String>(workspace.volume, "mounted")? != "yes" {
+                return Err(Error::InvalidArgument(format!(
+                    "{} is not mounted",
+                    workspace.volume
+                )));
+            }
+            Some(zfs::get_path_property(workspace.volume, "mountpoint")?)
+        }
+    };
+
+    let mut upload = aws(archive);
+    upload
+        .args(["s3", "cp", "-", &url, "--only-show-errors"])
+        .args(["--expected-size", &size.to_string()])
+        .args(["--metadata", &metadata]);
+    if let Some(storage_class) = &archive.storage_class {
+        upload.args(["--storage-class", storage_class]);
+    }
+    let uploaded = upload
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(Error::from)
+        .and_then(|mut upload| {
+            let stdin = upload.stdin.take().unwrap();
+            let sent = match &mountpoint {
+                None => zfs::send(workspace.volume, &snapshot, stdin).map_err(Error::from),
+                Some(mountpoint) => tarball::export_as_root(mountpoint, stdin),
+            };
+            let status = upload.wait()?;
+            sent?;
+            if !status.success() {
+                return Err(Error::Command(format!("aws exited with {}", status)));
+            }
+            Ok(())
+        });
+    if mountpoint.is_none() {
+        zfs::destroy(&format!("{}@{}", workspace.volume, snapshot), false)?;
+    }
+    if let Err(e) = uploaded {
+        let _ = remove(Some(archive), &url);
+        return Err(e);
+    }
+    Ok(url)
+}
+
+/// Removes an object uploaded by [`upload`]
+pub fn remove(archive: Option<&config::Archive>, url: &str) -> Result<(), Error> {
+    let Some(archive) = archive else {
+        return Err(Error::InvalidArgument(format!(
+            "no archive is configured to remove {} from",
+            url
+        )));
+    };
+    if dry_run::skip(format_args!("removal of {}", url)) {
+        return Ok(());
+    }
+    let status = aws(archive)
+        .args(["s3", "rm", url, "--only-show-errors"])
+        .status()?;
+    if !status.success() {
+        return Err(Error::Command(format!("aws exited with {}", status)));
+    }
+    Ok(())
+}
@@ -0,0 +1,56 @@
+//! Audit trail of workspace changes in syslog's auth facility
+//!
+//! Changes are additionally kept in the database's `events` table, from which
+//! `workspaces stats --global` is computed.
+
+use crate::db::Connection;
+use crate::{db, dry_run, hooks, server::caller_uid};
+use chrono::{Local, SecondsFormat};
+use std::process;
+
+/// Logs a change to a workspace along with the UID of the user who caused it
+///
+/// Like hooks, this happens after the change has been made, so failing to log
+/// only results in a warning.
+pub fn log(conn: &Connection, event: hooks::Event, workspace: &hooks::Workspace) {
+    // nothing happened that could be audited
+    if dry_run::enabled() {
+        return;
+    }
+    if let Err(e) = conn.execute(
+        "INSERT INTO events (time, event, filesystem, user, name)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+        (
+            db::Timestamp::from(Local::now()),
+            event.to_string(),
+            workspace.filesystem_name,
+            workspace.user,
+            workspace.name,
+        ),
+    ) {
+        tracing::warn!("could not record the event in the database: {}", e);
+    }
+    let message = format!(
+        "action={} uid={} filesystem={} user={} name={} volume={} expiration_time={}",
+        event,
+        caller_uid(),
+        workspace.filesystem_name,
+        workspace.user,
+        workspace.name,
+        workspace.volume,
+        workspace
+            .expiration_time
+            .to_rfc3339_opts(SecondsFormat::Secs, true),
+    );
+    let result = syslog::unix(syslog::Formatter3164 {
+        facility: syslog::Facility::LOG_AUTH,
+        hostname: None,
+        process: "workspaces".into(),
+        pid: process::id(),
+    })
+    .map_err(|e| e.to_string())
+    .and_then(|mut logger| logger.notice(&message).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        tracing::warn!("could not write to the audit log: {}", e);
+    }
+}
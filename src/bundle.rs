@@ -0,0 +1,261 @@
+//! Bundling a workspace's data for publication
+
+use crate::{cli::ArchiveFormat, dry_run, error::Error, pool, zfs};
+use chrono::{DateTime, Datelike, Local, SecondsFormat};
+use nix::{
+    fcntl::{openat, OFlag},
+    sys::stat::Mode,
+    unistd::{unlinkat, UnlinkatFlags},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek},
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::{
+            fs::{fchown, OpenOptionsExt},
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    process::Command,
+};
+use walkdir::WalkDir;
+
+/// Metadata of the workspace being bundled
+pub struct Workspace<'a> {
+    pub filesystem_name: &'a str,
+    pub user: &'a str,
+    pub name: &'a str,
+    pub volume: &'a str,
+    pub mountpoint: &'a Path,
+    pub expiration_time: &'a DateTime<Local>,
+    pub class: &'a str,
+    pub comment: Option<&'a str>,
+    pub tags: &'a [String],
+}
+
+/// Where the bundle is written to and who it belongs to
+pub struct Output<'a> {
+    /// Only used in messages, files are created relative to `handle`
+    pub dir: &'a Path,
+    /// The directory as it was when its ownership was checked
+    pub handle: File,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Output<'_> {
+    /// Creates a new file in the output directory, owned by the caller
+    ///
+    /// Existing files and symlinks are never overwritten.
+    pub fn create(&self, file_name: &str) -> Result<(PathBuf, File), Error> {
+        let path = self.dir.join(file_name);
+        if file_name.contains('/') {
+            return Err(Error::InvalidArgument(format!(
+                "{} is not a file name",
+                file_name
+            )));
+        }
+        let fd = match openat(
+            Some(self.handle.as_raw_fd()),
+            file_name,
+            OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::from_bits_truncate(0o644),
+        ) {
+            Ok(fd) => fd,
+            Err(nix::errno::Errno::EEXIST) => {
+                return Err(Error::InvalidArgument(format!(
+                    "{} already exists",
+                    path.display()
+                )));
+            }
+            Err(e) => return Err(io::Error::from(e).into()),
+        };
+        // SAFETY: the descriptor was just opened and is owned by nobody else
+        let file = unsafe { File::from_raw_fd(fd) };
+        fchown(&file, Some(self.uid), Some(self.gid))?;
+        Ok((path, file))
+    }
+
+    /// Removes a file created by [`Output::create`]
+    pub fn remove(&self, file_name: &str) -> Result<(), Error> {
+        unlinkat(
+            Some(self.handle.as_raw_fd()),
+            file_name,
+            UnlinkatFlags::NoRemoveDir,
+        )
+        .map_err(io::Error::from)?;
+        Ok(())
+    }
+}
+
+/// Opens the regular file at `relative` below the directory `root`
+///
+/// Files are read as root, so no symlink is followed on the way, lest a file
+/// be swapped for a link to a file outside the workspace after the walk.
+fn open_beneath(root: &File, relative: &Path) -> io::Result<File> {
+    let components: Vec<_> = relative.components().collect();
+    let Some((file_name, parents)) = components.split_last() else {
+        return Err(io::Error::from(io::ErrorKind::NotFound));
+    };
+    let mut dir = None;
+    for parent in parents {
+        let fd = openat(
+            Some(dir.as_ref().unwrap_or(root).as_raw_fd()),
+            parent.as_os_str(),
+            OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        // SAFETY: the descriptor was just opened and is owned by nobody else
+        dir = Some(unsafe { File::from_raw_fd(fd) });
+    }
+    let fd = openat(
+        Some(dir.as_ref().unwrap_or(root).as_raw_fd()),
+        file_name.as_os_str(),
+        // don't block on a FIFO before it can be rejected
+        OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    // SAFETY: as above
+    let file = unsafe { File::from_raw_fd(fd) };
+    if !file.metadata()?.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is no longer a regular file", relative.display()),
+        ));
+    }
+    Ok(file)
+}
+
+fn sha256(mut reader: impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Writes a manifest of the workspace and, optionally, an archive of its data
+///
+/// The manifest lists the size and SHA-256 checksum of every file along with
+/// the metadata needed to describe the data set in a repository.
+pub fn write(
+    workspace: &Workspace,
+    output: &Output,
+    archive: Option<ArchiveFormat>,
+    parallelism: usize,
+) -> Result<(), Error> {
+    if dry_run::skip(format_args!(
+        "bundling of {} into {}",
+        workspace.volume,
+        output.dir.display()
+    )) {
+        return Ok(());
+    }
+    // fail before doing any work if a previous bundle is in the way
+    let (manifest_path, manifest_file) =
+        output.create(&format!("{}.manifest.json", workspace.name))?;
+
+    let root = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+        .open(workspace.mountpoint)?;
+    let mut paths: Vec<PathBuf> = vec![];
+    for entry in WalkDir::new(workspace.mountpoint) {
+        let entry = entry.map_err(io::Error::from)?;
+        if entry.file_type().is_file() {
+            paths.push(
+                entry
+                    .path()
+                    .strip_prefix(workspace.mountpoint)
+                    .unwrap()
+                    .to_path_buf(),
+            );
+        }
+    }
+    paths.sort();
+    let files = pool::map(&paths, parallelism, |path| {
+        let file = open_beneath(&root, path)?;
+        Ok::<_, io::Error>(serde_json::json!({
+            "path": path.to_string_lossy(),
+            "size": file.metadata()?.len(),
+            "sha256": sha256(file)?,
+        }))
+    })
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+    let total_size: u64 = files.iter().map(|f| f["size"].as_u64().unwrap()).sum();
+
+    let now = Local::now();
+    let mut manifest = serde_json::json!({
+        "title": workspace.name,
+        "creator": workspace.user,
+        "publication_year": now.year(),
+        "description": format!(
+            "Data set {} of {}, consisting of {} files with a total size of {} bytes.",
+            workspace.name,
+            workspace.user,
+            files.len(),
+            total_size
+        ),
+        "created": now.to_rfc3339_opts(SecondsFormat::Secs, true),
+        "workspace": {
+            "filesystem": workspace.filesystem_name,
+            "user": workspace.user,
+            "name": workspace.name,
+            "class": workspace.class,
+            "comment": workspace.comment,
+            "tags": workspace.tags,
+            "expiration_time": workspace.expiration_time.to_rfc3339_opts(SecondsFormat::Secs, true),
+        },
+        "total_size": total_size,
+        "files": files,
+    });
+
+    if let Some(format) = archive {
+        let (path, mut file) = match format {
+            ArchiveFormat::Tar => {
+                let (path, file) = output.create(&format!("{}.tar", workspace.name))?;
+                // only archive what the caller could have read themselves
+                let status = Command::new("tar")
+                    .arg("-C")
+                    .arg(workspace.mountpoint)
+                    .args(["-cf", "-", "."])
+                    .stdout(file.try_clone()?)
+                    .uid(output.uid)
+                    .gid(output.gid)
+                    .status()?;
+                if !status.success() {
+                    return Err(Error::Command(format!("tar exited with {}", status)));
+                }
+                (path, file)
+            }
+            ArchiveFormat::Zfs => {
+                let (path, file) = output.create(&format!("{}.zfs", workspace.name))?;
+                // send a consistent state, even if the workspace is in use
+                let snapshot = format!("bundle-{}", now.timestamp());
+                zfs::snapshot(workspace.volume, &snapshot)?;
+                let sent = zfs::send(workspace.volume, &snapshot, file.try_clone()?);
+                zfs::destroy(&format!("{}@{}", workspace.volume, snapshot), false)?;
+                sent?;
+                (path, file)
+            }
+        };
+        file.rewind()?;
+        manifest["archive"] = serde_json::json!({
+            "file": path.file_name().unwrap().to_string_lossy(),
+            "format": format.to_string(),
+            "size": file.metadata()?.len(),
+            "sha256": sha256(&file)?,
+        });
+        println!("Wrote {}", path.display());
+    }
+
+    serde_json::to_writer_pretty(manifest_file, &manifest).map_err(io::Error::from)?;
+    println!("Wrote {}", manifest_path.display());
+    Ok(())
+}
@@ -1,13 +1,55 @@
-use chrono::Duration;
-use clap::{Parser, Subcommand, ValueEnum};
-use std::{error::Error, fmt, num::ParseIntError};
-use users::get_current_username;
+use crate::{
+    config, exit_codes,
+    server::{caller_username, sudo_username},
+};
+use chrono::{Duration, NaiveDate};
+use clap::{parser::ValueSource, ArgAction, ArgMatches, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use std::{
+    error::Error,
+    ffi::{OsStr, OsString},
+    fmt,
+    num::ParseIntError,
+    path::PathBuf,
+    process,
+    sync::OnceLock,
+};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     #[command(subcommand)]
     pub command: Command,
+
+    /// Show sizes in bytes instead of human-readable units
+    #[arg(long, global = true)]
+    pub bytes: bool,
+
+    /// When to use colors
+    ///
+    /// With `auto`, colors are used if stdout is a terminal and `NO_COLOR` is not set.
+    #[arg(long, value_enum, global = true, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Log executed zfs commands to stderr
+    ///
+    /// Give twice for even more detail.  `RUST_LOG` takes precedence if set.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Only print the database changes and commands which would be run
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Don't ask for confirmation before destructive operations
+    #[arg(short, long, global = true)]
+    pub yes: bool,
+
+    /// Look up group memberships again instead of using cached ones
+    ///
+    /// Useful right after users were added to a group in LDAP.
+    #[arg(long, global = true)]
+    pub refresh_groups: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -21,19 +63,79 @@ pub enum Command {
         #[arg(value_parser = parse_pathsafe)]
         workspace_name: String,
 
-        /// Duration in days to extend the workspace to
+        /// Duration to extend the workspace to
         ///
+        /// Either a number of days or a number followed by one of the units
+        /// h(ours), d(ays), w(eeks), m(onths) or y(ears), e.g. `6w`.
         /// Must be less or equal to the DURATION given in `workspaces filesystems`.
-        #[arg(short, long, value_parser = |arg: &str| -> Result<Duration, ParseIntError> {Ok(Duration::days(arg.parse()?))})]
-        duration: Duration,
+        /// Defaults to the filesystem's default duration, if configured.
+        /// Not needed for reference workspaces.
+        #[arg(short, long, value_parser = parse_duration)]
+        duration: Option<Duration>,
 
         /// User the workspace belongs to
-        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
         user: String,
 
+        /// Kind of data the workspace holds
+        ///
+        /// Reference workspaces can only be created by admins.  They never
+        /// expire, are readable by everyone and are left alone by `clean`.
+        #[arg(long, value_enum, default_value_t = WorkspaceClass::Scratch)]
+        class: WorkspaceClass,
+
         /// Filesystem to create the workspace in
         #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
         filesystem_name: Option<String>,
+
+        /// Group the workspace belongs to
+        ///
+        /// Defaults to the group of the same name as the user.
+        #[arg(short, long, value_parser = parse_pathsafe)]
+        group: Option<String>,
+
+        /// Make the workspace writable for its group
+        ///
+        /// Sets the setgid bit and default ACLs, so that all files created
+        /// within the workspace belong to and are writable by the group.
+        #[arg(long, requires = "group")]
+        collaborative: bool,
+
+        /// Expected size of the workspace in GiB
+        ///
+        /// Passed on to the site's placement script, if one is configured.
+        #[arg(long, value_name = "SIZE")]
+        size_hint: Option<u64>,
+
+        /// Tag the workspace with TAG
+        ///
+        /// Can be specified multiple times
+        #[arg(short = 't', long = "tag", value_name = "TAG", value_parser = parse_pathsafe)]
+        tags: Vec<String>,
+
+        /// Describe what the workspace is for
+        #[arg(long)]
+        comment: Option<String>,
+
+        /// Project the workspace is created for
+        ///
+        /// The workspace counts towards the project's quota.  Unless another
+        /// group is given, it belongs to the project's group and is writable
+        /// by all its members.
+        #[arg(long, value_parser = parse_pathsafe)]
+        project: Option<String>,
+
+        /// Succeed if the workspace exists already instead of failing
+        ///
+        /// Meant for scripts which run repeatedly, such as job prologues.
+        /// Unless a filesystem is given, the workspace is looked for on all
+        /// filesystems.
+        #[arg(long)]
+        exist_ok: bool,
+
+        /// Extend the workspace to DURATION if it exists already
+        #[arg(long, requires = "exist_ok")]
+        extend: bool,
     },
     /// Rename an already existing workspace
     #[clap(alias = "mv")]
@@ -49,7 +151,7 @@ pub enum Command {
         dest_workspace_name: String,
 
         /// User the workspace belongs to
-        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
         user: String,
 
         /// Filesystem to create the workspace in
@@ -71,41 +173,370 @@ pub enum Command {
         #[arg(short = 'f', long = "filesytem", value_name = "FILESYSTEM", value_parser = parse_pathsafe)]
         filter_filesystems: Option<Vec<String>>,
 
+        /// Only show workspaces tagged TAG
+        ///
+        /// Can be specified multiple times
+        #[arg(short = 't', long = "tag", value_name = "TAG", value_parser = parse_pathsafe)]
+        filter_tags: Option<Vec<String>>,
+
+        /// Only show workspaces of PROJECT
+        ///
+        /// Can be specified multiple times
+        #[arg(short = 'p', long = "project", value_name = "PROJECT", value_parser = parse_pathsafe)]
+        filter_projects: Option<Vec<String>>,
+
+        /// Only show workspaces which expire within the next DAYS days
+        #[arg(long, value_name = "DAYS", value_parser = |arg: &str| -> Result<Duration, ParseIntError> {Ok(Duration::days(arg.parse()?))})]
+        expiring_within: Option<Duration>,
+
+        /// Only show expired workspaces
+        #[arg(long, conflicts_with_all = ["active", "expiring_within"])]
+        expired: bool,
+
+        /// Only show workspaces which have not expired yet
+        #[arg(long)]
+        active: bool,
+
         /// Columns to display
         ///
         /// Can be specified multiple times
         #[arg(short, long, value_name = "COLUMN")]
         output: Option<Vec<WorkspacesColumns>>,
+
+        /// Append the total size and number of workspaces, optionally with
+        /// subtotals per user or filesystem
+        #[arg(long, value_name = "BY", num_args = 0..=1, default_missing_value = "total")]
+        summary: Option<Summary>,
+
+        /// Query sizes from zfs instead of using the ones cached by the last clean
+        ///
+        /// Sizes cached more than twice the daemon's `clean_interval` ago are
+        /// always queried from zfs, as clean has apparently stopped running.
+        #[arg(long)]
+        fresh: bool,
     },
     /// Postpone the expiry date of an already existing workspace
     #[clap(alias = "ex")]
     Extend {
+        /// Names of the workspaces
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "pattern")]
+        names: Vec<String>,
+
+        /// Also extend all workspaces whose name matches PATTERN
+        ///
+        /// `*` matches any part of a name.
+        /// Unless `--yes` is given, the selected workspaces are listed for
+        /// confirmation first.
+        #[arg(long = "match", value_name = "PATTERN")]
+        pattern: Option<String>,
+
+        /// Only print the names of the selected workspaces
+        #[arg(long, hide = true)]
+        preview: bool,
+
+        /// Duration to extend the workspace until
+        ///
+        /// Either a number of days or a number followed by one of the units
+        /// h(ours), d(ays), w(eeks), m(onths) or y(ears), e.g. `6w`.
+        /// If this is shorter than the time until expiry, no action will be taken.
+        /// Defaults to the filesystem's default duration, if configured.
+        #[arg(short, long, value_parser = parse_duration)]
+        duration: Option<Duration>,
+
+        /// Extend the workspace until the end of DATE, given as YYYY-MM-DD
+        ///
+        /// Like `--duration`, this must lie within the filesystem's maximum duration.
+        #[arg(long, value_name = "DATE", conflicts_with = "duration")]
+        until: Option<NaiveDate>,
+
+        /// Raise the workspace's quota to SIZE
+        ///
+        /// Either a number of GiB or a number followed by one of the units
+        /// K, M, G, T or P.  Workspaces without a quota are left unlimited.
+        #[arg(short, long, value_name = "SIZE", value_parser = parse_size)]
+        quota: Option<u64>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+    },
+    /// Suggest ways to trim down a workspace
+    ///
+    /// Lists the largest directories, large files which have not been
+    /// modified in a while and large files which could be compressed.
+    Advise {
         /// Name of the workspace
         #[arg(value_parser = parse_pathsafe)]
         name: String,
 
-        /// Duration in days to extend the workspace until
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Number of entries to show per category
+        #[arg(short = 'n', long, default_value_t = 10)]
+        top: usize,
+
+        /// Consider files not modified in DAYS days as old
+        #[arg(long, value_name = "DAYS", default_value = "90", value_parser = |arg: &str| -> Result<Duration, ParseIntError> {Ok(Duration::days(arg.parse()?))})]
+        older_than: Duration,
+
+        /// Ignore files smaller than SIZE MiB
+        #[arg(long, value_name = "SIZE", default_value_t = 100)]
+        min_size: u64,
+
+        /// Stop scanning after this many files
+        #[arg(long, value_name = "FILES", default_value_t = 1_000_000)]
+        max_files: usize,
+    },
+    /// Show, add or remove the tags of a workspace
+    Tag {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// Tags to add as `+TAG` or remove as `-TAG`
+        #[arg(allow_hyphen_values = true)]
+        changes: Vec<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+    },
+    /// Show or set the comment of a workspace
+    Comment {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// New comment; an empty one removes the current comment
+        comment: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+    },
+    /// Find workspaces by name
+    Find {
+        /// Pattern the names have to match, in which `*` matches any characters
+        pattern: String,
+
+        /// Interpret PATTERN as regular expression matching any part of the name
+        #[arg(short = 'E', long)]
+        regex: bool,
+
+        /// Only search the workspaces of USER
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Search the workspaces of all users (root only)
+        #[arg(short, long, conflicts_with = "user")]
+        all_users: bool,
+
+        /// Columns to display
         ///
-        /// If this is fewer than the current days until expiry,
-        /// no action will be taken.
-        #[arg(short, long, value_parser = |arg: &str| -> Result<Duration, ParseIntError> {Ok(Duration::days(arg.parse()?))})]
-        duration: Duration,
+        /// Can be specified multiple times
+        #[arg(short, long, value_name = "COLUMN")]
+        output: Option<Vec<WorkspacesColumns>>,
+    },
+    /// Print the mountpoint of a workspace
+    ///
+    /// Meant for scripts, e.g. `cd $(workspaces path mydata)`.
+    Path {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
 
         /// User the workspace belongs to
-        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
         user: String,
 
         /// Filesystem of the workspace
         #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
         filesystem_name: Option<String>,
     },
-    /// Expire a workspace
-    Expire {
+    /// Mount a workspace, e.g. to browse an expired one that was unmounted
+    Mount {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+    },
+    /// Unmount a workspace to temporarily take it offline
+    ///
+    /// The workspace is mounted again by `mount`, or when the system boots.
+    Umount {
         /// Name of the workspace
         #[arg(value_parser = parse_pathsafe)]
         name: String,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+    },
+    /// Show the largest directories within a workspace
+    Du {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// Show directories up to DEPTH levels below the workspace's root
+        #[arg(short, long, default_value_t = 1)]
+        depth: usize,
+
+        /// Only show the COUNT largest directories
+        #[arg(short = 'n', long, value_name = "COUNT", default_value_t = 20)]
+        top: usize,
+
         /// User the workspace belongs to
-        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+    },
+    /// Show everything known about a workspace
+    ///
+    /// This includes its sizes, snapshots, important dates and its most recent
+    /// events, like being created or extended.
+    Info {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+    },
+    /// Bundle a workspace's data for publication
+    ///
+    /// Writes a manifest with the size and checksum of every file and the
+    /// workspace's metadata to DIR, which has to belong to you.  Optionally,
+    /// an archive of the data is written alongside it.
+    Bundle {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Directory to write the bundle to
+        #[arg(long, value_name = "DIR")]
+        out: PathBuf,
+
+        /// Also write an archive of the data
+        ///
+        /// A zfs stream contains every file regardless of its permissions,
+        /// so only admins may write one.
+        #[arg(long, value_name = "FORMAT")]
+        archive: Option<ArchiveFormat>,
+    },
+    /// Export a workspace's data to a tarball
+    ///
+    /// The tarball is compressed with zstd and can be unpacked with GNU tar
+    /// on any machine, or be imported into a new workspace with `workspaces
+    /// import`.  FILE must not exist yet and its directory has to belong to
+    /// you.
+    Export {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// File to write the tarball to, e.g. `testws.tar.zst`
+        #[arg(long, value_name = "FILE")]
+        to: PathBuf,
+    },
+    /// Show the changes made to a workspace since one of its snapshots
+    Diff {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// Name of the snapshot to compare against
+        snapshot: String,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// List every changed file instead of only counting them
+        #[arg(short, long)]
+        list: bool,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Expire a workspace
+    Expire {
+        /// Names of the workspaces
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "pattern")]
+        names: Vec<String>,
+
+        /// Also expire all workspaces whose name matches PATTERN
+        ///
+        /// `*` matches any part of a name.
+        /// Unless `--yes` is given, the selected workspaces are listed for
+        /// confirmation first.
+        #[arg(long = "match", value_name = "PATTERN")]
+        pattern: Option<String>,
+
+        /// Only print the names of the selected workspaces
+        #[arg(long, hide = true)]
+        preview: bool,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
         user: String,
 
         /// Filesystem of the workspace
@@ -119,6 +550,82 @@ pub enum Command {
         #[arg(long = "terminally")]
         delete_on_next_clean: bool,
     },
+    /// Stop managing a workspace without destroying its dataset
+    ///
+    /// The dataset is kept as it is, but no longer listed, extended, expired or
+    /// cleaned.  Use `workspaces adopt` to manage it again.
+    Release {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+    },
+    /// Lock a workspace, e.g. pending an investigation or for a legal hold
+    ///
+    /// Locked workspaces cannot be extended, renamed or bundled, and are
+    /// never deleted by `clean`, until they are unlocked again.
+    Lock {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Why the workspace is locked, shown to its owner
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Unlock a workspace locked by `lock`
+    Unlock {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+    },
+    /// Exempt a workspace from `clean`, regardless of its expiration
+    ///
+    /// Meant for datasets which are managed as workspaces but must never be
+    /// reaped, such as reference genomes or shared models.
+    Exempt {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Only exempt the workspace until the end of DATE, given as YYYY-MM-DD
+        #[arg(long, value_name = "DATE")]
+        until: Option<NaiveDate>,
+
+        /// Revoke the workspace's exemption
+        #[arg(long, conflicts_with = "until")]
+        revoke: bool,
+    },
     /// List all existing filesystems
     #[clap(alias = "fi")]
     Filesystems {
@@ -127,12 +634,483 @@ pub enum Command {
         /// Can be specified multiple times
         #[arg(short, long, value_name = "COLUMN")]
         output: Option<Vec<FilesystemsColumns>>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+
+        /// Include each filesystem's policy in JSON output
+        #[arg(long)]
+        include_policy: bool,
+    },
+    /// List workspaces on filesystems which are missing from the configuration
+    ///
+    /// These are marked as UNKNOWN-FS in `workspaces list`.  They are neither
+    /// extended nor cleaned until their filesystem is configured again.
+    Orphans,
+    /// Check the owners of all workspaces against the passwd database
+    ///
+    /// Owners who no longer exist are listed as renamed, if their workspaces'
+    /// files belong to a user of another name, or as deleted otherwise.
+    AuditUsers {
+        /// Transfer the workspaces of renamed owners to their new names
+        #[arg(long)]
+        reassign_renamed: bool,
+
+        /// Expire the workspaces of deleted owners
+        #[arg(long)]
+        expire_deleted: bool,
+    },
+    /// Find inconsistencies between the database and ZFS
+    ///
+    /// Reports datasets below the filesystems' roots which are not in the
+    /// database, e.g. because they were created by hand, and workspaces in the
+    /// database whose dataset is gone, e.g. because it was destroyed by hand.
+    /// Datasets given up with `workspaces release` are not reported.  On
+    /// filesystems with `sharenfs` configured, NFS exports which differ from
+    /// it are reported as well.
+    Fsck {
+        /// Adopt untracked datasets, forget workspaces without a dataset and
+        /// restore drifted exports
+        ///
+        /// Adopted datasets get the filesystem's default or, lacking one,
+        /// maximum duration.
+        #[arg(long)]
+        fix: bool,
+
+        /// Destroy untracked datasets instead of adopting them
+        #[arg(long, requires = "fix")]
+        destroy_untracked: bool,
+    },
+    /// Turn an existing dataset into a workspace
+    ///
+    /// The dataset has to be at `<root>/<USER>/<NAME>` below a filesystem's
+    /// root.  It is only registered as a workspace, so its data, mountpoint
+    /// and exports are left as they are.
+    Adopt {
+        /// Dataset to adopt, whose last component becomes the workspace's name
+        dataset: String,
+
+        /// User the workspace belongs to
+        #[arg(short, long, value_parser = parse_pathsafe, required_unless_present = "recursive")]
+        user: Option<String>,
+
+        /// Duration until the workspace expires
+        ///
+        /// Either a number of days or a number followed by one of the units
+        /// h(ours), d(ays), w(eeks), m(onths) or y(ears), e.g. `6w`.
+        #[arg(short, long, value_parser = parse_duration)]
+        duration: Duration,
+
+        /// Adopt all datasets at `<root>/<USER>/<NAME>` below DATASET instead
+        ///
+        /// Each dataset is assigned to the user in its path.  Datasets which
+        /// already are workspaces or were released are skipped.
+        #[arg(short, long, conflicts_with = "user")]
+        recursive: bool,
+
+        /// Project the workspaces are adopted for, whose quota they count towards
+        #[arg(long, value_parser = parse_pathsafe)]
+        project: Option<String>,
     },
     /// Clean up workspaces which not been extended in a while
     ///
     /// This will delete all workspaces marked as `deleted soon` in `workspaces list`,
-    /// including other users' workspaces.
-    Clean,
+    /// including other users' workspaces.  A summary of destroyed, newly
+    /// expired and failed workspaces is printed once done.
+    Clean {
+        /// Additionally write the clean summary to syslog
+        #[arg(long)]
+        syslog: bool,
+
+        /// Destroy workspaces' snapshots even on filesystems with
+        /// `destroy_recursive = false`
+        #[arg(long)]
+        with_snapshots: bool,
+    },
+    /// Periodically clean up workspaces and send reminders
+    ///
+    /// An alternative to running `clean` from a timer.  The intervals are set
+    /// in the `[daemon]` section of the configuration file.
+    Daemon,
+    /// Serve requests from unprivileged users over a Unix socket
+    ///
+    /// Runs commands on behalf of the connecting users, so the workspaces
+    /// binary does not have to be installed setuid-root.
+    Serve,
+    /// Show how the size of each workspace changed over the last day
+    ///
+    /// Sizes are sampled by `clean`, and kept for 90 days.
+    Report {
+        /// Only show workspaces which doubled in size or were emptied
+        #[arg(long)]
+        anomalies: bool,
+
+        /// Show each group's consumption of its storage share instead
+        #[arg(long, conflicts_with = "anomalies")]
+        shares: bool,
+
+        /// Show each project's consumption of its quota instead
+        #[arg(long, conflicts_with_all = ["anomalies", "shares"])]
+        projects: bool,
+
+        /// Show the storage consumed from the start of DATE instead, in GiB-days
+        /// per user, project and filesystem
+        #[arg(
+            long,
+            value_name = "DATE",
+            requires = "to",
+            conflicts_with_all = ["anomalies", "shares", "projects"]
+        )]
+        from: Option<NaiveDate>,
+
+        /// End of the accounting period, including DATE itself
+        #[arg(long, value_name = "DATE", requires = "from")]
+        to: Option<NaiveDate>,
+
+        /// Output format of the consumed storage
+        #[arg(long, default_value = "table", requires = "from")]
+        format: AccountingFormat,
+    },
+    /// Show how workspaces grew over the sizes sampled by `clean`
+    ///
+    /// Workspaces which grew by more than the threshold since the previous
+    /// sample are highlighted.
+    Stats {
+        /// Only show the workspace NAME
+        #[arg(value_parser = parse_pathsafe, conflicts_with = "global")]
+        name: Option<String>,
+
+        /// Show statistics of all workspaces instead, for capacity planning
+        ///
+        /// These are the number of workspaces created, extended, expired and
+        /// destroyed per week, the median lifetime of destroyed workspaces and
+        /// the distribution of the current workspaces' sizes.
+        #[arg(long)]
+        global: bool,
+
+        /// Number of weeks to show with `--global`
+        #[arg(long, default_value_t = 12, requires = "global")]
+        weeks: u32,
+
+        /// User the workspaces belong to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Only show workspaces on FILESYSTEM
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Highlight workspaces which grew by more than SIZE since the previous sample
+        ///
+        /// Either a number of GiB or a number followed by one of the units
+        /// K, M, G, T or P.
+        #[arg(long, value_name = "SIZE", default_value = "10", value_parser = parse_size)]
+        threshold: u64,
+    },
+    /// Show the number and size of each user's workspaces
+    ///
+    /// Users exceeding their filesystem's limit are highlighted.
+    Usage {
+        /// Only show USER
+        ///
+        /// Can be specified multiple times
+        #[arg(short = 'u', long = "user", value_name = "USER", value_parser = parse_pathsafe)]
+        filter_users: Option<Vec<String>>,
+    },
+    /// Manage notifications about expiring workspaces
+    Notify {
+        #[command(subcommand)]
+        command: NotifyCommand,
+    },
+    /// Administrative commands
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Manage the database
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Restore a tarball made by `export` into a new workspace, or take over
+    /// workspaces managed by other tools
+    ///
+    /// The tarball has to belong to you.
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Import {
+        #[command(subcommand)]
+        command: Option<ImportCommand>,
+
+        /// Tarball written by `workspaces export`
+        #[arg(required = true)]
+        file: Option<PathBuf>,
+
+        /// Name of the new workspace
+        #[arg(long, required = true, value_parser = parse_pathsafe)]
+        name: Option<String>,
+
+        /// User the new workspace belongs to
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem to create the workspace in
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Duration to create the workspace for
+        ///
+        /// Defaults to the filesystem's default duration, if configured.
+        #[arg(short, long, value_parser = parse_duration)]
+        duration: Option<Duration>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ImportCommand {
+    /// Import the workspaces of the HPC workspace tool `ws_allocate`
+    ///
+    /// Each workspace which has not expired yet is created anew with the same
+    /// user, name and expiration time.  Their data is not copied; use
+    /// `--adopt` if the workspaces already are ZFS datasets below the
+    /// filesystem's root.  Entries which cannot be imported are reported and
+    /// skipped.
+    WsAllocate {
+        /// One of ws_allocate's database directories or its `ws.conf`
+        path: PathBuf,
+
+        /// Filesystem to create the workspaces on
+        #[arg(short, long = "filesystem", value_parser = parse_pathsafe)]
+        filesystem_name: Option<String>,
+
+        /// Adopt the datasets mounted at the workspaces' paths instead of
+        /// creating new ones
+        #[arg(long)]
+        adopt: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// Copy the database to a new file
+    ///
+    /// The database stays usable while the copy is made, so there is no need
+    /// to stop the server or daemon.
+    Backup {
+        /// File to write the copy to
+        path: PathBuf,
+    },
+    /// Replace the database with a backup
+    ///
+    /// All changes since the backup was made are lost.  Neither workspaces
+    /// created since nor ones destroyed since are touched on ZFS.
+    Restore {
+        /// Backup made by `workspaces db backup`
+        path: PathBuf,
+    },
+    /// Check the database for corruption and inconsistencies with ZFS
+    ///
+    /// Runs SQLite's integrity check and makes sure a dataset exists for each
+    /// workspace in the database.
+    Check {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Update the database schema
+    ///
+    /// This normally happens automatically whenever workspaces is run.  Each
+    /// step is applied on its own, so an interrupted migration can simply be
+    /// repeated.  Use the global `--dry-run` to see what would be changed.
+    Migrate {
+        /// Schema version to migrate to instead of the newest one
+        #[arg(long, value_name = "VERSION")]
+        to: Option<usize>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Check the configuration for mistakes
+    ///
+    /// Verifies that each filesystem's root exists, that the database only
+    /// refers to configured filesystems and that all settings are sensible.
+    Check,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum NotifyCommand {
+    /// Show or change when and how you are notified
+    ///
+    /// Prints the current settings if no changes are requested.  If a
+    /// workspace is given, its notifications can be sent to another address
+    /// or be turned off, overriding your settings for it.
+    Config {
+        /// Workspace to configure notifications for
+        #[arg(value_parser = parse_pathsafe)]
+        name: Option<String>,
+
+        /// Days before expiry at which to send a reminder
+        ///
+        /// Separated by commas, e.g. `--days 14,3,1`.
+        #[arg(
+            short,
+            long,
+            value_delimiter = ',',
+            value_name = "DAYS",
+            conflicts_with = "name"
+        )]
+        days: Option<Vec<i64>>,
+
+        /// Channel to send notifications over
+        #[arg(short, long)]
+        channel: Option<config::Channel>,
+
+        /// Address to mail the workspace's notifications to
+        #[arg(long, requires = "name", value_parser = parse_email)]
+        email: Option<String>,
+
+        /// Reset to the system defaults, or to your settings for a workspace
+        #[arg(long, conflicts_with_all = ["days", "channel", "email"])]
+        reset: bool,
+
+        /// User to configure notifications for
+        #[arg(short, long, default_value_os = default_user(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(
+            short,
+            long = "filesystem",
+            value_name = "FILESYSTEM",
+            requires = "name"
+        )]
+        filesystem_name: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AdminCommand {
+    /// Transfer or expire all workspaces whose owner no longer exists
+    ///
+    /// These are the workspaces marked as ORPHAN-OWNER in `workspaces list`.
+    ReassignOrphans {
+        /// Transfer the workspaces to USER
+        #[arg(long, value_name = "USER", value_parser = parse_pathsafe, required_unless_present = "expire")]
+        to: Option<String>,
+
+        /// Expire the workspaces
+        #[arg(long, conflicts_with = "to")]
+        expire: bool,
+
+        /// Only consider workspaces on FILESYSTEM
+        ///
+        /// Can be specified multiple times
+        #[arg(short = 'f', long = "filesystem", value_name = "FILESYSTEM", value_parser = parse_pathsafe)]
+        filter_filesystems: Option<Vec<String>>,
+    },
+    /// Rename all of a user's workspaces matching a pattern
+    ///
+    /// Each workspace is renamed on its own, so an interrupted run can simply
+    /// be repeated to rename the remaining workspaces.
+    BulkRename {
+        /// Owner of the workspaces
+        #[arg(short, long, value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Names to rename, with a single `*` matching any part of the name
+        #[arg(long = "match", value_name = "PATTERN")]
+        pattern: String,
+
+        /// New names, with `*` replaced by the part matched in PATTERN
+        #[arg(long = "replace", value_name = "REPLACEMENT")]
+        replacement: String,
+
+        /// Only rename workspaces on FILESYSTEM
+        ///
+        /// Can be specified multiple times
+        #[arg(short = 'f', long = "filesystem", value_name = "FILESYSTEM", value_parser = parse_pathsafe)]
+        filter_filesystems: Option<Vec<String>>,
+    },
+    /// Move workspaces to another filesystem, e.g. to relieve a full one
+    ///
+    /// The smallest workspaces are moved first, using zfs send and receive.
+    /// Each workspace is read-only while it is being copied, so jobs still
+    /// writing to it will fail.
+    Rebalance {
+        /// Filesystem to move workspaces from
+        #[arg(long, value_name = "FILESYSTEM")]
+        from: String,
+
+        /// Filesystem to move workspaces to
+        #[arg(long, value_name = "FILESYSTEM")]
+        to: String,
+
+        /// Move at most SIZE in total
+        ///
+        /// Either a number of GiB or a number followed by one of the units
+        /// K, M, G, T or P.
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        max_size: Option<u64>,
+    },
+    /// List workspaces in the trash
+    Trash {
+        /// Only show workspaces on FILESYSTEM
+        ///
+        /// Can be specified multiple times
+        #[arg(short = 'f', long = "filesystem", value_name = "FILESYSTEM", value_parser = parse_pathsafe)]
+        filter_filesystems: Option<Vec<String>>,
+    },
+    /// Restore a workspace from the trash
+    ///
+    /// The workspace is restored in an expired state.  Extend it to make it
+    /// writable again.
+    Untrash {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// User the workspace belongs to
+        #[arg(short, long, value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+    },
+    /// Turn a scratch workspace into a reference workspace
+    ///
+    /// Reference workspaces are read-only from their creation on, so they are
+    /// filled as scratch workspaces and promoted once complete.  The
+    /// workspace then never expires and is readable by everyone.
+    Promote {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// User the workspace belongs to
+        #[arg(short, long, value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+    },
+    /// Summarize command latencies
+    ///
+    /// Besides latencies, the number of zfs calls and database rows changed by
+    /// each invocation are shown.  They are only recorded if `metrics` is
+    /// enabled in the configuration.
+    Perf {
+        /// Only consider invocations in the last DAYS days
+        #[arg(short, long = "days", value_name = "DAYS", default_value = "30", value_parser = |arg: &str| -> Result<Duration, ParseIntError> {Ok(Duration::days(arg.parse()?))})]
+        since: Duration,
+    },
 }
 
 #[derive(Clone, Debug, ValueEnum)]
@@ -143,12 +1121,24 @@ pub enum WorkspacesColumns {
     User,
     /// Filesystem the workspace is on
     Fs,
-    /// Size of the workspace in GiB
+    /// Size of the workspace
     Size,
     /// Days until expiry / deletion
     Expiry,
     /// Mountpoint of the workspace
     Mountpoint,
+    /// Tags of the workspace
+    Tags,
+    /// What the workspace is for
+    Comment,
+    /// Host serving the workspace's filesystem
+    Host,
+    /// Project the workspace was created for
+    Project,
+    /// Space used by the workspace's snapshots
+    Snapshots,
+    /// Compression ratio of the workspace
+    Compress,
 }
 
 impl fmt::Display for WorkspacesColumns {
@@ -163,12 +1153,96 @@ impl fmt::Display for WorkspacesColumns {
                 WorkspacesColumns::Size => "SIZE",
                 WorkspacesColumns::Expiry => "EXPIRY",
                 WorkspacesColumns::Mountpoint => "MOUNTPOINT",
+                WorkspacesColumns::Tags => "TAGS",
+                WorkspacesColumns::Comment => "COMMENT",
+                WorkspacesColumns::Host => "HOST",
+                WorkspacesColumns::Project => "PROJECT",
+                WorkspacesColumns::Snapshots => "SNAPSHOTS",
+                WorkspacesColumns::Compress => "COMPRESS",
             }
         )
     }
 }
 
-#[derive(Clone, Debug, ValueEnum)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum WorkspaceClass {
+    /// Ephemeral data which expires unless extended
+    Scratch,
+    /// Shared, admin-managed data such as genome indexes or model weights
+    Reference,
+}
+
+impl fmt::Display for WorkspaceClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                WorkspaceClass::Scratch => "scratch",
+                WorkspaceClass::Reference => "reference",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Summary {
+    /// Only the grand total
+    Total,
+    /// Subtotals per user
+    User,
+    /// Subtotals per filesystem
+    Filesystem,
+    /// Subtotals per project
+    Project,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    /// A tar archive of the workspace's files
+    Tar,
+    /// A zfs replication stream, which can be restored with `zfs receive`
+    Zfs,
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ArchiveFormat::Tar => "tar",
+                ArchiveFormat::Zfs => "zfs",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable table
+    Table,
+    /// Machine-readable JSON
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum AccountingFormat {
+    /// Human-readable table
+    Table,
+    /// Comma-separated values, e.g. for spreadsheets or billing systems
+    Csv,
+}
+
+#[derive(Clone, Debug, PartialEq, ValueEnum)]
 pub enum FilesystemsColumns {
     /// Name of the filesystem
     Name,
@@ -182,6 +1256,12 @@ pub enum FilesystemsColumns {
     Duration,
     /// Number of days a read-only copy of a workspace is retained after expiry
     Retention,
+    /// Number of workspaces on this filesystem
+    Workspaces,
+    /// Number of workspaces expiring within the next 7 days
+    Expiring,
+    /// Health of the ZFS pool backing this filesystem
+    Health,
 }
 
 impl fmt::Display for FilesystemsColumns {
@@ -196,6 +1276,9 @@ impl fmt::Display for FilesystemsColumns {
                 FilesystemsColumns::Total => "TOTAL",
                 FilesystemsColumns::Duration => "DURATION",
                 FilesystemsColumns::Retention => "RETENTION",
+                FilesystemsColumns::Workspaces => "WORKSPACES",
+                FilesystemsColumns::Expiring => "EXPIRING",
+                FilesystemsColumns::Health => "HEALTH",
             }
         )
     }
@@ -203,7 +1286,7 @@ impl fmt::Display for FilesystemsColumns {
 
 /// String contains characters which are not [A-Za-z0-9_-]
 #[derive(Debug)]
-struct NotPathsafeError {
+pub(crate) struct NotPathsafeError {
     str: String,
 }
 impl fmt::Display for NotPathsafeError {
@@ -217,8 +1300,138 @@ impl fmt::Display for NotPathsafeError {
 }
 impl Error for NotPathsafeError {}
 
+/// How the default for `--user` is chosen under sudo
+static SUDO_USER_POLICY: OnceLock<config::SudoUser> = OnceLock::new();
+
+/// Sets how the default for `--user` is chosen under sudo
+///
+/// Has to be called before the arguments are parsed.
+pub fn set_sudo_user_policy(policy: config::SudoUser) {
+    let _ = SUDO_USER_POLICY.set(policy);
+}
+
+/// The name of the calling user, used as default for `--user`
+///
+/// Under sudo, this is the invoking user unless configured otherwise.  Names
+/// which are not valid UTF-8 are passed on as-is, so they are rejected with a
+/// parse error rather than a panic.
+pub(crate) fn default_user() -> &'static OsStr {
+    static USER: OnceLock<OsString> = OnceLock::new();
+    USER.get_or_init(|| {
+        let sudo_user = match SUDO_USER_POLICY.get() {
+            Some(config::SudoUser::Invoker) | None => sudo_username(),
+            Some(_) => None,
+        };
+        sudo_user.or_else(caller_username).unwrap_or_default()
+    })
+}
+
+/// Checks that `--user` was given explicitly if required under sudo
+///
+/// Exits if it was omitted.
+pub fn check_sudo_user(matches: &ArgMatches) {
+    if SUDO_USER_POLICY.get() != Some(&config::SudoUser::Require) || sudo_username().is_none() {
+        return;
+    }
+    // the user argument belongs to the innermost subcommand
+    let mut matches = matches;
+    while let Some((_, sub_matches)) = matches.subcommand() {
+        matches = sub_matches;
+    }
+    if matches.try_contains_id("user").is_ok()
+        && matches.value_source("user") == Some(ValueSource::DefaultValue)
+    {
+        eprintln!("Running through sudo; please specify the user to act on behalf of with --user");
+        process::exit(exit_codes::INVALID_ARGUMENT);
+    }
+}
+
+/// Longest duration accepted on the command line, in days
+///
+/// Far beyond any sensible expiration, but small enough to never overflow
+/// when added to a date.
+const MAX_DURATION_DAYS: i64 = 100 * 365;
+
+/// Parses a duration such as `90`, `12h`, `90d`, `6w`, `3m` or `1y`
+///
+/// Plain numbers are days.  Months count as 30 and years as 365 days, so that
+/// e.g. `3m` stays within a maximum duration of 90 days.
+pub(crate) fn parse_duration(arg: &str) -> Result<Duration, String> {
+    let split = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    let (number, unit) = arg.split_at(split);
+    let number: i64 = number
+        .parse::<u32>()
+        .map_err(|e| format!("invalid duration `{}`: {}", arg, e))?
+        .into();
+    let hours_per_unit = match unit {
+        "h" => 1,
+        "" | "d" => 24,
+        "w" => 7 * 24,
+        "m" => 30 * 24,
+        "y" => 365 * 24,
+        _ => {
+            return Err(format!(
+                "unknown unit `{}`, expected one of h, d, w, m or y",
+                unit
+            ))
+        }
+    };
+    number
+        .checked_mul(hours_per_unit)
+        .filter(|hours| *hours <= MAX_DURATION_DAYS * 24)
+        .map(Duration::hours)
+        .ok_or_else(|| {
+            format!(
+                "duration `{}` is longer than {} years",
+                arg,
+                MAX_DURATION_DAYS / 365
+            )
+        })
+}
+
+/// Checks that a mail address is safe to put into a mail's header
+fn parse_email(arg: &str) -> Result<String, String> {
+    match arg.split_once('@') {
+        Some((local, domain))
+            if !local.is_empty()
+                && !domain.is_empty()
+                && !arg.contains(|c: char| c.is_whitespace() || c.is_control() || c == ',') =>
+        {
+            Ok(arg.to_string())
+        }
+        _ => Err(format!("invalid mail address `{}`", arg)),
+    }
+}
+
+/// Parses a size such as `500`, `500G` or `2T` into bytes
+///
+/// Plain numbers are GiB, like the sizes in the configuration.
+pub(crate) fn parse_size(arg: &str) -> Result<u64, String> {
+    let split = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    let (number, unit) = arg.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|e| format!("invalid size `{}`: {}", arg, e))?;
+    let shift = match unit {
+        "K" => 10,
+        "M" => 20,
+        "" | "G" => 30,
+        "T" => 40,
+        "P" => 50,
+        _ => {
+            return Err(format!(
+                "unknown unit `{}`, expected one of K, M, G, T or P",
+                unit
+            ))
+        }
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size `{}` is too large", arg))
+}
+
 /// Ensures string only contains the characters [A-Za-z0-9_-]
-fn parse_pathsafe(ident: &str) -> Result<String, NotPathsafeError> {
+pub(crate) fn parse_pathsafe(ident: &str) -> Result<String, NotPathsafeError> {
     if !ident.is_empty()
         && ident
             .chars()
@@ -231,3 +1444,90 @@ fn parse_pathsafe(ident: &str) -> Result<String, NotPathsafeError> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::format_size;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("500"), Ok(500 << 30));
+        assert_eq!(parse_size("1K"), Ok(1024));
+        assert_eq!(parse_size("3M"), Ok(3 << 20));
+        assert_eq!(parse_size("2T"), Ok(2 << 40));
+        assert_eq!(parse_size("0P"), Ok(0));
+    }
+
+    #[test]
+    fn size_round_trips() {
+        for (arg, formatted) in [
+            ("1K", "1.0K"),
+            ("12M", "12.0M"),
+            ("500G", "500.0G"),
+            ("2T", "2.0T"),
+            ("7P", "7.0P"),
+        ] {
+            let bytes = parse_size(arg).unwrap();
+            assert_eq!(format_size(bytes, false), formatted);
+            assert_eq!(parse_size(&format!("{}K", bytes >> 10)), Ok(bytes));
+            assert_eq!(format_size(bytes, true).parse::<u64>(), Ok(bytes));
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_sizes() {
+        assert_eq!(
+            parse_size("16384P"),
+            Err("size `16384P` is too large".to_string())
+        );
+        assert!(parse_size("17179869184").is_err());
+        assert!(parse_size("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for arg in ["", "G", "-1G", "1.5T", "1g", "1GB", "1 G"] {
+            assert!(parse_size(arg).is_err(), "accepted `{}`", arg);
+        }
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::days(90)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::hours(12)));
+        assert_eq!(parse_duration("90d"), Ok(Duration::days(90)));
+        assert_eq!(parse_duration("6w"), Ok(Duration::weeks(6)));
+        assert_eq!(parse_duration("3m"), Ok(Duration::days(90)));
+        assert_eq!(parse_duration("1y"), Ok(Duration::days(365)));
+    }
+
+    #[test]
+    fn rejects_overlong_durations() {
+        assert_eq!(
+            parse_duration("100y"),
+            Ok(Duration::days(MAX_DURATION_DAYS))
+        );
+        assert_eq!(
+            parse_duration("101y"),
+            Err("duration `101y` is longer than 100 years".to_string())
+        );
+        assert!(parse_duration("4294967295y").is_err());
+        assert!(parse_duration("4294967296").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for arg in ["", "d", "-1d", "1.5d", "1D", "2 weeks", "3s"] {
+            assert!(parse_duration(arg).is_err(), "accepted `{}`", arg);
+        }
+    }
+
+    #[test]
+    fn accepts_only_pathsafe_names() {
+        assert_eq!(parse_pathsafe("my-ws_2").unwrap(), "my-ws_2");
+        for ident in ["", ".", "..", "a/b", "a b", "a\nb"] {
+            assert!(parse_pathsafe(ident).is_err(), "accepted `{}`", ident);
+        }
+    }
+}
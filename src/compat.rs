@@ -0,0 +1,372 @@
+//! Command lines of the classic HPC workspace tools
+//!
+//! Installed under the names `ws_allocate`, `ws_extend`, `ws_release` and
+//! `ws_list`, e.g. as symlinks, workspaces accepts their syntax and runs the
+//! corresponding commands of its own, so existing scripts keep working.
+
+use crate::{cli, exit_codes};
+use clap::Parser;
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    io,
+    os::{fd::AsFd, unix::process::CommandExt},
+    path::Path,
+    process::{self, Command, Stdio},
+};
+use users::{get_group_by_gid, get_user_by_name};
+
+/// Allocate a workspace, or reuse an existing one, and print its path
+#[derive(Parser)]
+#[command(name = "ws_allocate")]
+struct Allocate {
+    /// Name of the workspace
+    name: String,
+
+    /// Days until the workspace expires
+    #[arg(value_name = "DURATION")]
+    days: Option<u32>,
+
+    /// Days until the workspace expires
+    #[arg(short, long, conflicts_with = "days")]
+    duration: Option<u32>,
+
+    /// Filesystem to create the workspace in
+    #[arg(short = 'F', long)]
+    filesystem: Option<String>,
+
+    /// User the workspace belongs to
+    #[arg(short, long)]
+    username: Option<String>,
+
+    /// Make the workspace readable by the user's primary group
+    #[arg(short, long)]
+    group: bool,
+
+    /// Make the workspace writable by GROUP
+    #[arg(short = 'G', long, value_name = "GROUP")]
+    groupname: Option<String>,
+
+    /// What the workspace is for
+    #[arg(short, long)]
+    comment: Option<String>,
+
+    /// Extend the workspace instead of allocating it
+    #[arg(short = 'x', long)]
+    extension: bool,
+
+    /// Ignored, see `workspaces notify`
+    #[arg(short, long, value_name = "DAYS")]
+    reminder: Option<u32>,
+
+    /// Ignored, see `workspaces notify`
+    #[arg(short, long, value_name = "ADDRESS")]
+    mailaddress: Option<String>,
+}
+
+/// Extend a workspace
+#[derive(Parser)]
+#[command(name = "ws_extend")]
+struct Extend {
+    /// Name of the workspace
+    name: String,
+
+    /// Days until the workspace expires
+    #[arg(value_name = "DURATION")]
+    days: Option<u32>,
+
+    /// Filesystem of the workspace
+    #[arg(short = 'F', long)]
+    filesystem: Option<String>,
+
+    /// User the workspace belongs to
+    #[arg(short, long)]
+    username: Option<String>,
+}
+
+/// Release a workspace, which is deleted on a later cleanup
+#[derive(Parser)]
+#[command(name = "ws_release")]
+struct Release {
+    /// Name of the workspace
+    name: String,
+
+    /// Filesystem of the workspace
+    #[arg(short = 'F', long)]
+    filesystem: Option<String>,
+
+    /// User the workspace belongs to
+    #[arg(short, long)]
+    username: Option<String>,
+
+    /// Delete the workspace on the next cleanup, without grace period
+    #[arg(long)]
+    delete_data: bool,
+}
+
+/// List your workspaces
+#[derive(Parser)]
+#[command(name = "ws_list")]
+struct List {
+    /// Only list workspaces on FILESYSTEM
+    #[arg(short = 'F', long)]
+    filesystem: Option<String>,
+
+    /// List workspaces on all filesystems, which is the default
+    #[arg(short, long, conflicts_with = "filesystem")]
+    all: bool,
+
+    /// List the available filesystems instead
+    #[arg(short = 'l', long)]
+    listfilesystems: bool,
+}
+
+/// Runs the classic tool this process was invoked as, if any
+///
+/// Returns if workspaces was invoked under a name of its own.
+pub fn run() {
+    let args: Vec<OsString> = env::args_os().collect();
+    let Some(tool) = args.first().and_then(|arg| Path::new(arg).file_name()) else {
+        return;
+    };
+    match tool.to_str() {
+        Some("ws_allocate") => allocate(Allocate::parse_from(args)),
+        Some("ws_extend") => extend(Extend::parse_from(args)),
+        Some("ws_release") => release(Release::parse_from(args)),
+        Some("ws_list") => list(List::parse_from(args)),
+        _ => {}
+    }
+}
+
+fn allocate(args: Allocate) -> ! {
+    if args.extension {
+        extend(Extend {
+            name: args.name,
+            days: args.days.or(args.duration),
+            filesystem: args.filesystem,
+            username: args.username,
+        });
+    }
+    if args.reminder.is_some() || args.mailaddress.is_some() {
+        eprintln!("Ignoring reminder settings, see `workspaces notify` instead");
+    }
+
+    let primary_group = match args.group && args.groupname.is_none() {
+        true => {
+            let user = args
+                .username
+                .clone()
+                .unwrap_or_else(|| cli::default_user().to_string_lossy().into_owned());
+            let Some(group) = get_user_by_name(&user)
+                .and_then(|user| get_group_by_gid(user.primary_group_id()))
+                .map(|group| group.name().to_owned())
+            else {
+                eprintln!("Could not find the primary group of {}", user);
+                process::exit(exit_codes::UNKNOWN_GROUP);
+            };
+            Some(group)
+        }
+        false => None,
+    };
+    let mut create = create_command(&args, primary_group.as_deref());
+
+    // like ws_allocate, only print the path on stdout and reuse existing workspaces
+    let stderr = io::stderr().as_fd().try_clone_to_owned().unwrap();
+    let status = create.stdout(Stdio::from(stderr)).status().unwrap();
+    match status.code() {
+        Some(0) | Some(exit_codes::WORKSPACE_EXISTS) => {}
+        code => process::exit(code.unwrap_or(exit_codes::OPERATION_FAILED)),
+    }
+
+    let mut path = command(["path", &args.name]);
+    add_workspace_args(&mut path, &args.filesystem, &args.username);
+    exec(path)
+}
+
+/// The `create` command `ws_allocate` stands for
+///
+/// `primary_group` is the group to give the workspace to for `--group`.
+fn create_command(args: &Allocate, primary_group: Option<&OsStr>) -> Command {
+    let mut create = command(["create", &args.name]);
+    if let Some(days) = args.days.or(args.duration) {
+        create.args(["--duration", &days.to_string()]);
+    }
+    add_workspace_args(&mut create, &args.filesystem, &args.username);
+    if let Some(group) = &args.groupname {
+        create.args(["--group", group, "--collaborative"]);
+    } else if let Some(group) = primary_group {
+        create.arg("--group").arg(group);
+    }
+    if let Some(comment) = &args.comment {
+        create.args(["--comment", comment]);
+    }
+    create
+}
+
+fn extend(args: Extend) -> ! {
+    exec(extend_command(&args))
+}
+
+fn extend_command(args: &Extend) -> Command {
+    let mut extend = command(["extend", &args.name]);
+    if let Some(days) = args.days {
+        extend.args(["--duration", &days.to_string()]);
+    }
+    add_workspace_args(&mut extend, &args.filesystem, &args.username);
+    extend
+}
+
+fn release(args: Release) -> ! {
+    exec(release_command(&args))
+}
+
+fn release_command(args: &Release) -> Command {
+    let mut expire = command(["expire", &args.name]);
+    if args.delete_data {
+        expire.arg("--terminally");
+    }
+    add_workspace_args(&mut expire, &args.filesystem, &args.username);
+    expire
+}
+
+fn list(args: List) -> ! {
+    exec(list_command(&args))
+}
+
+fn list_command(args: &List) -> Command {
+    if args.listfilesystems {
+        return command(["filesystems"]);
+    }
+    let mut list = command(["list"]);
+    if let Some(filesystem) = &args.filesystem {
+        list.args(["-f", filesystem]);
+    }
+    // ws_list only shows the caller's own workspaces
+    list.arg("--user").arg(cli::default_user());
+    list
+}
+
+/// A command line of workspaces itself
+fn command<const N: usize>(args: [&str; N]) -> Command {
+    let mut command = Command::new(env::current_exe().unwrap());
+    command.args(args);
+    command
+}
+
+fn add_workspace_args(
+    command: &mut Command,
+    filesystem: &Option<String>,
+    username: &Option<String>,
+) {
+    if let Some(filesystem) = filesystem {
+        command.args(["--filesystem", filesystem]);
+    }
+    if let Some(username) = username {
+        command.args(["--user", username]);
+    }
+}
+
+/// Replaces this process with `command`
+fn exec(mut command: Command) -> ! {
+    let e = command.exec();
+    eprintln!("Could not run workspaces: {}", e);
+    process::exit(exit_codes::OPERATION_FAILED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<&OsStr> {
+        command.get_args().collect()
+    }
+
+    #[test]
+    fn allocate_creates_a_workspace() {
+        let allocate = Allocate::parse_from(["ws_allocate", "sim", "30"]);
+        assert_eq!(
+            args(&create_command(&allocate, None)),
+            ["create", "sim", "--duration", "30"]
+        );
+        let allocate = Allocate::parse_from([
+            "ws_allocate",
+            "-F",
+            "bulk",
+            "-u",
+            "alice",
+            "-d",
+            "7",
+            "-c",
+            "input data",
+            "sim",
+        ]);
+        assert_eq!(
+            args(&create_command(&allocate, None)),
+            [
+                "create",
+                "sim",
+                "--duration",
+                "7",
+                "--filesystem",
+                "bulk",
+                "--user",
+                "alice",
+                "--comment",
+                "input data"
+            ]
+        );
+    }
+
+    #[test]
+    fn allocate_shares_with_groups() {
+        let allocate = Allocate::parse_from(["ws_allocate", "-G", "proj", "sim"]);
+        assert_eq!(
+            args(&create_command(&allocate, None)),
+            ["create", "sim", "--group", "proj", "--collaborative"]
+        );
+        let allocate = Allocate::parse_from(["ws_allocate", "-g", "sim"]);
+        assert_eq!(
+            args(&create_command(&allocate, Some(OsStr::new("users")))),
+            ["create", "sim", "--group", "users"]
+        );
+    }
+
+    #[test]
+    fn allocate_rejects_two_durations() {
+        assert!(Allocate::try_parse_from(["ws_allocate", "sim", "30", "-d", "7"]).is_err());
+    }
+
+    #[test]
+    fn extend_and_release_map_to_their_commands() {
+        let extend = Extend::parse_from(["ws_extend", "-F", "bulk", "sim", "10"]);
+        assert_eq!(
+            args(&extend_command(&extend)),
+            ["extend", "sim", "--duration", "10", "--filesystem", "bulk"]
+        );
+        let release = Release::parse_from(["ws_release", "sim"]);
+        assert_eq!(args(&release_command(&release)), ["expire", "sim"]);
+        let release = Release::parse_from(["ws_release", "--delete-data", "-u", "alice", "sim"]);
+        assert_eq!(
+            args(&release_command(&release)),
+            ["expire", "sim", "--terminally", "--user", "alice"]
+        );
+    }
+
+    #[test]
+    fn list_shows_own_workspaces_or_filesystems() {
+        let list = List::parse_from(["ws_list", "-F", "bulk"]);
+        let user = cli::default_user();
+        assert_eq!(
+            args(&list_command(&list)),
+            [
+                OsStr::new("list"),
+                OsStr::new("-f"),
+                OsStr::new("bulk"),
+                OsStr::new("--user"),
+                user
+            ]
+        );
+        let list = List::parse_from(["ws_list", "-l"]);
+        assert_eq!(args(&list_command(&list)), ["filesystems"]);
+    }
+}
@@ -1,6 +1,9 @@
+use crate::cli::ArchiveFormat;
 use chrono::Duration;
+use clap::ValueEnum;
 use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
+use std::fmt;
 use std::path::PathBuf;
 
 /// Path of the configuration file
@@ -13,9 +16,549 @@ pub struct Config {
     /// Workspaces database location
     #[serde(default = "default_db_path")]
     pub db_path: PathBuf,
+    /// PostgreSQL database to use instead of the SQLite one at `db_path`
+    ///
+    /// Lets many hosts share their workspaces' state.  Requires workspaces to
+    /// be built with the `postgres` feature.
+    pub database_url: Option<String>,
+    /// Socket on which `workspaces serve` listens for unprivileged clients
+    #[serde(default = "default_socket_path")]
+    pub socket_path: PathBuf,
+    /// Executable choosing the filesystem for new workspaces
+    ///
+    /// Only consulted if no filesystem is given on the command line.
+    pub placement_script: Option<PathBuf>,
+    /// How to choose the filesystem for new workspaces if none is given
+    ///
+    /// Takes precedence over `default_filesystem`, but not the placement script.
+    pub default_filesystem_policy: Option<FilesystemPolicy>,
+    /// Which user to act on behalf of by default when run through sudo
+    #[serde(default)]
+    pub sudo_user: SudoUser,
+    /// Whether to record command latencies for `workspaces admin perf`
+    #[serde(default)]
+    pub metrics: bool,
+    /// Seconds after which a hung zfs invocation is killed
+    #[serde(default = "default_zfs_timeout", deserialize_with = "from_seconds")]
+    pub zfs_timeout: Duration,
+    /// Seconds to wait for other processes to release the database
+    #[serde(default = "default_db_timeout", deserialize_with = "from_seconds")]
+    pub db_timeout: Duration,
+    /// Number of zfs commands run concurrently when querying many datasets
+    #[serde(default = "default_parallelism")]
+    pub parallelism: usize,
+    /// Seconds for which the groups of a user are cached
+    #[serde(default = "default_group_cache_ttl", deserialize_with = "from_seconds")]
+    pub group_cache_ttl: Duration,
+    /// Settings for notifying users about their expiring workspaces
+    ///
+    /// No notifications are sent if this is not given.
+    pub notifications: Option<Notifications>,
+    /// Scripts run on workspace lifecycle events
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// Settings for `workspaces daemon`
+    #[serde(default)]
+    pub daemon: Daemon,
+    /// Settings for `workspaces clean`
+    #[serde(default)]
+    pub clean: Clean,
+    /// Groups whose members may do anything root may within workspaces
+    #[serde(default)]
+    pub admin_groups: Vec<String>,
+    /// Storage purchased by each group, in GiB
+    #[serde(default)]
+    pub shares: HashMap<String, u64>,
+    /// Projects which can own workspaces, by name
+    #[serde(default)]
+    pub projects: HashMap<String, Project>,
     /// Workspace filesystem definitions
+    ///
+    /// Only holds the filesystems served by this host once [`split_remote`]
+    /// has been called.
     #[serde(default)]
     pub filesystems: HashMap<String, Filesystem>,
+    /// Filesystems served by other hosts sharing the database
+    #[serde(skip)]
+    pub remote_filesystems: HashMap<String, Filesystem>,
+}
+
+/// Moves the filesystems served by other hosts to `remote_filesystems`
+pub fn split_remote(config: &mut Config) {
+    let host = local_host();
+    let (local, remote) = config
+        .filesystems
+        .drain()
+        .partition(|(_, filesystem)| filesystem.host.as_ref().is_none_or(|h| *h == host));
+    config.filesystems = local;
+    config.remote_filesystems = remote;
+}
+
+/// Name of this host, as reported by `hostname`
+pub fn local_host() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Finds settings which parse, but make no sense, e.g. negative durations
+///
+/// Returns a description of each problem found.
+pub fn validate(config: &Config) -> Vec<String> {
+    let mut problems = vec![];
+    if let Some(name) = &config.default_filesystem {
+        if !config.filesystems.contains_key(name) && !config.remote_filesystems.contains_key(name) {
+            problems.push(format!("default_filesystem {} is not configured", name));
+        }
+    }
+    if config.zfs_timeout <= Duration::zero() {
+        problems.push("zfs_timeout has to be positive".to_string());
+    }
+    if config.db_timeout < Duration::zero() {
+        problems.push("db_timeout must not be negative".to_string());
+    }
+    if config.group_cache_ttl < Duration::zero() {
+        problems.push("group_cache_ttl must not be negative".to_string());
+    }
+    if config.parallelism == 0 {
+        problems.push("parallelism has to be at least 1".to_string());
+    }
+    for (name, interval) in [
+        ("clean_interval", config.daemon.clean_interval),
+        ("remind_interval", config.daemon.remind_interval),
+        ("permissions_interval", config.daemon.permissions_interval),
+    ] {
+        if interval <= Duration::zero() {
+            problems.push(format!("daemon.{} has to be positive", name));
+        }
+    }
+    if config.daemon.jitter < Duration::zero() {
+        problems.push("daemon.jitter must not be negative".to_string());
+    }
+    if let Some(notifications) = &config.notifications {
+        if notifications.min_days > notifications.max_days {
+            problems.push("notifications.min_days exceeds notifications.max_days".to_string());
+        }
+        if notifications
+            .default_days
+            .iter()
+            .any(|days| !(notifications.min_days..=notifications.max_days).contains(days))
+        {
+            problems.push(
+                "notifications.default_days lie outside of min_days and max_days".to_string(),
+            );
+        }
+        for (name, template) in notifications.templates.iter() {
+            for variable in template
+                .split('{')
+                .skip(1)
+                .filter_map(|part| part.split_once('}'))
+                .map(|(variable, _)| variable)
+                .filter(|variable| !TEMPLATE_VARIABLES.contains(variable))
+            {
+                problems.push(format!(
+                    "notifications.templates.{} uses the unknown variable {{{}}}",
+                    name, variable
+                ));
+            }
+        }
+    }
+
+    for group in &config.admin_groups {
+        if users::get_group_by_name(group).is_none() {
+            problems.push(format!("admin group {} does not exist", group));
+        }
+    }
+
+    let mut projects: Vec<&String> = config.projects.keys().collect();
+    projects.sort();
+    for name in projects {
+        let project = &config.projects[name];
+        if project.group.is_none() && project.members.is_empty() {
+            problems.push(format!("project {} has neither a group nor members", name));
+        }
+    }
+
+    let mut names: Vec<&String> = config.filesystems.keys().collect();
+    names.sort();
+    for name in names {
+        let filesystem = &config.filesystems[name];
+        let mut problem =
+            |message: String| problems.push(format!("filesystem {}: {}", name, message));
+        if filesystem.max_duration <= Duration::zero() {
+            problem("max_duration has to be positive".to_string());
+        }
+        if let Some(default_duration) = filesystem.default_duration {
+            if default_duration <= Duration::zero() {
+                problem("default_duration has to be positive".to_string());
+            } else if default_duration > filesystem.max_duration {
+                problem(format!(
+                    "default_duration of {} days exceeds max_duration of {} days",
+                    default_duration.num_days(),
+                    filesystem.max_duration.num_days()
+                ));
+            }
+        }
+        if filesystem.expired_retention < Duration::zero() {
+            problem("expired_retention must not be negative".to_string());
+        }
+        for tier in &filesystem.tiers {
+            if tier.days <= Duration::zero() {
+                problem(format!("days of tier {} have to be positive", tier.tier));
+            }
+            if tier.tier == Tier::Archived && filesystem.archive.is_none() {
+                problem("tier archived needs an archive".to_string());
+            }
+        }
+        if !filesystem.tiers.windows(2).all(|w| w[0].tier < w[1].tier) {
+            problem("tiers have to be in the order readonly, unmounted, archived".to_string());
+        }
+        if filesystem
+            .trash_retention
+            .is_some_and(|retention| retention < Duration::zero())
+        {
+            problem("trash_retention must not be negative".to_string());
+        }
+        if filesystem
+            .final_snapshot_retention
+            .is_some_and(|retention| retention < Duration::zero())
+        {
+            problem("final_snapshot_retention must not be negative".to_string());
+        }
+        if filesystem.expiry_warning < Duration::zero() {
+            problem("expiry_warning must not be negative".to_string());
+        }
+        for (setting, percent) in [
+            ("reserve_percent", filesystem.reserve_percent),
+            ("usage_warning", Some(filesystem.usage_warning)),
+            ("usage_critical", Some(filesystem.usage_critical)),
+        ] {
+            if percent.is_some_and(|percent| !(0. ..=100.).contains(&percent)) {
+                problem(format!("{} has to be between 0 and 100", setting));
+            }
+        }
+        if filesystem.usage_warning > filesystem.usage_critical {
+            problem("usage_warning exceeds usage_critical".to_string());
+        }
+        if let Some(template) = &filesystem.mountpoint_template {
+            if !template.starts_with('/') {
+                problem("mountpoint_template has to be an absolute path".to_string());
+            }
+            if !template.contains("{user}") || !template.contains("{name}") {
+                problem("mountpoint_template has to contain {user} and {name}".to_string());
+            }
+        }
+        if let Some(skeleton_dir) = &filesystem.skeleton_dir {
+            if !skeleton_dir.is_dir() {
+                problem(format!("{} is not a directory", skeleton_dir.display()));
+            }
+        }
+        if let Some(archive) = &filesystem.archive {
+            if !archive.url.starts_with("s3://") {
+                problem("archive.url has to start with s3://".to_string());
+            }
+        }
+    }
+    problems
+}
+
+/// Intervals at which `workspaces daemon` runs its tasks
+#[derive(Debug, Deserialize)]
+pub struct Daemon {
+    /// Minutes between two runs of clean
+    #[serde(default = "default_clean_interval", deserialize_with = "from_minutes")]
+    pub clean_interval: Duration,
+    /// Minutes between two checks for due reminders
+    #[serde(default = "default_remind_interval", deserialize_with = "from_minutes")]
+    pub remind_interval: Duration,
+    /// Minutes between two retries of failed permission setups
+    #[serde(
+        default = "default_permissions_interval",
+        deserialize_with = "from_minutes"
+    )]
+    pub permissions_interval: Duration,
+    /// Maximum number of minutes each run is randomly delayed by
+    #[serde(default = "default_jitter", deserialize_with = "from_minutes")]
+    pub jitter: Duration,
+}
+
+impl Default for Daemon {
+    fn default() -> Self {
+        Daemon {
+            clean_interval: default_clean_interval(),
+            remind_interval: default_remind_interval(),
+            permissions_interval: default_permissions_interval(),
+            jitter: default_jitter(),
+        }
+    }
+}
+
+fn default_clean_interval() -> Duration {
+    Duration::days(1)
+}
+
+fn default_remind_interval() -> Duration {
+    Duration::hours(1)
+}
+
+fn default_permissions_interval() -> Duration {
+    Duration::minutes(5)
+}
+
+fn default_jitter() -> Duration {
+    Duration::minutes(10)
+}
+
+/// How the filesystem of new workspaces is chosen automatically
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FilesystemPolicy {
+    /// The filesystem with the most free space
+    MostFree,
+    /// The filesystem holding the fewest workspaces
+    LeastWorkspaces,
+}
+
+/// Which user commands act on behalf of by default when run through sudo
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SudoUser {
+    /// The user who invoked sudo
+    #[default]
+    Invoker,
+    /// Nobody; the user has to be given explicitly
+    Require,
+    /// Root
+    Root,
+}
+
+/// A project or account which workspaces can be created for
+#[derive(Debug, Default, Deserialize)]
+pub struct Project {
+    /// Unix group whose members belong to the project
+    ///
+    /// Also the group new workspaces of the project belong to.
+    pub group: Option<String>,
+    /// Users belonging to the project in addition to the group's members
+    #[serde(default)]
+    pub members: Vec<String>,
+    /// Storage granted to the project across all filesystems, in GiB
+    pub quota: Option<u64>,
+}
+
+/// Workspaces `workspaces clean` never deletes
+#[derive(Debug, Default, Deserialize)]
+pub struct Clean {
+    /// Users whose workspaces are never deleted, e.g. service accounts
+    #[serde(default)]
+    pub exclude_users: Vec<String>,
+    /// Patterns of workspace names which are never deleted
+    ///
+    /// A `*` matches any number of characters.
+    #[serde(default)]
+    pub exclude_names: Vec<String>,
+    /// Tags of workspaces which are never deleted
+    #[serde(default)]
+    pub exclude_tags: Vec<String>,
+}
+
+impl Clean {
+    /// Whether a workspace is excluded from deletion
+    pub fn excludes(&self, user: &str, name: &str, tags: &[String]) -> bool {
+        self.exclude_users.iter().any(|u| u == user)
+            || self.exclude_tags.iter().any(|t| tags.contains(t))
+            || self
+                .exclude_names
+                .iter()
+                .any(|pattern| matches_pattern(pattern, name))
+    }
+}
+
+/// Matches `name` against a pattern in which `*` matches any characters
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always yields at least one part
+    let first = parts.next().unwrap();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // no `*` in the pattern
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Executables run before and after workspace lifecycle events
+#[derive(Debug, Default, Deserialize)]
+pub struct Hooks {
+    /// Run before a workspace is created, which is aborted if it fails
+    pub pre_create: Option<PathBuf>,
+    /// Run before a workspace is extended, which is aborted if it fails
+    pub pre_extend: Option<PathBuf>,
+    /// Run before a workspace expires, which is aborted if it fails
+    pub pre_expire: Option<PathBuf>,
+    /// Run before a workspace is destroyed, which is aborted if it fails
+    pub pre_destroy: Option<PathBuf>,
+    /// Run after a workspace has been created
+    pub on_create: Option<PathBuf>,
+    /// Run after a workspace has been extended
+    pub on_extend: Option<PathBuf>,
+    /// Run after a workspace has expired
+    pub on_expire: Option<PathBuf>,
+    /// Run after a workspace has been destroyed
+    pub on_destroy: Option<PathBuf>,
+}
+
+/// Bounds and defaults for user notifications
+#[derive(Debug, Deserialize)]
+pub struct Notifications {
+    /// Days before expiry at which users are reminded by default
+    #[serde(default = "default_reminder_days")]
+    pub default_days: Vec<i64>,
+    /// Channel used by default
+    #[serde(default)]
+    pub default_channel: Channel,
+    /// Smallest number of days before expiry a user may be reminded at
+    #[serde(default = "default_min_reminder_days")]
+    pub min_days: i64,
+    /// Largest number of days before expiry a user may be reminded at
+    #[serde(default = "default_max_reminder_days")]
+    pub max_days: i64,
+    /// Maximum number of reminders a user may configure
+    #[serde(default = "default_max_reminders")]
+    pub max_reminders: usize,
+    /// Channels users may choose from
+    #[serde(default = "default_channels")]
+    pub channels: Vec<Channel>,
+    /// Users notified instead of owners which no longer exist
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// Domain appended to user names to get their mail address
+    pub mail_domain: Option<String>,
+    /// Sender address of notification mails
+    pub from: Option<String>,
+    /// sendmail-compatible binary used to send mails
+    #[serde(default = "default_sendmail")]
+    pub sendmail: String,
+    /// Texts of the notifications, replacing the built-in ones
+    #[serde(default)]
+    pub templates: Templates,
+}
+
+/// Variables which can be used in [`Templates`], as `{variable}`
+pub const TEMPLATE_VARIABLES: [&str; 9] = [
+    "user",
+    "name",
+    "filesystem",
+    "expiry",
+    "deletion",
+    "days_left",
+    "retention",
+    "mountpoint",
+    "extend_command",
+];
+
+/// Subjects and bodies of the mails sent to workspace owners
+#[derive(Debug, Default, Deserialize)]
+pub struct Templates {
+    pub reminder_subject: Option<String>,
+    pub reminder_body: Option<String>,
+    pub expired_subject: Option<String>,
+    pub expired_body: Option<String>,
+}
+
+impl Templates {
+    /// The configured templates, by their name in the configuration
+    fn iter(&self) -> impl Iterator<Item = (&'static str, &String)> {
+        [
+            ("reminder_subject", &self.reminder_subject),
+            ("reminder_body", &self.reminder_body),
+            ("expired_subject", &self.expired_subject),
+            ("expired_body", &self.expired_body),
+        ]
+        .into_iter()
+        .filter_map(|(name, template)| Some((name, template.as_ref()?)))
+    }
+}
+
+fn default_reminder_days() -> Vec<i64> {
+    vec![7, 1]
+}
+
+fn default_min_reminder_days() -> i64 {
+    1
+}
+
+fn default_max_reminder_days() -> i64 {
+    30
+}
+
+fn default_max_reminders() -> usize {
+    5
+}
+
+fn default_channels() -> Vec<Channel> {
+    vec![Channel::Email, Channel::None]
+}
+
+fn default_sendmail() -> String {
+    "/usr/sbin/sendmail".to_string()
+}
+
+/// A way of notifying users
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    /// Send a mail
+    #[default]
+    Email,
+    /// Don't send any notifications
+    None,
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Channel::Email => "email",
+                Channel::None => "none",
+            }
+        )
+    }
+}
+
+fn default_db_timeout() -> Duration {
+    Duration::seconds(30)
+}
+
+fn default_zfs_timeout() -> Duration {
+    Duration::minutes(1)
+}
+
+fn default_group_cache_ttl() -> Duration {
+    Duration::minutes(15)
+}
+
+fn default_parallelism() -> usize {
+    4
+}
+
+fn default_socket_path() -> PathBuf {
+    PathBuf::from("/run/workspaces.sock")
 }
 
 fn default_db_path() -> PathBuf {
@@ -45,15 +588,177 @@ fn default_db_path() -> PathBuf {
 pub struct Filesystem {
     /// ZFS filesystem / volume which will act as the root for the datasets
     pub root: String,
+    /// Host serving this filesystem, if several hosts share the database
+    ///
+    /// Defaults to the local host.
+    pub host: Option<String>,
     /// Maximum number of days a workspace may exist
     #[serde(deserialize_with = "from_days")]
     pub max_duration: Duration,
+    /// Days a workspace is created or extended for if no duration is given
+    #[serde(default, deserialize_with = "from_optional_days")]
+    pub default_duration: Option<Duration>,
     /// Days after which an expired dataset will be removed
     #[serde(deserialize_with = "from_days")]
     pub expired_retention: Duration,
+    /// Tiers expired datasets pass through before they are removed
+    ///
+    /// If given, these replace `expired_retention`.
+    #[serde(default)]
+    pub tiers: Vec<RetentionTier>,
     /// Whether datasets can be created / extended
     #[serde(default)]
     pub disabled: bool,
+    /// Whether the filesystem is about to be removed
+    ///
+    /// Datasets cannot be created or extended on retired filesystems, not even
+    /// by root, but existing ones can still be listed, expired and cleaned.
+    #[serde(default)]
+    pub retired: bool,
+    /// Whether to forcibly unmount datasets which are still busy on clean
+    #[serde(default)]
+    pub force_unmount: bool,
+    /// Whether to unmount datasets once they expire, rather than only making
+    /// them read-only
+    #[serde(default)]
+    pub unmount_expired: bool,
+    /// Options to export datasets by NFS with, as for the `sharenfs` property
+    ///
+    /// If not given, datasets inherit the property from `root`.
+    pub sharenfs: Option<String>,
+    /// Where to mount datasets, with `{user}` and `{name}` substituted
+    ///
+    /// If not given, datasets inherit their mountpoint from `root`.
+    pub mountpoint_template: Option<String>,
+    /// Directory whose contents are copied into every new workspace
+    pub skeleton_dir: Option<PathBuf>,
+    /// Object storage datasets are uploaded to before they are deleted
+    pub archive: Option<Archive>,
+    /// Days an expired dataset is kept in the trash before it is destroyed
+    ///
+    /// If not given, datasets are destroyed right away.
+    #[serde(default, deserialize_with = "from_optional_days")]
+    pub trash_retention: Option<Duration>,
+    /// Days a held snapshot of deleted datasets is kept for
+    ///
+    /// Datasets then stay in the trash for at least this long.
+    #[serde(default, deserialize_with = "from_optional_days")]
+    pub final_snapshot_retention: Option<Duration>,
+    /// Whether to destroy datasets' snapshots along with them on clean
+    #[serde(default = "default_destroy_recursive")]
+    pub destroy_recursive: bool,
+    /// GiB users may raise their workspaces' quotas to with `extend --quota`
+    ///
+    /// If not given, only root may change quotas.
+    pub max_quota: Option<u64>,
+    /// GiB each user's workspaces should not exceed in total
+    ///
+    /// This is not enforced, but reported by `workspaces usage`.
+    pub user_limit: Option<u64>,
+    /// Days before expiry from which workspaces are highlighted in `workspaces list`
+    #[serde(default = "default_expiry_warning", deserialize_with = "from_days")]
+    pub expiry_warning: Duration,
+    /// Percentage of space which has to remain free for non-root users to create workspaces
+    pub reserve_percent: Option<f64>,
+    /// Percentage of space used from which the filesystem is shown in yellow
+    #[serde(default = "default_usage_warning")]
+    pub usage_warning: f64,
+    /// Percentage of space used from which the filesystem is shown in red
+    #[serde(default = "default_usage_critical")]
+    pub usage_critical: f64,
+}
+
+/// An S3-compatible bucket to archive datasets to
+#[derive(Debug, Deserialize)]
+pub struct Archive {
+    /// Bucket and prefix to upload to, e.g. `s3://workspaces-archive/hpc`
+    pub url: String,
+    /// Endpoint of the object storage, if it is not AWS itself
+    pub endpoint_url: Option<String>,
+    /// Whether to upload a zfs replication stream or a tarball
+    #[serde(default = "default_archive_format")]
+    pub format: ArchiveFormat,
+    /// Storage class to upload objects with, e.g. `GLACIER`
+    pub storage_class: Option<String>,
+}
+
+fn default_archive_format() -> ArchiveFormat {
+    ArchiveFormat::Zfs
+}
+
+/// What happens to expired datasets for a while before they are removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    /// Read-only, but still mounted
+    Readonly,
+    /// Read-only and unmounted
+    Unmounted,
+    /// Uploaded to the filesystem's archive and destroyed locally
+    Archived,
+}
+
+impl fmt::Display for Tier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Tier::Readonly => "readonly",
+                Tier::Unmounted => "unmounted",
+                Tier::Archived => "archived",
+            }
+        )
+    }
+}
+
+/// A tier of a filesystem's retention policy
+#[derive(Debug, Deserialize)]
+pub struct RetentionTier {
+    pub tier: Tier,
+    /// Days expired datasets stay in this tier
+    #[serde(deserialize_with = "from_days")]
+    pub days: Duration,
+}
+
+impl Filesystem {
+    /// Time after which expired datasets are removed
+    pub fn retention(&self) -> Duration {
+        if self.tiers.is_empty() {
+            return self.expired_retention;
+        }
+        self.tiers
+            .iter()
+            .fold(Duration::zero(), |total, tier| total + tier.days)
+    }
+
+    /// The tier datasets are in once they have been expired for `expired_for`
+    pub fn tier(&self, expired_for: Duration) -> Option<Tier> {
+        let mut end = Duration::zero();
+        for tier in &self.tiers {
+            end = end + tier.days;
+            if expired_for < end {
+                return Some(tier.tier);
+            }
+        }
+        None
+    }
+}
+
+fn default_destroy_recursive() -> bool {
+    true
+}
+
+fn default_expiry_warning() -> Duration {
+    Duration::days(30)
+}
+
+fn default_usage_warning() -> f64 {
+    75.
+}
+
+fn default_usage_critical() -> f64 {
+    90.
 }
 
 fn from_days<'de, D>(deserializer: D) -> Result<Duration, D::Error>
@@ -63,3 +768,118 @@ where
     let days: i64 = Deserialize::deserialize(deserializer)?;
     Ok(Duration::days(days))
 }
+
+fn from_seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let seconds: i64 = Deserialize::deserialize(deserializer)?;
+    Ok(Duration::seconds(seconds))
+}
+
+fn from_minutes<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let minutes: i64 = Deserialize::deserialize(deserializer)?;
+    Ok(Duration::minutes(minutes))
+}
+
+fn from_optional_days<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let days: Option<i64> = Deserialize::deserialize(deserializer)?;
+    Ok(days.map(Duration::days))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filesystem(tiers: &str) -> Filesystem {
+        toml::from_str(&format!(
+            "root = \"pool/ws\"\nmax_duration = 30\nexpired_retention = 10\n{}",
+            tiers
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn retention_without_tiers() {
+        let filesystem = filesystem("");
+        assert_eq!(filesystem.retention(), Duration::days(10));
+        assert_eq!(filesystem.tier(Duration::zero()), None);
+    }
+
+    #[test]
+    fn retention_is_the_sum_of_all_tiers() {
+        let filesystem = filesystem(
+            r#"tiers = [
+                { tier = "readonly", days = 14 },
+                { tier = "unmounted", days = 7 },
+                { tier = "archived", days = 90 },
+            ]"#,
+        );
+        assert_eq!(filesystem.retention(), Duration::days(111));
+    }
+
+    #[test]
+    fn tiers_follow_each_other() {
+        let filesystem = filesystem(
+            r#"tiers = [
+                { tier = "readonly", days = 14 },
+                { tier = "archived", days = 90 },
+            ]"#,
+        );
+        assert_eq!(filesystem.tier(Duration::zero()), Some(Tier::Readonly));
+        assert_eq!(
+            filesystem.tier(Duration::days(14) - Duration::seconds(1)),
+            Some(Tier::Readonly)
+        );
+        assert_eq!(filesystem.tier(Duration::days(14)), Some(Tier::Archived));
+        assert_eq!(filesystem.tier(Duration::days(103)), Some(Tier::Archived));
+        // due for deletion
+        assert_eq!(filesystem.tier(Duration::days(104)), None);
+    }
+
+    #[test]
+    fn matches_patterns_without_wildcards() {
+        assert!(matches_pattern("data", "data"));
+        assert!(!matches_pattern("data", "data2"));
+        assert!(!matches_pattern("data", "dat"));
+        assert!(matches_pattern("", ""));
+        assert!(!matches_pattern("", "data"));
+    }
+
+    #[test]
+    fn matches_patterns_with_wildcards() {
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("*", "data"));
+        assert!(matches_pattern("run-*", "run-1"));
+        assert!(matches_pattern("run-*", "run-"));
+        assert!(!matches_pattern("run-*", "run"));
+        assert!(matches_pattern("*-tmp", "sim-tmp"));
+        assert!(matches_pattern("sim-*-tmp", "sim-1-tmp"));
+        assert!(matches_pattern("a*b*c", "abc"));
+        assert!(matches_pattern("a*b*c", "axxbyyc"));
+        assert!(!matches_pattern("a*b*c", "acb"));
+    }
+
+    #[test]
+    fn matches_parts_without_overlapping() {
+        assert!(!matches_pattern("a*a", "a"));
+        assert!(matches_pattern("a*a", "aa"));
+        assert!(!matches_pattern("ab*ba", "aba"));
+        assert!(matches_pattern("*ab*b", "abb"));
+        assert!(!matches_pattern("*ab*b", "ab"));
+    }
+
+    #[test]
+    fn treats_other_characters_literally() {
+        assert!(matches_pattern("run?", "run?"));
+        assert!(!matches_pattern("run?", "run1"));
+        assert!(matches_pattern("[ab]*", "[ab]c"));
+        assert!(!matches_pattern("[ab]*", "ac"));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::db::Connection;
+use crate::{config, db, exit_codes, notify, permissions, server::caller_uid, shutdown};
+use sd_notify::NotifyState;
+use std::{
+    process, thread,
+    time::{Duration, Instant},
+};
+
+/// Maximum time the daemon sleeps before checking for a shutdown request
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A job run periodically by the daemon
+struct Task {
+    name: &'static str,
+    interval: Duration,
+    next_run: Instant,
+}
+
+impl Task {
+    fn new(name: &'static str, interval: chrono::Duration) -> Self {
+        Task {
+            name,
+            interval: interval.to_std().unwrap(),
+            // run each task once right after startup
+            next_run: Instant::now(),
+        }
+    }
+
+    /// Schedules the next run, delayed by a random amount of up to `jitter`
+    fn reschedule(&mut self, jitter: Duration) {
+        let jitter = jitter.mul_f64(fastrand::f64());
+        self.next_run = Instant::now() + self.interval + jitter;
+    }
+}
+
+/// Periodically runs clean and sends reminders
+///
+/// If started by systemd with `Type=notify`, readiness is signalled once the
+/// daemon is set up, and the watchdog is kept alive while it is running.  As
+/// a single clean of a large filesystem may take longer than the watchdog's
+/// timeout, it is fed by a thread of its own rather than between tasks.  On
+/// SIGTERM, a running task is finished before the daemon exits.
+pub fn run(conn: &mut Connection, config: &config::Config) -> ! {
+    if caller_uid() != 0 {
+        eprintln!("You are not allowed to execute this operation");
+        process::exit(exit_codes::INSUFFICIENT_PRIVILEGES);
+    }
+
+    let mut tasks = vec![
+        Task::new("clean", config.daemon.clean_interval),
+        Task::new("permissions", config.daemon.permissions_interval),
+    ];
+    if config.notifications.is_some() {
+        tasks.push(Task::new("remind", config.daemon.remind_interval));
+    }
+    let jitter = config.daemon.jitter.to_std().unwrap();
+
+    let mut watchdog_usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut watchdog_usec) {
+        // ping twice per watchdog period to be on the safe side
+        let watchdog_interval = Duration::from_micros(watchdog_usec) / 2;
+        thread::spawn(move || loop {
+            let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+            thread::sleep(watchdog_interval);
+        });
+    }
+    shutdown::install_handler();
+    let _ = sd_notify::notify(false, &[NotifyState::Ready]);
+
+    loop {
+        if shutdown::requested() {
+            let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+            process::exit(0);
+        }
+        for task in tasks.iter_mut().filter(|t| t.next_run <= Instant::now()) {
+            let _ = sd_notify::notify(
+                false,
+                &[NotifyState::Status(&format!("running {}", task.name))],
+            );
+            match task.name {
+                "clean" => run_clean(conn, config),
+                "remind" => run_remind(conn, config),
+                "permissions" => run_permissions(conn, config),
+                _ => unreachable!(),
+            }
+            task.reschedule(jitter);
+            let _ = sd_notify::notify(false, &[NotifyState::Status("idle")]);
+            if shutdown::requested() {
+                break;
+            }
+        }
+
+        let next_run = tasks.iter().map(|t| t.next_run).min().unwrap();
+        let sleep = next_run.saturating_duration_since(Instant::now());
+        // wake up regularly to notice shutdown requests
+        thread::sleep(sleep.min(SHUTDOWN_POLL_INTERVAL));
+    }
+}
+
+fn run_clean(conn: &mut Connection, config: &config::Config) {
+    // an admin may be running clean by hand; we'll just try again next time
+    let Some(_lock) = crate::try_lock_clean(&config.db_path) else {
+        eprintln!("Skipping clean, as another one is already running");
+        return;
+    };
+    if let Err(e) = crate::clean(
+        conn,
+        &config.filesystems,
+        &config.notifications,
+        &config.hooks,
+        &config.clean,
+        &config.shares,
+        false,
+        false,
+    ) {
+        eprintln!("Clean failed: {}", e);
+    }
+}
+
+fn run_permissions(conn: &mut Connection, config: &config::Config) {
+    let transaction = db::transaction(conn).unwrap();
+    if let Err(e) = permissions::retry(&transaction, &config.filesystems, &config.notifications) {
+        eprintln!("Retrying permission setups failed: {}", e);
+    }
+    transaction.commit().unwrap();
+}
+
+fn run_remind(conn: &mut Connection, config: &config::Config) {
+    let Some(notifications) = &config.notifications else {
+        return;
+    };
+    let transaction = db::transaction(conn).unwrap();
+    for (volume, days_left) in notify::remind(&transaction, notifications, &config.filesystems) {
+        println!("action=remind volume={} days_left={}", volume, days_left);
+    }
+    transaction.commit().unwrap();
+}
@@ -0,0 +1,590 @@
+//! Access to the database holding the state of all workspaces
+//!
+//! The database is usually a SQLite file on the host serving the workspaces.
+//! Sites sharing one database between many hosts can instead keep it in
+//! PostgreSQL, if built with the `postgres` feature.  The rest of workspaces
+//! only sees a [`Connection`], whose queries are run by one of the
+//! [`Backend`]s, so its SQL has to be understood by both of them.
+
+use chrono::{DateTime, Local, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use std::{cell::Cell, ops::Deref, path::Path, thread, time::Duration};
+
+/// Number of times starting a transaction is retried while the database is busy
+const BUSY_RETRIES: u32 = 5;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[cfg(feature = "postgres")]
+    #[error("{}", postgres_message(.0))]
+    Postgres(#[from] postgres::Error),
+    #[error("Query returned no rows")]
+    QueryReturnedNoRows,
+    #[error("Invalid value {value} in column {index}")]
+    InvalidColumnType { index: usize, value: String },
+    #[error("{0} cannot be stored in the database")]
+    ToSqlConversion(String),
+    #[error("{0}")]
+    Unsupported(&'static str),
+}
+
+impl Error {
+    /// Whether a uniqueness or other constraint was violated
+    pub fn is_constraint_violation(&self) -> bool {
+        match self {
+            Error::Sqlite(e) => {
+                e.sqlite_error_code() == Some(rusqlite::ErrorCode::ConstraintViolation)
+            }
+            #[cfg(feature = "postgres")]
+            Error::Postgres(e) => e.code().is_some_and(|code| code.code().starts_with("23")),
+            _ => false,
+        }
+    }
+
+    /// Whether the database was locked by others for longer than the timeout
+    pub fn is_busy(&self) -> bool {
+        match self {
+            Error::Sqlite(e) => e.sqlite_error_code() == Some(rusqlite::ErrorCode::DatabaseBusy),
+            #[cfg(feature = "postgres")]
+            Error::Postgres(e) => e.code() == Some(&postgres::error::SqlState::LOCK_NOT_AVAILABLE),
+            _ => false,
+        }
+    }
+}
+
+/// Describes a PostgreSQL error, including the server's message if any
+#[cfg(feature = "postgres")]
+fn postgres_message(e: &postgres::Error) -> String {
+    match e.as_db_error() {
+        Some(db_error) => db_error.to_string(),
+        None => e.to_string(),
+    }
+}
+
+/// A single value passed to or returned by a query
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// Types which can be passed as query parameters
+pub trait ToSql {
+    fn to_sql(&self) -> Result<Value>;
+}
+
+/// Types which can be read from query results
+pub trait FromSql: Sized {
+    /// Converts `value`, returning `None` if it is of an unsuitable type
+    fn from_sql(value: &Value) -> Option<Self>;
+}
+
+impl<T: ToSql + ?Sized> ToSql for &T {
+    fn to_sql(&self) -> Result<Value> {
+        (**self).to_sql()
+    }
+}
+
+impl<T: ToSql> ToSql for Option<T> {
+    fn to_sql(&self) -> Result<Value> {
+        match self {
+            Some(value) => value.to_sql(),
+            None => Ok(Value::Null),
+        }
+    }
+}
+
+impl ToSql for Value {
+    fn to_sql(&self) -> Result<Value> {
+        Ok(self.clone())
+    }
+}
+
+impl ToSql for str {
+    fn to_sql(&self) -> Result<Value> {
+        Ok(Value::Text(self.to_string()))
+    }
+}
+
+impl ToSql for String {
+    fn to_sql(&self) -> Result<Value> {
+        self.as_str().to_sql()
+    }
+}
+
+impl ToSql for [u8] {
+    fn to_sql(&self) -> Result<Value> {
+        Ok(Value::Blob(self.to_vec()))
+    }
+}
+
+impl ToSql for Vec<u8> {
+    fn to_sql(&self) -> Result<Value> {
+        self.as_slice().to_sql()
+    }
+}
+
+impl ToSql for bool {
+    fn to_sql(&self) -> Result<Value> {
+        Ok(Value::Integer(i64::from(*self)))
+    }
+}
+
+impl ToSql for f64 {
+    fn to_sql(&self) -> Result<Value> {
+        Ok(Value::Real(*self))
+    }
+}
+
+macro_rules! integer_sql {
+    ($($t:ty),*) => {
+        $(
+            impl ToSql for $t {
+                fn to_sql(&self) -> Result<Value> {
+                    i64::try_from(*self)
+                        .map(Value::Integer)
+                        .map_err(|_| Error::ToSqlConversion(self.to_string()))
+                }
+            }
+
+            impl FromSql for $t {
+                fn from_sql(value: &Value) -> Option<Self> {
+                    match value {
+                        Value::Integer(n) => (*n).try_into().ok(),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+integer_sql!(i32, i64, u32, u64, usize);
+
+impl<T: FromSql> FromSql for Option<T> {
+    fn from_sql(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(None),
+            value => T::from_sql(value).map(Some),
+        }
+    }
+}
+
+impl FromSql for Value {
+    fn from_sql(value: &Value) -> Option<Self> {
+        Some(value.clone())
+    }
+}
+
+impl FromSql for String {
+    fn from_sql(value: &Value) -> Option<Self> {
+        match value {
+            Value::Text(text) => Some(text.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromSql for Vec<u8> {
+    fn from_sql(value: &Value) -> Option<Self> {
+        match value {
+            Value::Blob(bytes) => Some(bytes.clone()),
+            Value::Text(text) => Some(text.as_bytes().to_vec()),
+            _ => None,
+        }
+    }
+}
+
+impl FromSql for bool {
+    fn from_sql(value: &Value) -> Option<Self> {
+        match value {
+            Value::Integer(n) => Some(*n != 0),
+            _ => None,
+        }
+    }
+}
+
+impl FromSql for f64 {
+    fn from_sql(value: &Value) -> Option<Self> {
+        match value {
+            Value::Real(x) => Some(*x),
+            Value::Integer(n) => Some(*n as f64),
+            _ => None,
+        }
+    }
+}
+
+/// Parameters of a query, usually a tuple or array of [`ToSql`] values
+pub trait Params {
+    fn values(self) -> Result<Vec<Value>>;
+}
+
+impl Params for () {
+    fn values(self) -> Result<Vec<Value>> {
+        Ok(vec![])
+    }
+}
+
+impl Params for [&dyn ToSql; 0] {
+    fn values(self) -> Result<Vec<Value>> {
+        Ok(vec![])
+    }
+}
+
+macro_rules! array_params {
+    ($($n:literal),*) => {
+        $(
+            impl<T: ToSql> Params for [T; $n] {
+                fn values(self) -> Result<Vec<Value>> {
+                    self.iter().map(ToSql::to_sql).collect()
+                }
+            }
+        )*
+    };
+}
+
+array_params!(1, 2, 3, 4, 5, 6, 7, 8);
+
+macro_rules! tuple_params {
+    ($(($($t:ident $i:tt),*)),*) => {
+        $(
+            impl<$($t: ToSql),*> Params for ($($t,)*) {
+                fn values(self) -> Result<Vec<Value>> {
+                    Ok(vec![$(self.$i.to_sql()?),*])
+                }
+            }
+        )*
+    };
+}
+
+tuple_params!(
+    (A 0),
+    (A 0, B 1),
+    (A 0, B 1, C 2),
+    (A 0, B 1, C 2, D 3),
+    (A 0, B 1, C 2, D 3, E 4),
+    (A 0, B 1, C 2, D 3, E 4, F 5),
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6),
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7),
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8),
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9),
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10),
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11)
+);
+
+/// A row returned by a query
+#[derive(Debug, Clone)]
+pub struct Row(pub Vec<Value>);
+
+impl Row {
+    /// Value of the column at `index`, converted to `T`
+    pub fn get<I: RowIndex, T: FromSql>(&self, index: I) -> Result<T> {
+        let index = index.index();
+        let value = self.0.get(index).unwrap_or(&Value::Null);
+        T::from_sql(value).ok_or_else(|| Error::InvalidColumnType {
+            index,
+            value: format!("{:?}", value),
+        })
+    }
+}
+
+/// Ways to refer to the columns of a [`Row`]
+pub trait RowIndex {
+    fn index(self) -> usize;
+}
+
+impl RowIndex for usize {
+    fn index(self) -> usize {
+        self
+    }
+}
+
+/// Rows returned by a query, which are all fetched up front
+pub struct Rows {
+    rows: std::vec::IntoIter<Row>,
+    current: Option<Row>,
+}
+
+impl Rows {
+    /// Advances to the next row, returning it unless there are no more
+    ///
+    /// Never fails, but returns a `Result` so it reads like the rest of the
+    /// database code.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<&Row>> {
+        self.current = self.rows.next();
+        Ok(self.current.as_ref())
+    }
+}
+
+/// A database a [`Connection`] is made to
+pub trait Backend {
+    /// Runs a statement, returning the number of rows it changed
+    fn execute(&self, sql: &str, params: &[Value]) -> Result<usize>;
+    /// Runs several statements separated by semicolons, without parameters
+    fn execute_batch(&self, sql: &str) -> Result<()>;
+    /// Runs a query, returning all rows of its result
+    fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<Row>>;
+    /// Starts a transaction, taking the write lock right away
+    fn begin(&self) -> Result<()>;
+    fn commit(&self) -> Result<()>;
+    fn rollback(&self) -> Result<()>;
+    /// Schema version of the database, which is 0 for new ones
+    fn version(&self) -> Result<usize>;
+    /// Records the schema version, within the transaction of a migration
+    fn set_version(&self, version: usize) -> Result<()>;
+    /// Prepares a new database for its first migration
+    fn initialize(&self) -> Result<()>;
+    /// Problems found by a check of the database's integrity
+    fn integrity_check(&self) -> Result<Vec<String>>;
+    /// Copies the database to a new SQLite file at `path`
+    fn backup(&self, path: &Path) -> Result<()>;
+    /// Replaces the contents of the database with the SQLite file at `path`
+    fn restore(&mut self, path: &Path) -> Result<()>;
+    /// A database to use in a dry run, printing instead of keeping changes
+    fn dry_run(&self) -> Result<Box<dyn Backend>>;
+}
+
+/// A connection to the database
+pub struct Connection {
+    backend: Box<dyn Backend>,
+    changes: Cell<i64>,
+}
+
+impl Connection {
+    pub fn new(backend: Box<dyn Backend>) -> Self {
+        Connection {
+            backend,
+            changes: Cell::new(0),
+        }
+    }
+
+    pub fn backend(&self) -> &dyn Backend {
+        self.backend.as_ref()
+    }
+
+    /// Runs a statement, returning the number of rows it changed
+    pub fn execute(&self, sql: &str, params: impl Params) -> Result<usize> {
+        let changed = self.backend.execute(sql, &params.values()?)?;
+        self.changes.set(self.changes.get() + changed as i64);
+        Ok(changed)
+    }
+
+    /// Runs several statements separated by semicolons, without parameters
+    pub fn execute_batch(&self, sql: &str) -> Result<()> {
+        self.backend.execute_batch(sql)
+    }
+
+    /// Runs a query, converting its first row with `f`
+    pub fn query_row<T>(
+        &self,
+        sql: &str,
+        params: impl Params,
+        f: impl FnOnce(&Row) -> Result<T>,
+    ) -> Result<T> {
+        match self.backend.query(sql, &params.values()?)?.first() {
+            Some(row) => f(row),
+            None => Err(Error::QueryReturnedNoRows),
+        }
+    }
+
+    pub fn prepare(&self, sql: &str) -> Result<Statement<'_>> {
+        Ok(Statement {
+            conn: self,
+            sql: sql.to_string(),
+        })
+    }
+
+    /// Number of rows inserted, updated or deleted through this connection
+    pub fn total_changes(&self) -> i64 {
+        self.changes.get()
+    }
+}
+
+/// A query to be run once or several times
+pub struct Statement<'a> {
+    conn: &'a Connection,
+    sql: String,
+}
+
+impl Statement<'_> {
+    pub fn query(&mut self, params: impl Params) -> Result<Rows> {
+        Ok(Rows {
+            rows: self
+                .conn
+                .backend
+                .query(&self.sql, &params.values()?)?
+                .into_iter(),
+            current: None,
+        })
+    }
+
+    /// Runs the query, converting each row with `f`
+    pub fn query_map<T>(
+        &mut self,
+        params: impl Params,
+        mut f: impl FnMut(&Row) -> Result<T>,
+    ) -> Result<std::vec::IntoIter<Result<T>>> {
+        let rows = self.conn.backend.query(&self.sql, &params.values()?)?;
+        Ok(rows.iter().map(&mut f).collect::<Vec<_>>().into_iter())
+    }
+}
+
+/// Turns the error of a query returning no rows into `None`
+pub trait OptionalExtension<T> {
+    fn optional(self) -> Result<Option<T>>;
+}
+
+impl<T> OptionalExtension<T> for Result<T> {
+    fn optional(self) -> Result<Option<T>> {
+        match self {
+            Ok(value) => Ok(Some(value)),
+            Err(Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A transaction which is rolled back unless committed
+pub struct Transaction<'a> {
+    conn: &'a mut Connection,
+    done: bool,
+}
+
+impl Transaction<'_> {
+    pub fn commit(mut self) -> Result<()> {
+        self.done = true;
+        self.conn.backend.commit()
+    }
+}
+
+impl Deref for Transaction<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.conn.backend.rollback();
+        }
+    }
+}
+
+/// A point in time as stored in the database
+///
+/// Timestamps are stored as UTC RFC3339 strings with millisecond precision,
+/// e.g. `2023-05-17T12:00:00.000Z`.  As they are all of the same width and
+/// timezone, they can be compared lexicographically in SQL, regardless of the
+/// timezone of the host that wrote them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(pub DateTime<Utc>);
+
+impl<Tz: TimeZone> From<DateTime<Tz>> for Timestamp {
+    fn from(time: DateTime<Tz>) -> Self {
+        Timestamp(time.with_timezone(&Utc))
+    }
+}
+
+impl From<Timestamp> for DateTime<Local> {
+    fn from(time: Timestamp) -> Self {
+        time.0.with_timezone(&Local)
+    }
+}
+
+impl ToSql for Timestamp {
+    fn to_sql(&self) -> Result<Value> {
+        Ok(Value::Text(
+            self.0.to_rfc3339_opts(SecondsFormat::Millis, true),
+        ))
+    }
+}
+
+impl FromSql for Timestamp {
+    fn from_sql(value: &Value) -> Option<Self> {
+        let Value::Text(text) = value else {
+            return None;
+        };
+        if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+            return Some(time.into());
+        }
+        // fall back to the formats used by databases before v2
+        let text = text.replacen(' ', "T", 1);
+        if let Ok(time) = DateTime::parse_from_rfc3339(&text) {
+            return Some(time.into());
+        }
+        NaiveDateTime::parse_from_str(&text, "%Y-%m-%dT%H:%M:%S%.f")
+            .ok()
+            .map(|time| Timestamp(Utc.from_utc_datetime(&time)))
+    }
+}
+
+/// Opens the database, waiting up to `timeout` whenever it is locked by others
+///
+/// The PostgreSQL database at `url` is used if one is given, the SQLite file
+/// at `path` otherwise.
+pub fn open(path: &Path, url: Option<&str>, timeout: Duration) -> Result<Connection> {
+    let backend: Box<dyn Backend> = match url {
+        #[cfg(feature = "postgres")]
+        Some(url) => Box::new(crate::pg::Postgres::open(url, timeout)?),
+        #[cfg(not(feature = "postgres"))]
+        Some(_) => {
+            return Err(Error::Unsupported(
+                "database_url requires workspaces to be built with the postgres feature",
+            ))
+        }
+        None => Box::new(crate::sqlite::Sqlite::open(path, timeout)?),
+    };
+    Ok(Connection::new(backend))
+}
+
+/// Starts a transaction which may write to the database
+///
+/// The write lock is taken right away, so concurrent writers wait for each
+/// other instead of failing when they try to upgrade their locks.  If the
+/// database stays busy beyond the busy timeout, this is retried a few times.
+pub fn transaction(conn: &mut Connection) -> Result<Transaction<'_>> {
+    let mut retries = 0;
+    loop {
+        // `conn` being borrowed mutably guarantees no other transaction is open
+        match conn.backend.begin() {
+            Err(e) if e.is_busy() => {
+                if retries == BUSY_RETRIES {
+                    return Err(e);
+                }
+                retries += 1;
+                tracing::warn!(retries, "database is busy, retrying");
+                thread::sleep(Duration::from_millis(100 * u64::from(retries)));
+            }
+            Err(e) => return Err(e),
+            Ok(()) => return Ok(Transaction { conn, done: false }),
+        }
+    }
+}
+
+/// Copies the database to a new file at `path`
+///
+/// The copy is made in small steps, so other processes can keep using the
+/// database meanwhile.
+pub fn backup(conn: &Connection, path: &Path) -> Result<()> {
+    conn.backend.backup(path)
+}
+
+/// Replaces the contents of the database with those of the backup at `path`
+pub fn restore(conn: &mut Connection, path: &Path) -> Result<()> {
+    conn.backend.restore(path)
+}
+
+/// Schema version of the database at `path`, which is left untouched
+pub fn version(path: &Path) -> Result<usize> {
+    crate::sqlite::version(path)
+}
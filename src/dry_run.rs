@@ -0,0 +1,49 @@
+//! Previewing the effect of a command without making any changes
+//!
+//! In a dry run, the database is replaced by one whose modifying statements
+//! are printed but never kept, e.g. an in-memory copy of a SQLite database,
+//! and commands with side effects, like changing zfs volumes, are printed
+//! instead of being run.
+
+use crate::db::{self, Connection};
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether this is a dry run
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Prints what would be done in a dry run, returning whether to skip doing it
+pub fn skip(action: impl fmt::Display) -> bool {
+    if enabled() {
+        println!("would run: {}", action);
+    }
+    enabled()
+}
+
+/// Starts a dry run, returning the connection to use instead of `conn`
+pub fn start(conn: &Connection) -> db::Result<Connection> {
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(Connection::new(conn.backend().dry_run()?))
+}
+
+/// Prints statements which would modify the database
+pub fn print_statement(sql: &str) {
+    let sql = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+    let keyword = sql
+        .split(' ')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+    if !matches!(
+        keyword.as_str(),
+        "SELECT" | "PRAGMA" | "BEGIN" | "COMMIT" | "ROLLBACK" | "SAVEPOINT" | "RELEASE"
+    ) {
+        println!("would execute: {}", sql);
+    }
+}
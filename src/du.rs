@@ -0,0 +1,132 @@
+//! Disk usage of the directories within a workspace
+
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+    thread,
+};
+
+/// A directory still to be read
+struct Job {
+    path: PathBuf,
+    /// Path relative to the root, truncated to the reported depth
+    key: PathBuf,
+    depth: usize,
+}
+
+struct Queue {
+    jobs: Vec<Job>,
+    /// Number of jobs currently being processed
+    active: usize,
+}
+
+/// Sizes of the directories below `root` up to `max_depth` levels deep
+pub struct Usage {
+    /// Directories relative to `root` and their size in bytes, including `root` itself as ""
+    pub dirs: HashMap<PathBuf, u64>,
+    /// Number of directories which could not be read
+    pub errors: usize,
+}
+
+/// Walks `root` with `workers` threads, adding up the allocated size of everything below it
+///
+/// Other filesystems mounted below `root` and symlinks are not followed.
+pub fn walk(root: &Path, max_depth: usize, workers: usize) -> std::io::Result<Usage> {
+    let dev = fs::symlink_metadata(root)?.dev();
+    let queue = Mutex::new(Queue {
+        jobs: vec![Job {
+            path: root.to_path_buf(),
+            key: PathBuf::new(),
+            depth: 0,
+        }],
+        active: 0,
+    });
+    let cvar = Condvar::new();
+
+    let results: Vec<(HashMap<PathBuf, u64>, usize)> = thread::scope(|s| {
+        let handles: Vec<_> = (0..workers.max(1))
+            .map(|_| s.spawn(|| worker(&queue, &cvar, dev, max_depth)))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let mut own: HashMap<PathBuf, u64> = HashMap::new();
+    let mut errors = 0;
+    for (sizes, errs) in results {
+        for (key, size) in sizes {
+            *own.entry(key).or_default() += size;
+        }
+        errors += errs;
+    }
+
+    // every directory also contains the sizes of its subdirectories
+    let mut dirs: HashMap<PathBuf, u64> = HashMap::new();
+    for (key, size) in own {
+        for ancestor in key.ancestors() {
+            *dirs.entry(ancestor.to_path_buf()).or_default() += size;
+        }
+    }
+    Ok(Usage { dirs, errors })
+}
+
+/// Processes jobs until there are none left, returning the sizes found and the number of errors
+fn worker(
+    queue: &Mutex<Queue>,
+    cvar: &Condvar,
+    dev: u64,
+    max_depth: usize,
+) -> (HashMap<PathBuf, u64>, usize) {
+    let mut sizes: HashMap<PathBuf, u64> = HashMap::new();
+    let mut errors = 0;
+    loop {
+        let job = {
+            let mut queue = queue.lock().unwrap();
+            loop {
+                if let Some(job) = queue.jobs.pop() {
+                    queue.active += 1;
+                    break job;
+                }
+                if queue.active == 0 {
+                    cvar.notify_all();
+                    return (sizes, errors);
+                }
+                queue = cvar.wait(queue).unwrap();
+            }
+        };
+
+        let mut children = Vec::new();
+        let mut size = 0;
+        match fs::read_dir(&job.path) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    let Ok(metadata) = entry.metadata() else {
+                        continue;
+                    };
+                    size += metadata.blocks() * 512;
+                    if metadata.is_dir() && metadata.dev() == dev {
+                        let depth = job.depth + 1;
+                        children.push(Job {
+                            path: entry.path(),
+                            key: if depth <= max_depth {
+                                job.key.join(entry.file_name())
+                            } else {
+                                job.key.clone()
+                            },
+                            depth,
+                        });
+                    }
+                }
+            }
+            Err(_) => errors += 1,
+        }
+        *sizes.entry(job.key).or_default() += size;
+
+        let mut queue = queue.lock().unwrap();
+        queue.jobs.append(&mut children);
+        queue.active -= 1;
+        cvar.notify_all();
+    }
+}
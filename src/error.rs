@@ -0,0 +1,133 @@
+use crate::{db, exit_codes, zfs};
+use chrono::Duration;
+use std::io;
+use thiserror::Error;
+
+/// An error aborting a command
+///
+/// Each error is mapped to the exit code reported to the user in `main`.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("You are not allowed to execute this operation")]
+    InsufficientPrivileges,
+    #[error("{user} is not a member of group {group}")]
+    NotGroupMember { user: String, group: String },
+    #[error("User {0} does not exist")]
+    UnknownUser(String),
+    #[error("Group {0} does not exist")]
+    UnknownGroup(String),
+    #[error("Project {0} does not exist")]
+    UnknownProject(String),
+    #[error("{user} is not a member of project {project}")]
+    NotProjectMember { user: String, project: String },
+    #[error(
+        "Project {0} has used up its quota. Please free some space or ask for a larger quota."
+    )]
+    ProjectQuotaExceeded(String),
+    /// The filesystem is retired; the message suggests how to proceed
+    #[error("Filesystem is retired. {0}")]
+    FilesystemRetired(&'static str),
+    /// The filesystem is disabled; the message suggests how to proceed
+    #[error("Filesystem is disabled. {0}")]
+    FilesystemDisabled(&'static str),
+    /// Less than the filesystem's reserved share of space is left
+    #[error("Filesystem is almost full, with less than {0}% of its space left. Please try another filesystem.")]
+    FilesystemFull(f64),
+    #[error("Duration can be at most {} days", .0.num_days())]
+    DurationTooHigh(Duration),
+    #[error("Quota can be at most {}", crate::units::format_size(*.0, false))]
+    QuotaTooHigh(u64),
+    #[error("Could not find a matching filesystem={filesystem}, user={user}, name={name}")]
+    UnknownWorkspace {
+        filesystem: String,
+        user: String,
+        name: String,
+    },
+    #[error(
+        "Could not find a trashed workspace filesystem={filesystem}, user={user}, name={name}"
+    )]
+    UnknownTrashedWorkspace {
+        filesystem: String,
+        user: String,
+        name: String,
+    },
+    #[error("This workspace already exists. You can extend it using `workspaces extend`.")]
+    WorkspaceExists,
+    #[error("Please specify a duration with `-d <DURATION>`")]
+    DurationRequired,
+    /// The workspace was locked by `workspaces lock`, for the given reason
+    #[error(
+        "This workspace is locked by an administrator{}",
+        .0.as_ref().map_or(String::new(), |reason| format!(": {}", reason))
+    )]
+    WorkspaceLocked(Option<String>),
+    #[error("Reference workspaces do not expire")]
+    ReferenceWorkspace,
+    #[error("The target workspace already exists")]
+    TargetExists,
+    #[error("{0}")]
+    InvalidArgument(String),
+    #[error("{failed} of {total} operations failed")]
+    BatchFailed { failed: usize, total: usize },
+    #[error("Another `workspaces clean` is already running")]
+    CleanRunning,
+    #[error("The database has schema version {version}, but this version of workspaces only supports up to {supported}. Please upgrade workspaces.")]
+    DatabaseTooNew { version: usize, supported: usize },
+    /// `config check` found problems, which it has already reported
+    #[error("Found {0} problem(s) in the configuration")]
+    InvalidConfig(usize),
+    /// `db check` found problems, which it has already reported
+    #[error("Found {0} problem(s) in the database")]
+    InconsistentDatabase(usize),
+    #[error("ZFS operation failed: {0}")]
+    Zfs(#[from] zfs::Error),
+    #[error("database operation failed: {0}")]
+    Database(#[from] db::Error),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// An external command, such as `setfacl`, failed
+    #[error("{0}")]
+    Command(String),
+    /// A hook run before the operation did not succeed
+    #[error("{0}")]
+    HookRejected(String),
+}
+
+impl Error {
+    /// The exit code to terminate with because of this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::InsufficientPrivileges
+            | Error::NotGroupMember { .. }
+            | Error::NotProjectMember { .. } => exit_codes::INSUFFICIENT_PRIVILEGES,
+            Error::UnknownUser(_) => exit_codes::UNKNOWN_USER,
+            Error::UnknownGroup(_) => exit_codes::UNKNOWN_GROUP,
+            Error::FilesystemRetired(_)
+            | Error::FilesystemDisabled(_)
+            | Error::FilesystemFull(_) => exit_codes::FS_DISABLED,
+            Error::DurationTooHigh(_) => exit_codes::TOO_HIGH_DURATION,
+            Error::QuotaTooHigh(_) => exit_codes::TOO_HIGH_QUOTA,
+            Error::UnknownWorkspace { .. } | Error::UnknownTrashedWorkspace { .. } => {
+                exit_codes::UNKNOWN_WORKSPACE
+            }
+            Error::WorkspaceExists => exit_codes::WORKSPACE_EXISTS,
+            Error::TargetExists => exit_codes::WORKSPACE_EXISTS,
+            Error::WorkspaceLocked(_) => exit_codes::WORKSPACE_LOCKED,
+            Error::DurationRequired
+            | Error::ReferenceWorkspace
+            | Error::UnknownProject(_)
+            | Error::InvalidArgument(_) => exit_codes::INVALID_ARGUMENT,
+            Error::CleanRunning => exit_codes::CLEAN_RUNNING,
+            Error::InvalidConfig(_) => exit_codes::INVALID_CONFIG,
+            Error::ProjectQuotaExceeded(_) => exit_codes::QUOTA_EXCEEDED,
+            Error::HookRejected(_) => exit_codes::HOOK_REJECTED,
+            Error::Zfs(_)
+            | Error::Database(_)
+            | Error::Io(_)
+            | Error::Command(_)
+            | Error::BatchFailed { .. }
+            | Error::InconsistentDatabase(_)
+            | Error::DatabaseTooNew { .. } => exit_codes::OPERATION_FAILED,
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Resolving the groups users belong to
+//!
+//! Memberships are looked up with `getgrouplist`, so they come from whatever
+//! the system's NSS is configured with, e.g. LDAP through SSSD, rather than
+//! the member lists of `/etc/group`.  As such lookups can be slow, each user's
+//! groups are cached in the database for `group_cache_ttl` seconds.
+
+use crate::db::{Connection, OptionalExtension};
+use crate::{db, dry_run};
+use chrono::{Duration, Local};
+use std::{
+    ffi::CString,
+    sync::atomic::{AtomicBool, AtomicI64, Ordering},
+};
+use users::{get_group_by_gid, get_user_by_name};
+
+static TTL_SECONDS: AtomicI64 = AtomicI64::new(900);
+static REFRESH: AtomicBool = AtomicBool::new(false);
+
+/// Sets how long cached memberships are used, and whether to ignore them
+pub fn set_policy(ttl: Duration, refresh: bool) {
+    TTL_SECONDS.store(ttl.num_seconds(), Ordering::Relaxed);
+    REFRESH.store(refresh, Ordering::Relaxed);
+}
+
+/// Names of all groups `user` belongs to, including the primary group
+///
+/// Unknown users belong to no groups.
+pub fn of_user(conn: &Connection, user: &str) -> Vec<String> {
+    if !REFRESH.load(Ordering::Relaxed) {
+        let since = Local::now() - Duration::seconds(TTL_SECONDS.load(Ordering::Relaxed));
+        let cached: Option<String> = conn
+            .query_row(
+                "SELECT groups FROM group_memberships WHERE user = ?1 AND time >= ?2",
+                (user, db::Timestamp::from(since)),
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap_or(None);
+        if let Some(groups) = cached {
+            return groups.split_whitespace().map(str::to_string).collect();
+        }
+    }
+
+    let groups = lookup(user);
+    // only a cache, so a busy database is no reason to fail
+    if !dry_run::enabled() {
+        let _ = conn.execute(
+            "INSERT INTO group_memberships (user, groups, time)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT (user) DO UPDATE
+                    SET groups = excluded.groups, time = excluded.time",
+            (user, groups.join(" "), db::Timestamp::from(Local::now())),
+        );
+    }
+    groups
+}
+
+/// Looks up the groups of `user` through NSS, bypassing the cache
+///
+/// `users::get_user_groups` is not used, as it reports the unused part of its
+/// buffer as memberships of group 0.
+pub fn lookup(user: &str) -> Vec<String> {
+    let (Some(user_info), Ok(name)) = (get_user_by_name(user), CString::new(user)) else {
+        return vec![];
+    };
+    let mut gids: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut count = gids.len() as libc::c_int;
+        // SAFETY: `count` is the length of the buffer, which is updated to the
+        // number of groups found
+        let result = unsafe {
+            libc::getgrouplist(
+                name.as_ptr(),
+                user_info.primary_group_id(),
+                gids.as_mut_ptr(),
+                &mut count,
+            )
+        };
+        if result >= 0 {
+            gids.truncate(count as usize);
+            break;
+        }
+        // `count` holds the required size, at least with glibc
+        gids.resize((count as usize).max(gids.len() * 2), 0);
+    }
+    gids.into_iter()
+        .filter_map(get_group_by_gid)
+        .map(|group| group.name().to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Checks whether `user` belongs to `group`, either as primary or as
+/// supplementary group
+pub fn is_member(conn: &Connection, user: &str, group: &str) -> bool {
+    of_user(conn, user).iter().any(|g| g == group)
+}
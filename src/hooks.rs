@@ -0,0 +1,117 @@
+use crate::{config, dry_run, error::Error, privileges, server::caller_uid};
+use chrono::{DateTime, Local, SecondsFormat};
+use std::{
+    fmt, io,
+    path::Path,
+    process::{Command, ExitStatus},
+};
+
+/// A point in a workspace's lifecycle at which hooks are run
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    Create,
+    Extend,
+    Expire,
+    Destroy,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Event::Create => "create",
+                Event::Extend => "extend",
+                Event::Expire => "expire",
+                Event::Destroy => "destroy",
+            }
+        )
+    }
+}
+
+/// The workspace a hook is run for
+pub struct Workspace<'a> {
+    pub filesystem_name: &'a str,
+    pub user: &'a str,
+    pub name: &'a str,
+    pub volume: &'a str,
+    pub expiration_time: &'a DateTime<Local>,
+}
+
+/// Runs the hook configured for `event`, if any
+///
+/// The workspace's metadata is passed in `WORKSPACES_*` environment variables.
+/// Hooks are run after the operation has completed, so a failing hook only
+/// results in a warning.
+pub fn run(hooks: &config::Hooks, event: Event, workspace: &Workspace) {
+    let hook = match event {
+        Event::Create => &hooks.on_create,
+        Event::Extend => &hooks.on_extend,
+        Event::Expire => &hooks.on_expire,
+        Event::Destroy => &hooks.on_destroy,
+    };
+    let Some(hook) = hook else {
+        return;
+    };
+    if dry_run::skip(format_args!("{} hook {}", event, hook.display())) {
+        return;
+    }
+
+    match execute(hook, event, workspace) {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Hook {:?} for {} exited with {}", hook, event, status),
+        Err(e) => eprintln!("Could not run hook {:?} for {}: {}", hook, event, e),
+    }
+}
+
+/// Runs the hook configured to be run before `event`, if any
+///
+/// The hook is given the same environment as those run afterwards, with the
+/// expiration time the workspace is about to get.  Unless it exits
+/// successfully, the operation is aborted.
+pub fn run_pre(hooks: &config::Hooks, event: Event, workspace: &Workspace) -> Result<(), Error> {
+    let hook = match event {
+        Event::Create => &hooks.pre_create,
+        Event::Extend => &hooks.pre_extend,
+        Event::Expire => &hooks.pre_expire,
+        Event::Destroy => &hooks.pre_destroy,
+    };
+    let Some(hook) = hook else {
+        return Ok(());
+    };
+    // pretend it agreed, to show what else would be done
+    if dry_run::skip(format_args!("pre-{} hook {}", event, hook.display())) {
+        return Ok(());
+    }
+
+    match execute(hook, event, workspace) {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Error::HookRejected(format!(
+            "Hook {:?} for {} exited with {}",
+            hook, event, status
+        ))),
+        Err(e) => Err(Error::HookRejected(format!(
+            "Could not run hook {:?} for {}: {}",
+            hook, event, e
+        ))),
+    }
+}
+
+/// Runs `hook` with the metadata of `workspace` in its environment
+fn execute(hook: &Path, event: Event, workspace: &Workspace) -> io::Result<ExitStatus> {
+    privileges::as_root(&mut Command::new(hook))
+        .env("WORKSPACES_EVENT", event.to_string())
+        .env("WORKSPACES_FILESYSTEM", workspace.filesystem_name)
+        .env("WORKSPACES_USER", workspace.user)
+        .env("WORKSPACES_NAME", workspace.name)
+        .env("WORKSPACES_VOLUME", workspace.volume)
+        .env(
+            "WORKSPACES_EXPIRATION_TIME",
+            workspace
+                .expiration_time
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+        )
+        .env("WORKSPACES_ACTING_UID", caller_uid().to_string())
+        .status()
+}
@@ -0,0 +1,112 @@
+//! Reading the databases of other workspace tools, to migrate their workspaces
+
+use chrono::{DateTime, Local, TimeZone};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// A workspace found in another tool's database
+#[derive(Debug)]
+pub struct Entry {
+    pub user: String,
+    pub name: String,
+    /// Directory holding the workspace's data
+    pub path: PathBuf,
+    pub expiration_time: DateTime<Local>,
+}
+
+/// Reads the workspaces of the HPC workspace tool `ws_allocate`
+///
+/// `path` is either one of its database directories or its `ws.conf`, in which
+/// case the databases of all its filesystems are read.  Each database holds a
+/// file `<USER>-<NAME>` per workspace, in either the current YAML format or
+/// the old format of an expiration time followed by the workspace's path.
+/// Returns the entries found and descriptions of the files which could not be
+/// read.
+pub fn read_ws_allocate(path: &Path) -> io::Result<(Vec<Entry>, Vec<String>)> {
+    let databases = if path.is_dir() {
+        vec![path.to_path_buf()]
+    } else {
+        fs::read_to_string(path)?
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("database:"))
+            .map(|database| PathBuf::from(unquote(database)))
+            .collect()
+    };
+
+    let mut entries = vec![];
+    let mut errors = vec![];
+    for database in databases {
+        let mut files: Vec<PathBuf> = fs::read_dir(&database)?
+            .filter_map(|entry| entry.ok())
+            // skips the directory of removed workspaces and lock files
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+            .map(|entry| entry.path())
+            .collect();
+        files.sort();
+        for file in files {
+            match read_entry(&file) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => errors.push(format!("{}: {}", file.display(), e)),
+            }
+        }
+    }
+    Ok((entries, errors))
+}
+
+/// Reads a single workspace of `ws_allocate`
+fn read_entry(file: &Path) -> Result<Entry, String> {
+    let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+    // user names rarely contain dashes, workspace names often do
+    let Some((user, name)) = file_name.split_once('-') else {
+        return Err("not named <USER>-<NAME>".to_string());
+    };
+    let contents = fs::read_to_string(file).map_err(|e| e.to_string())?;
+
+    let (expiration, path) = if let Some(expiration) = value(&contents, "expiration") {
+        let Some(path) = value(&contents, "workspace") else {
+            return Err("no workspace path".to_string());
+        };
+        (expiration, path)
+    } else {
+        // the old format
+        let mut lines = contents.lines().map(str::trim);
+        match (lines.next(), lines.next()) {
+            (Some(expiration), Some(path)) => (expiration, path),
+            _ => return Err("unknown format".to_string()),
+        }
+    };
+    let expiration: i64 = expiration
+        .parse()
+        .map_err(|e| format!("invalid expiration time: {}", e))?;
+    let Some(expiration_time) = Local.timestamp_opt(expiration, 0).single() else {
+        return Err(format!("invalid expiration time {}", expiration));
+    };
+
+    Ok(Entry {
+        user: user.to_string(),
+        name: name.to_string(),
+        path: PathBuf::from(path),
+        expiration_time,
+    })
+}
+
+/// Looks up the value of a top-level `key: value` line of a YAML document
+fn value<'a>(contents: &'a str, key: &str) -> Option<&'a str> {
+    contents
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| unquote(value))
+}
+
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+        .unwrap_or(value)
+}
@@ -1,23 +1,71 @@
-use chrono::{DateTime, Duration, Local};
-use clap::Parser;
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone, Utc};
+use clap::{CommandFactory, FromArgMatches};
 use cli::FilesystemsColumns;
+use db::{Connection, OptionalExtension};
+use error::Error;
+use nix::unistd::{Gid, Uid};
 use prettytable::{
     color,
     format::{Alignment, FormatBuilder},
     Attr, Cell, Row, Table,
 };
-use rusqlite::Connection;
+use regex::Regex;
+use serde::Serialize;
+use server::{caller_uid, is_admin, is_caller};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    env,
+    ffi::OsString,
     fs,
-    os::unix::prelude::PermissionsExt,
-    path::PathBuf,
-    process::{self, Command},
+    io::{self, IsTerminal, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    process,
 };
-use users::{get_current_uid, get_current_username};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+use users::{get_effective_uid, get_group_by_name, get_user_by_name, get_user_by_uid};
 
+mod accounting;
+mod advise;
+mod archive;
+mod audit;
+mod bundle;
 mod cli;
+mod compat;
 mod config;
+mod daemon;
+mod db;
+mod dry_run;
+mod du;
+mod error;
+mod groups;
+mod hooks;
+mod import;
+mod metrics;
+mod migrations;
+mod notify;
+mod permissions;
+#[cfg(feature = "postgres")]
+mod pg;
+mod placement;
+mod pool;
+mod privileges;
+mod projects;
+mod select;
+mod server;
+mod shares;
+mod shutdown;
+mod sizes;
+mod sqlite;
+mod statistics;
+mod table;
+mod tags;
+mod tarball;
+mod units;
 mod zfs;
 
 mod exit_codes {
@@ -34,627 +82,5841 @@ mod exit_codes {
     pub const WORKSPACE_EXISTS: i32 = 5;
     /// No filesystem given and no default specified in configuration file
     pub const NO_FILESYSTEM_SPECIFIED: i32 = 6;
+    /// The user specified does not exist on this system
+    pub const UNKNOWN_USER: i32 = 7;
+    /// Another `clean` is already running
+    pub const CLEAN_RUNNING: i32 = 8;
+    /// The user tried configuring notifications, but they are not enabled
+    pub const NOTIFICATIONS_DISABLED: i32 = 9;
+    /// An argument was outside of the bounds allowed by the configuration
+    pub const INVALID_ARGUMENT: i32 = 10;
+    /// The group specified does not exist on this system
+    pub const UNKNOWN_GROUP: i32 = 11;
+    /// The `workspaces serve` socket could not be connected to
+    pub const SERVER_UNREACHABLE: i32 = 12;
+    /// An operation on ZFS, the database or the system failed
+    pub const OPERATION_FAILED: i32 = 13;
+    /// The user declined to confirm a destructive operation
+    pub const ABORTED: i32 = 14;
+    /// The configuration file could not be parsed or contains mistakes
+    pub const INVALID_CONFIG: i32 = 15;
+    /// The filesystem specified is served by another host
+    pub const REMOTE_FILESYSTEM: i32 = 16;
+    /// The project specified has used up its quota
+    pub const QUOTA_EXCEEDED: i32 = 17;
+    /// The user tried raising a workspace's quota beyond the filesystem's maximum
+    pub const TOO_HIGH_QUOTA: i32 = 18;
+    /// The workspace was locked by an administrator
+    pub const WORKSPACE_LOCKED: i32 = 19;
+    /// A hook run before the operation rejected it
+    pub const HOOK_REJECTED: i32 = 20;
 }
 
 /// Creates a new workspace
+///
+/// The workspace belongs to `group`, or else the project's group or the
+/// user's group of the same name.  Collaborative workspaces, as well as those
+/// belonging to a project's group, are writable by their group.
+#[allow(clippy::too_many_arguments)]
 fn create(
     conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
     filesystem_name: &str,
     filesystem: &config::Filesystem,
     user: &str,
     name: &str,
-    duration: &Duration,
-) {
-    if get_current_username().unwrap() != user && get_current_uid() != 0 {
-        eprintln!("You are not allowed to execute this operation");
-        process::exit(exit_codes::INSUFFICIENT_PRIVILEGES);
+    duration: &Option<Duration>,
+    class: cli::WorkspaceClass,
+    group: &Option<String>,
+    collaborative: bool,
+    tags: &[String],
+    comment: &Option<String>,
+    project: Option<(&str, &config::Project)>,
+    hooks: &config::Hooks,
+) -> Result<(), Error> {
+    if !is_caller(user) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
     }
-    if filesystem.disabled && get_current_uid() != 0 {
-        eprintln!("Filesystem is disabled. Please try another filesystem.");
-        process::exit(exit_codes::FS_DISABLED);
+    if class == cli::WorkspaceClass::Reference && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
     }
-    if duration > &filesystem.max_duration && get_current_uid() != 0 {
-        eprintln!(
-            "Duration can be at most {} days",
-            filesystem.max_duration.num_days()
-        );
-        process::exit(exit_codes::TOO_HIGH_DURATION);
+    if let Some(group) = group {
+        if get_group_by_name(group).is_none() {
+            return Err(Error::UnknownGroup(group.clone()));
+        }
+        if !groups::is_member(conn, user, group) && !is_admin() {
+            return Err(Error::NotGroupMember {
+                user: user.to_string(),
+                group: group.clone(),
+            });
+        }
+    }
+    if let Some((project_name, project)) = project {
+        if !is_admin() {
+            if !projects::is_member(conn, user, project) {
+                return Err(Error::NotProjectMember {
+                    user: user.to_string(),
+                    project: project_name.to_string(),
+                });
+            }
+            projects::check_quota(conn, filesystems, project_name, project)?;
+        }
+    }
+    if filesystem.retired {
+        return Err(Error::FilesystemRetired("Please try another filesystem."));
+    }
+    if filesystem.disabled && !is_admin() {
+        return Err(Error::FilesystemDisabled("Please try another filesystem."));
+    }
+    if let Some(reserve_percent) = filesystem.reserve_percent {
+        if !is_admin() {
+            let used: u64 = zfs::get_property(&filesystem.root, "used")?;
+            let available: u64 = zfs::get_property(&filesystem.root, "available")?;
+            if (available as f64) < (used + available) as f64 * reserve_percent / 100. {
+                return Err(Error::FilesystemFull(reserve_percent));
+            }
+        }
     }
+    let expiration_time = match (class, duration) {
+        (cli::WorkspaceClass::Reference, _) => reference_expiration_time(),
+        (cli::WorkspaceClass::Scratch, None) if filesystem.default_duration.is_none() => {
+            return Err(Error::DurationRequired)
+        }
+        (cli::WorkspaceClass::Scratch, duration) => {
+            let duration = duration.or(filesystem.default_duration).unwrap();
+            if duration > filesystem.max_duration && !is_admin() {
+                return Err(Error::DurationTooHigh(filesystem.max_duration));
+            }
+            Local::now() + duration
+        }
+    };
+
+    let volume = to_volume_string(&filesystem.root, user, name);
+    let workspace = hooks::Workspace {
+        filesystem_name,
+        user,
+        name,
+        volume: &volume,
+        expiration_time: &expiration_time,
+    };
+    hooks::run_pre(hooks, hooks::Event::Create, &workspace)?;
 
-    let transaction = conn.transaction().unwrap();
+    let transaction = db::transaction(conn)?;
     match transaction.execute(
-        "INSERT INTO workspaces (filesystem, user, name, expiration_time)
-            VALUES (?1, ?2, ?3, ?4)",
-        (filesystem_name, user, name, Local::now() + *duration),
+        "INSERT INTO workspaces
+            (filesystem, user, name, expiration_time, class, comment, host, project)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        (
+            filesystem_name,
+            user,
+            name,
+            db::Timestamp::from(expiration_time),
+            class.to_string(),
+            comment,
+            config::local_host(),
+            project.map(|(project_name, _)| project_name),
+        ),
     ) {
         Ok(_) => {}
-        Err(rusqlite::Error::SqliteFailure(
-            libsqlite3_sys::Error {
-                code: libsqlite3_sys::ErrorCode::ConstraintViolation,
-                ..
-            },
-            _,
-        )) => {
-            eprintln!(
-                "This workspace already exists. You can extend it using `workspaces extend`."
-            );
-            process::exit(exit_codes::WORKSPACE_EXISTS);
-        }
-        Err(_) => unreachable!(),
+        Err(e) if e.is_constraint_violation() => return Err(Error::WorkspaceExists),
+        Err(e) => return Err(e.into()),
     };
+    tags::add(&transaction, filesystem_name, user, name, tags)?;
 
-    let volume = to_volume_string(&filesystem.root, user, name);
-
-    zfs::create(&volume).unwrap();
+    let project_group = project.and_then(|(_, project)| project.group.as_deref());
+    let collaborative = collaborative || (group.is_none() && project_group.is_some());
+    let group = group.as_deref().or(project_group).unwrap_or(user);
+    zfs::create(&volume)?;
+    let set_up = set_up_volume(
+        &transaction,
+        filesystem_name,
+        filesystem,
+        user,
+        name,
+        class,
+        group,
+        collaborative,
+    )
+    .and_then(|mountpoint| {
+        transaction.commit()?;
+        Ok(mountpoint)
+    });
+    let mountpoint = match set_up {
+        Ok(Some(mountpoint)) => mountpoint,
+        // there is no volume to set up in a dry run
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            // the row is gone, so retrying would fail on the dataset otherwise
+            let _ = zfs::destroy(&volume, true);
+            return Err(e);
+        }
+    };
 
-    let mountpoint = zfs::get_property(&volume, "mountpoint").unwrap();
+    hooks::run(hooks, hooks::Event::Create, &workspace);
+    audit::log(conn, hooks::Event::Create, &workspace);
 
-    let mut permissions = fs::metadata(&mountpoint).unwrap().permissions();
-    permissions.set_mode(0o750);
-    fs::set_permissions(&mountpoint, permissions).unwrap();
+    println!("Created workspace at {}", mountpoint.display());
+    Ok(())
+}
 
-    let status = Command::new("chown")
-        .args([&format!("{}:{}", user, user), &mountpoint])
-        .status()
-        .unwrap();
-    assert!(status.success(), "failed to change owner on dataset");
-    transaction.commit().unwrap();
+/// Sets up the newly created volume of a workspace, returning its mountpoint
+///
+/// Returns `None` in a dry run, as there is no volume to set up.
+#[allow(clippy::too_many_arguments)]
+fn set_up_volume(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    class: cli::WorkspaceClass,
+    group: &str,
+    collaborative: bool,
+) -> Result<Option<PathBuf>, Error> {
+    let volume = to_volume_string(&filesystem.root, user, name);
+    apply_mountpoint_template(&volume, filesystem, user, name)?;
+    share(&volume, filesystem)?;
+    if dry_run::enabled() {
+        return Ok(None);
+    }
 
-    println!("Created workspace at {}", mountpoint);
+    let mountpoint = zfs::get_path_property(&volume, "mountpoint")?;
+    if let Some(skeleton_dir) = &filesystem.skeleton_dir {
+        // the workspace is fine without it, so only warn
+        if let Err(e) = copy_skeleton(skeleton_dir, &mountpoint, user, group) {
+            eprintln!(
+                "Could not copy {} into {}: {}",
+                skeleton_dir.display(),
+                mountpoint.display(),
+                e
+            );
+        }
+    }
+    if let Err(e) = permissions::apply(&volume, &mountpoint, user, group, class, collaborative) {
+        // e.g. NSS being unreachable shouldn't keep users from working
+        permissions::defer(
+            conn,
+            filesystem_name,
+            user,
+            name,
+            group,
+            collaborative,
+            &e.to_string(),
+        )?;
+        eprintln!(
+            "Could not set up the permissions of {}: {}\n\
+            They will be set up automatically as soon as possible.",
+            mountpoint.display(),
+            e
+        );
+    }
+    Ok(Some(mountpoint))
 }
 
-fn to_volume_string(root: &str, user: &str, name: &str) -> String {
-    format!("{}/{}/{}", root, user, name)
+/// Finds the filesystem holding a workspace, for `create --exist-ok`
+///
+/// Only this host's filesystems are considered, or only `filesystem_name` if
+/// given.
+fn find_workspace(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    filesystem_name: &Option<String>,
+    user: &str,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    let mut statement = conn.prepare(
+        "SELECT filesystem FROM workspaces
+            WHERE user = ?1
+                AND name = ?2
+            ORDER BY filesystem",
+    )?;
+    let found = statement
+        .query_map([user, name], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(found.into_iter().find(|found| {
+        filesystems.contains_key(found) && filesystem_name.as_ref().is_none_or(|name| name == found)
+    }))
 }
 
-/// Renames an existing workspace
-fn rename(
-    conn: &mut Connection,
+/// Reuses an existing workspace for `create --exist-ok`, extending it if asked to
+#[allow(clippy::too_many_arguments)]
+fn reuse(
+    conn: &Connection,
     filesystem_name: &str,
     filesystem: &config::Filesystem,
     user: &str,
-    src_name: &str,
-    dest_name: &str,
-) {
-    if get_current_username().unwrap() != user && get_current_uid() != 0 {
-        eprintln!("You are not allowed to execute this operation");
-        process::exit(exit_codes::INSUFFICIENT_PRIVILEGES);
+    name: &str,
+    duration: &Option<Duration>,
+    extend_existing: bool,
+    hooks: &config::Hooks,
+) -> Result<(), Error> {
+    if !is_caller(user) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
     }
-    if filesystem.disabled && get_current_uid() != 0 {
-        eprintln!("Filesystem is disabled. Please try another filesystem.");
-        process::exit(exit_codes::FS_DISABLED);
+    if extend_existing {
+        let duration = duration
+            .or(filesystem.default_duration)
+            .ok_or(Error::DurationRequired)?;
+        extend(
+            conn,
+            filesystem_name,
+            filesystem,
+            user,
+            name,
+            &duration,
+            None,
+            hooks,
+        )?;
     }
+    let volume = to_volume_string(&filesystem.root, user, name);
+    let mountpoint = zfs::get_path_property(&volume, "mountpoint")?;
+    println!("Workspace already exists at {}", mountpoint.display());
+    Ok(())
+}
 
-    let transaction = conn.transaction().unwrap();
-    match transaction.execute(
-        "UPDATE workspaces
-                SET name = ?1
-                WHERE filesystem = ?2
-                    AND user = ?3
-                    AND name = ?4",
-        (dest_name, filesystem_name, user, src_name),
+/// Registers an existing dataset as a scratch workspace without touching it
+fn adopt(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    expiration_time: &DateTime<Local>,
+    project: Option<&str>,
+) -> Result<(), Error> {
+    match conn.execute(
+        "INSERT INTO workspaces (filesystem, user, name, expiration_time, class, host, project)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        (
+            filesystem_name,
+            user,
+            name,
+            db::Timestamp::from(*expiration_time),
+            cli::WorkspaceClass::Scratch.to_string(),
+            config::local_host(),
+            project,
+        ),
     ) {
         Ok(_) => {}
-        Err(rusqlite::Error::SqliteFailure(
-            libsqlite3_sys::Error {
-                code: libsqlite3_sys::ErrorCode::ConstraintViolation,
-                ..
-            },
-            _,
-        )) => {
-            eprintln!("The target workspace already exists");
-            process::exit(exit_codes::WORKSPACE_EXISTS);
-        }
-        Err(_) => unreachable!(),
+        Err(e) if e.is_constraint_violation() => return Err(Error::WorkspaceExists),
+        Err(e) => return Err(e.into()),
     }
-
-    let src_volume = to_volume_string(&filesystem.root, user, src_name);
-    let dest_volume = to_volume_string(&filesystem.root, user, dest_name);
-    zfs::rename(&src_volume, &dest_volume).unwrap();
-    transaction.commit().unwrap();
-}
-
-#[derive(Debug)]
-struct WorkspacesRow {
-    filesystem_name: String,
-    user: String,
-    name: String,
-    expiration_time: DateTime<Local>,
+    Ok(())
 }
 
-fn list(
+/// Adopts `dataset` as a workspace of `user`
+///
+/// Without a user, all datasets below `dataset` which look like workspaces
+/// are adopted instead, except for those released to other management.
+fn adopt_datasets(
     conn: &Connection,
     filesystems: &HashMap<String, config::Filesystem>,
-    filter_users: &Option<Vec<String>>,
-    filter_filesystems: &Option<Vec<String>>,
-    output: &Option<Vec<cli::WorkspacesColumns>>,
-) {
-    use cli::WorkspacesColumns;
-    // the default columns
-    let output = output.clone().unwrap_or(vec![
-        WorkspacesColumns::Name,
-        WorkspacesColumns::User,
-        WorkspacesColumns::Fs,
-        WorkspacesColumns::Size,
-        WorkspacesColumns::Expiry,
-        WorkspacesColumns::Mountpoint,
-    ]);
-
-    let mut table = Table::new();
-    table.set_format(FormatBuilder::new().padding(0, 2).build());
-
-    // bold title row
-    table.set_titles(Row::new(
-        output
-            .iter()
-            .map(|h| Cell::new(&h.to_string()).with_style(Attr::Bold))
-            .collect(),
-    ));
-
-    let mut statement = conn
-        .prepare("SELECT filesystem, user, name, expiration_time FROM workspaces")
-        .unwrap();
-    let workspace_iter = statement
-        .query_map([], |row| {
-            Ok(WorkspacesRow {
-                filesystem_name: row.get(0)?,
-                user: row.get(1)?,
-                name: row.get(2)?,
-                expiration_time: row.get(3)?,
-            })
-        })
-        .unwrap();
+    dataset: &str,
+    user: &Option<String>,
+    duration: &Duration,
+    project: Option<&str>,
+) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let Some((filesystem_name, filesystem)) = filesystems.iter().find(|(_, filesystem)| {
+        dataset == filesystem.root || dataset.starts_with(&format!("{}/", filesystem.root))
+    }) else {
+        return Err(Error::InvalidArgument(format!(
+            "{} is not below the root of any filesystem",
+            dataset
+        )));
+    };
+    let expiration_time = Local::now() + *duration;
 
-    for workspace in workspace_iter {
-        let workspace = workspace.unwrap();
-        if !filter_users
-            .as_ref()
-            .map_or(true, |us| us.contains(&workspace.user))
-            || !filter_filesystems
-                .as_ref()
-                .map_or(true, |fs| fs.contains(&workspace.filesystem_name))
-        {
-            continue;
-        }
-        let volume = to_volume_string(
-            &filesystems
-                .get(&workspace.filesystem_name)
-                .expect("found workspace in database without corresponding config entry")
-                .root,
-            &workspace.user,
-            &workspace.name,
-        );
-        let referenced = zfs::get_property::<usize>(&volume, "referenced");
-        let mountpoint = zfs::get_property::<PathBuf>(&volume, "mountpoint");
-        if mountpoint.is_err() || referenced.is_err() {
-            eprintln!("Failed to get info for {}", volume);
-            continue;
+    let Some(user) = user else {
+        // adopt everything looking like a workspace
+        let prefix = format!("{}/", filesystem.root);
+        let mut volumes: Vec<String> = zfs::list_datasets(dataset)?.into_keys().collect();
+        volumes.sort();
+        let mut statement =
+            conn.prepare("SELECT user, name FROM released WHERE filesystem = ?1")?;
+        let released: Vec<(String, String)> = statement
+            .query_map([filesystem_name], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let mut adopted = 0;
+        for volume in volumes {
+            let Some((user, name)) = volume
+                .strip_prefix(&prefix)
+                .and_then(|path| path.split_once('/'))
+                .filter(|(user, name)| *user != ".trash" && !name.contains('/'))
+            else {
+                continue;
+            };
+            if released.iter().any(|(u, n)| u == user && n == name) {
+                continue;
+            }
+            if let Err(e) = cli::parse_pathsafe(user).and(cli::parse_pathsafe(name)) {
+                eprintln!("Skipping {}: {}", volume, e);
+                continue;
+            }
+            if !user_exists(user) {
+                eprintln!("Skipping {}: there is no user {}", volume, user);
+                continue;
+            }
+            match adopt(conn, filesystem_name, user, name, &expiration_time, project) {
+                Ok(()) => {
+                    println!("Adopted {} as workspace {} of {}", volume, name, user);
+                    adopted += 1;
+                }
+                Err(Error::WorkspaceExists) => {}
+                Err(e) => return Err(e),
+            }
         }
-        table.add_row(Row::new(
-            output
-                .iter()
-                .map(|column| match column {
-                    WorkspacesColumns::Name => Cell::new(&workspace.name),
-                    WorkspacesColumns::User => Cell::new(&workspace.user),
-                    WorkspacesColumns::Fs => Cell::new(&workspace.filesystem_name),
-                    WorkspacesColumns::Expiry => {
-                        if Local::now()
-                            > workspace.expiration_time
-                                + filesystems[&workspace.filesystem_name].expired_retention
-                        {
-                            Cell::new("deleted soon")
-                                .with_style(Attr::Bold)
-                                .with_style(Attr::ForegroundColor(color::RED))
-                        } else if Local::now() > workspace.expiration_time {
-                            Cell::new_align(
-                                &format!(
-                                    "deleted in {:>2}d",
-                                    (workspace.expiration_time
-                                        + filesystems[&workspace.filesystem_name]
-                                            .expired_retention
-                                        - Local::now())
-                                    .num_days()
-                                ),
-                                Alignment::RIGHT,
-                            )
-                            .with_style(Attr::Bold)
-                            .with_style(Attr::ForegroundColor(color::RED))
-                        } else if workspace.expiration_time - Local::now() < Duration::days(30) {
-                            Cell::new_align(
-                                &format!(
-                                    "expires in {:>2}d",
-                                    (workspace.expiration_time - Local::now()).num_days()
-                                ),
-                                Alignment::RIGHT,
-                            )
-                            .with_style(Attr::ForegroundColor(color::YELLOW))
-                        } else {
-                            Cell::new_align(
-                                &format!(
-                                    "expires in {:>2}d",
-                                    (workspace.expiration_time - Local::now()).num_days()
-                                ),
-                                Alignment::RIGHT,
-                            )
-                        }
-                    }
-                    WorkspacesColumns::Size => Cell::new_align(
-                        &format!("{}G", referenced.as_ref().unwrap() / (1 << 30)),
-                        Alignment::RIGHT,
-                    ),
-                    WorkspacesColumns::Mountpoint => {
-                        Cell::new(mountpoint.as_ref().unwrap().to_str().unwrap())
-                    }
-                })
-                .collect(),
-        ));
-    }
+        println!("Adopted {} dataset(s)", adopted);
+        return Ok(());
+    };
 
-    table.printstd();
+    let name = dataset.rsplit('/').next().unwrap_or(dataset);
+    cli::parse_pathsafe(name).map_err(|e| Error::InvalidArgument(e.to_string()))?;
+    if !user_exists(user) {
+        return Err(Error::UnknownUser(user.clone()));
+    }
+    adopt_dataset(
+        conn,
+        filesystem_name,
+        filesystem,
+        dataset,
+        user,
+        name,
+        &expiration_time,
+        project,
+    )
 }
 
-fn extend(
+/// Adopts `dataset` as workspace `name` of `user` where it is
+///
+/// A dataset which was released before is taken back under management.
+#[allow(clippy::too_many_arguments)]
+fn adopt_dataset(
     conn: &Connection,
     filesystem_name: &str,
     filesystem: &config::Filesystem,
+    dataset: &str,
     user: &str,
     name: &str,
-    duration: &Duration,
-) {
-    if get_current_username().unwrap() != user && get_current_uid() != 0 {
-        eprintln!("You are not allowed to execute this operation");
-        process::exit(exit_codes::INSUFFICIENT_PRIVILEGES);
-    }
-    if filesystem.disabled && get_current_uid() != 0 {
-        eprintln!("Filesystem is disabled. Please recreate workspace on another filesystem.");
-        process::exit(exit_codes::FS_DISABLED);
-    }
-    if duration > &filesystem.max_duration && get_current_uid() != 0 {
-        eprintln!(
-            "Duration can be at most {} days",
-            filesystem.max_duration.num_days()
-        );
-        process::exit(exit_codes::TOO_HIGH_DURATION);
+    expiration_time: &DateTime<Local>,
+    project: Option<&str>,
+) -> Result<(), Error> {
+    // renaming the dataset would move its mountpoint under running jobs
+    let volume = to_volume_string(&filesystem.root, user, name);
+    if volume != dataset {
+        return Err(Error::InvalidArgument(format!(
+            "Workspace {} of {} has to be at {}. Please rename {} there first.",
+            name, user, volume, dataset
+        )));
     }
+    // make sure the dataset exists before registering it
+    zfs::get_property::<String>(dataset, "type")?;
+    adopt(conn, filesystem_name, user, name, expiration_time, project)?;
+    conn.execute(
+        "DELETE FROM released
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3",
+        (filesystem_name, user, name),
+    )?;
+    println!("Adopted {} as workspace {} of {}", volume, name, user);
+    Ok(())
+}
 
-    let rows_updated = conn
-        .execute(
-            "UPDATE workspaces
-            SET expiration_time = MAX(expiration_time, ?1)
-            WHERE filesystem = ?2
-                AND user = ?3
-                AND name = ?4",
-            (Local::now() + *duration, filesystem_name, user, name),
-        )
-        .unwrap();
-    match rows_updated {
-        0 => {
-            eprintln!(
-                "Could not find a matching filesystem={}, user={}, name={}",
-                filesystem_name, user, name
-            );
-            process::exit(exit_codes::UNKNOWN_WORKSPACE);
+/// Adopts `dataset` as workspace `name` of `user`, renaming it to where the
+/// workspace belongs
+///
+/// For imports from other tools, which lay out their datasets differently.
+/// Unlike [`adopt_dataset`], this may move the dataset's mountpoint.
+#[allow(clippy::too_many_arguments)]
+fn adopt_and_move_dataset(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    dataset: &str,
+    user: &str,
+    name: &str,
+    expiration_time: &DateTime<Local>,
+) -> Result<(), Error> {
+    // make sure the dataset exists before registering it
+    zfs::get_property::<String>(dataset, "type")?;
+    adopt(conn, filesystem_name, user, name, expiration_time, None)?;
+    let volume = to_volume_string(&filesystem.root, user, name);
+    if volume != dataset {
+        if let Err(e) = zfs::rename(dataset, &volume) {
+            conn.execute(
+                "DELETE FROM workspaces
+                    WHERE filesystem = ?1
+                        AND user = ?2
+                        AND name = ?3",
+                (filesystem_name, user, name),
+            )?;
+            return Err(e.into());
         }
-        1 => {}
-        _ => unreachable!(),
-    };
-
-    zfs::set_property(
-        &to_volume_string(&filesystem.root, user, name),
-        "readonly",
-        "off",
-    )
-    .unwrap();
+    }
+    conn.execute(
+        "DELETE FROM released
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3",
+        (filesystem_name, user, name),
+    )?;
+    apply_mountpoint_template(&volume, filesystem, user, name)?;
+    share(&volume, filesystem)?;
+    println!("Adopted {} as workspace {} of {}", volume, name, user);
+    Ok(())
 }
 
-fn expire(
-    conn: &Connection,
+/// Removes a workspace from the database, leaving its dataset alone
+fn release(
+    conn: &mut Connection,
     filesystem_name: &str,
     filesystem: &config::Filesystem,
     user: &str,
     name: &str,
-    delete_on_next_clean: bool,
-) {
-    if get_current_username().unwrap() != user && get_current_uid() != 0 {
-        eprintln!("You are not allowed to execute this operation");
-        process::exit(exit_codes::INSUFFICIENT_PRIVILEGES);
+) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let transaction = db::transaction(conn)?;
+    let deleted = transaction.execute(
+        "DELETE FROM workspaces
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3",
+        (filesystem_name, user, name),
+    )?;
+    if deleted == 0 {
+        return Err(Error::UnknownWorkspace {
+            filesystem: filesystem_name.to_string(),
+            user: user.to_string(),
+            name: name.to_string(),
+        });
     }
+    tags::clear(&transaction, filesystem_name, user, name)?;
+    transaction.execute(
+        "INSERT INTO released (filesystem, user, name) VALUES (?1, ?2, ?3)
+            ON CONFLICT DO NOTHING",
+        (filesystem_name, user, name),
+    )?;
+    transaction.commit()?;
+    println!(
+        "Released {}, which is no longer managed by workspaces",
+        to_volume_string(&filesystem.root, user, name)
+    );
+    Ok(())
+}
 
-    let expiration_time = if delete_on_next_clean {
-        // set the expiration time sufficiently far in the past
-        // for it to get cleaned up soon
-        Local::now() - filesystem.expired_retention
-    } else {
-        Local::now()
+/// Locks a workspace for `reason`, or unlocks it if `reason` is `None`
+fn set_locked(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    reason: Option<&str>,
+) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let Some(locked) = conn
+        .query_row(
+            "SELECT locked FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |row| row.get::<_, Option<String>>(0),
+        )
+        .optional()?
+    else {
+        return Err(Error::UnknownWorkspace {
+            filesystem: filesystem_name.to_string(),
+            user: user.to_string(),
+            name: name.to_string(),
+        });
     };
-    let rows_updated = conn
-        .execute(
-            "UPDATE workspaces
-            SET expiration_time = MIN(expiration_time, ?1)
+    if locked.is_none() && reason.is_none() {
+        println!("Workspace is not locked");
+        return Ok(());
+    }
+    conn.execute(
+        "UPDATE workspaces
+            SET locked = ?1
+            WHERE filesystem = ?2
+                AND user = ?3
+                AND name = ?4",
+        (reason, filesystem_name, user, name),
+    )?;
+    Ok(())
+}
+
+/// Exempts a workspace from `clean` until `until`, or revokes its exemption
+/// if `until` is `None`
+fn set_exempt(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    until: Option<DateTime<Local>>,
+) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let rows_updated = conn.execute(
+        "UPDATE workspaces
+            SET exempt_until = ?1
             WHERE filesystem = ?2
                 AND user = ?3
                 AND name = ?4",
-            (expiration_time, filesystem_name, user, name),
+        (until.map(db::Timestamp::from), filesystem_name, user, name),
+    )?;
+    if rows_updated == 0 {
+        return Err(Error::UnknownWorkspace {
+            filesystem: filesystem_name.to_string(),
+            user: user.to_string(),
+            name: name.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Imports the workspaces of `ws_allocate` found at `path` into `filesystem_name`
+///
+/// With `adopt`, the datasets mounted at the workspaces' paths are adopted,
+/// otherwise new workspaces are created.  Expired workspaces are skipped.
+#[allow(clippy::too_many_arguments)]
+fn import_ws_allocate(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    path: &Path,
+    adopt: bool,
+    hooks: &config::Hooks,
+) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let (entries, errors) = import::read_ws_allocate(path)?;
+    for error in &errors {
+        println!("failed {}", error);
+    }
+    let mountpoints: HashMap<PathBuf, String> = if adopt {
+        zfs::list_datasets(&filesystem.root)?
+            .into_iter()
+            .map(|(volume, dataset)| (dataset.mountpoint, volume))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    let now = Local::now();
+    let mut imported = 0;
+    let mut skipped = 0;
+    let mut failed = errors.len();
+    for entry in &entries {
+        let workspace = format!("{}/{}", entry.user, entry.name);
+        if entry.expiration_time <= now {
+            println!(
+                "skipped {}: expired on {}",
+                workspace, entry.expiration_time
+            );
+            skipped += 1;
+            continue;
+        }
+        if let Err(e) =
+            cli::parse_pathsafe(&entry.user).and_then(|_| cli::parse_pathsafe(&entry.name))
+        {
+            println!("failed {}: {}", workspace, e);
+            failed += 1;
+            continue;
+        }
+        let duration = entry.expiration_time - now;
+        let result = if adopt {
+            match mountpoints.get(&entry.path) {
+                Some(volume) => adopt_and_move_dataset(
+                    conn,
+                    filesystem_name,
+                    filesystem,
+                    volume,
+                    &entry.user,
+                    &entry.name,
+                    &entry.expiration_time,
+                ),
+                None => Err(Error::InvalidArgument(format!(
+                    "no dataset below {} is mounted at {}",
+                    filesystem.root,
+                    entry.path.display()
+                ))),
+            }
+        } else {
+            create(
+                conn,
+                filesystems,
+                filesystem_name,
+                filesystem,
+                &entry.user,
+                &entry.name,
+                &Some(duration),
+                cli::WorkspaceClass::Scratch,
+                &None,
+                false,
+                &[],
+                &None,
+                None,
+                hooks,
+            )
+        };
+        match result {
+            Ok(()) => imported += 1,
+            Err(e) => {
+                println!("failed {}: {}", workspace, e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!(
+        "Imported {} workspace(s), skipped {} expired, {} failed",
+        imported, skipped, failed
+    );
+    if failed > 0 {
+        return Err(Error::BatchFailed {
+            failed,
+            total: entries.len() + errors.len(),
+        });
+    }
+    Ok(())
+}
+
+/// The expiration time stored for reference workspaces, which never expire
+fn reference_expiration_time() -> DateTime<Local> {
+    Utc.with_ymd_and_hms(9999, 12, 31, 23, 59, 59)
+        .unwrap()
+        .with_timezone(&Local)
+}
+
+/// Whether a workspace is a reference workspace
+fn is_reference(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+) -> Result<bool, Error> {
+    Ok(conn
+        .query_row(
+            "SELECT class FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |row| row.get::<_, String>(0),
         )
-        .unwrap();
-    match rows_updated {
-        0 => {
+        .optional()?
+        .is_some_and(|class| class == cli::WorkspaceClass::Reference.to_string()))
+}
+
+/// Fails if a workspace was locked by `workspaces lock`
+fn check_unlocked(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+) -> Result<(), Error> {
+    let locked = conn
+        .query_row(
+            "SELECT locked FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |row| row.get::<_, Option<String>>(0),
+        )
+        .optional()?
+        .flatten();
+    match locked {
+        Some(reason) => Err(Error::WorkspaceLocked(
+            Some(reason).filter(|reason| !reason.is_empty()),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Where a workspace in the archived tier is stored, if it is in that tier
+fn archive_url(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    Ok(conn
+        .query_row(
+            "SELECT archive_url FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |row| row.get::<_, Option<String>>(0),
+        )
+        .optional()?
+        .flatten())
+}
+
+/// Turns a scratch workspace into a read-only reference workspace
+fn promote(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    check_unlocked(conn, filesystem_name, user, name)?;
+    let Some((class, archive_url)) = conn
+        .query_row(
+            "SELECT class, archive_url FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)),
+        )
+        .optional()?
+    else {
+        return Err(Error::UnknownWorkspace {
+            filesystem: filesystem_name.to_string(),
+            user: user.to_string(),
+            name: name.to_string(),
+        });
+    };
+    if class == cli::WorkspaceClass::Reference.to_string() {
+        return Err(Error::InvalidArgument(format!(
+            "{} is a reference workspace already",
+            name
+        )));
+    }
+    if archive_url.is_some() {
+        return Err(Error::InvalidArgument(format!(
+            "{} has been archived",
+            name
+        )));
+    }
+    let volume = to_volume_string(&filesystem.root, user, name);
+    if zfs::get_property::<String>(&volume, "mounted")? != "yes" {
+        return Err(Error::InvalidArgument(format!(
+            "{} is not mounted, please extend it first",
+            name
+        )));
+    }
+
+    conn.execute(
+        "UPDATE workspaces
+            SET class = ?1,
+                expiration_time = ?2
+            WHERE filesystem = ?3
+                AND user = ?4
+                AND name = ?5",
+        (
+            cli::WorkspaceClass::Reference.to_string(),
+            db::Timestamp::from(reference_expiration_time()),
+            filesystem_name,
+            user,
+            name,
+        ),
+    )?;
+    let mountpoint = zfs::get_path_property(&volume, "mountpoint")?;
+    if !dry_run::skip(format_args!(
+        "making {} readable by everyone",
+        mountpoint.display()
+    )) {
+        let _root = privileges::raise();
+        let mut permissions = fs::metadata(&mountpoint)?.permissions();
+        permissions.set_mode(permissions.mode() | 0o005);
+        fs::set_permissions(&mountpoint, permissions)?;
+    }
+    zfs::set_property(&volume, "readonly", "on")?;
+    println!("Promoted {} to a reference workspace", name);
+    Ok(())
+}
+
+pub(crate) fn to_volume_string(root: &str, user: &str, name: &str) -> String {
+    format!("{}/{}/{}", root, user, name)
+}
+
+/// Mounts a workspace's volume where the filesystem's template says, if any
+///
+/// Has to be called again whenever the workspace's user or name changes.
+fn apply_mountpoint_template(
+    volume: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+) -> Result<(), zfs::Error> {
+    let Some(template) = &filesystem.mountpoint_template else {
+        return Ok(());
+    };
+    let mountpoint = template.replace("{user}", user).replace("{name}", name);
+    zfs::set_property(volume, "mountpoint", &mountpoint)
+}
+
+/// Exports a workspace's volume by NFS with the filesystem's options, if any
+fn share(volume: &str, filesystem: &config::Filesystem) -> Result<(), zfs::Error> {
+    let Some(sharenfs) = &filesystem.sharenfs else {
+        return Ok(());
+    };
+    zfs::set_property(volume, "sharenfs", sharenfs)
+}
+
+/// Withdraws the NFS export set up by [`share`]
+fn unshare(volume: &str, filesystem: &config::Filesystem) -> Result<(), zfs::Error> {
+    if filesystem.sharenfs.is_none() {
+        return Ok(());
+    }
+    zfs::set_property(volume, "sharenfs", "off")
+}
+
+/// Returns the volume a workspace is moved to when it is put into the trash
+///
+/// The time of trashing is part of the name, so a user can trash several
+/// workspaces of the same name.
+fn to_trash_volume_string(
+    root: &str,
+    user: &str,
+    name: &str,
+    trashed_time: &DateTime<Local>,
+) -> String {
+    format!(
+        "{}/.trash/{}/{}-{}",
+        root,
+        user,
+        name,
+        trashed_time.timestamp()
+    )
+}
+
+/// Copies the contents of `skeleton_dir` into a new workspace, handing them
+/// to its owner
+///
+/// Has to happen before the owner gets access to the workspace, as the copy is
+/// made as root.
+fn copy_skeleton(
+    skeleton_dir: &Path,
+    mountpoint: &Path,
+    user: &str,
+    group: &str,
+) -> Result<(), Error> {
+    let status = privileges::as_root(
+        process::Command::new("cp")
+            .args(["-R", "--preserve=mode,timestamps"])
+            .arg(skeleton_dir.join("."))
+            .arg(mountpoint),
+    )
+    .status()?;
+    if !status.success() {
+        return Err(Error::Command(format!("cp exited with {}", status)));
+    }
+    for entry in fs::read_dir(skeleton_dir)? {
+        let status = privileges::as_root(
+            process::Command::new("chown")
+                .args(["-R", "-h", &format!("{}:{}", user, group)])
+                .arg(mountpoint.join(entry?.file_name())),
+        )
+        .status()?;
+        if !status.success() {
+            return Err(Error::Command(format!("chown exited with {}", status)));
+        }
+    }
+    Ok(())
+}
+
+/// Makes `user` and `group` the owners of a workspace's mountpoint
+pub(crate) fn chown(user: &str, group: &str, mountpoint: &Path) -> Result<(), Error> {
+    if dry_run::skip(format_args!(
+        "chown {}:{} {}",
+        user,
+        group,
+        mountpoint.display()
+    )) {
+        return Ok(());
+    }
+    let uid = get_user_by_name(user)
+        .ok_or_else(|| Error::UnknownUser(user.to_string()))?
+        .uid();
+    let gid = get_group_by_name(group)
+        .ok_or_else(|| Error::UnknownGroup(group.to_string()))?
+        .gid();
+    nix::unistd::chown(
+        mountpoint,
+        Some(Uid::from_raw(uid)),
+        Some(Gid::from_raw(gid)),
+    )
+    .map_err(io::Error::from)?;
+    Ok(())
+}
+
+/// Whether `user` still exists on this system
+///
+/// Workspaces whose owner has been deleted or renamed are flagged as
+/// `ORPHAN-OWNER`.
+pub(crate) fn user_exists(user: &str) -> bool {
+    get_user_by_name(user).is_some()
+}
+
+/// Renames an existing workspace
+fn rename(
+    conn: &mut Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    src_name: &str,
+    dest_name: &str,
+) -> Result<(), Error> {
+    if !is_caller(user) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    if filesystem.disabled && !is_admin() {
+        return Err(Error::FilesystemDisabled("Please try another filesystem."));
+    }
+    check_unlocked(conn, filesystem_name, user, src_name)?;
+
+    let transaction = db::transaction(conn)?;
+    match transaction.execute(
+        "UPDATE workspaces
+                SET name = ?1,
+                    cached_size = NULL,
+                    cached_mountpoint = NULL,
+                    cached_at = NULL
+                WHERE filesystem = ?2
+                    AND user = ?3
+                    AND name = ?4",
+        (dest_name, filesystem_name, user, src_name),
+    ) {
+        Ok(0) => {
+            return Err(Error::UnknownWorkspace {
+                filesystem: filesystem_name.to_string(),
+                user: user.to_string(),
+                name: src_name.to_string(),
+            })
+        }
+        Ok(_) => {}
+        Err(e) if e.is_constraint_violation() => return Err(Error::TargetExists),
+        Err(e) => return Err(e.into()),
+    }
+    tags::rename(
+        &transaction,
+        filesystem_name,
+        (user, src_name),
+        (user, dest_name),
+    )?;
+    move_history(
+        &transaction,
+        (filesystem_name, user, src_name),
+        (filesystem_name, user, dest_name),
+    )?;
+
+    let src_volume = to_volume_string(&filesystem.root, user, src_name);
+    let dest_volume = to_volume_string(&filesystem.root, user, dest_name);
+    zfs::rename(&src_volume, &dest_volume)?;
+    apply_mountpoint_template(&dest_volume, filesystem, user, dest_name)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Renames all of a user's workspaces matching `pattern`
+///
+/// The pattern contains a single `*`, which is replaced by the matched part of
+/// the name in `replacement`.  Each workspace is renamed in its own
+/// transaction, so an interrupted run can be resumed by running it again.
+fn bulk_rename(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    filter_filesystems: &Option<Vec<String>>,
+    user: &str,
+    pattern: &str,
+    replacement: &str,
+) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let Some((prefix, suffix)) = pattern.split_once('*').filter(|(_, s)| !s.contains('*')) else {
+        return Err(Error::InvalidArgument(
+            "The pattern has to contain exactly one `*`".to_string(),
+        ));
+    };
+    if replacement.matches('*').count() > 1 {
+        return Err(Error::InvalidArgument(
+            "The replacement may contain at most one `*`".to_string(),
+        ));
+    }
+
+    let mut renames = vec![];
+    {
+        let mut statement = conn.prepare(
+            "SELECT filesystem, name FROM workspaces
+                WHERE user = ?1
+                ORDER BY filesystem, name",
+        )?;
+        let mut rows = statement.query([user])?;
+        while let Some(row) = rows.next()? {
+            let filesystem_name: String = row.get(0)?;
+            let name: String = row.get(1)?;
+            if !filter_filesystems
+                .as_ref()
+                .is_none_or(|fs| fs.contains(&filesystem_name))
+            {
+                continue;
+            }
+            let Some(matched) = name
+                .strip_prefix(prefix)
+                .and_then(|rest| rest.strip_suffix(suffix))
+            else {
+                continue;
+            };
+            let new_name = replacement.replace('*', matched);
+            renames.push((filesystem_name, name, new_name));
+        }
+    }
+
+    let mut failed = 0;
+    for (filesystem_name, name, new_name) in &renames {
+        let Some(filesystem) = filesystems.get(filesystem_name) else {
+            println!("skipped {}/{}: unknown filesystem", filesystem_name, name);
+            failed += 1;
+            continue;
+        };
+        if let Err(e) = cli::parse_pathsafe(new_name) {
+            println!("skipped {}/{}: {}", filesystem_name, name, e);
+            failed += 1;
+            continue;
+        }
+        if dry_run::skip(format_args!(
+            "rename {}/{} to {}",
+            filesystem_name, name, new_name
+        )) {
+            continue;
+        }
+        match rename(conn, filesystem_name, filesystem, user, name, new_name) {
+            Ok(()) => println!("renamed {}/{} to {}", filesystem_name, name, new_name),
+            Err(e) => {
+                println!("failed {}/{}: {}", filesystem_name, name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(Error::BatchFailed {
+            failed,
+            total: renames.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Snapshot workspaces are copied from when they are moved to another filesystem
+const REBALANCE_SNAPSHOT: &str = "workspaces-rebalance";
+
+/// User property keeping a volume's own `readonly` while a move is interrupted
+const REBALANCE_READONLY: &str = "workspaces:readonly";
+
+/// Moves the smallest workspaces on one filesystem to another
+///
+/// Workspaces are moved until `max_size` bytes have been moved or the target
+/// filesystem runs out of space.  Each workspace is moved on its own, so an
+/// interrupted run can be resumed by running it again.
+fn rebalance(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    from: &str,
+    to: &str,
+    max_size: Option<u64>,
+) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    if from == to {
+        return Err(Error::InvalidArgument(
+            "Workspaces have to be moved to another filesystem".to_string(),
+        ));
+    }
+    let (source, target) = (&filesystems[from], &filesystems[to]);
+    if target.retired {
+        return Err(Error::FilesystemRetired("Please try another filesystem."));
+    }
+
+    let mut candidates = vec![];
+    let mut failed = 0;
+    {
+        // archived workspaces have no dataset left to move
+        let mut statement = conn.prepare(
+            "SELECT user, name FROM workspaces
+                WHERE filesystem = ?1
+                    AND archive_url IS NULL
+                    AND NOT EXISTS (
+                        SELECT 1 FROM workspaces AS target
+                            WHERE target.filesystem = ?2
+                                AND target.user = workspaces.user
+                                AND target.name = workspaces.name
+                    )",
+        )?;
+        let mut rows = statement.query([from, to])?;
+        while let Some(row) = rows.next()? {
+            let user: String = row.get(0)?;
+            let name: String = row.get(1)?;
+            match zfs::get_property(&to_volume_string(&source.root, &user, &name), "used") {
+                Ok(used) => candidates.push((used, user, name)),
+                Err(e) => {
+                    println!("failed {}/{}/{}: {}", from, user, name, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    candidates.sort();
+    let total = candidates.len() + failed;
+
+    let mut available: u64 = zfs::get_property(&target.root, "available")?;
+    let mut budget = max_size.unwrap_or(u64::MAX);
+    let mut moved = 0;
+    for (used, user, name) in &candidates {
+        if *used > budget || *used >= available {
+            break;
+        }
+        if !dry_run::skip(format_args!(
+            "move {}/{}/{} to {} ({})",
+            from,
+            user,
+            name,
+            to,
+            units::format_size(*used, false)
+        )) {
+            match move_workspace(conn, from, source, to, target, user, name) {
+                Ok(()) => println!(
+                    "moved {}/{}/{} to {} ({})",
+                    from,
+                    user,
+                    name,
+                    to,
+                    units::format_size(*used, false)
+                ),
+                Err(e) => {
+                    println!("failed {}/{}/{}: {}", from, user, name, e);
+                    failed += 1;
+                    continue;
+                }
+            }
+        }
+        budget -= used;
+        available -= used;
+        moved += 1;
+    }
+    if moved + failed < total {
+        println!("left {} workspaces on {}", total - moved - failed, from);
+    }
+
+    if failed > 0 {
+        return Err(Error::BatchFailed {
+            failed,
+            total: moved + failed,
+        });
+    }
+    Ok(())
+}
+
+/// Copies a workspace to another filesystem and destroys the original
+///
+/// The copy replaces the original only once it is complete, which is kept
+/// read-only in the meantime.  If anything goes wrong before that, the
+/// original is left as it was, unless the copy was interrupted and can be
+/// resumed.  The original is then kept read-only, and moving it again picks
+/// up where the copy stopped.
+fn move_workspace(
+    conn: &mut Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    dest_filesystem_name: &str,
+    dest_filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+) -> Result<(), Error> {
+    let volume = to_volume_string(&filesystem.root, user, name);
+    let dest_volume = to_volume_string(&dest_filesystem.root, user, name);
+    let resuming = zfs::resume_token(&dest_volume)?.is_some();
+    // e.g. released by its owner, and nothing to overwrite or clean up after
+    if !resuming && zfs::get_property::<String>(&dest_volume, "type").is_ok() {
+        return Err(Error::TargetExists);
+    }
+    let readonly: String = match resuming {
+        true => zfs::get_property(&volume, REBALANCE_READONLY)?,
+        false => zfs::get_property(&volume, "readonly")?,
+    };
+    let canmount: String = zfs::get_property(&volume, "canmount")?;
+    let quota: u64 = zfs::get_property(&volume, "quota")?;
+
+    zfs::set_property(&volume, "readonly", "on")?;
+    let copied = match resuming {
+        true => Ok(()),
+        false => zfs::snapshot(&volume, REBALANCE_SNAPSHOT),
+    }
+    .and_then(|()| zfs::create(&format!("{}/{}", dest_filesystem.root, user)))
+    .and_then(|()| zfs::replicate(&volume, REBALANCE_SNAPSHOT, &dest_volume))
+    .and_then(|()| match zfs::is_mounted(&volume)? {
+        true => zfs::unmount(&volume, filesystem.force_unmount),
+        false => Ok(()),
+    });
+    if let Err(e) = copied {
+        if zfs::resume_token(&dest_volume).is_ok_and(|token| token.is_some()) {
+            zfs::set_property(&volume, REBALANCE_READONLY, &readonly)?;
             eprintln!(
-                "Could not find a matching filesystem={}, user={}, name={}",
-                filesystem_name, user, name
+                "Copying {} was interrupted and is resumed when moving it again",
+                volume
+            );
+            return Err(e.into());
+        }
+        // best effort, the original volume is what matters
+        let _ = zfs::destroy(&dest_volume, true);
+        let _ = zfs::destroy(&format!("{}@{}", volume, REBALANCE_SNAPSHOT), false);
+        let _ = zfs::set_property(&volume, "readonly", &readonly);
+        if canmount == "on" && !zfs::is_mounted(&volume).unwrap_or(true) {
+            let _ = zfs::mount(&volume);
+        }
+        return Err(e.into());
+    }
+
+    let transaction = db::transaction(conn)?;
+    transaction.execute(
+        "UPDATE workspaces
+            SET filesystem = ?1,
+                cached_size = NULL,
+                cached_mountpoint = NULL,
+                cached_at = NULL
+            WHERE filesystem = ?2
+                AND user = ?3
+                AND name = ?4",
+        (dest_filesystem_name, filesystem_name, user, name),
+    )?;
+    tags::relocate(
+        &transaction,
+        filesystem_name,
+        dest_filesystem_name,
+        user,
+        name,
+    )?;
+    move_history(
+        &transaction,
+        (filesystem_name, user, name),
+        (dest_filesystem_name, user, name),
+    )?;
+    transaction.commit()?;
+
+    zfs::destroy(&format!("{}@{}", dest_volume, REBALANCE_SNAPSHOT), false)?;
+    zfs::set_property(&dest_volume, "readonly", &readonly)?;
+    if quota != 0 {
+        zfs::set_property(&dest_volume, "quota", &quota.to_string())?;
+    }
+    apply_mountpoint_template(&dest_volume, dest_filesystem, user, name)?;
+    share(&dest_volume, dest_filesystem)?;
+    if canmount == "on" {
+        zfs::mount(&dest_volume)?;
+    } else {
+        // e.g. expired workspaces which have been unmounted
+        zfs::set_property(&dest_volume, "canmount", &canmount)?;
+    }
+    zfs::destroy(&volume, true)?;
+    Ok(())
+}
+
+/// Moves what is recorded about a workspace besides its row and tags to a new
+/// filesystem, user or name
+///
+/// Rows left behind under the new key by an earlier workspace of the same
+/// name are replaced, as is a record of a dataset released there.
+fn move_history(
+    conn: &Connection,
+    (filesystem_name, user, name): (&str, &str, &str),
+    (dest_filesystem_name, dest_user, dest_name): (&str, &str, &str),
+) -> Result<(), Error> {
+    for table in ["pending_permissions", "reminders_sent", "released"] {
+        conn.execute(
+            &format!(
+                "DELETE FROM {} WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+                table
+            ),
+            (dest_filesystem_name, dest_user, dest_name),
+        )?;
+    }
+    for table in [
+        "size_history",
+        "pending_permissions",
+        "reminders_sent",
+        "events",
+    ] {
+        conn.execute(
+            &format!(
+                "UPDATE {}
+                    SET filesystem = ?1,
+                        user = ?2,
+                        name = ?3
+                    WHERE filesystem = ?4
+                        AND user = ?5
+                        AND name = ?6",
+                table
+            ),
+            (
+                dest_filesystem_name,
+                dest_user,
+                dest_name,
+                filesystem_name,
+                user,
+                name,
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct WorkspacesRow {
+    filesystem_name: String,
+    user: String,
+    name: String,
+    expiration_time: DateTime<Local>,
+    is_reference: bool,
+    /// Size and mountpoint as of the last `clean`
+    cached: Option<zfs::Dataset>,
+    /// Whether the cache is too old to be trusted, e.g. as `clean` stopped
+    cache_is_stale: bool,
+    tags: Vec<String>,
+    comment: Option<String>,
+    /// Host which created the workspace, unknown for old workspaces
+    host: Option<String>,
+    project: Option<String>,
+    is_locked: bool,
+    /// End of the workspace's exemption from `clean`, if any
+    exempt_until: Option<DateTime<Local>>,
+    /// Whether the workspace's data only remains in its filesystem's archive
+    is_archived: bool,
+}
+
+/// Lists workspaces, including those on filesystems served by other hosts
+///
+/// Sizes cached longer than `max_cache_age` are queried from zfs, unless the
+/// workspace is served by another host.
+#[allow(clippy::too_many_arguments)]
+fn list(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    remote_filesystems: &HashMap<String, config::Filesystem>,
+    filter_users: &Option<Vec<String>>,
+    filter_filesystems: &Option<Vec<String>>,
+    filter_name: &dyn Fn(&str) -> bool,
+    filter_tags: &Option<Vec<String>>,
+    filter_projects: &Option<Vec<String>>,
+    expiring_within: Option<Duration>,
+    expired: bool,
+    active: bool,
+    output: &Option<Vec<cli::WorkspacesColumns>>,
+    summary: Option<cli::Summary>,
+    fresh: bool,
+    max_cache_age: Duration,
+    parallelism: usize,
+    exact_sizes: bool,
+) {
+    use cli::WorkspacesColumns;
+    // the default columns
+    let output = output.clone().unwrap_or(vec![
+        WorkspacesColumns::Name,
+        WorkspacesColumns::User,
+        WorkspacesColumns::Fs,
+        WorkspacesColumns::Size,
+        WorkspacesColumns::Expiry,
+        WorkspacesColumns::Mountpoint,
+    ]);
+
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+
+    // bold title row
+    table.set_titles(Row::new(
+        output
+            .iter()
+            .map(|h| Cell::new(&h.to_string()).with_style(Attr::Bold))
+            .collect(),
+    ));
+
+    let now = Local::now();
+    let mut all_tags = tags::all(conn).unwrap();
+    let mut statement = conn
+        .prepare(
+            "SELECT filesystem, user, name, expiration_time, class,
+                    cached_size, cached_mountpoint, comment, host, project, locked,
+                    exempt_until, archive_url IS NOT NULL, cached_at
+                FROM workspaces",
+        )
+        .unwrap();
+    let workspaces: Vec<WorkspacesRow> = statement
+        .query_map([], |row| {
+            let cached_size: Option<usize> = row.get(5)?;
+            let cached_mountpoint: Option<Vec<u8>> = row.get(6)?;
+            let key: tags::Key = (row.get(0)?, row.get(1)?, row.get(2)?);
+            Ok(WorkspacesRow {
+                tags: all_tags.remove(&key).unwrap_or_default(),
+                comment: row.get(7)?,
+                host: row.get(8)?,
+                project: row.get(9)?,
+                is_locked: row.get::<_, Option<String>>(10)?.is_some(),
+                exempt_until: row
+                    .get::<_, Option<db::Timestamp>>(11)?
+                    .map(DateTime::from)
+                    .filter(|until| *until > now),
+                is_archived: row.get(12)?,
+                cache_is_stale: row
+                    .get::<_, Option<db::Timestamp>>(13)?
+                    .is_none_or(|cached_at| DateTime::from(cached_at) < now - max_cache_age),
+                filesystem_name: key.0,
+                user: key.1,
+                name: key.2,
+                expiration_time: row.get::<_, db::Timestamp>(3)?.into(),
+                is_reference: row.get::<_, String>(4)?
+                    == cli::WorkspaceClass::Reference.to_string(),
+                cached: cached_size
+                    .zip(cached_mountpoint)
+                    .map(|(size, mountpoint)| zfs::Dataset {
+                        referenced: size,
+                        mountpoint: PathBuf::from(OsString::from_vec(mountpoint)),
+                        usedbysnapshots: None,
+                        compressratio: None,
+                    }),
+            })
+        })
+        .unwrap()
+        .map(|workspace| workspace.unwrap())
+        .filter(|workspace| {
+            filter_users
+                .as_ref()
+                .is_none_or(|us| us.contains(&workspace.user))
+                && filter_filesystems
+                    .as_ref()
+                    .is_none_or(|fs| fs.contains(&workspace.filesystem_name))
+        })
+        .filter(|workspace| filter_name(&workspace.name))
+        .filter(|workspace| {
+            filter_tags
+                .as_ref()
+                .is_none_or(|tags| workspace.tags.iter().any(|t| tags.contains(t)))
+        })
+        .filter(|workspace| {
+            filter_projects.as_ref().is_none_or(|projects| {
+                workspace
+                    .project
+                    .as_ref()
+                    .is_some_and(|p| projects.contains(p))
+            })
+        })
+        .filter(|workspace| {
+            // reference workspaces never expire
+            let is_expired = !workspace.is_reference && workspace.expiration_time <= now;
+            (!expired || is_expired)
+                && (!active || !is_expired)
+                && expiring_within.is_none_or(|within| {
+                    !workspace.is_reference
+                        && !is_expired
+                        && workspace.expiration_time <= now + within
+                })
+        })
+        .collect();
+
+    // snapshot usage and compression ratios are not cached
+    let uncached = output.iter().any(|c| {
+        matches!(
+            c,
+            WorkspacesColumns::Snapshots | WorkspacesColumns::Compress
+        )
+    });
+    let fresh = fresh || uncached;
+    // only query zfs if its properties are actually shown
+    let needs_datasets = summary.is_some()
+        || uncached
+        || output
+            .iter()
+            .any(|c| matches!(c, WorkspacesColumns::Size | WorkspacesColumns::Mountpoint));
+    // datasets of each filesystem without cached sizes, retrieved with a
+    // single zfs call each
+    let listed: Vec<(&String, &config::Filesystem)> = filesystems
+        .iter()
+        .filter(|(name, _)| {
+            needs_datasets
+                && workspaces.iter().any(|w| {
+                    &w.filesystem_name == *name && (fresh || w.cached.is_none() || w.cache_is_stale)
+                })
+        })
+        .collect();
+    let datasets: HashMap<&String, Result<HashMap<String, zfs::Dataset>, zfs::Error>> = listed
+        .iter()
+        .map(|(name, _)| *name)
+        .zip(pool::map(&listed, parallelism, |(_, info)| {
+            zfs::list_datasets(&info.root)
+        }))
+        .collect();
+    // workspace count and bytes per summary group, with `None` as grand total
+    let mut subtotals: BTreeMap<Option<&String>, (usize, usize)> = BTreeMap::new();
+    let mut unknown_filesystems = 0;
+    let local_host = config::local_host();
+    for workspace in &workspaces {
+        // workspaces whose filesystem was removed from the configuration are
+        // still listed, but without anything derived from it
+        let remote = remote_filesystems.get(&workspace.filesystem_name);
+        let filesystem = filesystems.get(&workspace.filesystem_name).or(remote);
+        let dataset = match (&workspace.cached, filesystem) {
+            // only cached sizes are known of other hosts' workspaces
+            (Some(cached), _) if !(fresh || workspace.cache_is_stale) || remote.is_some() => {
+                Some(cached)
+            }
+            (_, Some(filesystem)) if remote.is_none() => {
+                let volume = to_volume_string(&filesystem.root, &workspace.user, &workspace.name);
+                let dataset = datasets
+                    .get(&workspace.filesystem_name)
+                    .and_then(|datasets| datasets.as_ref().ok())
+                    .and_then(|datasets| datasets.get(&volume));
+                if needs_datasets && dataset.is_none() && !workspace.is_archived {
+                    eprintln!("Failed to get info for {}", volume);
+                    continue;
+                }
+                dataset
+            }
+            _ => None,
+        };
+        let host = match (&workspace.host, remote) {
+            (Some(host), _) => host.as_str(),
+            (None, Some(remote)) => remote.host.as_deref().unwrap_or_default(),
+            (None, None) => &local_host,
+        };
+        if filesystem.is_none() {
+            unknown_filesystems += 1;
+        }
+        table.add_row(Row::new(
+            output
+                .iter()
+                .map(|column| match column {
+                    WorkspacesColumns::Name if workspace.is_locked => {
+                        Cell::new(&format!("{} (LOCKED)", workspace.name))
+                            .with_style(Attr::ForegroundColor(color::MAGENTA))
+                    }
+                    WorkspacesColumns::Name => Cell::new(&workspace.name),
+                    WorkspacesColumns::User => {
+                        if user_exists(&workspace.user) {
+                            Cell::new(&workspace.user)
+                        } else {
+                            Cell::new(&format!("{} (ORPHAN-OWNER)", workspace.user))
+                                .with_style(Attr::ForegroundColor(color::RED))
+                        }
+                    }
+                    WorkspacesColumns::Fs => match filesystem {
+                        Some(_) => Cell::new(&workspace.filesystem_name),
+                        None => Cell::new(&format!("{} (UNKNOWN-FS)", workspace.filesystem_name))
+                            .with_style(Attr::ForegroundColor(color::RED)),
+                    },
+                    WorkspacesColumns::Expiry if workspace.is_reference => {
+                        Cell::new("reference").with_style(Attr::ForegroundColor(color::CYAN))
+                    }
+                    WorkspacesColumns::Expiry if workspace.exempt_until.is_some() => {
+                        let until = workspace.exempt_until.unwrap();
+                        let text = if until == reference_expiration_time() {
+                            "exempt".to_string()
+                        } else {
+                            format!("exempt to {}", until.format("%Y-%m-%d"))
+                        };
+                        Cell::new(&text).with_style(Attr::ForegroundColor(color::CYAN))
+                    }
+                    // without its filesystem, it is unknown when it will be deleted
+                    WorkspacesColumns::Expiry if filesystem.is_none() => Cell::new_align(
+                        &workspace.expiration_time.format("%Y-%m-%d").to_string(),
+                        Alignment::RIGHT,
+                    ),
+                    WorkspacesColumns::Expiry => {
+                        let filesystem = filesystem.unwrap();
+                        if Local::now() > workspace.expiration_time + filesystem.retention() {
+                            Cell::new("deleted soon")
+                                .with_style(Attr::Bold)
+                                .with_style(Attr::ForegroundColor(color::RED))
+                        } else if Local::now() > workspace.expiration_time {
+                            let tier = if workspace.is_archived {
+                                Some(config::Tier::Archived)
+                            } else {
+                                filesystem
+                                    .tier(Local::now() - workspace.expiration_time)
+                                    // only clean archives them
+                                    .filter(|tier| *tier != config::Tier::Archived)
+                            };
+                            Cell::new_align(
+                                &format!(
+                                    "{}deleted in {:>2}d",
+                                    tier.map_or(String::new(), |tier| format!("{}, ", tier)),
+                                    (workspace.expiration_time + filesystem.retention()
+                                        - Local::now())
+                                    .num_days()
+                                ),
+                                Alignment::RIGHT,
+                            )
+                            .with_style(Attr::Bold)
+                            .with_style(Attr::ForegroundColor(color::RED))
+                        } else if workspace.expiration_time - Local::now()
+                            < filesystem.expiry_warning
+                        {
+                            Cell::new_align(
+                                &format!(
+                                    "expires in {:>2}d",
+                                    (workspace.expiration_time - Local::now()).num_days()
+                                ),
+                                Alignment::RIGHT,
+                            )
+                            .with_style(Attr::ForegroundColor(color::YELLOW))
+                        } else {
+                            Cell::new_align(
+                                &format!(
+                                    "expires in {:>2}d",
+                                    (workspace.expiration_time - Local::now()).num_days()
+                                ),
+                                Alignment::RIGHT,
+                            )
+                        }
+                    }
+                    WorkspacesColumns::Size => Cell::new_align(
+                        &dataset.map_or("-".to_string(), |d| {
+                            units::format_size(d.referenced as u64, exact_sizes)
+                        }),
+                        Alignment::RIGHT,
+                    ),
+                    WorkspacesColumns::Mountpoint => Cell::new(
+                        &dataset.map_or("-".to_string(), |d| d.mountpoint.display().to_string()),
+                    ),
+                    WorkspacesColumns::Tags => Cell::new(&workspace.tags.join(",")),
+                    WorkspacesColumns::Comment => {
+                        Cell::new(workspace.comment.as_deref().unwrap_or(""))
+                    }
+                    WorkspacesColumns::Host => Cell::new(host),
+                    WorkspacesColumns::Project => {
+                        Cell::new(workspace.project.as_deref().unwrap_or(""))
+                    }
+                    WorkspacesColumns::Snapshots => Cell::new_align(
+                        &dataset
+                            .and_then(|d| d.usedbysnapshots)
+                            .map_or("-".to_string(), |bytes| {
+                                units::format_size(bytes as u64, exact_sizes)
+                            }),
+                        Alignment::RIGHT,
+                    ),
+                    WorkspacesColumns::Compress => Cell::new_align(
+                        &dataset
+                            .and_then(|d| d.compressratio.as_ref())
+                            .map_or("-".to_string(), |ratio| format!("{}x", ratio)),
+                        Alignment::RIGHT,
+                    ),
+                })
+                .collect(),
+        ));
+
+        if let Some(summary) = summary {
+            let key = match summary {
+                cli::Summary::Total => None,
+                cli::Summary::User => Some(&workspace.user),
+                cli::Summary::Filesystem => Some(&workspace.filesystem_name),
+                cli::Summary::Project => workspace.project.as_ref(),
+            };
+            let bytes = dataset.map_or(0, |d| d.referenced);
+            let mut add = |key| {
+                let (count, sum) = subtotals.entry(key).or_default();
+                *count += 1;
+                *sum += bytes;
+            };
+            add(None);
+            if key.is_some() {
+                add(key);
+            }
+        }
+    }
+
+    if let Some(summary) = summary {
+        table.add_empty_row();
+        // the grand total is sorted first, but shown last
+        let mut subtotals: Vec<_> = subtotals.into_iter().collect();
+        subtotals.rotate_left(1);
+        for (key, (count, bytes)) in subtotals {
+            let key = key.map_or("", |k| k.as_str());
+            table.add_row(Row::new(
+                output
+                    .iter()
+                    .map(|column| match column {
+                        WorkspacesColumns::Name => {
+                            Cell::new(if key.is_empty() { "TOTAL" } else { "SUBTOTAL" })
+                        }
+                        WorkspacesColumns::User if summary == cli::Summary::User => Cell::new(key),
+                        WorkspacesColumns::Fs if summary == cli::Summary::Filesystem => {
+                            Cell::new(key)
+                        }
+                        WorkspacesColumns::Project if summary == cli::Summary::Project => {
+                            Cell::new(key)
+                        }
+                        WorkspacesColumns::Size => Cell::new_align(
+                            &units::format_size(bytes as u64, exact_sizes),
+                            Alignment::RIGHT,
+                        ),
+                        WorkspacesColumns::Expiry => {
+                            Cell::new_align(&format!("{} workspace(s)", count), Alignment::RIGHT)
+                        }
+                        _ => Cell::new(""),
+                    })
+                    .map(|c| c.with_style(Attr::Bold))
+                    .collect(),
+            ));
+        }
+    }
+
+    table::print(&table);
+
+    if unknown_filesystems > 0 {
+        eprintln!(
+            "{} workspace(s) are on filesystems missing from {}. \
+            See `workspaces orphans` for details.",
+            unknown_filesystems,
+            config::CONFIG_PATH
+        );
+    }
+}
+
+/// Lists the workspaces on filesystems missing from the configuration
+fn orphans(conn: &Connection, config: &config::Config) -> Result<(), Error> {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["FILESYSTEM", "USER", "NAME", "EXPIRY"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+
+    let mut statement = conn.prepare(
+        "SELECT filesystem, user, name, expiration_time, class
+            FROM workspaces
+            ORDER BY filesystem, user, name",
+    )?;
+    let mut rows = statement.query([])?;
+    let mut unknown: Vec<String> = vec![];
+    while let Some(row) = rows.next()? {
+        let filesystem_name: String = row.get(0)?;
+        if config.filesystems.contains_key(&filesystem_name)
+            || config.remote_filesystems.contains_key(&filesystem_name)
+        {
+            continue;
+        }
+        let expiration_time: DateTime<Local> = row.get::<_, db::Timestamp>(3)?.into();
+        let expiry = if row.get::<_, String>(4)? == cli::WorkspaceClass::Reference.to_string() {
+            "reference".to_string()
+        } else {
+            expiration_time.format("%Y-%m-%d").to_string()
+        };
+        table.add_row(Row::new(vec![
+            Cell::new(&filesystem_name),
+            Cell::new(&row.get::<_, String>(1)?),
+            Cell::new(&row.get::<_, String>(2)?),
+            Cell::new(&expiry),
+        ]));
+        if !unknown.contains(&filesystem_name) {
+            unknown.push(filesystem_name);
+        }
+    }
+
+    if unknown.is_empty() {
+        println!("All workspaces are on configured filesystems");
+        return Ok(());
+    }
+    table::print(&table);
+    println!();
+    println!(
+        "To resolve this, add {} back to {}, e.g. as a retired filesystem, \
+        and migrate or expire the workspaces.",
+        unknown.join(", "),
+        config::CONFIG_PATH
+    );
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn extend(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    duration: &Duration,
+    quota: Option<u64>,
+    hooks: &config::Hooks,
+) -> Result<(), Error> {
+    if !is_caller(user) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    if is_reference(conn, filesystem_name, user, name)? {
+        return Err(Error::ReferenceWorkspace);
+    }
+    check_unlocked(conn, filesystem_name, user, name)?;
+    if let Some(url) = archive_url(conn, filesystem_name, user, name)? {
+        return Err(Error::InvalidArgument(format!(
+            "This workspace has been archived to {} and can no longer be extended",
+            url
+        )));
+    }
+    if filesystem.retired {
+        return Err(Error::FilesystemRetired(
+            "Please recreate workspace on another filesystem.",
+        ));
+    }
+    if filesystem.disabled && !is_admin() {
+        return Err(Error::FilesystemDisabled(
+            "Please recreate workspace on another filesystem.",
+        ));
+    }
+    if duration > &filesystem.max_duration && !is_admin() {
+        return Err(Error::DurationTooHigh(filesystem.max_duration));
+    }
+    if let Some(quota) = quota {
+        match filesystem.max_quota {
+            _ if is_admin() => {}
+            Some(max_quota) if quota <= max_quota << 30 => {}
+            Some(max_quota) => return Err(Error::QuotaTooHigh(max_quota << 30)),
+            None => return Err(Error::InsufficientPrivileges),
+        }
+    }
+
+    let volume = to_volume_string(&filesystem.root, user, name);
+    let expiration_time = Local::now() + *duration;
+    let workspace = hooks::Workspace {
+        filesystem_name,
+        user,
+        name,
+        volume: &volume,
+        expiration_time: &expiration_time.max(current_expiration_time(
+            conn,
+            filesystem_name,
+            user,
+            name,
+        )?),
+    };
+    hooks::run_pre(hooks, hooks::Event::Extend, &workspace)?;
+
+    let rows_updated = conn.execute(
+        "UPDATE workspaces
+            SET expiration_time = CASE
+                WHEN expiration_time > ?1 THEN expiration_time
+                ELSE ?1
+            END
+            WHERE filesystem = ?2
+                AND user = ?3
+                AND name = ?4",
+        (
+            db::Timestamp::from(expiration_time),
+            filesystem_name,
+            user,
+            name,
+        ),
+    )?;
+    match rows_updated {
+        0 => {
+            return Err(Error::UnknownWorkspace {
+                filesystem: filesystem_name.to_string(),
+                user: user.to_string(),
+                name: name.to_string(),
+            })
+        }
+        1 => {}
+        _ => unreachable!(),
+    };
+
+    if let Some(quota) = quota {
+        // like the expiration time, quotas are only ever raised; none means unlimited
+        let current: u64 = zfs::get_property(&volume, "quota")?;
+        if current != 0 && current < quota {
+            zfs::set_property(&volume, "quota", &quota.to_string())?;
+        }
+    }
+    zfs::set_property(&volume, "readonly", "off")?;
+    // the volume may have been unmounted before unmounting was disabled
+    if zfs::get_property::<String>(&volume, "canmount")? == "noauto" {
+        zfs::set_property(&volume, "canmount", "on")?;
+    }
+    if !zfs::is_mounted(&volume)? {
+        zfs::mount(&volume)?;
+    }
+
+    let workspace = hooks::Workspace {
+        filesystem_name,
+        user,
+        name,
+        volume: &volume,
+        expiration_time: &current_expiration_time(conn, filesystem_name, user, name)?,
+    };
+    hooks::run(hooks, hooks::Event::Extend, &workspace);
+    audit::log(conn, hooks::Event::Extend, &workspace);
+    Ok(())
+}
+
+/// The time from now until the end of `date`
+fn duration_until(date: NaiveDate) -> Result<Duration, Error> {
+    let end = date
+        .succ_opt()
+        .and_then(|next_day| next_day.and_hms_opt(0, 0, 0))
+        .and_then(|midnight| midnight.and_local_timezone(Local).earliest())
+        .ok_or_else(|| Error::InvalidArgument(format!("{} is not a valid date", date)))?;
+    let duration = end - Local::now();
+    if duration <= Duration::zero() {
+        return Err(Error::InvalidArgument(format!("{} lies in the past", date)));
+    }
+    Ok(duration)
+}
+
+/// Suggests ways to trim down a workspace
+fn advise(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    options: &advise::Options,
+    exact_sizes: bool,
+) -> Result<(), Error> {
+    if !is_caller(user) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let exists = conn
+        .query_row(
+            "SELECT 1 FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if !exists {
+        return Err(Error::UnknownWorkspace {
+            filesystem: filesystem_name.to_string(),
+            user: user.to_string(),
+            name: name.to_string(),
+        });
+    }
+
+    let volume = to_volume_string(&filesystem.root, user, name);
+    let mountpoint = zfs::get_path_property(&volume, "mountpoint")?;
+    let compressratio: String = zfs::get_property(&volume, "compressratio")?;
+    println!("Current compression ratio: {}\n", compressratio);
+    advise::advise(&mountpoint, options, exact_sizes)
+}
+
+/// Adds or removes tags of a workspace and prints its resulting tags
+///
+/// Each change is either `+TAG` or `TAG` to add a tag, or `-TAG` to remove it.
+fn tag(
+    conn: &mut Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    changes: &[String],
+) -> Result<(), Error> {
+    if !changes.is_empty() && !is_caller(user) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let exists = conn
+        .query_row(
+            "SELECT 1 FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if !exists {
+        return Err(Error::UnknownWorkspace {
+            filesystem: filesystem_name.to_string(),
+            user: user.to_string(),
+            name: name.to_string(),
+        });
+    }
+
+    let mut added = vec![];
+    let mut removed = vec![];
+    for change in changes {
+        let (tags, tag) = match change.strip_prefix('-') {
+            Some(tag) => (&mut removed, tag),
+            None => (&mut added, change.strip_prefix('+').unwrap_or(change)),
+        };
+        let tag = cli::parse_pathsafe(tag).map_err(|e| Error::InvalidArgument(e.to_string()))?;
+        tags.push(tag);
+    }
+    let transaction = db::transaction(conn)?;
+    tags::remove(&transaction, filesystem_name, user, name, &removed)?;
+    tags::add(&transaction, filesystem_name, user, name, &added)?;
+    transaction.commit()?;
+
+    println!(
+        "{}",
+        tags::get(conn, filesystem_name, user, name)?.join(" ")
+    );
+    Ok(())
+}
+
+/// Sets the comment of a workspace, or prints it if none is given
+///
+/// An empty comment removes the current one.
+fn comment(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    comment: &Option<String>,
+) -> Result<(), Error> {
+    let unknown_workspace = || Error::UnknownWorkspace {
+        filesystem: filesystem_name.to_string(),
+        user: user.to_string(),
+        name: name.to_string(),
+    };
+    let Some(comment) = comment else {
+        let comment: Option<String> = conn
+            .query_row(
+                "SELECT comment FROM workspaces
+                    WHERE filesystem = ?1
+                        AND user = ?2
+                        AND name = ?3",
+                (filesystem_name, user, name),
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(unknown_workspace)?;
+        if let Some(comment) = comment {
+            println!("{}", comment);
+        }
+        return Ok(());
+    };
+
+    if !is_caller(user) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let updated = conn.execute(
+        "UPDATE workspaces
+            SET comment = ?4
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3",
+        (
+            filesystem_name,
+            user,
+            name,
+            Some(comment).filter(|c| !c.is_empty()),
+        ),
+    )?;
+    if updated == 0 {
+        return Err(unknown_workspace());
+    }
+    Ok(())
+}
+
+/// Lists the workspaces whose name matches a pattern
+///
+/// The pattern is either a glob in which `*` matches any characters, or a
+/// regular expression.  Only root may search the workspaces of all users.
+fn find(
+    conn: &Connection,
+    config: &config::Config,
+    pattern: &str,
+    regex: bool,
+    user: &Option<String>,
+    output: &Option<Vec<cli::WorkspacesColumns>>,
+    exact_sizes: bool,
+) -> Result<(), Error> {
+    if user.is_none() && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let filter_name: Box<dyn Fn(&str) -> bool> = if regex {
+        let regex = Regex::new(pattern).map_err(|e| Error::InvalidArgument(e.to_string()))?;
+        Box::new(move |name| regex.is_match(name))
+    } else {
+        Box::new(|name| config::matches_pattern(pattern, name))
+    };
+    list(
+        conn,
+        &config.filesystems,
+        &config.remote_filesystems,
+        &user.clone().map(|user| vec![user]),
+        &None,
+        &filter_name,
+        &None,
+        &None,
+        None,
+        false,
+        false,
+        output,
+        None,
+        false,
+        config.daemon.clean_interval * 2,
+        config.parallelism,
+        exact_sizes,
+    );
+    Ok(())
+}
+
+/// Prints only the mountpoint of a workspace, for use in scripts
+fn path(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+) -> Result<(), Error> {
+    let exists = conn
+        .query_row(
+            "SELECT 1 FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if !exists {
+        return Err(Error::UnknownWorkspace {
+            filesystem: filesystem_name.to_string(),
+            user: user.to_string(),
+            name: name.to_string(),
+        });
+    }
+    let volume = to_volume_string(&filesystem.root, user, name);
+    let mountpoint = zfs::get_path_property(&volume, "mountpoint")?;
+    io::stdout().write_all(mountpoint.as_os_str().as_bytes())?;
+    println!();
+    Ok(())
+}
+
+/// Mounts a workspace, or unmounts it if `mounted` is unset
+fn set_mounted(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    mounted: bool,
+) -> Result<(), Error> {
+    if !is_caller(user) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let exists = conn
+        .query_row(
+            "SELECT 1 FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if !exists {
+        return Err(Error::UnknownWorkspace {
+            filesystem: filesystem_name.to_string(),
+            user: user.to_string(),
+            name: name.to_string(),
+        });
+    }
+    let volume = to_volume_string(&filesystem.root, user, name);
+    if zfs::is_mounted(&volume)? == mounted {
+        println!(
+            "Workspace is already {}",
+            if mounted { "mounted" } else { "unmounted" }
+        );
+        return Ok(());
+    }
+    // canmount is left alone, so unmount_expired's noauto is kept for `extend`
+    if mounted {
+        zfs::mount(&volume)?;
+    } else {
+        zfs::unmount(&volume, false)?;
+    }
+    Ok(())
+}
+
+/// Asks for confirmation before running a destructive command from a terminal
+///
+/// Exits if the user declines.  Without a terminal, e.g. in cron jobs, there
+/// is nobody to ask and the command proceeds.
+fn confirm(command: &cli::Command) {
+    let question = match command {
+        cli::Command::Expire {
+            names,
+            pattern: None,
+            user,
+            delete_on_next_clean: true,
+            ..
+        } if names.len() == 1 => format!(
+            "Workspace {} of {} will be deleted on the next clean. Continue?",
+            names[0], user
+        ),
+        cli::Command::Clean { .. } => {
+            "All workspaces past their retention period will be deleted. Continue?".to_string()
+        }
+        cli::Command::Admin {
+            command: cli::AdminCommand::ReassignOrphans { expire: true, .. },
+        } => "All workspaces of deleted users will be expired. Continue?".to_string(),
+        cli::Command::AuditUsers {
+            expire_deleted: true,
+            ..
+        } => "All workspaces of deleted users will be expired. Continue?".to_string(),
+        cli::Command::Fsck {
+            destroy_untracked: true,
+            ..
+        } => "All datasets not in the database will be destroyed. Continue?".to_string(),
+        cli::Command::Db {
+            command: cli::DbCommand::Restore { path },
+        } => format!(
+            "The database will be replaced with {}, losing all changes since. Continue?",
+            path.display()
+        ),
+        _ => return,
+    };
+    if !io::stdin().is_terminal() {
+        return;
+    }
+    ask(&question);
+}
+
+/// Lists the workspaces selected by an `extend` or `expire` of several ones
+/// and asks for confirmation
+///
+/// The selection is resolved by running the command with `--preview`, through
+/// the server if need be, as only it can read the database.
+fn confirm_selection(config: &config::Config, command: &cli::Command) {
+    let (names, pattern, outcome) = match command {
+        cli::Command::Extend { names, pattern, .. } => (names, pattern, "extended"),
+        cli::Command::Expire {
+            names,
+            pattern,
+            delete_on_next_clean,
+            ..
+        } => (
+            names,
+            pattern,
+            if *delete_on_next_clean {
+                "deleted on the next clean"
+            } else {
+                "expired"
+            },
+        ),
+        _ => return,
+    };
+    if (names.len() <= 1 && pattern.is_none()) || !io::stdin().is_terminal() {
+        return;
+    }
+
+    let mut args: Vec<OsString> = env::args_os().collect();
+    args.push("--preview".into());
+    let preview = server::capture(config, args);
+    if preview.status != 0 {
+        eprint!("{}", preview.stderr);
+        process::exit(preview.status);
+    }
+    eprintln!("The following workspaces will be {}:", outcome);
+    for name in preview.stdout.lines() {
+        eprintln!("  {}", name);
+    }
+    ask("Continue?");
+}
+
+/// Asks a yes/no question on the terminal, exiting unless the user agrees
+fn ask(question: &str) {
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    let _ = io::stdin().read_line(&mut answer);
+    if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        eprintln!("Aborted.");
+        process::exit(exit_codes::ABORTED);
+    }
+}
+
+/// Logs to stderr at a level depending on `verbose`, unless overridden by `RUST_LOG`
+fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let filter = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy();
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .init();
+}
+
+/// Prints the largest directories within a workspace
+#[allow(clippy::too_many_arguments)]
+fn du(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    depth: usize,
+    top: usize,
+    parallelism: usize,
+    exact_sizes: bool,
+) -> Result<(), Error> {
+    // we run as root, so don't reveal the contents of other users' workspaces
+    if !is_caller(user) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let exists = conn
+        .query_row(
+            "SELECT 1 FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if !exists {
+        return Err(Error::UnknownWorkspace {
+            filesystem: filesystem_name.to_string(),
+            user: user.to_string(),
+            name: name.to_string(),
+        });
+    }
+    let volume = to_volume_string(&filesystem.root, user, name);
+    let mountpoint = zfs::get_path_property(&volume, "mountpoint")?;
+
+    let usage = du::walk(&mountpoint, depth, parallelism)?;
+    let mut dirs: Vec<_> = usage.dirs.into_iter().collect();
+    dirs.sort_by(|(a_path, a_size), (b_path, b_size)| {
+        b_size.cmp(a_size).then_with(|| a_path.cmp(b_path))
+    });
+
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["SIZE", "PATH"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+    for (path, size) in dirs.into_iter().take(top) {
+        table.add_row(Row::new(vec![
+            Cell::new_align(&units::format_size(size, exact_sizes), Alignment::RIGHT),
+            Cell::new(
+                &if path.as_os_str().is_empty() {
+                    mountpoint.clone()
+                } else {
+                    mountpoint.join(path)
+                }
+                .display()
+                .to_string(),
+            ),
+        ]));
+    }
+    table::print(&table);
+    if usage.errors > 0 {
+        eprintln!(
+            "warning: {} directories could not be read and were skipped",
+            usage.errors
+        );
+    }
+    Ok(())
+}
+
+/// Prints everything known about a single workspace
+fn info(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    exact_sizes: bool,
+) -> Result<(), Error> {
+    let Some((expiration_time, class, comment, project, locked, exempt_until, archive_url)) = conn
+        .query_row(
+            "SELECT expiration_time, class, comment, project, locked, exempt_until, archive_url
+                FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |row| {
+                Ok((
+                    row.get::<_, db::Timestamp>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, Option<db::Timestamp>>(5)?,
+                    row.get::<_, Option<String>>(6)?,
+                ))
+            },
+        )
+        .optional()?
+    else {
+        return Err(Error::UnknownWorkspace {
+            filesystem: filesystem_name.to_string(),
+            user: user.to_string(),
+            name: name.to_string(),
+        });
+    };
+    let expiration_time: DateTime<Local> = expiration_time.into();
+    let is_reference = class == cli::WorkspaceClass::Reference.to_string();
+    let deletion_time = expiration_time + filesystem.retention();
+
+    // only the metadata is left of archived workspaces
+    if let Some(url) = archive_url {
+        println!("Name:        {}", name);
+        println!("Owner:       {}", user);
+        println!("Filesystem:  {}", filesystem_name);
+        println!("Archived:    {}", url);
+        println!("Expired:     {}", expiration_time.format("%F %R"));
+        println!("Deletion:    {}", deletion_time.format("%F %R"));
+        print_recent_events(conn, filesystem_name, user, name)?;
+        return Ok(());
+    }
+
+    let volume = to_volume_string(&filesystem.root, user, name);
+    let mountpoint = zfs::get_path_property(&volume, "mountpoint")?;
+    let referenced: u64 = zfs::get_property(&volume, "referenced")?;
+    let used: u64 = zfs::get_property(&volume, "used")?;
+    let quota: u64 = zfs::get_property(&volume, "quota")?;
+    let compressratio: String = zfs::get_property(&volume, "compressratio")?;
+    let creation: i64 = zfs::get_property(&volume, "creation")?;
+    let snapshots = zfs::list_snapshots(&volume)?;
+
+    println!("Name:        {}", name);
+    println!("Owner:       {}", user);
+    if let Some(project) = project {
+        println!("Project:     {}", project);
+    }
+    println!("Filesystem:  {}", filesystem_name);
+    println!("Class:       {}", class);
+    if let Some(comment) = comment {
+        println!("Comment:     {}", comment);
+    }
+    match locked.as_deref() {
+        Some("") => println!("Locked:      yes"),
+        Some(reason) => println!("Locked:      {}", reason),
+        None => {}
+    }
+    println!("Dataset:     {}", volume);
+    println!("Mountpoint:  {}", mountpoint.display());
+    println!(
+        "Size:        {} ({} including snapshots)",
+        units::format_size(referenced, exact_sizes),
+        units::format_size(used, exact_sizes)
+    );
+    if quota == 0 {
+        println!("Quota:       none");
+    } else {
+        println!("Quota:       {}", units::format_size(quota, exact_sizes));
+    }
+    println!("Compression: {}", compressratio);
+    if let Some(created) = Local.timestamp_opt(creation, 0).single() {
+        println!("Created:     {}", created.format("%F %R"));
+    }
+    if is_reference {
+        println!("Expires:     never");
+    } else {
+        println!("Expires:     {}", expiration_time.format("%F %R"));
+        if let Some(tier) = filesystem.tier(Local::now() - expiration_time) {
+            if expiration_time < Local::now() {
+                println!("Tier:        {}", tier);
+            }
+        }
+        println!("Deletion:    {}", deletion_time.format("%F %R"));
+    }
+    match exempt_until.map(DateTime::<Local>::from) {
+        Some(until) if until == reference_expiration_time() => {
+            println!("Exempt:      from clean")
+        }
+        Some(until) if until > Local::now() => {
+            println!("Exempt:      from clean until {}", until.format("%F %R"))
+        }
+        _ => {}
+    }
+    if snapshots.is_empty() {
+        println!("Snapshots:   none");
+    } else {
+        println!("Snapshots:   {}", snapshots.join(", "));
+    }
+
+    let mut statement = conn.prepare(
+        "SELECT days FROM reminders_sent
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3
+                AND expiration_time = ?4
+            ORDER BY days DESC",
+    )?;
+    let reminders = statement
+        .query_map(
+            (
+                filesystem_name,
+                user,
+                name,
+                db::Timestamp::from(expiration_time),
+            ),
+            |row| row.get::<_, i64>(0),
+        )?
+        .map(|days| days.map(|days| format!("{}d", days)))
+        .collect::<Result<Vec<_>, _>>()?;
+    if !reminders.is_empty() {
+        println!("Reminded:    {} before expiry", reminders.join(", "));
+    }
+    if let Some(error) = conn
+        .query_row(
+            "SELECT last_error FROM pending_permissions
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?
+    {
+        println!("Permissions: not set up yet ({})", error);
+    }
+    print_recent_events(conn, filesystem_name, user, name)?;
+    Ok(())
+}
+
+/// Number of audit events shown by `info`
+const RECENT_EVENTS: usize = 5;
+
+/// Prints the latest audit events of a workspace, oldest first
+fn print_recent_events(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+) -> Result<(), Error> {
+    let mut statement = conn.prepare(
+        "SELECT time, event FROM events
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3
+            ORDER BY time DESC
+            LIMIT ?4",
+    )?;
+    let mut events = statement
+        .query_map((filesystem_name, user, name, RECENT_EVENTS), |row| {
+            Ok((row.get::<_, db::Timestamp>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    events.reverse();
+    for (i, (time, event)) in events.into_iter().enumerate() {
+        println!(
+            "{:13}{} {}",
+            if i == 0 { "Events:" } else { "" },
+            DateTime::<Local>::from(time).format("%F %R"),
+            event
+        );
+    }
+    Ok(())
+}
+
+/// Writes a publication bundle of a workspace to a directory of the caller
+#[allow(clippy::too_many_arguments)]
+fn bundle(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    out: &Path,
+    archive: Option<cli::ArchiveFormat>,
+    parallelism: usize,
+) -> Result<(), Error> {
+    if !is_caller(user) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    // a stream includes files of others the caller may not be able to read
+    if archive == Some(cli::ArchiveFormat::Zfs) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    check_unlocked(conn, filesystem_name, user, name)?;
+    let Some((expiration_time, class, comment)) = conn
+        .query_row(
+            "SELECT expiration_time, class, comment FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |row| {
+                Ok((
+                    row.get::<_, db::Timestamp>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            },
+        )
+        .optional()?
+    else {
+        return Err(Error::UnknownWorkspace {
+            filesystem: filesystem_name.to_string(),
+            user: user.to_string(),
+            name: name.to_string(),
+        });
+    };
+
+    let volume = to_volume_string(&filesystem.root, user, name);
+    let mountpoint = zfs::get_path_property(&volume, "mountpoint")?;
+    bundle::write(
+        &bundle::Workspace {
+            filesystem_name,
+            user,
+            name,
+            volume: &volume,
+            mountpoint: &mountpoint,
+            expiration_time: &expiration_time.into(),
+            class: &class,
+            comment: comment.as_deref(),
+            tags: &tags::get(conn, filesystem_name, user, name)?,
+        },
+        &output_dir(out)?,
+        archive,
+        parallelism,
+    )
+}
+
+/// Checks that the caller may write to `dir`, which files are then created in
+///
+/// The directory is opened once, so it cannot be swapped for another one
+/// between the check and writing to it.
+fn output_dir(dir: &Path) -> Result<bundle::Output<'_>, Error> {
+    // we run as root, so only write where the caller could have written
+    let handle = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY)
+        .open(dir)
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::ENOTDIR) => {
+                Error::InvalidArgument(format!("{} is not a directory", dir.display()))
+            }
+            _ => e.into(),
+        })?;
+    let metadata = handle.metadata()?;
+    if metadata.uid() != caller_uid() && caller_uid() != 0 {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let gid = get_user_by_uid(caller_uid())
+        .map(|u| u.primary_group_id())
+        .unwrap_or(metadata.gid());
+    Ok(bundle::Output {
+        dir,
+        handle,
+        uid: caller_uid(),
+        gid,
+    })
+}
+
+/// Exports a workspace's data to a tarball at `to`
+fn export(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    to: &Path,
+) -> Result<(), Error> {
+    if !is_caller(user) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    check_unlocked(conn, filesystem_name, user, name)?;
+    let exists = conn
+        .query_row(
+            "SELECT 1 FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if !exists {
+        return Err(Error::UnknownWorkspace {
+            filesystem: filesystem_name.to_string(),
+            user: user.to_string(),
+            name: name.to_string(),
+        });
+    }
+
+    let Some(file_name) = to.file_name() else {
+        return Err(Error::InvalidArgument(format!(
+            "{} is not a file name",
+            to.display()
+        )));
+    };
+    let dir = match to.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let file_name = file_name.to_string_lossy();
+    let output = output_dir(dir)?;
+    let (path, file) = output.create(&file_name)?;
+    let mountpoint = zfs::get_path_property(
+        &to_volume_string(&filesystem.root, user, name),
+        "mountpoint",
+    )?;
+    if let Err(e) = tarball::export(&mountpoint, file, output.uid, output.gid) {
+        // don't leave a truncated tarball behind
+        let _ = output.remove(&file_name);
+        return Err(e);
+    }
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+/// Creates a workspace and restores the data of a tarball written by `export`
+/// into it
+#[allow(clippy::too_many_arguments)]
+fn import_tarball(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    path: &Path,
+    duration: &Option<Duration>,
+    hooks: &config::Hooks,
+) -> Result<(), Error> {
+    if !is_caller(user) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    // we run as root, so only read what the caller could have read, and
+    // don't block on FIFOs or follow symlinks before that is checked
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::ELOOP) => {
+                Error::InvalidArgument(format!("{} is not a file", path.display()))
+            }
+            _ => e.into(),
+        })?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(Error::InvalidArgument(format!(
+            "{} is not a file",
+            path.display()
+        )));
+    }
+    if metadata.uid() != caller_uid() && caller_uid() != 0 {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let owner = get_user_by_name(user).ok_or_else(|| Error::UnknownUser(user.to_string()))?;
+
+    create(
+        conn,
+        filesystems,
+        filesystem_name,
+        filesystem,
+        user,
+        name,
+        duration,
+        cli::WorkspaceClass::Scratch,
+        &None,
+        false,
+        &[],
+        &None,
+        None,
+        hooks,
+    )?;
+    let mountpoint = zfs::get_path_property(
+        &to_volume_string(&filesystem.root, user, name),
+        "mountpoint",
+    )?;
+    tarball::import(file, &mountpoint, owner.uid(), owner.primary_group_id())?;
+    println!("Imported {} into {}", path.display(), mountpoint.display());
+    Ok(())
+}
+
+/// Shows the changes made to a workspace since one of its snapshots
+#[allow(clippy::too_many_arguments)]
+fn diff(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    snapshot: &str,
+    list: bool,
+    json: bool,
+) {
+    if !is_caller(user) && !is_admin() {
+        eprintln!("You are not allowed to execute this operation");
+        process::exit(exit_codes::INSUFFICIENT_PRIVILEGES);
+    }
+    let exists = conn
+        .query_row(
+            "SELECT 1 FROM workspaces
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3",
+            (filesystem_name, user, name),
+            |_| Ok(()),
+        )
+        .optional()
+        .unwrap()
+        .is_some();
+    if !exists {
+        eprintln!(
+            "Could not find a matching filesystem={}, user={}, name={}",
+            filesystem_name, user, name
+        );
+        process::exit(exit_codes::UNKNOWN_WORKSPACE);
+    }
+
+    let volume = to_volume_string(&filesystem.root, user, name);
+    let changes = match zfs::diff(&volume, snapshot) {
+        Ok(changes) => changes,
+        Err(e) => {
+            eprintln!("Could not diff {} against {}: {}", name, snapshot, e);
+            process::exit(exit_codes::UNKNOWN_WORKSPACE);
+        }
+    };
+
+    let count = |f: fn(&zfs::Change) -> bool| changes.iter().filter(|c| f(c)).count();
+    let added = count(|c| matches!(c, zfs::Change::Added { .. }));
+    let modified = count(|c| matches!(c, zfs::Change::Modified { .. }));
+    let removed = count(|c| matches!(c, zfs::Change::Removed { .. }));
+    let renamed = count(|c| matches!(c, zfs::Change::Renamed { .. }));
+
+    if json {
+        let mut output = serde_json::json!({
+            "added": added,
+            "modified": modified,
+            "removed": removed,
+            "renamed": renamed,
+        });
+        if list {
+            output["changes"] = serde_json::to_value(&changes).unwrap();
+        }
+        println!("{}", output);
+        return;
+    }
+
+    if list {
+        for change in &changes {
+            match change {
+                zfs::Change::Added { path } => println!("+ {}", path),
+                zfs::Change::Modified { path } => println!("M {}", path),
+                zfs::Change::Removed { path } => println!("- {}", path),
+                zfs::Change::Renamed { path, new_path } => println!("R {} -> {}", path, new_path),
+            }
+        }
+    }
+    println!(
+        "{} added, {} modified, {} removed, {} renamed since {}",
+        added, modified, removed, renamed, snapshot
+    );
+}
+
+/// Retrieves the current expiration time of a workspace
+fn current_expiration_time(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+) -> Result<DateTime<Local>, Error> {
+    conn.query_row(
+        "SELECT expiration_time FROM workspaces
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3",
+        (filesystem_name, user, name),
+        |row| row.get::<_, db::Timestamp>(0),
+    )
+    .optional()?
+    .map(DateTime::from)
+    .ok_or_else(|| Error::UnknownWorkspace {
+        filesystem: filesystem_name.to_string(),
+        user: user.to_string(),
+        name: name.to_string(),
+    })
+}
+
+fn expire(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    delete_on_next_clean: bool,
+    hooks: &config::Hooks,
+) -> Result<(), Error> {
+    if !is_caller(user) && !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    if is_reference(conn, filesystem_name, user, name)? {
+        return Err(Error::ReferenceWorkspace);
+    }
+
+    let expiration_time = if delete_on_next_clean {
+        // set the expiration time sufficiently far in the past
+        // for it to get cleaned up soon
+        Local::now() - filesystem.retention()
+    } else {
+        Local::now()
+    };
+    let volume = to_volume_string(&filesystem.root, user, name);
+    let workspace = hooks::Workspace {
+        filesystem_name,
+        user,
+        name,
+        volume: &volume,
+        expiration_time: &expiration_time.min(current_expiration_time(
+            conn,
+            filesystem_name,
+            user,
+            name,
+        )?),
+    };
+    hooks::run_pre(hooks, hooks::Event::Expire, &workspace)?;
+    let rows_updated = conn.execute(
+        "UPDATE workspaces
+            SET expiration_time = CASE
+                WHEN expiration_time < ?1 THEN expiration_time
+                ELSE ?1
+            END
+            WHERE filesystem = ?2
+                AND user = ?3
+                AND name = ?4",
+        (
+            db::Timestamp::from(expiration_time),
+            filesystem_name,
+            user,
+            name,
+        ),
+    )?;
+    match rows_updated {
+        0 => {
+            return Err(Error::UnknownWorkspace {
+                filesystem: filesystem_name.to_string(),
+                user: user.to_string(),
+                name: name.to_string(),
+            })
+        }
+        1 => {}
+        _ => unreachable!(),
+    };
+
+    zfs::set_property(&volume, "readonly", "on")?;
+    if filesystem.unmount_expired {
+        unmount_expired(&volume, filesystem)?;
+    }
+
+    let workspace = hooks::Workspace {
+        filesystem_name,
+        user,
+        name,
+        volume: &volume,
+        expiration_time: &current_expiration_time(conn, filesystem_name, user, name)?,
+    };
+    hooks::run(hooks, hooks::Event::Expire, &workspace);
+    audit::log(conn, hooks::Event::Expire, &workspace);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn filesystems(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    output: Option<Vec<cli::FilesystemsColumns>>,
+    format: cli::OutputFormat,
+    include_policy: bool,
+    shares: &HashMap<String, u64>,
+    parallelism: usize,
+    exact_sizes: bool,
+) {
+    let mut names: Vec<&String> = filesystems.keys().collect();
+    names.sort();
+    let usages = pool::map(&names, parallelism, |name| {
+        let root = &filesystems[*name].root;
+        let used = zfs::get_property::<usize>(root, "used").unwrap();
+        let available = zfs::get_property::<usize>(root, "available").unwrap();
+        (used, available)
+    });
+
+    if format == cli::OutputFormat::Json {
+        filesystems_json(filesystems, &names, &usages, include_policy);
+        return;
+    }
+
+    // the default columns
+    let output = output.unwrap_or(vec![
+        FilesystemsColumns::Name,
+        FilesystemsColumns::Used,
+        FilesystemsColumns::Free,
+        FilesystemsColumns::Total,
+        FilesystemsColumns::Duration,
+        FilesystemsColumns::Retention,
+    ]);
+
+    let counts = workspace_counts(conn).unwrap();
+    // querying zpool is only worth it if the column is shown
+    let healths = if output.contains(&FilesystemsColumns::Health) {
+        pool::map(&names, parallelism, |name| {
+            zfs::pool_health(&filesystems[*name].root).ok()
+        })
+    } else {
+        vec![None; names.len()]
+    };
+
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+
+    // bold title row
+    table.set_titles(Row::new(
+        output
+            .iter()
+            .map(|h| Cell::new(&h.to_string()).with_style(Attr::Bold))
+            .collect(),
+    ));
+
+    for ((name, &(used, available)), health) in names.into_iter().zip(&usages).zip(&healths) {
+        let info = &filesystems[name];
+        let total = used + available;
+        let (count, expiring) = counts.get(name).copied().unwrap_or_default();
+        let unhealthy = matches!(
+            health.as_deref(),
+            Some("DEGRADED" | "FAULTED" | "UNAVAIL" | "SUSPENDED")
+        );
+        table.add_row(Row::new(
+            output
+                .iter()
+                .map(|column| match column {
+                    FilesystemsColumns::Name => Cell::new(name),
+                    FilesystemsColumns::Used => Cell::new_align(
+                        &units::format_size(used as u64, exact_sizes),
+                        Alignment::RIGHT,
+                    ),
+                    FilesystemsColumns::Free => Cell::new_align(
+                        &units::format_size(available as u64, exact_sizes),
+                        Alignment::RIGHT,
+                    ),
+                    FilesystemsColumns::Total => Cell::new_align(
+                        &units::format_size(total as u64, exact_sizes),
+                        Alignment::RIGHT,
+                    ),
+                    FilesystemsColumns::Duration => match (info.retired, info.disabled) {
+                        (true, _) => Cell::new("retired"),
+                        (false, true) => Cell::new("disabled"),
+                        (false, false) => {
+                            Cell::new(&format!("{}d", info.max_duration.num_days())).style_spec("r")
+                        }
+                    },
+                    FilesystemsColumns::Retention => {
+                        Cell::new(&format!("{}d", info.retention().num_days())).style_spec("r")
+                    }
+                    FilesystemsColumns::Workspaces => {
+                        Cell::new_align(&count.to_string(), Alignment::RIGHT)
+                    }
+                    FilesystemsColumns::Expiring => {
+                        Cell::new_align(&expiring.to_string(), Alignment::RIGHT)
+                    }
+                    FilesystemsColumns::Health => Cell::new(health.as_deref().unwrap_or("-")),
+                })
+                .map(|c| {
+                    // color if the pool is in a risky state or almost full
+                    if unhealthy || used as f64 > total as f64 * info.usage_critical / 100. {
+                        c.with_style(Attr::ForegroundColor(color::RED))
+                    } else if used as f64 > total as f64 * info.usage_warning / 100. {
+                        c.with_style(Attr::ForegroundColor(color::YELLOW))
+                    } else {
+                        c
+                    }
+                })
+                .map(|c| {
+                    // dim if disabled
+                    if info.disabled || info.retired {
+                        c.with_style(Attr::Dim)
+                    } else {
+                        c
+                    }
+                })
+                .collect(),
+        ));
+    }
+
+    table::print(&table);
+
+    if !shares.is_empty() {
+        println!();
+        shares::print(&shares::usage(conn, shares).unwrap(), exact_sizes);
+    }
+
+    warn_retired(conn, filesystems);
+}
+
+/// Number of workspaces per filesystem and how many of them expire within the next week
+fn workspace_counts(conn: &Connection) -> Result<HashMap<String, (usize, usize)>, Error> {
+    let now = Local::now();
+    let mut statement = conn.prepare(
+        "SELECT filesystem,
+                COUNT(*),
+                COUNT(CASE WHEN class = 'scratch'
+                    AND expiration_time >= ?1
+                    AND expiration_time < ?2 THEN 1 END)
+            FROM workspaces
+            GROUP BY filesystem",
+    )?;
+    let counts = statement
+        .query_map(
+            (
+                db::Timestamp::from(now),
+                db::Timestamp::from(now + Duration::days(7)),
+            ),
+            |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))),
+        )?
+        .collect::<db::Result<_>>()?;
+    Ok(counts)
+}
+
+/// Prints filesystem information and, optionally, policy as JSON
+///
+/// Sizes are given in bytes and durations in days.
+fn filesystems_json(
+    filesystems: &HashMap<String, config::Filesystem>,
+    names: &[&String],
+    usages: &[(usize, usize)],
+    include_policy: bool,
+) {
+    let output: Vec<serde_json::Value> = names
+        .iter()
+        .zip(usages)
+        .map(|(&name, &(used, available))| {
+            let info = &filesystems[name];
+            let mut entry = serde_json::json!({
+                "name": name,
+                "used": used,
+                "free": available,
+                "total": used + available,
+            });
+            if include_policy {
+                entry["policy"] = serde_json::json!({
+                    "backend": "zfs",
+                    "root": info.root,
+                    "max_duration": info.max_duration.num_days(),
+                    "default_duration": info.default_duration.map(|d| d.num_days()),
+                    "expired_retention": info.retention().num_days(),
+                    "trash_retention": info.trash_retention.map(|d| d.num_days()),
+                    "disabled": info.disabled,
+                    "retired": info.retired,
+                });
+            }
+            entry
+        })
+        .collect();
+    println!("{}", serde_json::Value::Array(output));
+}
+
+/// Warns about retired filesystems which still hold workspaces
+fn warn_retired(conn: &Connection, filesystems: &HashMap<String, config::Filesystem>) {
+    for (name, _) in filesystems.iter().filter(|(_, info)| info.retired) {
+        let count: usize = conn
+            .query_row(
+                "SELECT COUNT(*) FROM workspaces WHERE filesystem = ?1",
+                [name],
+                |row| row.get(0),
+            )
+            .unwrap();
+        if count == 0 {
+            eprintln!(
+                "Filesystem {} is retired and holds no more workspaces. \
+                It can now be removed from {}.",
+                name,
+                config::CONFIG_PATH
+            );
+        } else {
+            eprintln!(
+                "Filesystem {} is retired but still holds {} workspace(s). \
+                Please migrate or expire them before removing it from {}.",
+                name,
+                count,
+                config::CONFIG_PATH
+            );
+        }
+    }
+}
+
+/// Reports all problems with the configuration
+///
+/// Besides the checks of [`config::validate`], this makes sure each
+/// filesystem's root exists and that the database only refers to configured
+/// filesystems.
+fn check_config(conn: &Connection, config: &config::Config) -> Result<(), Error> {
+    let mut problems = config::validate(config);
+
+    let mut names: Vec<&String> = config.filesystems.keys().collect();
+    names.sort();
+    let roots = pool::map(&names, config.parallelism.max(1), |name| {
+        zfs::get_property::<String>(&config.filesystems[*name].root, "type")
+    });
+    for (name, root) in names.into_iter().zip(roots) {
+        if let Err(e) = root {
+            problems.push(format!(
+                "filesystem {}: root {} is not a ZFS dataset: {}",
+                name, config.filesystems[name].root, e
+            ));
+        }
+    }
+
+    let mut statement = conn.prepare(
+        "SELECT filesystem, COUNT(*) FROM workspaces GROUP BY filesystem ORDER BY filesystem",
+    )?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        let count: usize = row.get(1)?;
+        if !config.filesystems.contains_key(&name) && !config.remote_filesystems.contains_key(&name)
+        {
+            problems.push(format!(
+                "the database holds {} workspace(s) on unknown filesystem {}",
+                count, name
+            ));
+        }
+    }
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("{} looks good", config::CONFIG_PATH);
+        Ok(())
+    } else {
+        Err(Error::InvalidConfig(problems.len()))
+    }
+}
+
+/// Copies the database to `path`, which must not exist yet
+fn backup_db(conn: &Connection, path: &Path) -> Result<(), Error> {
+    // not for admins, as the backup is written as root
+    if caller_uid() != 0 {
+        return Err(Error::InsufficientPrivileges);
+    }
+    if path.exists() {
+        return Err(Error::InvalidArgument(format!(
+            "{} already exists",
+            path.display()
+        )));
+    }
+    if dry_run::skip(format_args!("back up the database to {}", path.display())) {
+        return Ok(());
+    }
+    db::backup(conn, path)?;
+    println!("Backed up the database to {}", path.display());
+    Ok(())
+}
+
+/// Migrates the database to schema version `to`, or the newest one
+fn migrate_db(conn: &mut Connection, to: Option<usize>) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let from = migrations::version(conn)?;
+    let target = to.unwrap_or(migrations::NEWEST_VERSION);
+    migrations::migrate(conn, target, |version, migration| {
+        println!(
+            "{} to version {}: {}",
+            if dry_run::enabled() {
+                "Would migrate"
+            } else {
+                "Migrated"
+            },
+            version,
+            migration.description
+        );
+    })?;
+    if from >= target {
+        println!("The database is at version {} already", from);
+    }
+    Ok(())
+}
+
+/// Replaces the database with the backup at `path`
+fn restore_db(conn: &mut Connection, path: &Path) -> Result<(), Error> {
+    // not for admins, as the backup is read as root
+    if caller_uid() != 0 {
+        return Err(Error::InsufficientPrivileges);
+    }
+    if !path.is_file() {
+        return Err(Error::InvalidArgument(format!(
+            "{} is not a file",
+            path.display()
+        )));
+    }
+    // older backups are updated on the next run, newer ones cannot be read
+    let version = db::version(path)?;
+    if version > migrations::NEWEST_VERSION {
+        return Err(Error::InvalidArgument(format!(
+            "{} is from a more current version of workspaces",
+            path.display()
+        )));
+    }
+    if dry_run::skip(format_args!("restore the database from {}", path.display())) {
+        return Ok(());
+    }
+    db::restore(conn, path)?;
+    println!("Restored the database from {}", path.display());
+    Ok(())
+}
+
+/// Finds, and optionally fixes, datasets without workspace and vice versa
+///
+/// Only datasets exactly two levels below a filesystem's root, i.e. at
+/// `<root>/<user>/<name>`, are considered workspaces.  The trash is skipped.
+fn fsck(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    fix: bool,
+    destroy_untracked: bool,
+    parallelism: usize,
+) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+
+    let mut names: Vec<&String> = filesystems.keys().collect();
+    names.sort();
+    let datasets = pool::map(&names, parallelism, |name| {
+        zfs::list_datasets(&filesystems[*name].root)
+    });
+
+    let mut problems = 0;
+    let mut fixed = 0;
+    let transaction = db::transaction(conn)?;
+    for (filesystem_name, datasets) in names.into_iter().zip(datasets) {
+        let filesystem = &filesystems[filesystem_name];
+        let datasets = match datasets {
+            Ok(datasets) => datasets,
+            Err(e) => {
+                eprintln!("Could not list the datasets of {}: {}", filesystem_name, e);
+                problems += 1;
+                continue;
+            }
+        };
+
+        let mut statement = transaction.prepare(
+            "SELECT user, name FROM workspaces
+                WHERE filesystem = ?1
+                    AND archive_url IS NULL
+                ORDER BY user, name",
+        )?;
+        let tracked: Vec<(String, String)> = statement
+            .query_map([filesystem_name], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        drop(statement);
+        let mut statement =
+            transaction.prepare("SELECT user, name FROM released WHERE filesystem = ?1")?;
+        let released: Vec<(String, String)> = statement
+            .query_map([filesystem_name], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        drop(statement);
+
+        for (user, name) in &tracked {
+            let volume = to_volume_string(&filesystem.root, user, name);
+            if datasets.contains_key(&volume) {
+                let Some(sharenfs) = &filesystem.sharenfs else {
+                    continue;
+                };
+                let current = match zfs::get_property::<String>(&volume, "sharenfs") {
+                    Ok(current) if current == *sharenfs => continue,
+                    Ok(current) => current,
+                    Err(e) => {
+                        eprintln!("Could not check the export of {}: {}", volume, e);
+                        problems += 1;
+                        continue;
+                    }
+                };
+                problems += 1;
+                if !fix {
+                    println!("drifted export: {} (sharenfs={})", volume, current);
+                    continue;
+                }
+                match share(&volume, filesystem) {
+                    Ok(()) => {
+                        println!("restored export: {}", volume);
+                        fixed += 1;
+                    }
+                    Err(e) => eprintln!("Could not export {}: {}", volume, e),
+                }
+                continue;
+            }
+            problems += 1;
+            if !fix {
+                println!("missing dataset: {}", volume);
+                continue;
+            }
+            transaction.execute(
+                "DELETE FROM workspaces
+                    WHERE filesystem = ?1
+                        AND user = ?2
+                        AND name = ?3",
+                (filesystem_name, user, name),
+            )?;
+            tags::clear(&transaction, filesystem_name, user, name)?;
+            println!("forgot workspace without dataset: {}", volume);
+            fixed += 1;
+        }
+
+        let prefix = format!("{}/", filesystem.root);
+        let mut untracked: Vec<(&str, &str)> = datasets
+            .keys()
+            .filter_map(|volume| volume.strip_prefix(&prefix)?.split_once('/'))
+            .filter(|(user, name)| {
+                *user != ".trash"
+                    && !name.contains('/')
+                    && !tracked.iter().any(|(u, n)| u == user && n == name)
+                    && !released.iter().any(|(u, n)| u == user && n == name)
+            })
+            .collect();
+        untracked.sort();
+        for (user, name) in untracked {
+            let volume = to_volume_string(&filesystem.root, user, name);
+            problems += 1;
+            if !fix {
+                println!("untracked dataset: {}", volume);
+                continue;
+            }
+            if destroy_untracked {
+                match zfs::destroy(&volume, filesystem.destroy_recursive) {
+                    Ok(()) => {
+                        println!("destroyed untracked dataset: {}", volume);
+                        fixed += 1;
+                    }
+                    Err(e) => eprintln!("Could not destroy {}: {}", volume, e),
+                }
+                continue;
+            }
+            let expiration_time = Local::now()
+                + filesystem
+                    .default_duration
+                    .unwrap_or(filesystem.max_duration);
+            adopt(
+                &transaction,
+                filesystem_name,
+                user,
+                name,
+                &expiration_time,
+                None,
+            )?;
+            println!(
+                "adopted untracked dataset: {} (expires on {})",
+                volume,
+                expiration_time.format("%Y-%m-%d")
+            );
+            fixed += 1;
+        }
+    }
+    transaction.commit()?;
+
+    if problems == 0 {
+        println!("No problems found");
+    }
+    if problems > fixed {
+        return Err(Error::InconsistentDatabase(problems - fixed));
+    }
+    Ok(())
+}
+
+/// A problem found by `db check`
+#[derive(Debug, Serialize)]
+struct DbProblem {
+    /// `integrity`, `schema_version`, `unknown_filesystem`, `missing_dataset`
+    /// or `zfs_error`
+    kind: &'static str,
+    /// The workspace affected, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    workspace: Option<DbWorkspace>,
+    detail: String,
+}
+
+#[derive(Debug, Serialize)]
+struct DbWorkspace {
+    filesystem: String,
+    user: String,
+    name: String,
+}
+
+/// Checks the database's integrity and that each workspace has a dataset
+///
+/// Only the datasets of this host's filesystems are checked.
+fn check_db(
+    conn: &Connection,
+    config: &config::Config,
+    format: cli::OutputFormat,
+) -> Result<(), Error> {
+    let filesystems = &config.filesystems;
+    let mut problems = vec![];
+
+    for message in conn.backend().integrity_check()? {
+        problems.push(DbProblem {
+            kind: "integrity",
+            workspace: None,
+            detail: message,
+        });
+    }
+
+    let version = migrations::version(conn)?;
+    if version != migrations::NEWEST_VERSION {
+        problems.push(DbProblem {
+            kind: "schema_version",
+            workspace: None,
+            detail: format!("expected {}, found {}", migrations::NEWEST_VERSION, version),
+        });
+    }
+
+    let mut names: Vec<&String> = filesystems.keys().collect();
+    names.sort();
+    let datasets: HashMap<&String, _> = names
+        .iter()
+        .copied()
+        .zip(pool::map(&names, config.parallelism, |name| {
+            zfs::list_datasets(&filesystems[*name].root)
+        }))
+        .collect();
+    for (name, datasets) in &datasets {
+        if let Err(e) = datasets {
+            problems.push(DbProblem {
+                kind: "zfs_error",
+                workspace: None,
+                detail: format!("could not list the datasets of {}: {}", name, e),
+            });
+        }
+    }
+
+    let mut statement = conn
+        .prepare("SELECT filesystem, user, name FROM workspaces ORDER BY filesystem, user, name")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let workspace = DbWorkspace {
+            filesystem: row.get(0)?,
+            user: row.get(1)?,
+            name: row.get(2)?,
+        };
+        if config
+            .remote_filesystems
+            .contains_key(&workspace.filesystem)
+        {
+            continue;
+        }
+        let Some(filesystem) = filesystems.get(&workspace.filesystem) else {
+            problems.push(DbProblem {
+                kind: "unknown_filesystem",
+                detail: format!("filesystem {} is not configured", workspace.filesystem),
+                workspace: Some(workspace),
+            });
+            continue;
+        };
+        let volume = to_volume_string(&filesystem.root, &workspace.user, &workspace.name);
+        if let Ok(datasets) = &datasets[&workspace.filesystem] {
+            if !datasets.contains_key(&volume) {
+                problems.push(DbProblem {
+                    kind: "missing_dataset",
+                    detail: format!("dataset {} does not exist", volume),
+                    workspace: Some(workspace),
+                });
+            }
+        }
+    }
+
+    if format == cli::OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "schema_version": version,
+                "problems": problems,
+            })
+        );
+    } else {
+        for problem in &problems {
+            match &problem.workspace {
+                Some(workspace) => println!(
+                    "{}: {}/{}/{}: {}",
+                    problem.kind,
+                    workspace.filesystem,
+                    workspace.user,
+                    workspace.name,
+                    problem.detail
+                ),
+                None => println!("{}: {}", problem.kind, problem.detail),
+            }
+        }
+        if problems.is_empty() {
+            println!("No problems found");
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::InconsistentDatabase(problems.len()))
+    }
+}
+
+/// Summary of the actions taken by a `clean` run
+#[derive(Debug, Default)]
+struct CleanReport {
+    /// Destroyed volumes and the number of bytes they used
+    destroyed: Vec<(String, usize)>,
+    /// Volumes which have been set read-only during this run
+    expired: Vec<String>,
+    /// Volumes which could not be cleaned up, with the reason why
+    failed: Vec<(String, String)>,
+    /// Expired volumes whose owner no longer exists
+    orphaned: Vec<String>,
+    /// Volumes which have been moved to the trash
+    trashed: Vec<String>,
+    /// Volumes which have been uploaded to their filesystem's archive
+    archived: Vec<String>,
+    /// Volumes whose owners have been reminded of their impending expiry
+    reminded: Vec<String>,
+    /// Volumes whose size changed unexpectedly
+    anomalies: Vec<String>,
+    /// Volumes due for deletion which are excluded by the configuration
+    excluded: Vec<String>,
+    /// Groups which have exceeded their storage share during this run
+    shares_exceeded: Vec<String>,
+}
+
+impl CleanReport {
+    fn bytes_reclaimed(&self) -> usize {
+        self.destroyed.iter().map(|(_, bytes)| bytes).sum()
+    }
+}
+
+/// Logs a single `clean` event to stdout and, optionally, syslog
+///
+/// Events are formatted as space-separated `key=value` pairs so they can be
+/// easily filtered in the journal.
+fn log_clean_event(
+    syslog: &mut Option<syslog::Logger<syslog::LoggerBackend, syslog::Formatter3164>>,
+    is_error: bool,
+    message: &str,
+) {
+    if is_error {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+    if let Some(logger) = syslog {
+        // failing to log should never keep us from cleaning up
+        let _ = if is_error {
+            logger.err(message)
+        } else {
+            logger.info(message)
+        };
+    }
+}
+
+/// Takes an exclusive lock preventing concurrent `clean` runs
+///
+/// The lock file is placed next to the database and the lock is held until
+/// the returned file is dropped.  Returns `None` if another clean is running.
+fn try_lock_clean(db_path: &Path) -> Option<fs::File> {
+    let mut lock_path = db_path.as_os_str().to_owned();
+    lock_path.push(".clean.lock");
+    let _root = privileges::raise();
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .unwrap();
+    match file.try_lock() {
+        Ok(()) => Some(file),
+        Err(fs::TryLockError::WouldBlock) => None,
+        Err(fs::TryLockError::Error(e)) => panic!("could not lock {:?}: {}", lock_path, e),
+    }
+}
+
+/// Unmounts a volume and moves it to the trash
+fn move_to_trash(
+    volume: &str,
+    trash_volume: &str,
+    filesystem: &config::Filesystem,
+) -> Result<(), zfs::Error> {
+    // keep the volume from being mounted again after being renamed
+    zfs::set_property(volume, "canmount", "noauto")?;
+    unshare(volume, filesystem)?;
+    // expired volumes may have been unmounted already
+    if zfs::is_mounted(volume)? {
+        zfs::unmount(volume, filesystem.force_unmount)?;
+    }
+    zfs::rename(volume, trash_volume)
+}
+
+/// Snapshot taken of volumes as they are moved to the trash
+const FINAL_SNAPSHOT: &str = "workspaces-final";
+
+/// Tag of the hold keeping [`FINAL_SNAPSHOT`] from being destroyed
+const FINAL_SNAPSHOT_HOLD: &str = "workspaces";
+
+/// Days a volume stays in the trash, including those its final snapshot is held
+fn trash_retention(filesystem: &config::Filesystem) -> Duration {
+    filesystem
+        .trash_retention
+        .unwrap_or_else(Duration::zero)
+        .max(
+            filesystem
+                .final_snapshot_retention
+                .unwrap_or_else(Duration::zero),
+        )
+}
+
+/// Snapshots a trashed volume and holds the snapshot
+fn take_final_snapshot(trash_volume: &str) -> Result<(), zfs::Error> {
+    zfs::snapshot(trash_volume, FINAL_SNAPSHOT)?;
+    zfs::hold(trash_volume, FINAL_SNAPSHOT, FINAL_SNAPSHOT_HOLD)
+}
+
+/// Releases and destroys a volume's final snapshot, if it has one
+fn discard_final_snapshot(volume: &str) -> Result<(), zfs::Error> {
+    if !zfs::list_snapshots(volume)?
+        .iter()
+        .any(|snapshot| snapshot == FINAL_SNAPSHOT)
+    {
+        return Ok(());
+    }
+    zfs::release(volume, FINAL_SNAPSHOT, FINAL_SNAPSHOT_HOLD)?;
+    zfs::destroy(&format!("{}@{}", volume, FINAL_SNAPSHOT), false)
+}
+
+/// Unmounts an expired volume, so it cannot be used by running jobs anymore
+///
+/// The volume is also kept from being mounted on boot until it is extended.
+fn unmount_expired(volume: &str, filesystem: &config::Filesystem) -> Result<(), zfs::Error> {
+    if !zfs::is_mounted(volume)? {
+        return Ok(());
+    }
+    zfs::set_property(volume, "canmount", "noauto")?;
+    zfs::unmount(volume, filesystem.force_unmount)
+}
+
+/// Lists the workspaces in the trash
+fn list_trash(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    filter_filesystems: &Option<Vec<String>>,
+) {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["NAME", "USER", "FS", "TRASHED", "DELETION", "VOLUME"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+
+    let mut statement = conn
+        .prepare("SELECT filesystem, user, name, trashed_time FROM trash ORDER BY trashed_time")
+        .unwrap();
+    let mut rows = statement.query([]).unwrap();
+    while let Some(row) = rows.next().unwrap() {
+        let filesystem_name: String = row.get(0).unwrap();
+        let user: String = row.get(1).unwrap();
+        let name: String = row.get(2).unwrap();
+        let trashed_time: DateTime<Local> = row.get::<_, db::Timestamp>(3).unwrap().into();
+        if !filter_filesystems
+            .as_ref()
+            .is_none_or(|fs| fs.contains(&filesystem_name))
+        {
+            continue;
+        }
+        let Some(filesystem) = filesystems.get(&filesystem_name) else {
+            continue;
+        };
+        let deletion_time = trashed_time + trash_retention(filesystem);
+        table.add_row(Row::new(vec![
+            Cell::new(&name),
+            Cell::new(&user),
+            Cell::new(&filesystem_name),
+            Cell::new(&trashed_time.format("%F").to_string()),
+            Cell::new(&deletion_time.format("%F").to_string()),
+            Cell::new(&to_trash_volume_string(
+                &filesystem.root,
+                &user,
+                &name,
+                &trashed_time,
+            )),
+        ]));
+    }
+
+    table::print(&table);
+}
+
+/// Restores the most recently trashed workspace of the given name
+///
+/// The restored workspace is expired, so it will be cleaned up again after the
+/// filesystem's retention period unless it is extended.
+fn untrash(
+    conn: &mut Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+
+    let transaction = db::transaction(conn)?;
+    let trashed_time: DateTime<Local> = match transaction.query_row(
+        "SELECT trashed_time FROM trash
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3
+            ORDER BY trashed_time DESC",
+        (filesystem_name, user, name),
+        |row| row.get::<_, db::Timestamp>(0),
+    ) {
+        Ok(trashed_time) => trashed_time.into(),
+        Err(db::Error::QueryReturnedNoRows) => {
+            return Err(Error::UnknownTrashedWorkspace {
+                filesystem: filesystem_name.to_string(),
+                user: user.to_string(),
+                name: name.to_string(),
+            })
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    match transaction.execute(
+        "INSERT INTO workspaces (filesystem, user, name, expiration_time, host)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+        (
+            filesystem_name,
+            user,
+            name,
+            db::Timestamp::from(Local::now()),
+            config::local_host(),
+        ),
+    ) {
+        Ok(_) => {}
+        // the trashed workspace has to be renamed first
+        Err(e) if e.is_constraint_violation() => return Err(Error::TargetExists),
+        Err(e) => return Err(e.into()),
+    }
+    transaction.execute(
+        "DELETE FROM trash
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3
+                AND trashed_time = ?4",
+        (
+            filesystem_name,
+            user,
+            name,
+            db::Timestamp::from(trashed_time),
+        ),
+    )?;
+
+    let trash_volume = to_trash_volume_string(&filesystem.root, user, name, &trashed_time);
+    let volume = to_volume_string(&filesystem.root, user, name);
+    zfs::rename(&trash_volume, &volume)?;
+    discard_final_snapshot(&volume)?;
+    zfs::set_property(&volume, "canmount", "on")?;
+    share(&volume, filesystem)?;
+    zfs::mount(&volume)?;
+    transaction.commit()?;
+
+    println!(
+        "Restored {} to {}. Extend it to make it writable again.",
+        trash_volume, volume
+    );
+    Ok(())
+}
+
+/// Number of times `clean` tries to destroy a busy volume
+const DESTROY_ATTEMPTS: u32 = 3;
+
+/// Destroys a volume, retrying with exponential backoff if that fails
+///
+/// Snapshots are destroyed as well if `recursive` is set.  Volumes on
+/// filesystems with `force_unmount` set are forcibly unmounted before each
+/// retry, as a failure is most likely due to the volume still being busy.
+fn destroy_with_retries(
+    volume: &str,
+    filesystem: &config::Filesystem,
+    recursive: bool,
+) -> Result<(), zfs::Error> {
+    let mut backoff = std::time::Duration::from_secs(1);
+    let mut attempt = 1;
+    loop {
+        match zfs::destroy(volume, recursive) {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= DESTROY_ATTEMPTS => return Err(e),
+            Err(_) => {}
+        }
+        if filesystem.force_unmount {
+            // if this fails, the next destroy will tell us
+            let _ = zfs::unmount(volume, true);
+        }
+        std::thread::sleep(backoff);
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// Removes a workspace whose data has been destroyed from the database
+fn forget_destroyed(conn: &mut Connection, workspace: &hooks::Workspace) -> Result<(), Error> {
+    let transaction = db::transaction(conn)?;
+    transaction.execute(
+        "DELETE FROM workspaces
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3",
+        (workspace.filesystem_name, workspace.user, workspace.name),
+    )?;
+    tags::clear(
+        &transaction,
+        workspace.filesystem_name,
+        workspace.user,
+        workspace.name,
+    )?;
+    audit::log(&transaction, hooks::Event::Destroy, workspace);
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Replaces a workspace in the database by its entry in the trash
+fn record_trashed(
+    conn: &mut Connection,
+    workspace: &hooks::Workspace,
+    trashed_time: &DateTime<Local>,
+) -> Result<(), Error> {
+    let transaction = db::transaction(conn)?;
+    transaction.execute(
+        "DELETE FROM workspaces
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3",
+        (workspace.filesystem_name, workspace.user, workspace.name),
+    )?;
+    tags::clear(
+        &transaction,
+        workspace.filesystem_name,
+        workspace.user,
+        workspace.name,
+    )?;
+    transaction.execute(
+        "INSERT INTO trash (filesystem, user, name, expiration_time, trashed_time)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+        (
+            workspace.filesystem_name,
+            workspace.user,
+            workspace.name,
+            db::Timestamp::from(*workspace.expiration_time),
+            db::Timestamp::from(*trashed_time),
+        ),
+    )?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Removes a trashed workspace whose volume has been destroyed from the database
+fn forget_trashed(
+    conn: &mut Connection,
+    workspace: &hooks::Workspace,
+    trashed_time: &DateTime<Local>,
+) -> Result<(), Error> {
+    let transaction = db::transaction(conn)?;
+    transaction.execute(
+        "DELETE FROM trash
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3
+                AND trashed_time = ?4",
+        (
+            workspace.filesystem_name,
+            workspace.user,
+            workspace.name,
+            db::Timestamp::from(*trashed_time),
+        ),
+    )?;
+    audit::log(&transaction, hooks::Event::Destroy, workspace);
+    transaction.commit()?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn clean(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    notifications: &Option<config::Notifications>,
+    hooks: &config::Hooks,
+    exclusions: &config::Clean,
+    shares: &HashMap<String, u64>,
+    use_syslog: bool,
+    with_snapshots: bool,
+) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let mut syslog = if use_syslog {
+        match syslog::unix(syslog::Formatter3164 {
+            facility: syslog::Facility::LOG_DAEMON,
+            hostname: None,
+            process: "workspaces".into(),
+            pid: process::id(),
+        }) {
+            Ok(logger) => Some(logger),
+            Err(e) => {
+                eprintln!("Could not connect to syslog: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Each workspace's changes are committed right after its zfs or archive
+    // operation succeeded, so the write lock is never held while waiting for
+    // zfs, archives, hooks or mail, and the database never refers to data
+    // which is already gone.
+    let mut report = CleanReport::default();
+    {
+        let mut rows = conn
+            .prepare(
+                "SELECT filesystem, user, name, expiration_time, locked IS NOT NULL, archive_url
+                    FROM workspaces
+                    WHERE expiration_time < ?1
+                        AND class = 'scratch'
+                        AND (exempt_until IS NULL OR exempt_until < ?1)",
+            )?
+            .query([db::Timestamp::from(Local::now())])?;
+        while let Some(row) = rows.next()? {
+            let filesystem_name: String = row.get(0)?;
+            let user: String = row.get(1)?;
+            let name: String = row.get(2)?;
+            let expiration_time: DateTime<Local> = row.get::<_, db::Timestamp>(3)?.into();
+            let is_locked: bool = row.get(4)?;
+            let archive_url: Option<String> = row.get(5)?;
+
+            // other hosts clean their own filesystems
+            let Some(filesystem) = filesystems.get(&filesystem_name) else {
+                continue;
+            };
+            let volume = to_volume_string(&filesystem.root, &user, &name);
+            if !user_exists(&user) {
+                log_clean_event(
+                    &mut syslog,
+                    false,
+                    &format!("action=flag volume={} reason=orphan-owner", volume),
+                );
+                report.orphaned.push(volume.clone());
+            }
+            let due_for_deletion = expiration_time < Local::now() - filesystem.retention();
+            let tier = filesystem.tier(Local::now() - expiration_time);
+            // archiving removes the local data as well
+            let to_archive =
+                archive_url.is_none() && (due_for_deletion || tier == Some(config::Tier::Archived));
+            let excluded = (due_for_deletion || to_archive)
+                && !is_locked
+                && exclusions.excludes(
+                    &user,
+                    &name,
+                    &tags::get(conn, &filesystem_name, &user, &name)?,
+                );
+            if let Some(archive) = filesystem
+                .archive
+                .as_ref()
+                .filter(|_| to_archive && !is_locked && !excluded)
+            {
+                let workspace = hooks::Workspace {
+                    filesystem_name: &filesystem_name,
+                    user: &user,
+                    name: &name,
+                    volume: &volume,
+                    expiration_time: &expiration_time,
+                };
+                match archive::upload(archive, &workspace) {
+                    Ok(url) => {
+                        log_clean_event(
+                            &mut syslog,
+                            false,
+                            &format!("action=archive volume={} url={}", volume, url),
+                        );
+                        report.archived.push(volume.clone());
+                        if !due_for_deletion {
+                            let discarded = unshare(&volume, filesystem).and_then(|()| {
+                                destroy_with_retries(
+                                    &volume,
+                                    filesystem,
+                                    filesystem.destroy_recursive || with_snapshots,
+                                )
+                            });
+                            if let Err(e) = discarded {
+                                log_clean_event(
+                                    &mut syslog,
+                                    true,
+                                    &format!("action=destroy volume={} error=\"{}\"", volume, e),
+                                );
+                                report.failed.push((volume, e.to_string()));
+                                continue;
+                            }
+                            if let Err(e) = conn.execute(
+                                "UPDATE workspaces
+                                    SET archive_url = ?1,
+                                        cached_size = NULL,
+                                        cached_mountpoint = NULL
+                                    WHERE filesystem = ?2
+                                        AND user = ?3
+                                        AND name = ?4",
+                                (&url, &filesystem_name, &user, &name),
+                            ) {
+                                log_clean_event(
+                                    &mut syslog,
+                                    true,
+                                    &format!("action=archive volume={} error=\"{}\"", volume, e),
+                                );
+                                report.failed.push((volume, e.to_string()));
+                            }
+                            continue;
+                        }
+                    }
+                    Err(e) => {
+                        // keep the data until it is safely archived
+                        log_clean_event(
+                            &mut syslog,
+                            true,
+                            &format!("action=archive volume={} error=\"{}\"", volume, e),
+                        );
+                        report.failed.push((volume, e.to_string()));
+                        continue;
+                    }
+                }
+            }
+            if due_for_deletion && is_locked {
+                log_clean_event(
+                    &mut syslog,
+                    false,
+                    &format!("action=skip volume={} reason=locked", volume),
+                );
+                report.excluded.push(volume);
+            } else if due_for_deletion && excluded {
+                // keep reporting them so they aren't forgotten about
+                log_clean_event(
+                    &mut syslog,
+                    false,
+                    &format!("action=skip volume={} reason=excluded", volume),
+                );
+                report.excluded.push(volume);
+            } else if let (true, Some(url)) = (due_for_deletion, archive_url.as_ref()) {
+                let workspace = hooks::Workspace {
+                    filesystem_name: &filesystem_name,
+                    user: &user,
+                    name: &name,
+                    volume: &volume,
+                    expiration_time: &expiration_time,
+                };
+                if let Err(e) = hooks::run_pre(hooks, hooks::Event::Destroy, &workspace)
+                    .and_then(|()| archive::remove(filesystem.archive.as_ref(), url))
+                {
+                    log_clean_event(
+                        &mut syslog,
+                        true,
+                        &format!("action=destroy url={} error=\"{}\"", url, e),
+                    );
+                    report.failed.push((url.clone(), e.to_string()));
+                    continue;
+                }
+                if let Err(e) = forget_destroyed(conn, &workspace) {
+                    log_clean_event(
+                        &mut syslog,
+                        true,
+                        &format!("action=destroy url={} error=\"{}\"", url, e),
+                    );
+                    report.failed.push((url.clone(), e.to_string()));
+                    continue;
+                }
+                hooks::run(hooks, hooks::Event::Destroy, &workspace);
+                log_clean_event(&mut syslog, false, &format!("action=destroy url={}", url));
+                report.destroyed.push((volume, 0));
+            } else if due_for_deletion
+                && (filesystem.trash_retention.is_some()
+                    || filesystem.final_snapshot_retention.is_some())
+            {
+                let trashed_time = Local::now();
+                let trash_volume =
+                    to_trash_volume_string(&filesystem.root, &user, &name, &trashed_time);
+                if let Err(e) = move_to_trash(&volume, &trash_volume, filesystem) {
+                    log_clean_event(
+                        &mut syslog,
+                        true,
+                        &format!("action=trash volume={} error=\"{}\"", volume, e),
+                    );
+                    report.failed.push((volume, e.to_string()));
+                    continue;
+                }
+                if filesystem.final_snapshot_retention.is_some() {
+                    // the volume is in the trash either way, so carry on
+                    if let Err(e) = take_final_snapshot(&trash_volume) {
+                        log_clean_event(
+                            &mut syslog,
+                            true,
+                            &format!("action=snapshot volume={} error=\"{}\"", trash_volume, e),
+                        );
+                        report.failed.push((trash_volume.clone(), e.to_string()));
+                    }
+                }
+                let workspace = hooks::Workspace {
+                    filesystem_name: &filesystem_name,
+                    user: &user,
+                    name: &name,
+                    volume: &volume,
+                    expiration_time: &expiration_time,
+                };
+                if let Err(e) = record_trashed(conn, &workspace, &trashed_time) {
+                    log_clean_event(
+                        &mut syslog,
+                        true,
+                        &format!("action=trash volume={} error=\"{}\"", volume, e),
+                    );
+                    report.failed.push((volume, e.to_string()));
+                    continue;
+                }
+                log_clean_event(
+                    &mut syslog,
+                    false,
+                    &format!(
+                        "action=trash volume={} trash_volume={}",
+                        volume, trash_volume
+                    ),
+                );
+                report.trashed.push(volume);
+            } else if due_for_deletion {
+                let used = zfs::get_property::<usize>(&volume, "used").unwrap_or(0);
+                let workspace = hooks::Workspace {
+                    filesystem_name: &filesystem_name,
+                    user: &user,
+                    name: &name,
+                    volume: &volume,
+                    expiration_time: &expiration_time,
+                };
+                if let Err(e) = hooks::run_pre(hooks, hooks::Event::Destroy, &workspace) {
+                    log_clean_event(
+                        &mut syslog,
+                        true,
+                        &format!("action=destroy volume={} error=\"{}\"", volume, e),
+                    );
+                    report.failed.push((volume, e.to_string()));
+                    continue;
+                }
+                // withdraw the export even if the volume turns out to be busy
+                if let Err(e) = unshare(&volume, filesystem) {
+                    log_clean_event(
+                        &mut syslog,
+                        true,
+                        &format!("action=unshare volume={} error=\"{}\"", volume, e),
+                    );
+                    report.failed.push((volume, e.to_string()));
+                    continue;
+                }
+                if let Err(e) = destroy_with_retries(
+                    &volume,
+                    filesystem,
+                    filesystem.destroy_recursive || with_snapshots,
+                ) {
+                    log_clean_event(
+                        &mut syslog,
+                        true,
+                        &format!("action=destroy volume={} error=\"{}\"", volume, e),
+                    );
+                    report.failed.push((volume, e.to_string()));
+                    continue;
+                }
+                if let Err(e) = forget_destroyed(conn, &workspace) {
+                    log_clean_event(
+                        &mut syslog,
+                        true,
+                        &format!("action=destroy volume={} error=\"{}\"", volume, e),
+                    );
+                    report.failed.push((volume, e.to_string()));
+                    continue;
+                }
+                hooks::run(hooks, hooks::Event::Destroy, &workspace);
+                log_clean_event(
+                    &mut syslog,
+                    false,
+                    &format!("action=destroy volume={} bytes={}", volume, used),
+                );
+                report.destroyed.push((volume, used));
+            } else if archive_url.is_none() {
+                let workspace = hooks::Workspace {
+                    filesystem_name: &filesystem_name,
+                    user: &user,
+                    name: &name,
+                    volume: &volume,
+                    expiration_time: &expiration_time,
+                };
+                // read-only volumes have expired in an earlier run
+                let expiring =
+                    !zfs::get_property::<String>(&volume, "readonly").is_ok_and(|r| r == "on");
+                if expiring {
+                    if let Err(e) = hooks::run_pre(hooks, hooks::Event::Expire, &workspace) {
+                        log_clean_event(
+                            &mut syslog,
+                            true,
+                            &format!("action=expire volume={} error=\"{}\"", volume, e),
+                        );
+                        report.failed.push((volume, e.to_string()));
+                        continue;
+                    }
+                }
+                if filesystem.unmount_expired
+                    || tier.is_some_and(|tier| tier >= config::Tier::Unmounted)
+                {
+                    if let Err(e) = unmount_expired(&volume, filesystem) {
+                        log_clean_event(
+                            &mut syslog,
+                            true,
+                            &format!("action=unmount volume={} error=\"{}\"", volume, e),
+                        );
+                        report.failed.push((volume.clone(), e.to_string()));
+                    }
+                }
+                if expiring {
+                    match zfs::set_property(&volume, "readonly", "on") {
+                        Ok(()) => {
+                            log_clean_event(
+                                &mut syslog,
+                                false,
+                                &format!("action=expire volume={}", volume),
+                            );
+                            audit::log(conn, hooks::Event::Expire, &workspace);
+                            hooks::run(hooks, hooks::Event::Expire, &workspace);
+                            if let Some(notifications) = notifications {
+                                if let Err(e) = notify::expired(
+                                    conn,
+                                    notifications,
+                                    &filesystem_name,
+                                    filesystem,
+                                    &user,
+                                    &name,
+                                    &expiration_time,
+                                ) {
+                                    eprintln!("Failed to notify owner of {}: {}", volume, e);
+                                }
+                            }
+                            report.expired.push(volume);
+                        }
+                        Err(e) => {
+                            log_clean_event(
+                                &mut syslog,
+                                true,
+                                &format!("action=expire volume={} error=\"{}\"", volume, e),
+                            );
+                            report.failed.push((volume, e.to_string()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    {
+        let mut rows = conn
+            .prepare("SELECT filesystem, user, name, expiration_time, trashed_time FROM trash")?
+            .query([])?;
+        while let Some(row) = rows.next()? {
+            let filesystem_name: String = row.get(0)?;
+            let user: String = row.get(1)?;
+            let name: String = row.get(2)?;
+            let expiration_time: DateTime<Local> = row.get::<_, db::Timestamp>(3)?.into();
+            let trashed_time: DateTime<Local> = row.get::<_, db::Timestamp>(4)?.into();
+
+            let Some(filesystem) = filesystems.get(&filesystem_name) else {
+                continue;
+            };
+            // if the trash has been disabled in the meantime, empty it right away
+            if trashed_time >= Local::now() - trash_retention(filesystem) {
+                continue;
+            }
+            let volume = to_trash_volume_string(&filesystem.root, &user, &name, &trashed_time);
+            let used = zfs::get_property::<usize>(&volume, "used").unwrap_or(0);
+            let workspace = hooks::Workspace {
+                filesystem_name: &filesystem_name,
+                user: &user,
+                name: &name,
+                volume: &volume,
+                expiration_time: &expiration_time,
+            };
+            if let Err(e) = hooks::run_pre(hooks, hooks::Event::Destroy, &workspace) {
+                log_clean_event(
+                    &mut syslog,
+                    true,
+                    &format!("action=destroy volume={} error=\"{}\"", volume, e),
+                );
+                report.failed.push((volume, e.to_string()));
+                continue;
+            }
+            if let Err(e) = discard_final_snapshot(&volume) {
+                log_clean_event(
+                    &mut syslog,
+                    true,
+                    &format!("action=release volume={} error=\"{}\"", volume, e),
+                );
+                report.failed.push((volume, e.to_string()));
+                continue;
+            }
+            if let Err(e) = destroy_with_retries(
+                &volume,
+                filesystem,
+                filesystem.destroy_recursive || with_snapshots,
+            ) {
+                log_clean_event(
+                    &mut syslog,
+                    true,
+                    &format!("action=destroy volume={} error=\"{}\"", volume, e),
+                );
+                report.failed.push((volume, e.to_string()));
+                continue;
+            }
+            if let Err(e) = forget_trashed(conn, &workspace, &trashed_time) {
+                log_clean_event(
+                    &mut syslog,
+                    true,
+                    &format!("action=destroy volume={} error=\"{}\"", volume, e),
+                );
+                report.failed.push((volume, e.to_string()));
+                continue;
+            }
+            hooks::run(hooks, hooks::Event::Destroy, &workspace);
+            log_clean_event(
+                &mut syslog,
+                false,
+                &format!("action=destroy volume={} bytes={}", volume, used),
+            );
+            report.destroyed.push((volume, used));
+        }
+    }
+    sizes::refresh_cache(conn, filesystems)?;
+    for alert in sizes::sample(conn, filesystems)? {
+        log_clean_event(
+            &mut syslog,
+            false,
+            &format!(
+                "action=anomaly volume={} change=\"{}\"",
+                alert.volume, alert.anomaly
+            ),
+        );
+        if let Some(notifications) = notifications {
+            if let Err(e) = notify::anomaly(
+                conn,
+                notifications,
+                &alert.filesystem_name,
+                &alert.user,
+                &alert.name,
+                &alert.anomaly,
+            ) {
+                eprintln!("Failed to notify about {}: {}", alert.volume, e);
+            }
+        }
+        report.anomalies.push(alert.volume);
+    }
+    for usage in shares::newly_exceeded(conn, shares::usage(conn, shares)?)? {
+        log_clean_event(
+            &mut syslog,
+            false,
+            &format!(
+                "action=share-exceeded group={} used={} share={}",
+                usage.group, usage.used, usage.share
+            ),
+        );
+        if let Some(notifications) = notifications {
+            if let Err(e) = notify::share_exceeded(notifications, &usage) {
+                eprintln!("Failed to notify about the share of {}: {}", usage.group, e);
+            }
+        }
+        report.shares_exceeded.push(usage.group);
+    }
+    if let Some(notifications) = notifications {
+        for (volume, days_left) in notify::remind(conn, notifications, filesystems) {
+            log_clean_event(
+                &mut syslog,
+                false,
+                &format!("action=remind volume={} days_left={}", volume, days_left),
+            );
+            report.reminded.push(volume);
+        }
+    }
+
+    log_clean_event(
+        &mut syslog,
+        !report.failed.is_empty(),
+        &format!(
+            "action=summary destroyed={} bytes_reclaimed={} expired={} trashed={} archived={} \
+            failed={} orphaned={} reminded={} anomalies={} excluded={} shares_exceeded={}",
+            report.destroyed.len(),
+            report.bytes_reclaimed(),
+            report.expired.len(),
+            report.trashed.len(),
+            report.archived.len(),
+            report.failed.len(),
+            report.orphaned.len(),
+            report.reminded.len(),
+            report.anomalies.len(),
+            report.excluded.len(),
+            report.shares_exceeded.len()
+        ),
+    );
+    for (volume, reason) in &report.failed {
+        eprintln!("Failed to clean up {}: {}", volume, reason);
+    }
+
+    warn_retired(conn, filesystems);
+    Ok(())
+}
+
+/// Transfers a workspace to another user, who has to exist
+fn transfer(
+    conn: &mut Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    to: &str,
+) -> Result<(), Error> {
+    let transaction = db::transaction(conn)?;
+    match transaction.execute(
+        "UPDATE workspaces
+            SET user = ?1,
+                cached_size = NULL,
+                cached_mountpoint = NULL,
+                cached_at = NULL,
+                notify_channel = NULL,
+                notify_email = NULL
+            WHERE filesystem = ?2
+                AND user = ?3
+                AND name = ?4",
+        (to, filesystem_name, user, name),
+    ) {
+        Ok(_) => {}
+        Err(e) if e.is_constraint_violation() => return Err(Error::TargetExists),
+        Err(e) => return Err(e.into()),
+    }
+    tags::rename(&transaction, filesystem_name, (user, name), (to, name))?;
+    let volume = to_volume_string(&filesystem.root, user, name);
+    let dest_volume = to_volume_string(&filesystem.root, to, name);
+    zfs::rename(&volume, &dest_volume)?;
+    apply_mountpoint_template(&dest_volume, filesystem, to, name)?;
+    let mountpoint = zfs::get_path_property(&dest_volume, "mountpoint")?;
+    chown(to, to, &mountpoint)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Workspaces of an owner missing from the passwd database
+#[derive(Default)]
+struct MissingOwner {
+    /// The owner's new name, if their uid still exists
+    renamed_to: Option<String>,
+    /// Filesystems and names of the workspaces
+    workspaces: Vec<(String, String)>,
+    bytes: u64,
+}
+
+/// Checks the owners of all workspaces against the passwd database
+///
+/// Owners whose workspaces' files belong to a uid which exists under another
+/// name are considered renamed, others deleted.  Optionally, the workspaces of
+/// renamed owners are transferred to their new names, and those of deleted
+/// owners expired.
+fn audit_users(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    reassign_renamed: bool,
+    expire_deleted: bool,
+    hooks: &config::Hooks,
+    exact_sizes: bool,
+) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    let mut owners: BTreeMap<String, MissingOwner> = BTreeMap::new();
+    {
+        let mut statement = conn.prepare(
+            "SELECT filesystem, user, name, COALESCE(cached_size, 0) FROM workspaces
+                ORDER BY user, filesystem, name",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let user: String = row.get(1)?;
+            if user_exists(&user) {
+                continue;
+            }
+            let filesystem_name: String = row.get(0)?;
+            let name: String = row.get(2)?;
+            let owner = owners.entry(user.clone()).or_default();
+            if owner.renamed_to.is_none() {
+                owner.renamed_to = filesystems
+                    .get(&filesystem_name)
+                    .map(|filesystem| to_volume_string(&filesystem.root, &user, &name))
+                    .and_then(|volume| zfs::get_path_property(&volume, "mountpoint").ok())
+                    .and_then(|mountpoint| fs::metadata(mountpoint).ok())
+                    // e.g. not set up yet, which says nothing about the owner
+                    .filter(|metadata| metadata.uid() != 0)
+                    .and_then(|metadata| get_user_by_uid(metadata.uid()))
+                    .map(|user| user.name().to_string_lossy().into_owned());
+            }
+            owner.bytes += row.get::<_, u64>(3)?;
+            owner.workspaces.push((filesystem_name, name));
+        }
+    }
+    if owners.is_empty() {
+        println!("All owners of workspaces exist");
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["USER", "WORKSPACES", "SIZE", "STATUS"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+    for (user, owner) in &owners {
+        table.add_row(Row::new(vec![
+            Cell::new(user),
+            Cell::new_align(&owner.workspaces.len().to_string(), Alignment::RIGHT),
+            Cell::new_align(
+                &units::format_size(owner.bytes, exact_sizes),
+                Alignment::RIGHT,
+            ),
+            match &owner.renamed_to {
+                Some(new_name) => Cell::new(&format!("renamed to {}", new_name))
+                    .with_style(Attr::ForegroundColor(color::YELLOW)),
+                None => Cell::new("deleted").with_style(Attr::ForegroundColor(color::RED)),
+            },
+        ]));
+    }
+    table::print(&table);
+
+    let (mut total, mut failed) = (0, 0);
+    for (user, owner) in &owners {
+        for (filesystem_name, name) in &owner.workspaces {
+            let Some(filesystem) = filesystems.get(filesystem_name) else {
+                continue;
+            };
+            let volume = to_volume_string(&filesystem.root, user, name);
+            let result = match &owner.renamed_to {
+                Some(new_name) if reassign_renamed => {
+                    transfer(conn, filesystem_name, filesystem, user, name, new_name)
+                        .map(|()| format!("transferred {} to {}", volume, new_name))
+                }
+                None if expire_deleted => {
+                    expire(conn, filesystem_name, filesystem, user, name, false, hooks)
+                        .map(|()| format!("expired {}", volume))
+                }
+                _ => continue,
+            };
+            total += 1;
+            match result {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    println!("failed {}: {}", volume, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(Error::BatchFailed { failed, total });
+    }
+    Ok(())
+}
+
+/// Transfers or expires all workspaces whose owner no longer exists
+fn reassign_orphans(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    filter_filesystems: &Option<Vec<String>>,
+    to: &Option<String>,
+    hooks: &config::Hooks,
+) -> Result<(), Error> {
+    if !is_admin() {
+        return Err(Error::InsufficientPrivileges);
+    }
+    if let Some(to) = to {
+        if !user_exists(to) {
+            return Err(Error::UnknownUser(to.clone()));
+        }
+    }
+
+    let orphans: Vec<(String, String, String)> = {
+        let mut statement = conn.prepare("SELECT filesystem, user, name FROM workspaces")?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter()
+            .filter(|(filesystem_name, user, _): &(String, String, String)| {
+                !user_exists(user)
+                    && filter_filesystems
+                        .as_ref()
+                        .is_none_or(|fs| fs.contains(filesystem_name))
+            })
+            .collect()
+    };
+
+    let mut failed = 0;
+    for (filesystem_name, user, name) in &orphans {
+        let Some(filesystem) = filesystems.get(filesystem_name) else {
+            eprintln!(
+                "Skipping {}/{}: unknown filesystem {}",
+                user, name, filesystem_name
+            );
+            continue;
+        };
+        let volume = to_volume_string(&filesystem.root, user, name);
+        match to {
+            Some(to) => match transfer(conn, filesystem_name, filesystem, user, name, to) {
+                Ok(()) => println!(
+                    "Transferred {} to {}",
+                    volume,
+                    to_volume_string(&filesystem.root, to, name)
+                ),
+                Err(Error::TargetExists) => {
+                    eprintln!(
+                        "Skipping {}: {} already has a workspace named {}",
+                        volume, to, name
+                    );
+                }
+                Err(e) => {
+                    eprintln!("Failed to transfer {}: {}", volume, e);
+                    failed += 1;
+                }
+            },
+            None => {
+                if let Err(e) = expire(conn, filesystem_name, filesystem, user, name, false, hooks)
+                {
+                    eprintln!("Failed to expire {}: {}", volume, e);
+                    failed += 1;
+                    continue;
+                }
+                println!("Expired {}", volume);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(Error::BatchFailed {
+            failed,
+            total: orphans.len(),
+        });
+    }
+    Ok(())
+}
+
+fn main() {
+    privileges::sanitize_environment();
+
+    // Read config
+    let toml_str =
+        fs::read_to_string(config::CONFIG_PATH).expect("could not find configuration file");
+    let mut config: config::Config = toml::from_str(&toml_str).unwrap_or_else(|e| {
+        eprintln!("Error parsing {}: {}", config::CONFIG_PATH, e);
+        process::exit(exit_codes::INVALID_CONFIG)
+    });
+    config::split_remote(&mut config);
+
+    let start = std::time::Instant::now();
+    cli::set_sudo_user_policy(config.sudo_user);
+    compat::run();
+    let matches = cli::Args::command().get_matches();
+    cli::check_sudo_user(&matches);
+    let args = cli::Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    zfs::set_timeout(config.zfs_timeout.to_std().unwrap());
+    groups::set_policy(config.group_cache_ttl, args.refresh_groups);
+
+    // ask before forwarding, as the server has no terminal to ask on
+    if !args.yes && !args.dry_run {
+        confirm(&args.command);
+        confirm_selection(&config, &args.command);
+    }
+
+    // Without root privileges, let the server do the work for us
+    if get_effective_uid() != 0 {
+        server::forward(&config);
+    }
+
+    // a shared database may well be unreachable, which deserves more than a panic
+    let mut conn = match db::open(
+        &config.db_path,
+        config.database_url.as_deref(),
+        config.db_timeout.to_std().unwrap(),
+    ) {
+        Ok(conn) => conn,
+        Err(e) => {
+            let e = Error::from(e);
+            eprintln!("Could not open the database: {}", e);
+            process::exit(e.exit_code());
+        }
+    };
+    if let Err(e) = migrations::check_version(&conn) {
+        eprintln!("{}", e);
+        process::exit(e.exit_code());
+    }
+    if args.dry_run {
+        conn = dry_run::start(&conn).unwrap();
+    }
+    // Make sure database schema is current, unless asked to migrate explicitly
+    if !matches!(
+        args.command,
+        cli::Command::Db {
+            command: cli::DbCommand::Migrate { .. }
+        }
+    ) {
+        if let Err(e) = migrations::migrate(&mut conn, migrations::NEWEST_VERSION, |_, _| {}) {
+            eprintln!("Could not update the database: {}", e);
+            process::exit(e.exit_code());
+        }
+    }
+    server::set_admin_groups(&conn, &config.admin_groups);
+    let changes_before = metrics::changes(&conn);
+    // the database stays open, everything else only needs root occasionally
+    privileges::lower();
+
+    let exact_sizes = args.bytes;
+    table::set_color(args.color);
+    init_logging(args.verbose);
+    let result = match args.command {
+        cli::Command::Create {
+            filesystem_name,
+            workspace_name: name,
+            duration,
+            user,
+            class,
+            group,
+            collaborative,
+            size_hint,
+            tags,
+            comment,
+            project,
+            exist_ok,
+            extend,
+        } => {
+            let existing = if exist_ok {
+                find_workspace(&conn, &config.filesystems, &filesystem_name, &user, &name)
+            } else {
+                Ok(None)
+            };
+            // skips placement, which might pick another filesystem
+            let filesystem_name =
+                filesystem_name.or_else(|| existing.as_ref().ok().cloned().flatten());
+            let project = project
+                .as_ref()
+                .map(|name| {
+                    config
+                        .projects
+                        .get(name)
+                        .map(|project| (name.as_str(), project))
+                        .ok_or_else(|| Error::UnknownProject(name.clone()))
+                })
+                .transpose();
+            let filesystem_name = filesystem_name.or_else(|| {
+                config.placement_script.as_ref().and_then(|script| {
+                    placement::choose(
+                        script,
+                        &placement::Request::new(
+                            &user,
+                            &name,
+                            &duration,
+                            class,
+                            size_hint,
+                            &config.filesystems,
+                        ),
+                    )
+                })
+            });
+            let filesystem_name = filesystem_name.or_else(|| {
+                config.default_filesystem_policy.and_then(|policy| {
+                    placement::by_policy(&conn, policy, &config.filesystems, config.parallelism)
+                })
+            });
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            let reuse_existing = |conn: &Connection| {
+                reuse(
+                    conn,
+                    &filesystem_name,
+                    &config.filesystems[&filesystem_name],
+                    &user,
+                    &name,
+                    &duration,
+                    extend,
+                    &config.hooks,
+                )
+            };
+            match existing {
+                Ok(Some(_)) => reuse_existing(&conn),
+                Ok(None) => project
+                    .and_then(|project| {
+                        create(
+                            &mut conn,
+                            &config.filesystems,
+                            &filesystem_name,
+                            &config.filesystems[&filesystem_name],
+                            &user,
+                            &name,
+                            &duration,
+                            class,
+                            &group,
+                            collaborative,
+                            &tags,
+                            &comment,
+                            project,
+                            &config.hooks,
+                        )
+                    })
+                    // e.g. created by a concurrent run of the same script
+                    .or_else(|e| match e {
+                        Error::WorkspaceExists if exist_ok => reuse_existing(&conn),
+                        e => Err(e),
+                    }),
+                Err(e) => Err(e),
+            }
+        }
+        cli::Command::List {
+            filter_users,
+            filter_filesystems,
+            filter_tags,
+            filter_projects,
+            expiring_within,
+            expired,
+            active,
+            output,
+            summary,
+            fresh,
+        } => {
+            list(
+                &conn,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &filter_users,
+                &filter_filesystems,
+                &|_| true,
+                &filter_tags,
+                &filter_projects,
+                expiring_within,
+                expired,
+                active,
+                &output,
+                summary,
+                fresh,
+                config.daemon.clean_interval * 2,
+                config.parallelism,
+                exact_sizes,
+            );
+            Ok(())
+        }
+        cli::Command::Rename {
+            src_workspace_name,
+            dest_workspace_name,
+            user,
+            filesystem_name,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            rename(
+                &mut conn,
+                &filesystem_name,
+                &config.filesystems[&filesystem_name],
+                &user,
+                &src_workspace_name,
+                &dest_workspace_name,
+            )
+        }
+        cli::Command::Extend {
+            filesystem_name,
+            names,
+            pattern,
+            preview,
+            user,
+            duration,
+            until,
+            quota,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            let filesystem = &config.filesystems[&filesystem_name];
+            match (duration, until, filesystem.default_duration) {
+                (Some(duration), _, _) => Ok(duration),
+                (None, Some(until), _) => duration_until(until),
+                (None, None, Some(duration)) => Ok(duration),
+                (None, None, None) => Err(Error::DurationRequired),
+            }
+            .and_then(|duration| {
+                let names = select::resolve(&conn, &filesystem_name, &user, &names, &pattern)?;
+                if preview {
+                    names.iter().for_each(|name| println!("{}", name));
+                    return Ok(());
+                }
+                select::apply(&names, "extended", |name| {
+                    extend(
+                        &conn,
+                        &filesystem_name,
+                        filesystem,
+                        &user,
+                        name,
+                        &duration,
+                        quota,
+                        &config.hooks,
+                    )
+                })
+            })
+        }
+        cli::Command::Advise {
+            name,
+            user,
+            filesystem_name,
+            top,
+            older_than,
+            min_size,
+            max_files,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            advise(
+                &conn,
+                &filesystem_name,
+                &config.filesystems[&filesystem_name],
+                &user,
+                &name,
+                &advise::Options {
+                    top,
+                    older_than,
+                    min_size: min_size << 20,
+                    max_files,
+                    workers: config.parallelism,
+                },
+                exact_sizes,
+            )
+        }
+        cli::Command::Tag {
+            name,
+            changes,
+            user,
+            filesystem_name,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            tag(&mut conn, &filesystem_name, &user, &name, &changes)
+        }
+        cli::Command::Comment {
+            name,
+            comment,
+            user,
+            filesystem_name,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            self::comment(&conn, &filesystem_name, &user, &name, &comment)
+        }
+        cli::Command::Find {
+            pattern,
+            regex,
+            user,
+            all_users,
+            output,
+        } => find(
+            &conn,
+            &config,
+            &pattern,
+            regex,
+            &(!all_users).then_some(user),
+            &output,
+            exact_sizes,
+        ),
+        cli::Command::Du {
+            name,
+            depth,
+            top,
+            user,
+            filesystem_name,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            du(
+                &conn,
+                &filesystem_name,
+                &config.filesystems[&filesystem_name],
+                &user,
+                &name,
+                depth,
+                top,
+                config.parallelism,
+                exact_sizes,
+            )
+        }
+        cli::Command::Path {
+            name,
+            user,
+            filesystem_name,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            path(
+                &conn,
+                &filesystem_name,
+                &config.filesystems[&filesystem_name],
+                &user,
+                &name,
+            )
+        }
+        cli::Command::Mount {
+            name,
+            user,
+            filesystem_name,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            set_mounted(
+                &conn,
+                &filesystem_name,
+                &config.filesystems[&filesystem_name],
+                &user,
+                &name,
+                true,
+            )
+        }
+        cli::Command::Umount {
+            name,
+            user,
+            filesystem_name,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            set_mounted(
+                &conn,
+                &filesystem_name,
+                &config.filesystems[&filesystem_name],
+                &user,
+                &name,
+                false,
+            )
+        }
+        cli::Command::Info {
+            name,
+            user,
+            filesystem_name,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            info(
+                &conn,
+                &filesystem_name,
+                &config.filesystems[&filesystem_name],
+                &user,
+                &name,
+                exact_sizes,
+            )
+        }
+        cli::Command::Bundle {
+            name,
+            user,
+            filesystem_name,
+            out,
+            archive,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            bundle(
+                &conn,
+                &filesystem_name,
+                &config.filesystems[&filesystem_name],
+                &user,
+                &name,
+                &out,
+                archive,
+                config.parallelism,
+            )
+        }
+        cli::Command::Export {
+            name,
+            user,
+            filesystem_name,
+            to,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            export(
+                &conn,
+                &filesystem_name,
+                &config.filesystems[&filesystem_name],
+                &user,
+                &name,
+                &to,
+            )
+        }
+        cli::Command::Diff {
+            name,
+            snapshot,
+            user,
+            filesystem_name,
+            list,
+            json,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            diff(
+                &conn,
+                &filesystem_name,
+                &config.filesystems[&filesystem_name],
+                &user,
+                &name,
+                &snapshot,
+                list,
+                json,
             );
-            process::exit(exit_codes::UNKNOWN_WORKSPACE);
+            Ok(())
         }
-        1 => {}
-        _ => unreachable!(),
-    };
-
-    zfs::set_property(
-        &to_volume_string(&filesystem.root, user, name),
-        "readonly",
-        "on",
-    )
-    .unwrap();
-}
-
-fn filesystems(
-    filesystems: &HashMap<String, config::Filesystem>,
-    output: Option<Vec<cli::FilesystemsColumns>>,
-) {
-    // the default columns
-    let output = output.unwrap_or(vec![
-        FilesystemsColumns::Name,
-        FilesystemsColumns::Used,
-        FilesystemsColumns::Free,
-        FilesystemsColumns::Total,
-        FilesystemsColumns::Duration,
-        FilesystemsColumns::Retention,
-    ]);
-
-    let mut table = Table::new();
-    table.set_format(FormatBuilder::new().padding(0, 2).build());
-
-    // bold title row
-    table.set_titles(Row::new(
-        output
-            .iter()
-            .map(|h| Cell::new(&h.to_string()).with_style(Attr::Bold))
-            .collect(),
-    ));
-
-    for (name, info) in filesystems {
-        let used = zfs::get_property::<usize>(&info.root, "used").unwrap();
-        let available = zfs::get_property::<usize>(&info.root, "available").unwrap();
-        let total = used + available;
-        table.add_row(Row::new(
-            output
-                .iter()
-                .map(|column| match column {
-                    FilesystemsColumns::Name => Cell::new(name),
-                    FilesystemsColumns::Used => {
-                        Cell::new_align(&format!("{}G", used / (1 << 30)), Alignment::RIGHT)
-                    }
-                    FilesystemsColumns::Free => {
-                        Cell::new_align(&format!("{}G", available / (1 << 30)), Alignment::RIGHT)
-                    }
-                    FilesystemsColumns::Total => {
-                        Cell::new_align(&format!("{}G", total / (1 << 30)), Alignment::RIGHT)
-                    }
-                    FilesystemsColumns::Duration => match info.disabled {
-                        true => Cell::new("disabled"),
-                        false => {
-                            Cell::new(&format!("{}d", info.max_duration.num_days())).style_spec("r")
-                        }
-                    },
-                    FilesystemsColumns::Retention => {
-                        Cell::new(&format!("{}d", info.expired_retention.num_days()))
-                            .style_spec("r")
-                    }
-                })
-                .map(|c| {
-                    // color if almost full
-                    if used as f64 > total as f64 * 0.9 {
-                        c.with_style(Attr::ForegroundColor(color::RED))
-                    } else if used as f64 > total as f64 * 0.75 {
-                        c.with_style(Attr::ForegroundColor(color::YELLOW))
-                    } else {
-                        c
-                    }
-                })
-                .map(|c| {
-                    // dim if disabled
-                    if info.disabled {
-                        c.with_style(Attr::Dim)
-                    } else {
-                        c
-                    }
-                })
-                .collect(),
-        ));
-    }
-
-    table.printstd();
-}
-
-fn clean(conn: &mut Connection, filesystems: &HashMap<String, config::Filesystem>) {
-    let transaction = conn.transaction().unwrap();
-    {
-        let mut statement = transaction
-            .prepare(
-                "SELECT filesystem, user, name, expiration_time
-                    FROM workspaces
-                    WHERE expiration_time < ?1",
-            )
-            .unwrap();
-        let mut rows = statement.query([Local::now()]).unwrap();
-        while let Some(row) = rows.next().unwrap() {
-            let filesystem_name: String = row.get(0).unwrap();
-            let user: String = row.get(1).unwrap();
-            let name: String = row.get(2).unwrap();
-            let expiration_time: DateTime<Local> = row.get(3).unwrap();
-
-            let filesystem = &filesystems
-                .get(&filesystem_name)
-                .expect("unknown filesystem name");
-            let volume = to_volume_string(&filesystem.root, &user, &name);
-            if expiration_time < Local::now() - filesystem.expired_retention {
-                if zfs::destroy(&volume).is_err() {
-                    continue;
+        cli::Command::Expire {
+            filesystem_name,
+            names,
+            pattern,
+            preview,
+            user,
+            delete_on_next_clean,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            select::resolve(&conn, &filesystem_name, &user, &names, &pattern).and_then(|names| {
+                if preview {
+                    names.iter().for_each(|name| println!("{}", name));
+                    return Ok(());
                 }
-                transaction
-                    .execute(
-                        "DELETE FROM workspaces
-                            WHERE filesystem = ?1
-                                AND user = ?2
-                                AND name = ?3",
-                        (filesystem_name, user, name),
+                select::apply(&names, "expired", |name| {
+                    expire(
+                        &conn,
+                        &filesystem_name,
+                        &config.filesystems[&filesystem_name],
+                        &user,
+                        name,
+                        delete_on_next_clean,
+                        &config.hooks,
                     )
-                    .unwrap();
-            } else {
-                zfs::set_property(&volume, "readonly", "on").unwrap();
-            }
+                })
+            })
         }
-    }
-    transaction.commit().unwrap();
-}
-
-//TODO make result
-const UPDATE_DB: &[fn(&mut Connection)] = &[|conn| {
-    // Creates initial database
-    conn.pragma_update(None, "journal_mode", "WAL").unwrap();
-    let transaction = conn.transaction().unwrap();
-    transaction
-        .execute(
-            "CREATE TABLE workspaces (
-                filesystem      TEXT     NOT NULL,
-                user            TEXT     NOT NULL,
-                name            TEXT     NOT NULL,
-                expiration_time DATETIME NOT NULL,
-                UNIQUE(filesystem, user, name)
-            )",
-            (),
-        )
-        .unwrap();
-    transaction.pragma_update(None, "user_version", 1).unwrap();
-    transaction.commit().unwrap();
-}];
-const NEWEST_DB_VERSION: usize = UPDATE_DB.len();
-
-fn main() {
-    // Read config
-    let toml_str =
-        fs::read_to_string(config::CONFIG_PATH).expect("could not find configuration file");
-    let config: config::Config =
-        toml::from_str(&toml_str).expect("error parsing configuration file");
-
-    let args = cli::Args::parse();
-
-    // Make sure database schema is current
-    let mut conn = Connection::open(config.db_path).unwrap();
-    let db_version: usize = conn
-        .pragma_query_value(None, "user_version", |row| row.get(0))
-        .unwrap();
-    assert!(
-        db_version <= NEWEST_DB_VERSION,
-        "database seems to be from a more current version of workspaces"
-    );
-    // Iteratively apply necessary database updates
-    UPDATE_DB[db_version..].iter().for_each(|f| f(&mut conn));
-
-    match args.command {
-        cli::Command::Create {
+        cli::Command::Release {
             filesystem_name,
-            workspace_name: name,
-            duration,
+            name,
             user,
         } => {
             let filesystem_name = filesystem_or_default_or_exit(
                 &filesystem_name,
                 &config.filesystems,
+                &config.remote_filesystems,
                 &config.default_filesystem,
             );
-            create(
+            release(
                 &mut conn,
                 &filesystem_name,
                 &config.filesystems[&filesystem_name],
                 &user,
                 &name,
-                &duration,
             )
         }
-        cli::Command::List {
-            filter_users,
-            filter_filesystems,
-            output,
-        } => list(
-            &conn,
-            &config.filesystems,
-            &filter_users,
-            &filter_filesystems,
-            &output,
-        ),
-        cli::Command::Rename {
-            src_workspace_name,
-            dest_workspace_name,
+        cli::Command::Lock {
+            name,
             user,
             filesystem_name,
+            reason,
         } => {
             let filesystem_name = filesystem_or_default_or_exit(
                 &filesystem_name,
                 &config.filesystems,
+                &config.remote_filesystems,
                 &config.default_filesystem,
             );
-            rename(
-                &mut conn,
+            set_locked(
+                &conn,
                 &filesystem_name,
-                &config.filesystems[&filesystem_name],
                 &user,
-                &src_workspace_name,
-                &dest_workspace_name,
+                &name,
+                Some(reason.as_deref().unwrap_or_default()),
             )
         }
-        cli::Command::Extend {
+        cli::Command::Unlock {
+            name,
+            user,
             filesystem_name,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            set_locked(&conn, &filesystem_name, &user, &name, None)
+        }
+        cli::Command::Exempt {
             name,
             user,
+            filesystem_name,
+            until,
+            revoke,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            match (revoke, until) {
+                (true, _) => Ok(None),
+                (false, Some(until)) => {
+                    duration_until(until).map(|duration| Some(Local::now() + duration))
+                }
+                (false, None) => Ok(Some(reference_expiration_time())),
+            }
+            .and_then(|until| set_exempt(&conn, &filesystem_name, &user, &name, until))
+        }
+        cli::Command::Filesystems {
+            output,
+            format,
+            include_policy,
+        } => {
+            filesystems(
+                &conn,
+                &config.filesystems,
+                output,
+                format,
+                include_policy,
+                &config.shares,
+                config.parallelism,
+                exact_sizes,
+            );
+            Ok(())
+        }
+        cli::Command::Orphans => orphans(&conn, &config),
+        cli::Command::AuditUsers {
+            reassign_renamed,
+            expire_deleted,
+        } => audit_users(
+            &mut conn,
+            &config.filesystems,
+            reassign_renamed,
+            expire_deleted,
+            &config.hooks,
+            exact_sizes,
+        ),
+        cli::Command::Adopt {
+            dataset,
+            user,
             duration,
+            project,
+            ..
+        } => match &project {
+            Some(project) if !config.projects.contains_key(project) => {
+                Err(Error::UnknownProject(project.clone()))
+            }
+            _ => adopt_datasets(
+                &conn,
+                &config.filesystems,
+                &dataset,
+                &user,
+                &duration,
+                project.as_deref(),
+            ),
+        },
+        cli::Command::Fsck {
+            fix,
+            destroy_untracked,
+        } => fsck(
+            &mut conn,
+            &config.filesystems,
+            fix,
+            destroy_untracked,
+            config.parallelism,
+        ),
+        cli::Command::Clean {
+            syslog,
+            with_snapshots,
+        } => match try_lock_clean(&config.db_path) {
+            Some(_lock) => clean(
+                &mut conn,
+                &config.filesystems,
+                &config.notifications,
+                &config.hooks,
+                &config.clean,
+                &config.shares,
+                syslog,
+                with_snapshots,
+            ),
+            None => Err(Error::CleanRunning),
+        },
+        cli::Command::Daemon => daemon::run(&mut conn, &config),
+        cli::Command::Serve => server::serve(&config),
+        cli::Command::Notify {
+            command:
+                cli::NotifyCommand::Config {
+                    name: Some(name),
+                    channel,
+                    email,
+                    reset,
+                    user,
+                    filesystem_name,
+                    ..
+                },
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            notify::configure_workspace(
+                &conn,
+                &config.notifications,
+                &filesystem_name,
+                &user,
+                &name,
+                &channel,
+                &email,
+                reset,
+            );
+            Ok(())
+        }
+        cli::Command::Notify {
+            command:
+                cli::NotifyCommand::Config {
+                    name: None,
+                    days,
+                    channel,
+                    reset,
+                    user,
+                    ..
+                },
+        } => {
+            notify::configure(&conn, &config.notifications, &user, &days, &channel, reset);
+            Ok(())
+        }
+        cli::Command::Admin {
+            command:
+                cli::AdminCommand::ReassignOrphans {
+                    filter_filesystems,
+                    to,
+                    ..
+                },
+        } => reassign_orphans(
+            &mut conn,
+            &config.filesystems,
+            &filter_filesystems,
+            &to,
+            &config.hooks,
+        ),
+        cli::Command::Admin {
+            command:
+                cli::AdminCommand::BulkRename {
+                    user,
+                    pattern,
+                    replacement,
+                    filter_filesystems,
+                },
+        } => bulk_rename(
+            &mut conn,
+            &config.filesystems,
+            &filter_filesystems,
+            &user,
+            &pattern,
+            &replacement,
+        ),
+        cli::Command::Admin {
+            command: cli::AdminCommand::Rebalance { from, to, max_size },
+        } => {
+            let from = filesystem_or_default_or_exit(
+                &Some(from),
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            let to = filesystem_or_default_or_exit(
+                &Some(to),
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            rebalance(&mut conn, &config.filesystems, &from, &to, max_size)
+        }
+        cli::Command::Admin {
+            command: cli::AdminCommand::Trash { filter_filesystems },
+        } => {
+            list_trash(&conn, &config.filesystems, &filter_filesystems);
+            Ok(())
+        }
+        cli::Command::Admin {
+            command:
+                cli::AdminCommand::Untrash {
+                    name,
+                    user,
+                    filesystem_name,
+                },
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            untrash(
+                &mut conn,
+                &filesystem_name,
+                &config.filesystems[&filesystem_name],
+                &user,
+                &name,
+            )
+        }
+        cli::Command::Admin {
+            command:
+                cli::AdminCommand::Promote {
+                    name,
+                    user,
+                    filesystem_name,
+                },
         } => {
             let filesystem_name = filesystem_or_default_or_exit(
                 &filesystem_name,
                 &config.filesystems,
+                &config.remote_filesystems,
                 &config.default_filesystem,
             );
-            extend(
+            promote(
                 &conn,
                 &filesystem_name,
                 &config.filesystems[&filesystem_name],
                 &user,
                 &name,
-                &duration,
             )
         }
-        cli::Command::Expire {
-            filesystem_name,
+        cli::Command::Stats {
+            global: true,
+            weeks,
+            ..
+        } => statistics::global(&conn, weeks, exact_sizes),
+        cli::Command::Stats {
             name,
             user,
-            delete_on_next_clean,
+            filesystem_name,
+            threshold,
+            ..
+        } => sizes::stats(
+            &conn,
+            &filesystem_name,
+            &user,
+            &name,
+            threshold,
+            exact_sizes,
+        ),
+        cli::Command::Usage { filter_users } => {
+            sizes::usage(&conn, &config.filesystems, &filter_users, exact_sizes)
+        }
+        cli::Command::Report {
+            from: Some(from),
+            to: Some(to),
+            format,
+            ..
+        } => {
+            let from = from
+                .and_hms_opt(0, 0, 0)
+                .and_then(|midnight| midnight.and_local_timezone(Local).earliest());
+            let to = to
+                .succ_opt()
+                .and_then(|next_day| next_day.and_hms_opt(0, 0, 0))
+                .and_then(|midnight| midnight.and_local_timezone(Local).earliest());
+            match (from, to) {
+                (Some(from), Some(to)) if from < to => accounting::consumption(&conn, &from, &to)
+                    .map(|consumptions| accounting::print(&consumptions, format)),
+                _ => Err(Error::InvalidArgument(
+                    "The accounting period has to end after it starts".to_string(),
+                )),
+            }
+        }
+        cli::Command::Report {
+            anomalies,
+            shares: false,
+            projects: false,
+            ..
+        } => {
+            sizes::report(&conn, anomalies, exact_sizes);
+            Ok(())
+        }
+        cli::Command::Report { shares: true, .. } => {
+            shares::usage(&conn, &config.shares).map(|usages| shares::print(&usages, exact_sizes))
+        }
+        cli::Command::Report { projects: true, .. } => projects::usage(&conn, &config.projects)
+            .map(|usages| projects::print(&usages, exact_sizes)),
+        cli::Command::Admin {
+            command: cli::AdminCommand::Perf { since },
+        } => {
+            metrics::perf(&conn, &since);
+            Ok(())
+        }
+        cli::Command::Config {
+            command: cli::ConfigCommand::Check,
+        } => check_config(&conn, &config),
+        cli::Command::Db {
+            command: cli::DbCommand::Backup { path },
+        } => backup_db(&conn, &path),
+        cli::Command::Db {
+            command: cli::DbCommand::Restore { path },
+        } => restore_db(&mut conn, &path),
+        cli::Command::Db {
+            command: cli::DbCommand::Check { format },
+        } => check_db(&conn, &config, format),
+        cli::Command::Db {
+            command: cli::DbCommand::Migrate { to },
+        } => migrate_db(&mut conn, to),
+        cli::Command::Import {
+            command:
+                Some(cli::ImportCommand::WsAllocate {
+                    path,
+                    filesystem_name,
+                    adopt,
+                }),
+            ..
         } => {
             let filesystem_name = filesystem_or_default_or_exit(
                 &filesystem_name,
                 &config.filesystems,
+                &config.remote_filesystems,
                 &config.default_filesystem,
             );
-            expire(
-                &conn,
+            import_ws_allocate(
+                &mut conn,
+                &config.filesystems,
+                &filesystem_name,
+                &config.filesystems[&filesystem_name],
+                &path,
+                adopt,
+                &config.hooks,
+            )
+        }
+        cli::Command::Import {
+            command: None,
+            file: Some(path),
+            name: Some(name),
+            user,
+            filesystem_name,
+            duration,
+        } => {
+            let filesystem_name = filesystem_or_default_or_exit(
+                &filesystem_name,
+                &config.filesystems,
+                &config.remote_filesystems,
+                &config.default_filesystem,
+            );
+            import_tarball(
+                &mut conn,
+                &config.filesystems,
                 &filesystem_name,
                 &config.filesystems[&filesystem_name],
                 &user,
                 &name,
-                delete_on_next_clean,
+                &path,
+                &duration,
+                &config.hooks,
             )
         }
-        cli::Command::Filesystems { output } => filesystems(&config.filesystems, output),
-        cli::Command::Clean => clean(&mut conn, &config.filesystems),
+        cli::Command::Import { .. } => {
+            unreachable!("clap requires a tarball and a name without a subcommand")
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(e.exit_code());
+    }
+
+    if config.metrics && !dry_run::enabled() {
+        metrics::record(
+            &conn,
+            &metrics::command_name(&matches),
+            start.elapsed(),
+            changes_before,
+        );
     }
 }
 
@@ -665,10 +5927,12 @@ fn main() {
 /// - the default filesystem, if specified in the config
 /// - the only filesystem if there is only one
 ///
-/// Otherwise, it terminates the program
+/// Otherwise, it terminates the program, also if the filesystem is served by
+/// another host
 fn filesystem_or_default_or_exit(
     filesystem_name: &Option<String>,
     filesystems: &HashMap<String, config::Filesystem>,
+    remote_filesystems: &HashMap<String, config::Filesystem>,
     default: &Option<String>,
 ) -> String {
     let filesystem_name: String = if let Some(name) = filesystem_name {
@@ -684,6 +5948,13 @@ fn filesystem_or_default_or_exit(
 
     if filesystems.contains_key(&filesystem_name) {
         filesystem_name
+    } else if let Some(filesystem) = remote_filesystems.get(&filesystem_name) {
+        eprintln!(
+            "Filesystem {} is served by {}. Please run this command there.",
+            filesystem_name,
+            filesystem.host.as_deref().unwrap_or_default()
+        );
+        process::exit(exit_codes::REMOTE_FILESYSTEM);
     } else {
         eprint!("Invalid filesystem name. Please use one of the following:");
         for name in filesystems.keys() {
@@ -0,0 +1,115 @@
+use crate::{
+    db::{self, Connection},
+    table,
+};
+use chrono::{Duration, Local};
+use clap::ArgMatches;
+use prettytable::{
+    format::{Alignment, FormatBuilder},
+    Attr, Cell, Row, Table,
+};
+use std::{collections::BTreeMap, time};
+
+/// Number of rows inserted, updated or deleted through `conn` so far
+pub fn changes(conn: &Connection) -> i64 {
+    conn.total_changes()
+}
+
+/// Records the performance of a single invocation in the metrics spool
+///
+/// The rows recorded are those changed since [`changes`] returned
+/// `changes_before`.  Metrics never leave the database; they are only used to
+/// give admins an idea of when the database or pools need attention.
+pub fn record(conn: &Connection, command: &str, elapsed: time::Duration, changes_before: i64) {
+    let rows = changes(conn) - changes_before;
+    conn.execute(
+        "INSERT INTO metrics (time, command, duration_ms, zfs_calls, rows)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+        (
+            db::Timestamp::from(Local::now()),
+            command,
+            elapsed.as_millis() as i64,
+            crate::zfs::call_count() as i64,
+            rows,
+        ),
+    )
+    .unwrap();
+}
+
+/// Returns the full name of the invoked subcommand, e.g. `admin perf`
+pub fn command_name(matches: &ArgMatches) -> String {
+    let mut names = vec![];
+    let mut matches = matches;
+    while let Some((name, sub_matches)) = matches.subcommand() {
+        names.push(name);
+        matches = sub_matches;
+    }
+    names.join(" ")
+}
+
+/// Latencies and call counts recorded for a single command
+#[derive(Default)]
+struct CommandStats {
+    durations_ms: Vec<i64>,
+    zfs_calls: Vec<i64>,
+    rows: Vec<i64>,
+}
+
+/// Returns the `p`th percentile of an ascendingly sorted, non-empty slice
+fn percentile(sorted: &[i64], p: usize) -> i64 {
+    let rank = (sorted.len() * p).div_ceil(100);
+    sorted[rank.saturating_sub(1)]
+}
+
+/// Summarizes the recorded metrics of the last `since` per command
+pub fn perf(conn: &Connection, since: &Duration) {
+    let mut stats: BTreeMap<String, CommandStats> = BTreeMap::new();
+    let mut statement = conn
+        .prepare(
+            "SELECT command, duration_ms, zfs_calls, rows
+                FROM metrics
+                WHERE time > ?1
+                ORDER BY time",
+        )
+        .unwrap();
+    let mut rows = statement
+        .query([db::Timestamp::from(Local::now() - *since)])
+        .unwrap();
+    while let Some(row) = rows.next().unwrap() {
+        let entry = stats.entry(row.get(0).unwrap()).or_default();
+        entry.durations_ms.push(row.get(1).unwrap());
+        entry.zfs_calls.push(row.get(2).unwrap());
+        entry.rows.push(row.get(3).unwrap());
+    }
+
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["COMMAND", "CALLS", "P50", "P95", "ZFS/CALL", "ROWS/CALL"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+    for (command, mut stats) in stats {
+        stats.durations_ms.sort_unstable();
+        let calls = stats.durations_ms.len();
+        let zfs_calls_per_call = stats.zfs_calls.iter().sum::<i64>() as f64 / calls as f64;
+        let rows_per_call = stats.rows.iter().sum::<i64>() as f64 / calls as f64;
+        table.add_row(Row::new(vec![
+            Cell::new(&command),
+            Cell::new_align(&calls.to_string(), Alignment::RIGHT),
+            Cell::new_align(
+                &format!("{}ms", percentile(&stats.durations_ms, 50)),
+                Alignment::RIGHT,
+            ),
+            Cell::new_align(
+                &format!("{}ms", percentile(&stats.durations_ms, 95)),
+                Alignment::RIGHT,
+            ),
+            Cell::new_align(&format!("{:.1}", zfs_calls_per_call), Alignment::RIGHT),
+            Cell::new_align(&format!("{:.1}", rows_per_call), Alignment::RIGHT),
+        ]));
+    }
+
+    table::print(&table);
+}
@@ -0,0 +1,359 @@
+//! Updates of the database schema
+//!
+//! The schema version is stored in SQLite's `user_version`, or a table of its
+//! own in PostgreSQL.  The SQL of migrations is understood by both.  Each migration
+//! brings the database from its index to the next version and is applied in
+//! a transaction of its own, so an interrupted migration leaves the database
+//! at the last completed version.
+
+use crate::{
+    db::{self, Connection, Transaction},
+    error::Error,
+};
+
+/// A single step of the schema's history
+pub struct Migration {
+    /// What the migration changes
+    pub description: &'static str,
+    apply: fn(&Transaction) -> db::Result<()>,
+}
+
+/// All migrations, with the one at index `i` updating version `i` to `i + 1`
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "Create initial database",
+        apply: |transaction| {
+            transaction.execute(
+                "CREATE TABLE workspaces (
+                    filesystem      TEXT     NOT NULL,
+                    user            TEXT     NOT NULL,
+                    name            TEXT     NOT NULL,
+                    expiration_time DATETIME NOT NULL,
+                    UNIQUE(filesystem, user, name)
+                )",
+                (),
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Convert all timestamps to UTC RFC3339",
+        apply: |transaction| {
+            let mut statement = transaction
+                .prepare("SELECT filesystem, user, name, expiration_time FROM workspaces")?;
+            let rows: Vec<(String, String, String, db::Timestamp)> = statement
+                .query_map([], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                })?
+                .collect::<Result<_, _>>()?;
+            for (filesystem, user, name, expiration_time) in rows {
+                transaction.execute(
+                    "UPDATE workspaces SET expiration_time = ?1
+                        WHERE filesystem = ?2 AND user = ?3 AND name = ?4",
+                    (expiration_time, filesystem, user, name),
+                )?;
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Add metrics spool",
+        apply: |transaction| {
+            transaction.execute(
+                "CREATE TABLE metrics (
+                    time        DATETIME NOT NULL,
+                    command     TEXT     NOT NULL,
+                    duration_ms INTEGER  NOT NULL,
+                    zfs_calls   INTEGER  NOT NULL,
+                    rows        INTEGER  NOT NULL
+                )",
+                (),
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Add notification preferences and bookkeeping of sent reminders",
+        apply: |transaction| {
+            transaction.execute_batch(
+                "CREATE TABLE notification_preferences (
+                    user    TEXT NOT NULL PRIMARY KEY,
+                    days    TEXT NOT NULL,
+                    channel TEXT NOT NULL
+                );
+                CREATE TABLE reminders_sent (
+                    filesystem      TEXT     NOT NULL,
+                    user            TEXT     NOT NULL,
+                    name            TEXT     NOT NULL,
+                    expiration_time DATETIME NOT NULL,
+                    days            INTEGER  NOT NULL,
+                    UNIQUE(filesystem, user, name, expiration_time, days)
+                );",
+            )
+        },
+    },
+    Migration {
+        description: "Add trash",
+        apply: |transaction| {
+            transaction.execute(
+                "CREATE TABLE trash (
+                    filesystem      TEXT     NOT NULL,
+                    user            TEXT     NOT NULL,
+                    name            TEXT     NOT NULL,
+                    expiration_time DATETIME NOT NULL,
+                    trashed_time    DATETIME NOT NULL,
+                    UNIQUE(filesystem, user, name, trashed_time)
+                )",
+                (),
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Add size history",
+        apply: |transaction| {
+            transaction.execute_batch(
+                "CREATE TABLE size_history (
+                    filesystem TEXT     NOT NULL,
+                    user       TEXT     NOT NULL,
+                    name       TEXT     NOT NULL,
+                    time       DATETIME NOT NULL,
+                    bytes      INTEGER  NOT NULL,
+                    alerted    BOOLEAN  NOT NULL
+                );
+                CREATE INDEX size_history_workspace
+                    ON size_history (filesystem, user, name, time);",
+            )
+        },
+    },
+    Migration {
+        description: "Add workspace classes",
+        apply: |transaction| {
+            transaction.execute(
+                "ALTER TABLE workspaces ADD COLUMN class TEXT NOT NULL DEFAULT 'scratch'",
+                (),
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Add a cache of workspace sizes, refreshed by clean",
+        apply: |transaction| {
+            transaction.execute_batch(
+                "ALTER TABLE workspaces ADD COLUMN cached_size INTEGER;
+                ALTER TABLE workspaces ADD COLUMN cached_mountpoint BLOB;
+                ALTER TABLE workspaces ADD COLUMN cached_at TEXT;",
+            )
+        },
+    },
+    Migration {
+        description: "Add the groups known to have exceeded their storage share",
+        apply: |transaction| {
+            transaction.execute(
+                "CREATE TABLE exceeded_shares (group_name TEXT PRIMARY KEY NOT NULL)",
+                (),
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Add the queue of permission setups to be retried by the daemon",
+        apply: |transaction| {
+            transaction.execute(
+                "CREATE TABLE pending_permissions (
+                    filesystem TEXT NOT NULL,
+                    user TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    group_name TEXT NOT NULL,
+                    collaborative INTEGER NOT NULL,
+                    attempts INTEGER NOT NULL,
+                    last_error TEXT NOT NULL,
+                    PRIMARY KEY (filesystem, user, name)
+                )",
+                (),
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Add workspace tags",
+        apply: |transaction| {
+            transaction.execute(
+                "CREATE TABLE tags (
+                    filesystem TEXT NOT NULL,
+                    user TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    tag TEXT NOT NULL,
+                    PRIMARY KEY (filesystem, user, name, tag)
+                )",
+                (),
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Add free-form workspace comments",
+        apply: |transaction| {
+            transaction.execute("ALTER TABLE workspaces ADD COLUMN comment TEXT", ())?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Remember released datasets, so fsck leaves them alone",
+        apply: |transaction| {
+            transaction.execute(
+                "CREATE TABLE released (
+                    filesystem TEXT NOT NULL,
+                    user TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    PRIMARY KEY (filesystem, user, name)
+                )",
+                (),
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Record which host serves each workspace, for shared databases",
+        apply: |transaction| {
+            transaction.execute("ALTER TABLE workspaces ADD COLUMN host TEXT", ())?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Add the project owning each workspace",
+        apply: |transaction| {
+            transaction.execute("ALTER TABLE workspaces ADD COLUMN project TEXT", ())?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Allow administrators to lock workspaces, with the reason why",
+        apply: |transaction| {
+            transaction.execute("ALTER TABLE workspaces ADD COLUMN locked TEXT", ())?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Allow exempting workspaces from clean",
+        apply: |transaction| {
+            transaction.execute("ALTER TABLE workspaces ADD COLUMN exempt_until TEXT", ())?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Record the project of each size sample, for accounting",
+        apply: |transaction| {
+            transaction.execute("ALTER TABLE size_history ADD COLUMN project TEXT", ())?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Keep a history of workspace events, for statistics",
+        apply: |transaction| {
+            transaction.execute(
+                "CREATE TABLE events (
+                    time       TEXT NOT NULL,
+                    event      TEXT NOT NULL,
+                    filesystem TEXT NOT NULL,
+                    user       TEXT NOT NULL,
+                    name       TEXT NOT NULL
+                )",
+                (),
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Remember where workspaces in the archived tier are stored",
+        apply: |transaction| {
+            transaction.execute("ALTER TABLE workspaces ADD COLUMN archive_url TEXT", ())?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Allow notification settings per workspace",
+        apply: |transaction| {
+            transaction.execute_batch(
+                "ALTER TABLE workspaces ADD COLUMN notify_channel TEXT;
+                ALTER TABLE workspaces ADD COLUMN notify_email TEXT;",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Cache the groups of users, as looking them up in LDAP is slow",
+        apply: |transaction| {
+            transaction.execute(
+                "CREATE TABLE group_memberships (
+                    user   TEXT PRIMARY KEY,
+                    groups TEXT NOT NULL,
+                    time   TEXT NOT NULL
+                )",
+                (),
+            )?;
+            Ok(())
+        },
+    },
+];
+
+/// The schema version this binary works with
+pub const NEWEST_VERSION: usize = MIGRATIONS.len();
+
+/// The schema version of the database
+pub fn version(conn: &Connection) -> db::Result<usize> {
+    conn.backend().version()
+}
+
+/// Fails if the database is newer than this binary
+pub fn check_version(conn: &Connection) -> Result<(), Error> {
+    let version = version(conn)?;
+    if version > NEWEST_VERSION {
+        return Err(Error::DatabaseTooNew {
+            version,
+            supported: NEWEST_VERSION,
+        });
+    }
+    Ok(())
+}
+
+/// Migrates the database to version `target`, calling `applied` after each step
+///
+/// Concurrent invocations are safe, as the version is checked again after
+/// taking the write lock for each step.  Downgrades are not supported.
+pub fn migrate(
+    conn: &mut Connection,
+    target: usize,
+    mut applied: impl FnMut(usize, &Migration),
+) -> Result<(), Error> {
+    check_version(conn)?;
+    if target > NEWEST_VERSION {
+        return Err(Error::InvalidArgument(format!(
+            "There is no schema version {}, the newest is {}",
+            target, NEWEST_VERSION
+        )));
+    }
+    let version = version(conn)?;
+    if target < version {
+        return Err(Error::InvalidArgument(format!(
+            "The database is at version {} already, and cannot be downgraded to {}",
+            version, target
+        )));
+    }
+    if version == 0 {
+        conn.backend().initialize()?;
+    }
+
+    loop {
+        let transaction = db::transaction(conn)?;
+        let version = self::version(&transaction)?;
+        if version >= target {
+            return Ok(());
+        }
+        let migration = &MIGRATIONS[version];
+        (migration.apply)(&transaction)?;
+        transaction.backend().set_version(version + 1)?;
+        transaction.commit()?;
+        applied(version + 1, migration);
+    }
+}
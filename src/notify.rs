@@ -0,0 +1,676 @@
+use crate::db::{Connection, OptionalExtension};
+use crate::{
+    config, db, dry_run, exit_codes, privileges, server::is_admin, server::is_caller, shares,
+    sizes, units, user_exists, zfs,
+};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use std::{
+    collections::HashMap,
+    io::Write,
+    process::{self, Command, Stdio},
+};
+
+/// How a user wants to be notified about their workspaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preferences {
+    /// Days before expiry at which to send a reminder
+    pub days: Vec<i64>,
+    /// Channel to send notifications over
+    pub channel: config::Channel,
+}
+
+/// Retrieves a user's notification preferences, falling back to the defaults
+pub fn preferences(
+    conn: &Connection,
+    notifications: &config::Notifications,
+    user: &str,
+) -> Preferences {
+    conn.query_row(
+        "SELECT days, channel FROM notification_preferences WHERE user = ?1",
+        [user],
+        |row| {
+            let days: String = row.get(0)?;
+            let channel: String = row.get(1)?;
+            Ok(Preferences {
+                days: days
+                    .split(',')
+                    .filter(|d| !d.is_empty())
+                    .map(|d| d.parse().unwrap())
+                    .collect(),
+                channel: config::Channel::from_str(&channel, true).unwrap(),
+            })
+        },
+    )
+    .optional()
+    .unwrap()
+    .unwrap_or_else(|| Preferences {
+        days: notifications.default_days.clone(),
+        channel: notifications.default_channel,
+    })
+}
+
+/// Notification settings of a single workspace, overriding its owner's
+fn workspace_overrides(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+) -> Option<(Option<config::Channel>, Option<String>)> {
+    conn.query_row(
+        "SELECT notify_channel, notify_email FROM workspaces
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3",
+        (filesystem_name, user, name),
+        |row| {
+            let channel: Option<String> = row.get(0)?;
+            Ok((
+                channel.map(|channel| config::Channel::from_str(&channel, true).unwrap()),
+                row.get(1)?,
+            ))
+        },
+    )
+    .optional()
+    .unwrap()
+}
+
+/// Shows or changes the notification settings of one of a user's workspaces
+#[allow(clippy::too_many_arguments)]
+pub fn configure_workspace(
+    conn: &Connection,
+    notifications: &Option<config::Notifications>,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    channel: &Option<config::Channel>,
+    email: &Option<String>,
+    reset: bool,
+) {
+    let Some(notifications) = notifications else {
+        eprintln!("Notifications are not enabled on this system");
+        process::exit(exit_codes::NOTIFICATIONS_DISABLED);
+    };
+    if !is_caller(user) && !is_admin() {
+        eprintln!("You are not allowed to execute this operation");
+        process::exit(exit_codes::INSUFFICIENT_PRIVILEGES);
+    }
+    let Some((mut current_channel, mut current_email)) =
+        workspace_overrides(conn, filesystem_name, user, name)
+    else {
+        eprintln!(
+            "Could not find a matching filesystem={}, user={}, name={}",
+            filesystem_name, user, name
+        );
+        process::exit(exit_codes::UNKNOWN_WORKSPACE);
+    };
+
+    if reset {
+        (current_channel, current_email) = (None, None);
+    } else if channel.is_some() || email.is_some() {
+        if channel.is_some_and(|channel| !notifications.channels.contains(&channel)) {
+            eprintln!("Channel {} is not allowed on this system", channel.unwrap());
+            process::exit(exit_codes::INVALID_ARGUMENT);
+        }
+        current_channel = channel.or(current_channel);
+        current_email = email.clone().or(current_email);
+    }
+    if reset || channel.is_some() || email.is_some() {
+        conn.execute(
+            "UPDATE workspaces
+                SET notify_channel = ?1,
+                    notify_email = ?2
+                WHERE filesystem = ?3
+                    AND user = ?4
+                    AND name = ?5",
+            (
+                current_channel.map(|channel| channel.to_string()),
+                &current_email,
+                filesystem_name,
+                user,
+                name,
+            ),
+        )
+        .unwrap();
+    }
+
+    let defaults = preferences(conn, notifications, user);
+    match current_channel {
+        Some(channel) => println!("channel: {}", channel),
+        None => println!("channel: {} (from your settings)", defaults.channel),
+    }
+    match current_email {
+        Some(email) => println!("email: {}", email),
+        None => println!("email: {} (default)", address(notifications, user)),
+    }
+}
+
+/// Shows or changes a user's notification preferences
+pub fn configure(
+    conn: &Connection,
+    notifications: &Option<config::Notifications>,
+    user: &str,
+    days: &Option<Vec<i64>>,
+    channel: &Option<config::Channel>,
+    reset: bool,
+) {
+    let Some(notifications) = notifications else {
+        eprintln!("Notifications are not enabled on this system");
+        process::exit(exit_codes::NOTIFICATIONS_DISABLED);
+    };
+    if !is_caller(user) && !is_admin() {
+        eprintln!("You are not allowed to execute this operation");
+        process::exit(exit_codes::INSUFFICIENT_PRIVILEGES);
+    }
+
+    if reset {
+        conn.execute(
+            "DELETE FROM notification_preferences WHERE user = ?1",
+            [user],
+        )
+        .unwrap();
+    } else if days.is_some() || channel.is_some() {
+        let current = preferences(conn, notifications, user);
+        let mut days = days.clone().unwrap_or(current.days);
+        days.sort_unstable_by(|a, b| b.cmp(a));
+        days.dedup();
+        let channel = channel.unwrap_or(current.channel);
+
+        if let Some(day) = days
+            .iter()
+            .find(|&&d| d < notifications.min_days || d > notifications.max_days)
+        {
+            eprintln!(
+                "Reminder at {} days is outside the allowed range of {} to {} days",
+                day, notifications.min_days, notifications.max_days
+            );
+            process::exit(exit_codes::INVALID_ARGUMENT);
+        }
+        if days.len() > notifications.max_reminders {
+            eprintln!(
+                "At most {} reminders can be configured",
+                notifications.max_reminders
+            );
+            process::exit(exit_codes::INVALID_ARGUMENT);
+        }
+        if !notifications.channels.contains(&channel) {
+            eprintln!("Channel {} is not allowed on this system", channel);
+            process::exit(exit_codes::INVALID_ARGUMENT);
+        }
+
+        conn.execute(
+            "INSERT INTO notification_preferences (user, days, channel)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT (user) DO UPDATE
+                    SET days = excluded.days, channel = excluded.channel",
+            (
+                user,
+                days.iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                channel.to_string(),
+            ),
+        )
+        .unwrap();
+    }
+
+    let preferences = preferences(conn, notifications, user);
+    println!(
+        "days: {}",
+        preferences
+            .days
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    println!("channel: {}", preferences.channel);
+}
+
+/// Sends a notification concerning one of `user`'s workspaces
+///
+/// The workspace's own settings take precedence over its owner's.  If the
+/// user no longer exists, the notification is sent to the configured admins
+/// instead.
+fn send(
+    conn: &Connection,
+    notifications: &config::Notifications,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    subject: &str,
+    body: &str,
+) -> Result<(), String> {
+    let recipients = if user_exists(user) {
+        let (channel, email) =
+            workspace_overrides(conn, filesystem_name, user, name).unwrap_or_default();
+        match channel.unwrap_or(preferences(conn, notifications, user).channel) {
+            config::Channel::None => return Ok(()),
+            config::Channel::Email => vec![email.unwrap_or(user.to_string())],
+        }
+    } else {
+        notifications.admins.clone()
+    };
+    mail(notifications, &recipients, subject, body)
+}
+
+/// The mail address of a user, or `recipient` itself if it is an address
+fn address(notifications: &config::Notifications, recipient: &str) -> String {
+    match &notifications.mail_domain {
+        Some(domain) if !recipient.contains('@') => format!("{}@{}", recipient, domain),
+        _ => recipient.to_string(),
+    }
+}
+
+/// Mails a notification to each of `recipients`
+fn mail(
+    notifications: &config::Notifications,
+    recipients: &[String],
+    subject: &str,
+    body: &str,
+) -> Result<(), String> {
+    for recipient in recipients {
+        let address = address(notifications, recipient);
+        if dry_run::skip(format_args!("mail \"{}\" to {}", subject, address)) {
+            continue;
+        }
+        let mut child = privileges::as_root(&mut Command::new(&notifications.sendmail))
+            .arg("-t")
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("could not run {}: {}", notifications.sendmail, e))?;
+        let mut stdin = child.stdin.take().unwrap();
+        writeln!(stdin, "To: {}", address).map_err(|e| e.to_string())?;
+        if let Some(from) = &notifications.from {
+            writeln!(stdin, "From: {}", from).map_err(|e| e.to_string())?;
+        }
+        // templates may be in any language
+        writeln!(stdin, "MIME-Version: 1.0").map_err(|e| e.to_string())?;
+        writeln!(stdin, "Content-Type: text/plain; charset=utf-8").map_err(|e| e.to_string())?;
+        writeln!(stdin, "Subject: {}\n\n{}", encode_header(subject), body)
+            .map_err(|e| e.to_string())?;
+        drop(stdin);
+        let status = child.wait().map_err(|e| e.to_string())?;
+        if !status.success() {
+            return Err(format!("{} exited with {}", notifications.sendmail, status));
+        }
+    }
+    Ok(())
+}
+
+const REMINDER_SUBJECT: &str = "Workspace {name} on {filesystem} expires in {days_left} days";
+
+const REMINDER_BODY: &str = "\
+The workspace {name} of {user} on filesystem {filesystem} will expire on {expiry}.  \
+It will then become read-only and be deleted {retention} days later.
+
+To extend it, run:
+
+    {extend_command}
+";
+
+const EXPIRED_SUBJECT: &str = "Workspace {name} on {filesystem} has expired";
+
+const EXPIRED_BODY: &str = "\
+The workspace {name} of {user} on filesystem {filesystem} has expired and is now \
+read-only.  It will be deleted on {deletion}.
+
+To make it writable again, run:
+
+    {extend_command}
+";
+
+/// Substitutes the `{variables}` of a notification template
+///
+/// Unknown variables are left as they are, and substituted values are not
+/// searched for variables again.
+fn render(template: &str, variables: &[(&str, String)]) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            variables
+                .iter()
+                .find(|(variable, _)| *variable == &rest[1..end])
+                .map(|(_, value)| (value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                rendered.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Values of the template variables for one of `user`'s workspaces
+fn variables(
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    expiration_time: &DateTime<Local>,
+) -> Vec<(&'static str, String)> {
+    let volume = crate::to_volume_string(&filesystem.root, user, name);
+    vec![
+        ("user", user.to_string()),
+        ("name", name.to_string()),
+        ("filesystem", filesystem_name.to_string()),
+        ("expiry", expiration_time.format("%F").to_string()),
+        (
+            "deletion",
+            (*expiration_time + filesystem.retention())
+                .format("%F")
+                .to_string(),
+        ),
+        (
+            "days_left",
+            (*expiration_time - Local::now())
+                .num_days()
+                .max(0)
+                .to_string(),
+        ),
+        ("retention", filesystem.retention().num_days().to_string()),
+        (
+            "mountpoint",
+            zfs::get_path_property(&volume, "mountpoint")
+                .map(|mountpoint| mountpoint.display().to_string())
+                .unwrap_or_default(),
+        ),
+        (
+            "extend_command",
+            format!(
+                "workspaces extend -f {} -d <DAYS> {}",
+                filesystem_name, name
+            ),
+        ),
+    ]
+}
+
+/// Encodes a header's value as an RFC 2047 encoded word if it is not ASCII
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+    let encoded: String = value
+        .bytes()
+        .map(|b| match b {
+            b' ' => "_".to_string(),
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => (b as char).to_string(),
+            _ => format!("={:02X}", b),
+        })
+        .collect();
+    format!("=?utf-8?Q?{}?=", encoded)
+}
+
+/// Notifies the owner of a workspace that it has expired
+pub fn expired(
+    conn: &Connection,
+    notifications: &config::Notifications,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    expiration_time: &DateTime<Local>,
+) -> Result<(), String> {
+    let templates = &notifications.templates;
+    let variables = variables(filesystem_name, filesystem, user, name, expiration_time);
+    send(
+        conn,
+        notifications,
+        filesystem_name,
+        user,
+        name,
+        &render(
+            templates
+                .expired_subject
+                .as_deref()
+                .unwrap_or(EXPIRED_SUBJECT),
+            &variables,
+        ),
+        &render(
+            templates.expired_body.as_deref().unwrap_or(EXPIRED_BODY),
+            &variables,
+        ),
+    )
+}
+
+/// Alerts the owner of a workspace and the admins about an anomalous change
+/// in the workspace's size
+pub fn anomaly(
+    conn: &Connection,
+    notifications: &config::Notifications,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    anomaly: &sizes::Anomaly,
+) -> Result<(), String> {
+    let subject = format!(
+        "Unexpected size change of workspace {} on {}",
+        name, filesystem_name
+    );
+    let body = format!(
+        "The workspace {name} of {user} on filesystem {filesystem_name} {anomaly} within \
+        a day.  If this was not intended, please check for runaway jobs or accidental \
+        deletions.\n",
+    );
+    send(
+        conn,
+        notifications,
+        filesystem_name,
+        user,
+        name,
+        &subject,
+        &body,
+    )?;
+    if user_exists(user) {
+        mail(notifications, &notifications.admins, &subject, &body)?;
+    }
+    Ok(())
+}
+
+/// Alerts the admins and the group's workspace owners about a group having
+/// exceeded its storage share
+pub fn share_exceeded(
+    notifications: &config::Notifications,
+    usage: &shares::Usage,
+) -> Result<(), String> {
+    let subject = format!("Group {} has exceeded its storage share", usage.group);
+    let body = format!(
+        "The workspaces of group {} use {}, which exceeds its share of {}.  Please \
+        delete data which is no longer needed or contact the admins about purchasing \
+        more storage.\n",
+        usage.group,
+        units::format_size(usage.used, false),
+        units::format_size(usage.share, false)
+    );
+    mail(notifications, &usage.owners, &subject, &body)?;
+    mail(notifications, &notifications.admins, &subject, &body)
+}
+
+/// Alerts the admins about a workspace whose permissions could repeatedly
+/// not be set up
+pub fn permissions_failed(
+    notifications: &config::Notifications,
+    volume: &str,
+    error: &str,
+) -> Result<(), String> {
+    let subject = format!("Could not set up permissions of {}", volume);
+    let body = format!(
+        "The permissions of the new workspace {} could repeatedly not be set up: {}\n\n\
+        Until this is fixed, its owner cannot use it.  Retries continue in the background.\n",
+        volume, error
+    );
+    mail(notifications, &notifications.admins, &subject, &body)
+}
+
+/// Sends reminders for all workspaces about to expire
+///
+/// Each user is reminded once for every number of days in their preferences.
+/// If several reminders are due at once, e.g. because clean wasn't run for a
+/// while, only a single one is sent.  Returns the reminded volumes together
+/// with the days left until their expiry.
+pub fn remind(
+    conn: &Connection,
+    notifications: &config::Notifications,
+    filesystems: &HashMap<String, config::Filesystem>,
+) -> Vec<(String, i64)> {
+    // forget about reminders for workspaces which have since been extended or deleted
+    conn.execute(
+        "DELETE FROM reminders_sent
+            WHERE NOT EXISTS (
+                SELECT 1 FROM workspaces
+                    WHERE workspaces.filesystem = reminders_sent.filesystem
+                        AND workspaces.user = reminders_sent.user
+                        AND workspaces.name = reminders_sent.name
+                        AND workspaces.expiration_time = reminders_sent.expiration_time
+            )",
+        (),
+    )
+    .unwrap();
+
+    let mut reminded = vec![];
+    let mut statement = conn
+        .prepare(
+            "SELECT filesystem, user, name, expiration_time
+                FROM workspaces
+                WHERE expiration_time > ?1
+                    AND class = 'scratch'",
+        )
+        .unwrap();
+    let mut rows = statement
+        .query([db::Timestamp::from(Local::now())])
+        .unwrap();
+    while let Some(row) = rows.next().unwrap() {
+        let filesystem_name: String = row.get(0).unwrap();
+        let user: String = row.get(1).unwrap();
+        let name: String = row.get(2).unwrap();
+        let expiration_time: db::Timestamp = row.get(3).unwrap();
+        let Some(filesystem) = filesystems.get(&filesystem_name) else {
+            continue;
+        };
+
+        let days_left = (DateTime::<Local>::from(expiration_time) - Local::now()).num_days();
+        let mut due: Vec<i64> = vec![];
+        for days in preferences(conn, notifications, &user).days {
+            let already_sent = conn
+                .query_row(
+                    "SELECT 1 FROM reminders_sent
+                        WHERE filesystem = ?1
+                            AND user = ?2
+                            AND name = ?3
+                            AND expiration_time = ?4
+                            AND days = ?5",
+                    (&filesystem_name, &user, &name, expiration_time, days),
+                    |_| Ok(()),
+                )
+                .optional()
+                .unwrap()
+                .is_some();
+            if days_left <= days && !already_sent {
+                due.push(days);
+            }
+        }
+        if due.is_empty() {
+            continue;
+        }
+
+        let volume = crate::to_volume_string(&filesystem.root, &user, &name);
+        let templates = &notifications.templates;
+        let variables = variables(
+            &filesystem_name,
+            filesystem,
+            &user,
+            &name,
+            &expiration_time.into(),
+        );
+        if let Err(e) = send(
+            conn,
+            notifications,
+            &filesystem_name,
+            &user,
+            &name,
+            &render(
+                templates
+                    .reminder_subject
+                    .as_deref()
+                    .unwrap_or(REMINDER_SUBJECT),
+                &variables,
+            ),
+            &render(
+                templates.reminder_body.as_deref().unwrap_or(REMINDER_BODY),
+                &variables,
+            ),
+        ) {
+            eprintln!("Failed to send reminder for {}: {}", volume, e);
+            continue;
+        }
+        for days in due {
+            conn.execute(
+                "INSERT INTO reminders_sent (filesystem, user, name, expiration_time, days)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                (&filesystem_name, &user, &name, expiration_time, days),
+            )
+            .unwrap();
+        }
+        reminded.push((volume, days_left));
+    }
+    reminded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_known_variables() {
+        let variables = [("user", "alice".to_string()), ("name", "sim".to_string())];
+        assert_eq!(
+            render("Dear {user}, {name} of {user} expired.", &variables),
+            "Dear alice, sim of alice expired."
+        );
+    }
+
+    #[test]
+    fn leaves_unknown_variables_and_braces_alone() {
+        let variables = [("name", "sim".to_string())];
+        assert_eq!(render("{unknown} {name}", &variables), "{unknown} sim");
+        assert_eq!(render("{{name}} {", &variables), "{sim} {");
+        assert_eq!(render("} {name", &variables), "} {name");
+    }
+
+    #[test]
+    fn does_not_render_substituted_values() {
+        let variables = [
+            ("name", "{user}".to_string()),
+            ("user", "alice".to_string()),
+        ];
+        assert_eq!(render("{name}", &variables), "{user}");
+    }
+
+    #[test]
+    fn keeps_ascii_headers() {
+        assert_eq!(
+            encode_header("Workspace sim expires soon"),
+            "Workspace sim expires soon"
+        );
+    }
+
+    #[test]
+    fn encodes_non_ascii_headers() {
+        assert_eq!(
+            encode_header("Arbeitsbereich läuft ab"),
+            "=?utf-8?Q?Arbeitsbereich_l=C3=A4uft_ab?="
+        );
+        assert_eq!(encode_header("ü=?_"), "=?utf-8?Q?=C3=BC=3D=3F=5F?=");
+    }
+}
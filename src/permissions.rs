@@ -0,0 +1,175 @@
+//! Setting up the ownership and permissions of new workspaces
+//!
+//! If this fails, e.g. because NSS or automounted directories are temporarily
+//! unavailable, the workspace is created nonetheless and the setup is retried
+//! by `workspaces daemon`.
+
+use crate::db::Connection;
+use crate::{chown, cli, config, dry_run, error::Error, notify, privileges, to_volume_string, zfs};
+use std::{
+    collections::HashMap, fs, os::unix::prelude::PermissionsExt, path::Path, process::Command,
+};
+
+/// Number of failed attempts after which the admins are notified
+const ALERT_AFTER_ATTEMPTS: u32 = 12;
+
+/// Gives a workspace to its owner and sets its permissions
+pub fn apply(
+    volume: &str,
+    mountpoint: &Path,
+    user: &str,
+    group: &str,
+    class: cli::WorkspaceClass,
+    collaborative: bool,
+) -> Result<(), Error> {
+    if dry_run::skip(format_args!(
+        "setup of the ownership and permissions of {}",
+        mountpoint.display()
+    )) {
+        return Ok(());
+    }
+    let _root = privileges::raise();
+    chown(user, group, mountpoint)?;
+
+    let mut permissions = fs::metadata(mountpoint)?.permissions();
+    // setgid, so files created within inherit the workspace's group
+    let mode = if collaborative { 0o2770 } else { 0o750 };
+    // reference data is readable by everyone
+    permissions.set_mode(match class {
+        cli::WorkspaceClass::Scratch => mode,
+        cli::WorkspaceClass::Reference => mode | 0o005,
+    });
+    fs::set_permissions(mountpoint, permissions)?;
+    // and shared by everyone, so nobody may change it
+    if class == cli::WorkspaceClass::Reference {
+        zfs::set_property(volume, "readonly", "on")?;
+    }
+
+    if collaborative {
+        zfs::set_property(volume, "acltype", "posixacl")?;
+        let status = Command::new("setfacl")
+            .args(["-d", "-m", "u::rwx,g::rwx,o::---"])
+            .arg(mountpoint)
+            .status()?;
+        if !status.success() {
+            return Err(Error::Command(
+                "failed to set default ACLs on dataset".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Queues the permission setup of a workspace to be retried later
+pub fn defer(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    group: &str,
+    collaborative: bool,
+    error: &str,
+) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO pending_permissions
+            (filesystem, user, name, group_name, collaborative, attempts, last_error)
+            VALUES (?1, ?2, ?3, ?4, ?5, 1, ?6)
+            ON CONFLICT (filesystem, user, name) DO UPDATE
+                SET group_name = excluded.group_name,
+                    collaborative = excluded.collaborative,
+                    attempts = 1,
+                    last_error = excluded.last_error",
+        (filesystem_name, user, name, group, collaborative, error),
+    )?;
+    Ok(())
+}
+
+/// Retries all queued permission setups
+///
+/// Admins are notified about workspaces whose setup keeps failing.
+pub fn retry(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    notifications: &Option<config::Notifications>,
+) -> Result<(), Error> {
+    // forget about workspaces which have since been deleted or renamed
+    conn.execute(
+        "DELETE FROM pending_permissions
+            WHERE NOT EXISTS (
+                SELECT 1 FROM workspaces
+                    WHERE workspaces.filesystem = pending_permissions.filesystem
+                        AND workspaces.user = pending_permissions.user
+                        AND workspaces.name = pending_permissions.name
+            )",
+        (),
+    )?;
+
+    let mut statement = conn.prepare(
+        "SELECT p.filesystem, p.user, p.name, p.group_name, p.collaborative, p.attempts, w.class
+            FROM pending_permissions p
+            JOIN workspaces w USING (filesystem, user, name)",
+    )?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let filesystem_name: String = row.get(0)?;
+        let user: String = row.get(1)?;
+        let name: String = row.get(2)?;
+        let group: String = row.get(3)?;
+        let collaborative: bool = row.get(4)?;
+        let attempts: u32 = row.get(5)?;
+        let class = if row.get::<_, String>(6)? == cli::WorkspaceClass::Reference.to_string() {
+            cli::WorkspaceClass::Reference
+        } else {
+            cli::WorkspaceClass::Scratch
+        };
+
+        let Some(filesystem) = filesystems.get(&filesystem_name) else {
+            continue;
+        };
+        let volume = to_volume_string(&filesystem.root, &user, &name);
+        let result = zfs::get_path_property(&volume, "mountpoint")
+            .map_err(Error::from)
+            .and_then(|mountpoint| {
+                apply(&volume, &mountpoint, &user, &group, class, collaborative)
+            });
+        match result {
+            Ok(()) => {
+                conn.execute(
+                    "DELETE FROM pending_permissions
+                        WHERE filesystem = ?1
+                            AND user = ?2
+                            AND name = ?3",
+                    (&filesystem_name, &user, &name),
+                )?;
+                println!("action=permissions volume={}", volume);
+            }
+            Err(e) => {
+                conn.execute(
+                    "UPDATE pending_permissions
+                        SET attempts = attempts + 1,
+                            last_error = ?1
+                        WHERE filesystem = ?2
+                            AND user = ?3
+                            AND name = ?4",
+                    (e.to_string(), &filesystem_name, &user, &name),
+                )?;
+                eprintln!(
+                    "action=permissions volume={} attempts={} error=\"{}\"",
+                    volume,
+                    attempts + 1,
+                    e
+                );
+                if attempts + 1 == ALERT_AFTER_ATTEMPTS {
+                    if let Some(notifications) = notifications {
+                        if let Err(e) =
+                            notify::permissions_failed(notifications, &volume, &e.to_string())
+                        {
+                            eprintln!("Failed to notify admins about {}: {}", volume, e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
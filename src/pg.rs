@@ -0,0 +1,369 @@
+//! The PostgreSQL database backend, which lets many hosts share one database
+//!
+//! Queries are written for SQLite and translated on the fly, see
+//! [`translate`].  As in SQLite, there is only ever one writing transaction
+//! at a time, which is ensured by an advisory lock.  Connections are not
+//! encrypted, so the database should be reached through a Unix socket or a
+//! trusted network.
+
+use crate::{
+    db::{Backend, Error, Result, Row, Value},
+    dry_run,
+};
+use bytes::BytesMut;
+use postgres::{
+    types::{to_sql_checked, FromSql, IsNull, ToSql, Type},
+    Client, NoTls,
+};
+use std::{cell::RefCell, path::Path, time::Duration};
+
+/// Key of the advisory lock held by writing transactions
+const WRITE_LOCK: i64 = 0x776f_726b_7370_6163;
+
+type BoxError = Box<dyn std::error::Error + Sync + Send>;
+
+pub struct Postgres {
+    client: RefCell<Client>,
+    url: String,
+    timeout: Duration,
+    /// Whether everything happens within a transaction which is never committed
+    dry_run: bool,
+}
+
+impl Postgres {
+    /// Connects to the database at `url`, waiting up to `timeout` for locks
+    pub fn open(url: &str, timeout: Duration) -> Result<Self> {
+        let mut client = Client::connect(url, NoTls)?;
+        client.batch_execute(&format!("SET lock_timeout = {}", timeout.as_millis()))?;
+        Ok(Postgres {
+            client: RefCell::new(client),
+            url: url.to_string(),
+            timeout,
+            dry_run: false,
+        })
+    }
+}
+
+impl Backend for Postgres {
+    fn execute(&self, sql: &str, params: &[Value]) -> Result<usize> {
+        if self.dry_run {
+            dry_run::print_statement(&expand(sql, params));
+        }
+        let params: Vec<_> = params.iter().map(Param).collect();
+        let params: Vec<_> = params.iter().map(|p| p as _).collect();
+        let changed = self.client.borrow_mut().execute(&translate(sql), &params)?;
+        Ok(changed as usize)
+    }
+
+    fn execute_batch(&self, sql: &str) -> Result<()> {
+        if self.dry_run {
+            dry_run::print_statement(sql);
+        }
+        Ok(self.client.borrow_mut().batch_execute(&translate(sql))?)
+    }
+
+    fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<Row>> {
+        let params: Vec<_> = params.iter().map(Param).collect();
+        let params: Vec<_> = params.iter().map(|p| p as _).collect();
+        let rows = self.client.borrow_mut().query(&translate(sql), &params)?;
+        rows.iter()
+            .map(|row| {
+                let values = (0..row.len())
+                    .map(|i| row.try_get::<_, Column>(i).map(|column| column.0))
+                    .collect::<Result<_, _>>()?;
+                Ok(Row(values))
+            })
+            .collect()
+    }
+
+    fn begin(&self) -> Result<()> {
+        let mut client = self.client.borrow_mut();
+        if self.dry_run {
+            return Ok(client.batch_execute("SAVEPOINT dry_run")?);
+        }
+        client.batch_execute("BEGIN")?;
+        if let Err(e) = client.execute("SELECT pg_advisory_xact_lock($1)", &[&WRITE_LOCK]) {
+            client.batch_execute("ROLLBACK")?;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<()> {
+        let sql = match self.dry_run {
+            true => "RELEASE SAVEPOINT dry_run",
+            false => "COMMIT",
+        };
+        Ok(self.client.borrow_mut().batch_execute(sql)?)
+    }
+
+    fn rollback(&self) -> Result<()> {
+        let sql = match self.dry_run {
+            true => "ROLLBACK TO SAVEPOINT dry_run; RELEASE SAVEPOINT dry_run",
+            false => "ROLLBACK",
+        };
+        Ok(self.client.borrow_mut().batch_execute(sql)?)
+    }
+
+    fn version(&self) -> Result<usize> {
+        let mut client = self.client.borrow_mut();
+        // queried first, as a failed query would abort the current transaction
+        let exists: bool = client
+            .query_one("SELECT to_regclass('schema_version') IS NOT NULL", &[])?
+            .try_get(0)?;
+        if !exists {
+            return Ok(0);
+        }
+        let version: Option<i64> = client
+            .query_one("SELECT MAX(version) FROM schema_version", &[])?
+            .try_get(0)?;
+        Ok(version.unwrap_or(0) as usize)
+    }
+
+    fn set_version(&self, version: usize) -> Result<()> {
+        let mut client = self.client.borrow_mut();
+        client.batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_version (version BIGINT NOT NULL);
+            DELETE FROM schema_version;",
+        )?;
+        client.execute(
+            "INSERT INTO schema_version (version) VALUES ($1)",
+            &[&(version as i64)],
+        )?;
+        Ok(())
+    }
+
+    fn initialize(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Finds no problems, as PostgreSQL checks the consistency of its data itself
+    fn integrity_check(&self) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    fn backup(&self, _: &Path) -> Result<()> {
+        Err(Error::Unsupported(
+            "PostgreSQL databases are backed up with pg_dump",
+        ))
+    }
+
+    fn restore(&mut self, _: &Path) -> Result<()> {
+        Err(Error::Unsupported(
+            "PostgreSQL databases are restored with pg_restore",
+        ))
+    }
+
+    /// Connects once more, making all changes in a transaction which is
+    /// rolled back when the connection is closed
+    fn dry_run(&self) -> Result<Box<dyn Backend>> {
+        let mut copy = Postgres::open(&self.url, self.timeout)?;
+        copy.client.get_mut().batch_execute("BEGIN")?;
+        copy.dry_run = true;
+        Ok(Box::new(copy))
+    }
+}
+
+/// Translates SQL written for SQLite into PostgreSQL's dialect
+///
+/// Parameters are numbered `$1` instead of `?1`, the column `user` is quoted
+/// as it is a reserved word in PostgreSQL, and the column types used in
+/// migrations are replaced by their PostgreSQL equivalents.
+fn translate(sql: &str) -> String {
+    let mut result = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                result.push(c);
+                for quoted in chars.by_ref() {
+                    result.push(quoted);
+                    if quoted == c {
+                        break;
+                    }
+                }
+            }
+            '?' => result.push('$'),
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut word = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                result.push_str(match word.as_str() {
+                    "user" => "\"user\"",
+                    "DATETIME" => "TEXT",
+                    "INTEGER" => "BIGINT",
+                    "BLOB" => "BYTEA",
+                    word => word,
+                });
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// `sql` with its parameters filled in, for printing in dry runs
+fn expand(sql: &str, params: &[Value]) -> String {
+    let mut sql = sql.to_string();
+    // backwards, so `?1` does not match the start of `?10`
+    for (i, param) in params.iter().enumerate().rev() {
+        let literal = match param {
+            Value::Null => "NULL".to_string(),
+            Value::Integer(n) => n.to_string(),
+            Value::Real(x) => x.to_string(),
+            Value::Text(text) => format!("'{}'", text.replace('\'', "''")),
+            Value::Blob(bytes) => format!(
+                "X'{}'",
+                bytes
+                    .iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect::<String>()
+            ),
+        };
+        sql = sql.replace(&format!("?{}", i + 1), &literal);
+    }
+    sql
+}
+
+/// A parameter, converted to whatever type PostgreSQL infers for it
+#[derive(Debug)]
+struct Param<'a>(&'a Value);
+
+impl ToSql for Param<'_> {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, BoxError> {
+        match (self.0, ty) {
+            (Value::Null, _) => Ok(IsNull::Yes),
+            (Value::Integer(n), &Type::BOOL) => (*n != 0).to_sql(ty, out),
+            (Value::Integer(n), &Type::INT2) => i16::try_from(*n)?.to_sql(ty, out),
+            (Value::Integer(n), &Type::INT4) => i32::try_from(*n)?.to_sql(ty, out),
+            (Value::Integer(n), &Type::INT8) => n.to_sql(ty, out),
+            (Value::Integer(n), &Type::FLOAT8) => (*n as f64).to_sql(ty, out),
+            (Value::Integer(n), _) => n.to_string().to_sql(ty, out),
+            (Value::Real(x), &Type::FLOAT8) => x.to_sql(ty, out),
+            (Value::Real(x), &Type::FLOAT4) => (*x as f32).to_sql(ty, out),
+            (Value::Real(x), _) => x.to_string().to_sql(ty, out),
+            (Value::Text(text), _) => text.to_sql(ty, out),
+            (Value::Blob(bytes), _) => bytes.to_sql(ty, out),
+        }
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+/// A column of a result, of whatever type PostgreSQL returns
+struct Column(Value);
+
+impl<'a> FromSql<'a> for Column {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, BoxError> {
+        let value = match *ty {
+            Type::BOOL => Value::Integer(i64::from(bool::from_sql(ty, raw)?)),
+            Type::INT2 => Value::Integer(i16::from_sql(ty, raw)?.into()),
+            Type::INT4 => Value::Integer(i32::from_sql(ty, raw)?.into()),
+            Type::INT8 => Value::Integer(i64::from_sql(ty, raw)?),
+            Type::FLOAT4 => Value::Real(f32::from_sql(ty, raw)?.into()),
+            Type::FLOAT8 => Value::Real(f64::from_sql(ty, raw)?),
+            // the result of `SUM` and `AVG`
+            Type::NUMERIC => numeric(raw)?,
+            Type::BYTEA => Value::Blob(raw.to_vec()),
+            _ => Value::Text(String::from_sql(ty, raw)?),
+        };
+        Ok(Column(value))
+    }
+
+    fn from_sql_null(_: &Type) -> Result<Self, BoxError> {
+        Ok(Column(Value::Null))
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+}
+
+/// Decodes a `NUMERIC` in PostgreSQL's binary format
+///
+/// Whole numbers become integers, as they would be in SQLite.
+fn numeric(raw: &[u8]) -> Result<Value, BoxError> {
+    let words: Vec<i16> = raw
+        .chunks_exact(2)
+        .map(|word| i16::from_be_bytes([word[0], word[1]]))
+        .collect();
+    let [count, weight, sign, _scale, digits @ ..] = words.as_slice() else {
+        return Err("truncated NUMERIC".into());
+    };
+    if *sign as u16 == 0xc000 || digits.len() != *count as usize {
+        return Err("invalid NUMERIC".into());
+    }
+    // the digits are in base 10000, with the first one at 10000^weight
+    let mut value = 0.;
+    for (i, digit) in digits.iter().enumerate() {
+        value += f64::from(*digit) * 10_000f64.powi(i32::from(*weight) - i as i32);
+    }
+    if *sign != 0 {
+        value = -value;
+    }
+    if value.fract() == 0. && value.abs() < 2f64.powi(53) {
+        Ok(Value::Integer(value as i64))
+    } else {
+        Ok(Value::Real(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_to_postgres() {
+        assert_eq!(
+            translate("SELECT name FROM workspaces WHERE user = ?1 AND name = 'user?'"),
+            "SELECT name FROM workspaces WHERE \"user\" = $1 AND name = 'user?'"
+        );
+        assert_eq!(
+            translate("CREATE TABLE t (users INTEGER, time DATETIME, path BLOB)"),
+            "CREATE TABLE t (users BIGINT, time TEXT, path BYTEA)"
+        );
+    }
+
+    #[test]
+    fn expand_parameters() {
+        assert_eq!(
+            expand(
+                "UPDATE t SET a = ?1, b = ?2 WHERE c = ?10",
+                &[
+                    Value::Text("it's".to_string()),
+                    Value::Null,
+                    Value::Integer(3),
+                    Value::Integer(4),
+                    Value::Integer(5),
+                    Value::Integer(6),
+                    Value::Integer(7),
+                    Value::Integer(8),
+                    Value::Integer(9),
+                    Value::Blob(vec![0xab, 1]),
+                ],
+            ),
+            "UPDATE t SET a = 'it''s', b = NULL WHERE c = X'AB01'"
+        );
+    }
+
+    #[test]
+    fn decode_numeric() {
+        // 12345678, i.e. 1234 * 10000 + 5678
+        let raw = [0, 2, 0, 1, 0, 0, 0, 0, 0x04, 0xd2, 0x16, 0x2e];
+        assert_eq!(numeric(&raw).unwrap(), Value::Integer(12345678));
+        // -1.5, i.e. -(1 + 5000 / 10000)
+        let raw = [0, 2, 0, 0, 0x40, 0, 0, 1, 0, 1, 0x13, 0x88];
+        assert_eq!(numeric(&raw).unwrap(), Value::Real(-1.5));
+        // 0 has no digits at all
+        assert_eq!(numeric(&[0; 8]).unwrap(), Value::Integer(0));
+    }
+}
@@ -0,0 +1,142 @@
+use crate::db::Connection;
+use crate::{cli, config, groups, pool, privileges, zfs};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+/// A workspace creation request, as passed to the placement script
+#[derive(Serialize)]
+pub struct Request<'a> {
+    pub user: &'a str,
+    pub name: &'a str,
+    /// Requested duration, which is not given for reference workspaces
+    pub duration_days: Option<i64>,
+    pub class: String,
+    /// Expected size of the workspace in GiB, if the user gave one
+    pub size_hint: Option<u64>,
+    /// Groups the user is a member of
+    pub groups: Vec<String>,
+    /// Filesystems new workspaces may be created on
+    pub filesystems: Vec<&'a str>,
+}
+
+impl<'a> Request<'a> {
+    pub fn new(
+        user: &'a str,
+        name: &'a str,
+        duration: &Option<chrono::Duration>,
+        class: cli::WorkspaceClass,
+        size_hint: Option<u64>,
+        filesystems: &'a HashMap<String, config::Filesystem>,
+    ) -> Self {
+        let groups = groups::lookup(user);
+        Request {
+            user,
+            name,
+            duration_days: duration.map(|d| d.num_days()),
+            class: class.to_string(),
+            size_hint,
+            groups,
+            filesystems: filesystems
+                .iter()
+                .filter(|(_, fs)| !fs.disabled && !fs.retired)
+                .map(|(name, _)| name.as_str())
+                .collect(),
+        }
+    }
+}
+
+/// The answer of the placement script
+#[derive(Deserialize)]
+struct Decision {
+    filesystem: String,
+}
+
+/// Asks the site's placement script which filesystem to create a workspace on
+///
+/// The script receives the request as JSON on its stdin and has to print a
+/// JSON object of the form `{"filesystem": "<name>"}`.  If the script fails,
+/// a warning is printed and `None` is returned, so the usual default applies.
+pub fn choose(script: &Path, request: &Request) -> Option<String> {
+    let run = || -> Result<String, Box<dyn std::error::Error>> {
+        let mut child = privileges::as_root(&mut Command::new(script))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(serde_json::to_string(request)?.as_bytes())?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(format!("exited with {}", output.status).into());
+        }
+        let decision: Decision = serde_json::from_slice(&output.stdout)?;
+        Ok(decision.filesystem)
+    };
+    match run() {
+        Ok(filesystem) => Some(filesystem),
+        Err(e) => {
+            eprintln!("Placement script {:?} failed: {}", script, e);
+            None
+        }
+    }
+}
+
+/// Chooses the filesystem for a new workspace according to `policy`
+///
+/// Returns `None` if no filesystem is available, so the usual default applies.
+pub fn by_policy(
+    conn: &Connection,
+    policy: config::FilesystemPolicy,
+    filesystems: &HashMap<String, config::Filesystem>,
+    parallelism: usize,
+) -> Option<String> {
+    let mut candidates: Vec<&String> = filesystems
+        .iter()
+        .filter(|(_, fs)| !fs.disabled && !fs.retired)
+        .map(|(name, _)| name)
+        .collect();
+    // so ties are broken the same way every time
+    candidates.sort();
+
+    match policy {
+        config::FilesystemPolicy::MostFree => {
+            let available = pool::map(&candidates, parallelism, |name| {
+                zfs::get_property::<u64>(&filesystems[*name].root, "available")
+            });
+            candidates
+                .into_iter()
+                .zip(available)
+                .filter_map(|(name, available)| match available {
+                    Ok(available) => Some((name, available)),
+                    Err(e) => {
+                        eprintln!("Could not get the free space of {}: {}", name, e);
+                        None
+                    }
+                })
+                .rev()
+                .max_by_key(|(_, available)| *available)
+                .map(|(name, _)| name.clone())
+        }
+        config::FilesystemPolicy::LeastWorkspaces => {
+            let mut statement = conn
+                .prepare("SELECT filesystem, COUNT(*) FROM workspaces GROUP BY filesystem")
+                .ok()?;
+            let counts: HashMap<String, usize> = statement
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .ok()?
+                .collect::<Result<_, _>>()
+                .ok()?;
+            candidates
+                .into_iter()
+                .min_by_key(|name| counts.get(*name).copied().unwrap_or(0))
+                .cloned()
+        }
+    }
+}
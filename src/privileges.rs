@@ -0,0 +1,116 @@
+//! Privilege handling for installations setuid-root
+//!
+//! Such an installation runs with the environment of an unprivileged caller,
+//! so the environment is replaced by a minimal one, with a `PATH` containing
+//! only system directories.  Once the database is open, root privileges are
+//! dropped and only raised again for the operations which need them, such as
+//! changing a workspace's owner.  External commands like zfs run as root
+//! entirely, as e.g. shells drop privileges if their real uid is not root.
+//! Without the setuid bit, e.g. when run by root or by `workspaces serve`,
+//! all of this does nothing.
+
+use std::{
+    env, io,
+    os::unix::process::CommandExt,
+    process::Command,
+    sync::{Mutex, OnceLock},
+};
+use users::{get_current_uid, get_effective_uid};
+
+/// `PATH` external binaries are looked up in
+const SAFE_PATH: &str = "/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Environment variables kept from the caller, all of which only affect output
+const KEPT_VARS: &[&str] = &["TERM", "NO_COLOR", "RUST_LOG", "TZ"];
+
+/// The real uid, if running setuid-root on behalf of another user
+static SETUID_CALLER: OnceLock<Option<u32>> = OnceLock::new();
+
+/// Number of [`Root`] guards alive, as the effective uid is shared by all threads
+static RAISED: Mutex<usize> = Mutex::new(0);
+
+fn setuid_caller() -> Option<u32> {
+    *SETUID_CALLER.get_or_init(|| {
+        let uid = get_current_uid();
+        (uid != 0 && get_effective_uid() == 0).then_some(uid)
+    })
+}
+
+/// Replaces the caller's environment if running setuid-root
+///
+/// Has to be called before any other threads are started.
+pub fn sanitize_environment() {
+    if setuid_caller().is_none() {
+        return;
+    }
+    for (var, _) in env::vars_os() {
+        if !KEPT_VARS.iter().any(|kept| var == *kept) {
+            env::remove_var(var);
+        }
+    }
+    env::set_var("PATH", SAFE_PATH);
+}
+
+/// Continues with the caller's privileges if running setuid-root
+pub fn lower() {
+    if let Some(uid) = setuid_caller() {
+        set_effective_uid(uid);
+    }
+}
+
+/// Root privileges, held until the guard is dropped
+#[must_use]
+pub struct Root(());
+
+/// Regains root privileges for an operation which needs them
+pub fn raise() -> Root {
+    if setuid_caller().is_some() {
+        let mut raised = RAISED.lock().unwrap();
+        if *raised == 0 {
+            set_effective_uid(0);
+        }
+        *raised += 1;
+    }
+    Root(())
+}
+
+impl Drop for Root {
+    fn drop(&mut self) {
+        if let Some(uid) = setuid_caller() {
+            let mut raised = RAISED.lock().unwrap();
+            *raised -= 1;
+            if *raised == 0 {
+                set_effective_uid(uid);
+            }
+        }
+    }
+}
+
+/// Makes `command` run as root, even while this process's privileges are lowered
+pub fn as_root(command: &mut Command) -> &mut Command {
+    if setuid_caller().is_some() {
+        // SAFETY: setresuid is async-signal-safe and the closure allocates nothing
+        unsafe {
+            command.pre_exec(|| {
+                // allowed to unprivileged processes, as the saved uid is root
+                if libc::setresuid(0, 0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    command
+}
+
+fn set_effective_uid(uid: u32) {
+    // SAFETY: seteuid has no memory safety preconditions
+    if unsafe { libc::seteuid(uid) } != 0 {
+        // continuing with the wrong privileges would be worse
+        panic!(
+            "could not change effective uid to {}: {}",
+            uid,
+            io::Error::last_os_error()
+        );
+    }
+}
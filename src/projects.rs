@@ -0,0 +1,175 @@
+//! Workspaces owned by projects rather than individual users
+
+use crate::db::Connection;
+use crate::{config, error::Error, groups, table, to_volume_string, units, zfs};
+use prettytable::{
+    color,
+    format::{Alignment, FormatBuilder},
+    Attr, Cell, Row, Table,
+};
+use std::collections::{BTreeMap, HashMap};
+
+/// Whether `user` belongs to `project`, either explicitly or through its group
+pub fn is_member(conn: &Connection, user: &str, project: &config::Project) -> bool {
+    project.members.iter().any(|member| member == user)
+        || project
+            .group
+            .as_ref()
+            .is_some_and(|group| groups::is_member(conn, user, group))
+}
+
+/// How much of its quota a project consumes
+#[derive(Debug)]
+pub struct Usage {
+    pub project: String,
+    /// Granted capacity in bytes, if limited
+    pub quota: Option<u64>,
+    /// Bytes used by the project's workspaces
+    pub used: u64,
+    pub workspaces: usize,
+}
+
+impl Usage {
+    pub fn is_exceeded(&self) -> bool {
+        self.quota.is_some_and(|quota| self.used >= quota)
+    }
+}
+
+/// Sums up the sizes of all workspaces by the projects owning them
+///
+/// Sizes are taken from the cache refreshed by `clean`.  Projects which were
+/// removed from the configuration, but still own workspaces, are included
+/// without a quota.
+pub fn usage(
+    conn: &Connection,
+    projects: &HashMap<String, config::Project>,
+) -> Result<Vec<Usage>, Error> {
+    let mut usages: BTreeMap<String, Usage> = projects
+        .iter()
+        .map(|(name, project)| {
+            let usage = Usage {
+                project: name.clone(),
+                quota: project.quota.map(|quota| quota << 30),
+                used: 0,
+                workspaces: 0,
+            };
+            (name.clone(), usage)
+        })
+        .collect();
+    let mut statement = conn.prepare(
+        "SELECT project, COUNT(*), SUM(COALESCE(cached_size, 0)) FROM workspaces
+            WHERE project IS NOT NULL
+            GROUP BY project",
+    )?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let project: String = row.get(0)?;
+        let usage = usages.entry(project.clone()).or_insert(Usage {
+            project,
+            quota: None,
+            used: 0,
+            workspaces: 0,
+        });
+        usage.workspaces = row.get(1)?;
+        usage.used = row.get(2)?;
+    }
+    Ok(usages.into_values().collect())
+}
+
+/// Fails if `project` has used up its quota
+///
+/// The current sizes are queried from zfs, as the cache refreshed by `clean`
+/// would let a project grow past its quota between runs.  Only workspaces on
+/// other hosts are counted with their cached size.
+pub fn check_quota(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    name: &str,
+    project: &config::Project,
+) -> Result<(), Error> {
+    let Some(quota) = project.quota else {
+        return Ok(());
+    };
+    let mut statement = conn.prepare(
+        "SELECT filesystem, user, name, COALESCE(cached_size, 0) FROM workspaces
+            WHERE project = ?1
+                AND archive_url IS NULL",
+    )?;
+    let mut rows = statement.query([name])?;
+    let mut datasets: HashMap<&str, HashMap<String, zfs::Dataset>> = HashMap::new();
+    let mut used: u64 = 0;
+    while let Some(row) = rows.next()? {
+        let filesystem_name: String = row.get(0)?;
+        let Some((filesystem_name, filesystem)) = filesystems.get_key_value(&filesystem_name)
+        else {
+            used += row.get::<_, u64>(3)?;
+            continue;
+        };
+        if !datasets.contains_key(filesystem_name.as_str()) {
+            datasets.insert(filesystem_name, zfs::list_datasets(&filesystem.root)?);
+        }
+        let volume = to_volume_string(
+            &filesystem.root,
+            &row.get::<_, String>(1)?,
+            &row.get::<_, String>(2)?,
+        );
+        used += datasets[filesystem_name.as_str()]
+            .get(&volume)
+            .map_or(0, |dataset| {
+                (dataset.referenced + dataset.usedbysnapshots.unwrap_or(0)) as u64
+            });
+    }
+    if used >= quota << 30 {
+        return Err(Error::ProjectQuotaExceeded(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Prints each project's consumption of its quota
+pub fn print(usages: &[Usage], exact_sizes: bool) {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["PROJECT", "WORKSPACES", "USED", "QUOTA", "PERCENT"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+    for usage in usages {
+        let percent = usage
+            .quota
+            .map(|quota| (usage.used * 100).checked_div(quota).unwrap_or(100));
+        table.add_row(Row::new(
+            [
+                Cell::new(&usage.project),
+                Cell::new_align(&usage.workspaces.to_string(), Alignment::RIGHT),
+                Cell::new_align(
+                    &units::format_size(usage.used, exact_sizes),
+                    Alignment::RIGHT,
+                ),
+                Cell::new_align(
+                    &usage.quota.map_or("-".to_string(), |quota| {
+                        units::format_size(quota, exact_sizes)
+                    }),
+                    Alignment::RIGHT,
+                ),
+                Cell::new_align(
+                    &percent.map_or("-".to_string(), |percent| format!("{}%", percent)),
+                    Alignment::RIGHT,
+                ),
+            ]
+            .into_iter()
+            .map(|c| {
+                if usage.is_exceeded() {
+                    c.with_style(Attr::ForegroundColor(color::RED))
+                } else if percent.is_some_and(|percent| percent >= 90) {
+                    c.with_style(Attr::ForegroundColor(color::YELLOW))
+                } else {
+                    c
+                }
+            })
+            .collect(),
+        ));
+    }
+    table::print(&table);
+}
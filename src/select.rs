@@ -0,0 +1,78 @@
+//! Selecting several workspaces for one command, by name or by pattern
+
+use crate::config;
+use crate::db::Connection;
+use crate::error::Error;
+
+/// Resolves the workspaces of `user` named on the command line
+///
+/// These are the given `names` followed by all workspaces matching `pattern`,
+/// without duplicates.
+pub fn resolve(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    names: &[String],
+    pattern: &Option<String>,
+) -> Result<Vec<String>, Error> {
+    let mut selected = names.to_vec();
+    let Some(pattern) = pattern else {
+        return Ok(selected);
+    };
+    let mut statement = conn.prepare(
+        "SELECT name FROM workspaces
+            WHERE filesystem = ?1
+                AND user = ?2
+            ORDER BY name",
+    )?;
+    let mut rows = statement.query([filesystem_name, user])?;
+    let mut matched = false;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        if !config::matches_pattern(pattern, &name) {
+            continue;
+        }
+        matched = true;
+        if !selected.contains(&name) {
+            selected.push(name);
+        }
+    }
+    if !matched {
+        return Err(Error::InvalidArgument(format!(
+            "No workspaces of {} on {} match {}",
+            user, filesystem_name, pattern
+        )));
+    }
+    Ok(selected)
+}
+
+/// Runs `operation` on each selected workspace, reporting how each went
+///
+/// A single workspace is handled like without a selection, so its error is
+/// returned as it is.
+pub fn apply(
+    names: &[String],
+    done: &str,
+    mut operation: impl FnMut(&str) -> Result<(), Error>,
+) -> Result<(), Error> {
+    if let [name] = names {
+        return operation(name);
+    }
+    let mut failed = 0;
+    for name in names {
+        match operation(name) {
+            Ok(()) => println!("{} {}", done, name),
+            Err(e) => {
+                println!("failed {}: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(Error::BatchFailed {
+            failed,
+            total: names.len(),
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,331 @@
+use crate::db::Connection;
+use crate::{config, exit_codes, groups, shutdown};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, Read, Write},
+    mem,
+    net::Shutdown,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::PermissionsExt,
+        io::{AsRawFd, FromRawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    process::{self, Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+use users::{get_current_uid, get_effective_uid, get_user_by_uid};
+
+/// Environment variable through which the server passes on the client's uid
+const CALLER_UID_VAR: &str = "WORKSPACES_CALLER_UID";
+
+/// Whether the caller belongs to one of the configured `admin_groups`
+static IN_ADMIN_GROUP: AtomicBool = AtomicBool::new(false);
+
+/// Interval at which the server checks for new connections and shutdown
+/// requests
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A command line sent from a client to the server
+#[derive(Serialize, Deserialize)]
+struct Request {
+    /// Raw command line arguments, which need not be valid UTF-8
+    args: Vec<Vec<u8>>,
+    /// The client's working directory, which relative paths are resolved in
+    #[serde(default)]
+    cwd: Option<Vec<u8>>,
+}
+
+/// The outcome of running a client's command line
+#[derive(Serialize, Deserialize)]
+pub struct Response {
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// The uid of the user on whose behalf this process acts
+///
+/// This is the uid of the client if we were spawned by the server, and the
+/// real uid of the process otherwise.  The server's hint is only trusted if
+/// we are actually running as root.
+pub fn caller_uid() -> u32 {
+    let uid = get_current_uid();
+    if uid != 0 {
+        return uid;
+    }
+    env::var(CALLER_UID_VAR)
+        .ok()
+        .and_then(|var| var.parse().ok())
+        .unwrap_or(uid)
+}
+
+/// The name of the user on whose behalf this process acts
+pub fn caller_username() -> Option<OsString> {
+    get_user_by_uid(caller_uid()).map(|user| user.name().to_owned())
+}
+
+/// The user who invoked this process through sudo, if any
+///
+/// Only honored if we act on behalf of root, and never root itself.
+pub fn sudo_username() -> Option<OsString> {
+    if caller_uid() != 0 {
+        return None;
+    }
+    env::var_os("SUDO_USER").filter(|user| !user.is_empty() && user != "root")
+}
+
+/// Whether this process acts on behalf of `user`
+///
+/// Usernames which are not valid UTF-8 never match.
+pub fn is_caller(user: &str) -> bool {
+    caller_username().is_some_and(|name| name == user)
+}
+
+/// Records whether the caller belongs to one of `admin_groups`
+///
+/// Has to be called before [`is_admin`] is relied on.
+pub fn set_admin_groups(conn: &Connection, admin_groups: &[String]) {
+    let in_group = caller_username()
+        .and_then(|name| name.into_string().ok())
+        .is_some_and(|user| {
+            admin_groups
+                .iter()
+                .any(|group| groups::is_member(conn, &user, group))
+        });
+    IN_ADMIN_GROUP.store(in_group, Ordering::Relaxed);
+}
+
+/// Whether this process acts on behalf of root or a member of `admin_groups`
+///
+/// Admins may do anything root may within workspaces, but nothing which would
+/// give them root's access to files elsewhere, like writing database backups.
+pub fn is_admin() -> bool {
+    caller_uid() == 0 || IN_ADMIN_GROUP.load(Ordering::Relaxed)
+}
+
+/// Retrieves the uid of the process on the other end of a socket
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` and `len` are valid for writes and describe a `ucred`
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// Runs a client's command line on its behalf
+fn handle(mut stream: UnixStream) -> io::Result<()> {
+    let uid = peer_uid(&stream)?;
+    let mut request = String::new();
+    stream.read_to_string(&mut request)?;
+    let request: Request = serde_json::from_str(&request)?;
+
+    let mut command = Command::new(env::current_exe()?);
+    // the client can name any directory, but only gets the access to it
+    // which absolute paths would give it as well
+    if let Some(cwd) = request
+        .cwd
+        .as_deref()
+        .map(|cwd| Path::new(OsStr::from_bytes(cwd)))
+        .filter(|cwd| cwd.is_absolute() && cwd.is_dir())
+    {
+        command.current_dir(cwd);
+    }
+    let output = command
+        .args(
+            request
+                .args
+                .iter()
+                .skip(1)
+                .map(|arg| OsStr::from_bytes(arg)),
+        )
+        .env(CALLER_UID_VAR, uid.to_string())
+        // the client's identity is given by the socket alone
+        .env_remove("SUDO_USER")
+        .stdin(Stdio::null())
+        .output()?;
+    let response = Response {
+        status: output.status.code().unwrap_or(1),
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    };
+    stream.write_all(serde_json::to_string(&response)?.as_bytes())
+}
+
+/// Serves unprivileged clients over a Unix socket
+///
+/// Each connection is authenticated by the uid of the connecting process and
+/// its command line is then executed in a child process acting on behalf of
+/// that user.
+///
+/// If started through systemd socket activation, the passed socket is used
+/// instead of creating one.  On SIGTERM, no new connections are accepted and
+/// the server exits once all requests in flight have been answered.
+pub fn serve(config: &config::Config) -> ! {
+    if caller_uid() != 0 {
+        eprintln!("You are not allowed to execute this operation");
+        process::exit(exit_codes::INSUFFICIENT_PRIVILEGES);
+    }
+
+    let activated_fd = sd_notify::listen_fds().ok().and_then(|mut fds| fds.next());
+    let listener = match activated_fd {
+        // SAFETY: systemd passes us ownership of the listening socket
+        Some(fd) => unsafe { UnixListener::from_raw_fd(fd) },
+        None => {
+            // remove a socket left over from a previous run
+            let _ = fs::remove_file(&config.socket_path);
+            let listener = UnixListener::bind(&config.socket_path).unwrap();
+            fs::set_permissions(&config.socket_path, fs::Permissions::from_mode(0o666)).unwrap();
+            listener
+        }
+    };
+    listener.set_nonblocking(true).unwrap();
+    shutdown::install_handler();
+    let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]);
+
+    let mut in_flight: Vec<thread::JoinHandle<()>> = vec![];
+    while !shutdown::requested() {
+        in_flight.retain(|handle| !handle.is_finished());
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false).unwrap();
+                in_flight.push(thread::spawn(move || {
+                    if let Err(e) = handle(stream) {
+                        eprintln!("Error while handling request: {}", e);
+                    }
+                }));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+            Err(e) => eprintln!("Error while accepting connection: {}", e),
+        }
+    }
+
+    let _ = sd_notify::notify(
+        false,
+        &[
+            sd_notify::NotifyState::Stopping,
+            sd_notify::NotifyState::Status(&format!("draining {} requests", in_flight.len())),
+        ],
+    );
+    for handle in in_flight {
+        let _ = handle.join();
+    }
+    if activated_fd.is_none() {
+        let _ = fs::remove_file(&config.socket_path);
+    }
+    process::exit(0);
+}
+
+/// Has the server execute this process's command line and relays its output
+pub fn forward(config: &config::Config) -> ! {
+    let response = request(config, env::args_os().collect());
+    print!("{}", response.stdout);
+    eprint!("{}", response.stderr);
+    process::exit(response.status);
+}
+
+/// Runs a command line of ours, through the server if we are not root
+///
+/// Its output is captured, e.g. to preview a command before confirming it.
+pub fn capture(config: &config::Config, args: Vec<OsString>) -> Response {
+    if get_effective_uid() != 0 {
+        return request(config, args);
+    }
+    let output = env::current_exe()
+        .and_then(|exe| {
+            Command::new(exe)
+                .args(args.iter().skip(1))
+                .stdin(Stdio::null())
+                .output()
+        })
+        .unwrap();
+    Response {
+        status: output.status.code().unwrap_or(1),
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    }
+}
+
+/// Sends a command line to the server and waits for its outcome
+fn request(config: &config::Config, args: Vec<OsString>) -> Response {
+    let mut stream = UnixStream::connect(&config.socket_path).unwrap_or_else(|e| {
+        eprintln!(
+            "Could not connect to workspaces server at {}: {}",
+            config.socket_path.display(),
+            e
+        );
+        process::exit(exit_codes::SERVER_UNREACHABLE);
+    });
+
+    let request = Request {
+        args: args.into_iter().map(OsString::into_vec).collect(),
+        cwd: env::current_dir()
+            .ok()
+            .map(|cwd| cwd.into_os_string().into_vec()),
+    };
+    stream
+        .write_all(serde_json::to_string(&request).unwrap().as_bytes())
+        .unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    serde_json::from_str(&response).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_round_trips_non_utf8_arguments() {
+        let args = [
+            OsString::from("workspaces"),
+            OsString::from_vec(b"-u\xff".to_vec()),
+        ];
+        let request = Request {
+            args: args.iter().cloned().map(OsString::into_vec).collect(),
+            cwd: Some(b"/home/\xfe".to_vec()),
+        };
+        let request: Request =
+            serde_json::from_str(&serde_json::to_string(&request).unwrap()).unwrap();
+        let received: Vec<&OsStr> = request
+            .args
+            .iter()
+            .map(|arg| OsStr::from_bytes(arg))
+            .collect();
+        assert_eq!(
+            received,
+            args.iter().map(OsString::as_os_str).collect::<Vec<_>>()
+        );
+        assert_eq!(request.cwd.as_deref(), Some(&b"/home/\xfe"[..]));
+    }
+
+    #[test]
+    fn request_without_cwd_is_accepted() {
+        let request: Request = serde_json::from_str(r#"{"args":[[119,115]]}"#).unwrap();
+        assert_eq!(request.args, [b"ws".to_vec()]);
+        assert!(request.cwd.is_none());
+    }
+}
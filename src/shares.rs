@@ -0,0 +1,140 @@
+//! Consumption of the storage shares purchased by groups
+
+use crate::db::{Connection, OptionalExtension};
+use crate::{error::Error, groups, table, units};
+use prettytable::{
+    color,
+    format::{Alignment, FormatBuilder},
+    Attr, Cell, Row, Table,
+};
+use std::collections::{BTreeMap, HashMap};
+
+/// How much of its share a group consumes
+#[derive(Debug)]
+pub struct Usage {
+    pub group: String,
+    /// Purchased capacity in bytes
+    pub share: u64,
+    /// Bytes used by workspaces of the group's members
+    pub used: u64,
+    /// Members of the group owning workspaces
+    pub owners: Vec<String>,
+}
+
+impl Usage {
+    pub fn is_exceeded(&self) -> bool {
+        self.used > self.share
+    }
+}
+
+/// Sums up the sizes of all workspaces by the groups their owners belong to
+///
+/// Sizes are taken from the cache refreshed by `clean`.  Users belonging to
+/// several groups count towards each of their shares.  Reference workspaces
+/// are left out, as they are shared by everyone.
+pub fn usage(conn: &Connection, shares: &HashMap<String, u64>) -> Result<Vec<Usage>, Error> {
+    let mut used_by_user: BTreeMap<String, u64> = BTreeMap::new();
+    let mut statement = conn.prepare(
+        "SELECT user, SUM(cached_size) FROM workspaces
+            WHERE cached_size IS NOT NULL
+                AND class != 'reference'
+            GROUP BY user",
+    )?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        used_by_user.insert(row.get(0)?, row.get(1)?);
+    }
+
+    let mut groups: Vec<&String> = shares.keys().collect();
+    groups.sort();
+    Ok(groups
+        .into_iter()
+        .map(|group| {
+            let owners: Vec<String> = used_by_user
+                .keys()
+                .filter(|user| groups::is_member(conn, user, group))
+                .cloned()
+                .collect();
+            Usage {
+                group: group.clone(),
+                share: shares[group] << 30,
+                used: owners.iter().map(|user| used_by_user[user]).sum(),
+                owners,
+            }
+        })
+        .collect())
+}
+
+/// Prints each group's consumption of its share
+pub fn print(usages: &[Usage], exact_sizes: bool) {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["GROUP", "USED", "SHARE", "PERCENT"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+    for usage in usages {
+        let percent = (usage.used * 100).checked_div(usage.share).unwrap_or(100);
+        table.add_row(Row::new(
+            [
+                Cell::new(&usage.group),
+                Cell::new_align(
+                    &units::format_size(usage.used, exact_sizes),
+                    Alignment::RIGHT,
+                ),
+                Cell::new_align(
+                    &units::format_size(usage.share, exact_sizes),
+                    Alignment::RIGHT,
+                ),
+                Cell::new_align(&format!("{}%", percent), Alignment::RIGHT),
+            ]
+            .into_iter()
+            .map(|c| {
+                if usage.is_exceeded() {
+                    c.with_style(Attr::ForegroundColor(color::RED))
+                } else if percent >= 90 {
+                    c.with_style(Attr::ForegroundColor(color::YELLOW))
+                } else {
+                    c
+                }
+            })
+            .collect(),
+        ));
+    }
+    table::print(&table);
+}
+
+/// Returns the groups which have exceeded their share since the last check
+///
+/// Each group is only returned once until its consumption drops below its
+/// share again.
+pub fn newly_exceeded(conn: &Connection, usages: Vec<Usage>) -> Result<Vec<Usage>, Error> {
+    let mut exceeded = vec![];
+    for usage in usages {
+        if !usage.is_exceeded() {
+            conn.execute(
+                "DELETE FROM exceeded_shares WHERE group_name = ?1",
+                [&usage.group],
+            )?;
+            continue;
+        }
+        let known = conn
+            .query_row(
+                "SELECT 1 FROM exceeded_shares WHERE group_name = ?1",
+                [&usage.group],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if !known {
+            conn.execute(
+                "INSERT INTO exceeded_shares (group_name) VALUES (?1)",
+                [&usage.group],
+            )?;
+            exceeded.push(usage);
+        }
+    }
+    Ok(exceeded)
+}
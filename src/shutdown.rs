@@ -0,0 +1,26 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether SIGTERM or SIGINT has been received
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request(_signal: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGTERM and SIGINT request a graceful shutdown instead of killing us
+///
+/// Long-running commands have to poll [`requested`] and wind down once it
+/// returns true.
+pub fn install_handler() {
+    let handler = request as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+}
+
+/// Whether a graceful shutdown has been requested
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
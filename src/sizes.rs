@@ -0,0 +1,500 @@
+use crate::db::{Connection, OptionalExtension};
+use crate::{config, db, error::Error, table, to_volume_string, units, zfs};
+use chrono::{DateTime, Duration, Local};
+use prettytable::{
+    color,
+    format::{Alignment, FormatBuilder},
+    Attr, Cell, Row, Table,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    os::unix::ffi::OsStrExt,
+};
+
+/// Minimum age of the sample a workspace's current size is compared to
+fn comparison_window() -> Duration {
+    Duration::days(1)
+}
+
+/// How long size samples are kept
+///
+/// Long enough to account for the previous month even some weeks late.
+fn retention() -> Duration {
+    Duration::days(90)
+}
+
+/// Changes smaller than this number of bytes are never considered anomalous
+const MIN_ANOMALY_BYTES: i64 = 1 << 30;
+
+/// An unexpected change in a workspace's size
+#[derive(Debug, Clone, Copy)]
+pub enum Anomaly {
+    /// The workspace has at least doubled in size
+    Grew { from: i64, to: i64 },
+    /// The workspace has been emptied almost completely
+    Vanished { from: i64, to: i64 },
+}
+
+impl Anomaly {
+    /// Compares a workspace's current size to an earlier one
+    fn detect(from: i64, to: i64) -> Option<Anomaly> {
+        if to >= 2 * from && to - from >= MIN_ANOMALY_BYTES {
+            Some(Anomaly::Grew { from, to })
+        } else if to <= from / 100 && from - to >= MIN_ANOMALY_BYTES {
+            Some(Anomaly::Vanished { from, to })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::Grew { from, to } => {
+                write!(
+                    f,
+                    "grew from {} to {}",
+                    units::format_size(*from as u64, false),
+                    units::format_size(*to as u64, false)
+                )
+            }
+            Anomaly::Vanished { from, to } => {
+                write!(
+                    f,
+                    "shrank from {} to {}",
+                    units::format_size(*from as u64, false),
+                    units::format_size(*to as u64, false)
+                )
+            }
+        }
+    }
+}
+
+/// A workspace whose size changed anomalously
+pub struct Alert {
+    pub volume: String,
+    pub filesystem_name: String,
+    pub user: String,
+    pub name: String,
+    pub anomaly: Anomaly,
+}
+
+/// The size of a workspace a day ago, if it was sampled back then
+fn size_a_day_ago(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    now: &DateTime<Local>,
+) -> Result<Option<i64>, Error> {
+    Ok(conn
+        .query_row(
+            "SELECT bytes FROM size_history
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3
+                    AND time <= ?4
+                ORDER BY time DESC",
+            (
+                filesystem_name,
+                user,
+                name,
+                db::Timestamp::from(*now - comparison_window()),
+            ),
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+/// Records the current size of every workspace
+///
+/// Returns the workspaces whose size changed anomalously within the last day.
+/// Each anomaly is only returned once a day, so repeated runs do not flood
+/// the owner with alerts.
+pub fn sample(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+) -> Result<Vec<Alert>, Error> {
+    let now = Local::now();
+    conn.execute(
+        "DELETE FROM size_history WHERE time < ?1",
+        [db::Timestamp::from(now - retention())],
+    )?;
+
+    let mut anomalies = vec![];
+    let mut statement = conn.prepare("SELECT filesystem, user, name, project FROM workspaces")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let filesystem_name: String = row.get(0)?;
+        let user: String = row.get(1)?;
+        let name: String = row.get(2)?;
+        let project: Option<String> = row.get(3)?;
+        let Some(filesystem) = filesystems.get(&filesystem_name) else {
+            continue;
+        };
+        let volume = to_volume_string(&filesystem.root, &user, &name);
+        let Ok(bytes) = zfs::get_property::<i64>(&volume, "used") else {
+            continue;
+        };
+
+        let anomaly = size_a_day_ago(conn, &filesystem_name, &user, &name, &now)?
+            .and_then(|from| Anomaly::detect(from, bytes));
+        let already_alerted = conn
+            .query_row(
+                "SELECT 1 FROM size_history
+                    WHERE filesystem = ?1
+                        AND user = ?2
+                        AND name = ?3
+                        AND time > ?4
+                        AND alerted",
+                (
+                    &filesystem_name,
+                    &user,
+                    &name,
+                    db::Timestamp::from(now - comparison_window()),
+                ),
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        let alert = anomaly.is_some() && !already_alerted;
+        conn.execute(
+            "INSERT INTO size_history (filesystem, user, name, time, bytes, alerted, project)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            (
+                &filesystem_name,
+                &user,
+                &name,
+                db::Timestamp::from(now),
+                bytes,
+                alert,
+                &project,
+            ),
+        )?;
+        if let (Some(anomaly), true) = (anomaly, alert) {
+            anomalies.push(Alert {
+                volume,
+                filesystem_name,
+                user,
+                name,
+                anomaly,
+            });
+        }
+    }
+    Ok(anomalies)
+}
+
+/// Stores the current size and mountpoint of all workspaces in the database
+///
+/// This allows `workspaces list` to show them without querying zfs.
+pub fn refresh_cache(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+) -> Result<(), Error> {
+    let now = db::Timestamp::from(Local::now());
+    for (filesystem_name, filesystem) in filesystems {
+        let datasets = match zfs::list_datasets(&filesystem.root) {
+            Ok(datasets) => datasets,
+            Err(e) => {
+                eprintln!("Failed to refresh sizes on {}: {}", filesystem_name, e);
+                continue;
+            }
+        };
+        let mut statement =
+            conn.prepare("SELECT user, name FROM workspaces WHERE filesystem = ?1")?;
+        let mut rows = statement.query([filesystem_name])?;
+        while let Some(row) = rows.next()? {
+            let user: String = row.get(0)?;
+            let name: String = row.get(1)?;
+            let volume = to_volume_string(&filesystem.root, &user, &name);
+            let Some(dataset) = datasets.get(&volume) else {
+                continue;
+            };
+            conn.execute(
+                "UPDATE workspaces
+                    SET cached_size = ?1,
+                        cached_mountpoint = ?2,
+                        cached_at = ?3
+                    WHERE filesystem = ?4
+                        AND user = ?5
+                        AND name = ?6",
+                (
+                    dataset.referenced,
+                    dataset.mountpoint.as_os_str().as_bytes(),
+                    now,
+                    filesystem_name,
+                    &user,
+                    &name,
+                ),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Shows how the size of each workspace changed over the last day
+///
+/// If `only_anomalies` is set, only workspaces with an anomalous change are
+/// shown.
+pub fn report(conn: &Connection, only_anomalies: bool, exact_sizes: bool) {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["NAME", "USER", "FS", "SIZE", "DAY AGO", "ANOMALY"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+
+    let mut statement = conn
+        .prepare(
+            "SELECT filesystem, user, name, time, bytes
+                FROM size_history AS latest
+                WHERE time = (
+                    SELECT MAX(time) FROM size_history
+                        WHERE filesystem = latest.filesystem
+                            AND user = latest.user
+                            AND name = latest.name
+                )
+                ORDER BY filesystem, user, name",
+        )
+        .unwrap();
+    let mut rows = statement.query([]).unwrap();
+    while let Some(row) = rows.next().unwrap() {
+        let filesystem_name: String = row.get(0).unwrap();
+        let user: String = row.get(1).unwrap();
+        let name: String = row.get(2).unwrap();
+        let time: DateTime<Local> = row.get::<_, db::Timestamp>(3).unwrap().into();
+        let bytes: i64 = row.get(4).unwrap();
+
+        let before = size_a_day_ago(conn, &filesystem_name, &user, &name, &time).unwrap();
+        let anomaly = before.and_then(|from| Anomaly::detect(from, bytes));
+        if only_anomalies && anomaly.is_none() {
+            continue;
+        }
+        table.add_row(Row::new(vec![
+            Cell::new(&name),
+            Cell::new(&user),
+            Cell::new(&filesystem_name),
+            Cell::new_align(
+                &units::format_size(bytes as u64, exact_sizes),
+                Alignment::RIGHT,
+            ),
+            Cell::new_align(
+                &before.map_or("-".to_string(), |b| {
+                    units::format_size(b as u64, exact_sizes)
+                }),
+                Alignment::RIGHT,
+            ),
+            match anomaly {
+                Some(anomaly) => {
+                    Cell::new(&anomaly.to_string()).with_style(Attr::ForegroundColor(color::YELLOW))
+                }
+                None => Cell::new(""),
+            },
+        ]));
+    }
+
+    table::print(&table);
+}
+
+/// Times and sizes a workspace was sampled at, oldest first
+type History = Vec<(DateTime<Local>, i64)>;
+
+/// Number of characters a workspace's history is drawn with at most
+const SPARKLINE_WIDTH: usize = 30;
+
+/// Draws `values` as a line of bars, scaled between their minimum and maximum
+///
+/// If there are more values than fit, the last one of each group is drawn.
+fn sparkline(values: &[i64]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let step = values.len().div_ceil(SPARKLINE_WIDTH).max(1);
+    let values: Vec<i64> = values.rchunks(step).rev().map(|c| c[c.len() - 1]).collect();
+    let min = values.iter().copied().min().unwrap_or(0);
+    let max = values.iter().copied().max().unwrap_or(0);
+    values
+        .iter()
+        .map(|value| match max - min {
+            0 => BARS[0],
+            range => BARS[((value - min) * (BARS.len() as i64 - 1) / range) as usize],
+        })
+        .collect()
+}
+
+/// Shows the sampled sizes of a user's workspaces and how they grew
+///
+/// Workspaces which grew by more than `threshold` bytes since their previous
+/// sample are highlighted.
+pub fn stats(
+    conn: &Connection,
+    filter_filesystem: &Option<String>,
+    user: &str,
+    name: &Option<String>,
+    threshold: u64,
+    exact_sizes: bool,
+) -> Result<(), Error> {
+    let mut statement = conn.prepare(
+        "SELECT filesystem, name, time, bytes FROM size_history
+            WHERE user = ?1
+                AND filesystem = COALESCE(?2, filesystem)
+                AND name = COALESCE(?3, name)
+            ORDER BY filesystem, name, time",
+    )?;
+    let mut rows = statement.query((user, filter_filesystem, name))?;
+    let mut histories: BTreeMap<(String, String), History> = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        let time: DateTime<Local> = row.get::<_, db::Timestamp>(2)?.into();
+        histories
+            .entry((row.get(0)?, row.get(1)?))
+            .or_default()
+            .push((time, row.get(3)?));
+    }
+    if let (Some(name), true) = (name, histories.is_empty()) {
+        return Err(Error::InvalidArgument(format!(
+            "No sizes of {} have been sampled yet, they are sampled by `clean`",
+            name
+        )));
+    }
+
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["NAME", "FS", "SIZE", "CHANGE", "SINCE", "HISTORY"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+    for ((filesystem_name, name), history) in histories {
+        let (_, current) = history[history.len() - 1];
+        // growth since the previous sample
+        let change = match history.len() {
+            1 => None,
+            n => Some(current - history[n - 2].1),
+        };
+        let change_cell = match change {
+            Some(change) if change > threshold as i64 => Cell::new_align(
+                &format!("+{}", units::format_size(change as u64, exact_sizes)),
+                Alignment::RIGHT,
+            )
+            .with_style(Attr::Bold)
+            .with_style(Attr::ForegroundColor(color::YELLOW)),
+            Some(change) if change >= 0 => Cell::new_align(
+                &format!("+{}", units::format_size(change as u64, exact_sizes)),
+                Alignment::RIGHT,
+            ),
+            Some(change) => Cell::new_align(
+                &format!(
+                    "-{}",
+                    units::format_size(change.unsigned_abs(), exact_sizes)
+                ),
+                Alignment::RIGHT,
+            ),
+            None => Cell::new_align("-", Alignment::RIGHT),
+        };
+        let sizes: Vec<i64> = history.iter().map(|(_, bytes)| *bytes).collect();
+        table.add_row(Row::new(vec![
+            Cell::new(&name),
+            Cell::new(&filesystem_name),
+            Cell::new_align(
+                &units::format_size(current as u64, exact_sizes),
+                Alignment::RIGHT,
+            ),
+            change_cell,
+            Cell::new(&history[0].0.format("%Y-%m-%d").to_string()),
+            Cell::new(&sparkline(&sizes)),
+        ]));
+    }
+    table::print(&table);
+    Ok(())
+}
+
+/// Shows the number and total size of each user's workspaces per filesystem
+///
+/// Sizes are taken from the cache refreshed by `clean` where possible.
+/// Users exceeding their filesystem's `user_limit` are highlighted.  Reference
+/// workspaces are shared by everyone and hence not counted against their
+/// owners.
+pub fn usage(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    filter_users: &Option<Vec<String>>,
+    exact_sizes: bool,
+) -> Result<(), Error> {
+    let mut statement = conn.prepare(
+        "SELECT filesystem, user, name, cached_size FROM workspaces
+            WHERE class != 'reference'
+            ORDER BY user, filesystem",
+    )?;
+    let mut rows = statement.query([])?;
+    // workspace count and bytes per user and filesystem
+    let mut usages: BTreeMap<(String, String), (usize, u64)> = BTreeMap::new();
+    let mut live: HashMap<String, HashMap<String, zfs::Dataset>> = HashMap::new();
+    while let Some(row) = rows.next()? {
+        let filesystem_name: String = row.get(0)?;
+        let user: String = row.get(1)?;
+        let name: String = row.get(2)?;
+        let cached_size: Option<u64> = row.get(3)?;
+        if !filter_users.as_ref().is_none_or(|us| us.contains(&user)) {
+            continue;
+        }
+        let Some(filesystem) = filesystems.get(&filesystem_name) else {
+            continue;
+        };
+        let bytes = match cached_size {
+            Some(bytes) => bytes,
+            None => {
+                if !live.contains_key(&filesystem_name) {
+                    live.insert(
+                        filesystem_name.clone(),
+                        zfs::list_datasets(&filesystem.root)?,
+                    );
+                }
+                let volume = to_volume_string(&filesystem.root, &user, &name);
+                live[&filesystem_name]
+                    .get(&volume)
+                    .map_or(0, |d| d.referenced as u64)
+            }
+        };
+        let (count, sum) = usages.entry((user, filesystem_name)).or_default();
+        *count += 1;
+        *sum += bytes;
+    }
+
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["USER", "FS", "WORKSPACES", "SIZE", "LIMIT"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+    for ((user, filesystem_name), (count, bytes)) in usages {
+        let limit = filesystems[&filesystem_name].user_limit.map(|l| l << 30);
+        table.add_row(Row::new(
+            [
+                Cell::new(&user),
+                Cell::new(&filesystem_name),
+                Cell::new_align(&count.to_string(), Alignment::RIGHT),
+                Cell::new_align(&units::format_size(bytes, exact_sizes), Alignment::RIGHT),
+                Cell::new_align(
+                    &limit.map_or("-".to_string(), |l| units::format_size(l, exact_sizes)),
+                    Alignment::RIGHT,
+                ),
+            ]
+            .into_iter()
+            .map(|c| match limit {
+                Some(limit) if bytes > limit => c.with_style(Attr::ForegroundColor(color::RED)),
+                Some(limit) if bytes as f64 > limit as f64 * 0.9 => {
+                    c.with_style(Attr::ForegroundColor(color::YELLOW))
+                }
+                _ => c,
+            })
+            .collect(),
+        ));
+    }
+    table::print(&table);
+    Ok(())
+}
@@ -0,0 +1,128 @@
+//! The SQLite database backend, which keeps the database in a local file
+
+use crate::{
+    db::{Backend, Error, Result, Row, Value},
+    dry_run,
+};
+use rusqlite::{
+    backup::{Backup, Progress},
+    types, Connection, DatabaseName, OpenFlags,
+};
+use std::{path::Path, time::Duration};
+
+pub struct Sqlite(Connection);
+
+impl Sqlite {
+    /// Opens the database at `path`, waiting up to `timeout` for locks
+    pub fn open(path: &Path, timeout: Duration) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(timeout)?;
+        Ok(Sqlite(conn))
+    }
+}
+
+fn to_sqlite(value: &Value) -> types::Value {
+    match value {
+        Value::Null => types::Value::Null,
+        Value::Integer(n) => types::Value::Integer(*n),
+        Value::Real(x) => types::Value::Real(*x),
+        Value::Text(text) => types::Value::Text(text.clone()),
+        Value::Blob(bytes) => types::Value::Blob(bytes.clone()),
+    }
+}
+
+fn from_sqlite(value: types::Value) -> Value {
+    match value {
+        types::Value::Null => Value::Null,
+        types::Value::Integer(n) => Value::Integer(n),
+        types::Value::Real(x) => Value::Real(x),
+        types::Value::Text(text) => Value::Text(text),
+        types::Value::Blob(bytes) => Value::Blob(bytes),
+    }
+}
+
+impl Backend for Sqlite {
+    fn execute(&self, sql: &str, params: &[Value]) -> Result<usize> {
+        let params = rusqlite::params_from_iter(params.iter().map(to_sqlite));
+        Ok(self.0.execute(sql, params)?)
+    }
+
+    fn execute_batch(&self, sql: &str) -> Result<()> {
+        Ok(self.0.execute_batch(sql)?)
+    }
+
+    fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<Row>> {
+        let mut statement = self.0.prepare(sql)?;
+        let columns = statement.column_count();
+        let params = rusqlite::params_from_iter(params.iter().map(to_sqlite));
+        let mut rows = statement.query(params)?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            let values = (0..columns)
+                .map(|i| row.get(i).map(from_sqlite))
+                .collect::<rusqlite::Result<_>>()?;
+            result.push(Row(values));
+        }
+        Ok(result)
+    }
+
+    fn begin(&self) -> Result<()> {
+        Ok(self.0.execute_batch("BEGIN IMMEDIATE")?)
+    }
+
+    fn commit(&self) -> Result<()> {
+        Ok(self.0.execute_batch("COMMIT")?)
+    }
+
+    fn rollback(&self) -> Result<()> {
+        Ok(self.0.execute_batch("ROLLBACK")?)
+    }
+
+    fn version(&self) -> Result<usize> {
+        Ok(self
+            .0
+            .pragma_query_value(None, "user_version", |row| row.get(0))?)
+    }
+
+    fn set_version(&self, version: usize) -> Result<()> {
+        Ok(self.0.pragma_update(None, "user_version", version)?)
+    }
+
+    fn initialize(&self) -> Result<()> {
+        // cannot be changed within a transaction
+        Ok(self.0.pragma_update(None, "journal_mode", "WAL")?)
+    }
+
+    fn integrity_check(&self) -> Result<Vec<String>> {
+        let mut statement = self.0.prepare("PRAGMA integrity_check")?;
+        let messages = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(messages.into_iter().filter(|m| m != "ok").collect())
+    }
+
+    fn backup(&self, path: &Path) -> Result<()> {
+        Ok(self.0.backup(DatabaseName::Main, path, None)?)
+    }
+
+    fn restore(&mut self, path: &Path) -> Result<()> {
+        Ok(self
+            .0
+            .restore(DatabaseName::Main, path, None::<fn(Progress)>)?)
+    }
+
+    /// Copies the database into memory, printing modifying statements
+    fn dry_run(&self) -> Result<Box<dyn Backend>> {
+        let mut copy = Connection::open_in_memory()?;
+        Backup::new(&self.0, &mut copy)?.run_to_completion(i32::MAX, Duration::ZERO, None)?;
+        copy.trace(Some(dry_run::print_statement));
+        Ok(Box::new(Sqlite(copy)))
+    }
+}
+
+/// Schema version of the database at `path`, which is left untouched
+pub fn version(path: &Path) -> Result<usize> {
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(Error::from)
+}
@@ -0,0 +1,162 @@
+//! Aggregate statistics of all workspaces, for capacity planning
+
+use crate::db::Connection;
+use crate::{db, error::Error, table, units};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate};
+use prettytable::{
+    format::{Alignment, FormatBuilder},
+    Attr, Cell, Row, Table,
+};
+use std::collections::BTreeMap;
+
+/// Upper bounds of the size classes workspaces are counted in, in GiB
+const SIZE_CLASSES: [u64; 4] = [1, 10, 100, 1024];
+
+/// Number of each kind of event within a week
+#[derive(Default)]
+struct Week {
+    created: usize,
+    extended: usize,
+    expired: usize,
+    destroyed: usize,
+}
+
+/// The Monday starting the week of `time`
+fn week_of(time: &DateTime<Local>) -> NaiveDate {
+    let date = time.date_naive();
+    date - Duration::days(date.weekday().num_days_from_monday().into())
+}
+
+/// Prints the events of the last `weeks` weeks, the median lifetime of the
+/// workspaces destroyed within them and the sizes of current workspaces
+///
+/// Events are recorded since schema version 19, so lifetimes are only known
+/// for workspaces created since.
+pub fn global(conn: &Connection, weeks: u32, exact_sizes: bool) -> Result<(), Error> {
+    let now = Local::now();
+    let since = now - Duration::weeks(weeks.into());
+    let mut counts: BTreeMap<NaiveDate, Week> = BTreeMap::new();
+    let mut week = week_of(&since);
+    while week <= now.date_naive() {
+        counts.insert(week, Week::default());
+        week += Duration::weeks(1);
+    }
+    let mut statement = conn.prepare("SELECT time, event FROM events WHERE time >= ?1")?;
+    let mut rows = statement.query([db::Timestamp::from(since)])?;
+    while let Some(row) = rows.next()? {
+        let time: DateTime<Local> = row.get::<_, db::Timestamp>(0)?.into();
+        let event: String = row.get(1)?;
+        let week = counts.entry(week_of(&time)).or_default();
+        match event.as_str() {
+            "create" => week.created += 1,
+            "extend" => week.extended += 1,
+            "expire" => week.expired += 1,
+            "destroy" => week.destroyed += 1,
+            _ => {}
+        }
+    }
+
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["WEEK", "CREATED", "EXTENDED", "EXPIRED", "DESTROYED"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+    for (week, counts) in &counts {
+        table.add_row(Row::new(vec![
+            Cell::new(&week.format("%G-W%V").to_string()),
+            Cell::new_align(&counts.created.to_string(), Alignment::RIGHT),
+            Cell::new_align(&counts.extended.to_string(), Alignment::RIGHT),
+            Cell::new_align(&counts.expired.to_string(), Alignment::RIGHT),
+            Cell::new_align(&counts.destroyed.to_string(), Alignment::RIGHT),
+        ]));
+    }
+    table::print(&table);
+    println!();
+
+    // each destroyed workspace's lifetime since it was last created
+    let mut statement = conn.prepare(
+        "SELECT destroy.time, (
+                SELECT MAX(create_.time) FROM events AS create_
+                    WHERE create_.event = 'create'
+                        AND create_.filesystem = destroy.filesystem
+                        AND create_.user = destroy.user
+                        AND create_.name = destroy.name
+                        AND create_.time < destroy.time
+            )
+            FROM events AS destroy
+            WHERE destroy.event = 'destroy'
+                AND destroy.time >= ?1",
+    )?;
+    let mut lifetimes = statement
+        .query_map([db::Timestamp::from(since)], |row| {
+            Ok((
+                row.get::<_, db::Timestamp>(0)?,
+                row.get::<_, Option<db::Timestamp>>(1)?,
+            ))
+        })?
+        .filter_map(|times| match times {
+            Ok((destroyed, Some(created))) => Some(Ok(destroyed.0 - created.0)),
+            Ok((_, None)) => None,
+            Err(e) => Some(Err(e)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    lifetimes.sort_unstable();
+    match lifetimes.get(lifetimes.len() / 2) {
+        Some(median) => println!(
+            "Median lifetime: {}d (of {} destroyed workspaces)",
+            median.num_days(),
+            lifetimes.len()
+        ),
+        None => println!("Median lifetime: unknown, no workspace created and destroyed yet"),
+    }
+    println!();
+
+    // sizes are taken from the cache refreshed by `clean`
+    let mut classes = [(0usize, 0u64); SIZE_CLASSES.len() + 1];
+    let mut statement =
+        conn.prepare("SELECT cached_size FROM workspaces WHERE cached_size IS NOT NULL")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let bytes: u64 = row.get(0)?;
+        let class = SIZE_CLASSES
+            .iter()
+            .position(|gib| bytes < gib << 30)
+            .unwrap_or(SIZE_CLASSES.len());
+        classes[class].0 += 1;
+        classes[class].1 += bytes;
+    }
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["SIZE", "WORKSPACES", "TOTAL"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+    for (class, (count, bytes)) in classes.iter().enumerate() {
+        let label = match class {
+            0 => format!("< {}", units::format_size(SIZE_CLASSES[0] << 30, false)),
+            n if n == SIZE_CLASSES.len() => {
+                format!(
+                    ">= {}",
+                    units::format_size(SIZE_CLASSES[n - 1] << 30, false)
+                )
+            }
+            n => format!(
+                "{} - {}",
+                units::format_size(SIZE_CLASSES[n - 1] << 30, false),
+                units::format_size(SIZE_CLASSES[n] << 30, false)
+            ),
+        };
+        table.add_row(Row::new(vec![
+            Cell::new(&label),
+            Cell::new_align(&count.to_string(), Alignment::RIGHT),
+            Cell::new_align(&units::format_size(*bytes, exact_sizes), Alignment::RIGHT),
+        ]));
+    }
+    table::print(&table);
+    Ok(())
+}
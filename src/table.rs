@@ -0,0 +1,25 @@
+//! Printing of tables with or without colors
+
+use crate::cli::ColorChoice;
+use prettytable::Table;
+use std::{env, io, sync::OnceLock};
+
+static COLOR: OnceLock<ColorChoice> = OnceLock::new();
+
+/// Sets whether tables are printed with colors
+pub fn set_color(choice: ColorChoice) {
+    let _ = COLOR.set(choice);
+}
+
+/// Prints a table to stdout
+///
+/// Unless colors were forced, they are only used if stdout is a terminal and
+/// `NO_COLOR` is not set.
+pub fn print(table: &Table) {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let _ = match COLOR.get().copied().unwrap_or(ColorChoice::Auto) {
+        ColorChoice::Always => table.print_tty(true),
+        ColorChoice::Auto if !no_color => table.print_tty(false),
+        _ => table.print(&mut io::stdout()),
+    };
+}
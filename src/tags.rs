@@ -0,0 +1,140 @@
+//! Free-form tags attached to workspaces, e.g. to group them by project
+
+use crate::db::Connection;
+use crate::error::Error;
+use std::collections::HashMap;
+
+/// Identifies a workspace by its filesystem, user and name
+pub type Key = (String, String, String);
+
+/// Returns the tags of a workspace, sorted alphabetically
+pub fn get(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+) -> Result<Vec<String>, Error> {
+    let mut statement = conn.prepare(
+        "SELECT tag FROM tags
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3
+            ORDER BY tag",
+    )?;
+    let tags = statement
+        .query_map((filesystem_name, user, name), |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(tags)
+}
+
+/// Returns the tags of all workspaces
+pub fn all(conn: &Connection) -> Result<HashMap<Key, Vec<String>>, Error> {
+    let mut tags: HashMap<Key, Vec<String>> = HashMap::new();
+    let mut statement =
+        conn.prepare("SELECT filesystem, user, name, tag FROM tags ORDER BY tag")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        tags.entry((row.get(0)?, row.get(1)?, row.get(2)?))
+            .or_default()
+            .push(row.get(3)?);
+    }
+    Ok(tags)
+}
+
+/// Attaches tags to a workspace
+///
+/// Tags the workspace already has are ignored.
+pub fn add(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    tags: &[String],
+) -> Result<(), Error> {
+    for tag in tags {
+        conn.execute(
+            "INSERT INTO tags (filesystem, user, name, tag)
+                VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT DO NOTHING",
+            (filesystem_name, user, name, tag),
+        )?;
+    }
+    Ok(())
+}
+
+/// Removes tags from a workspace
+pub fn remove(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    tags: &[String],
+) -> Result<(), Error> {
+    for tag in tags {
+        conn.execute(
+            "DELETE FROM tags
+                WHERE filesystem = ?1
+                    AND user = ?2
+                    AND name = ?3
+                    AND tag = ?4",
+            (filesystem_name, user, name, tag),
+        )?;
+    }
+    Ok(())
+}
+
+/// Removes all tags of a workspace, e.g. because it has been deleted
+pub fn clear(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+) -> Result<(), Error> {
+    conn.execute(
+        "DELETE FROM tags
+            WHERE filesystem = ?1
+                AND user = ?2
+                AND name = ?3",
+        (filesystem_name, user, name),
+    )?;
+    Ok(())
+}
+
+/// Moves the tags of a workspace along with it to another filesystem
+pub fn relocate(
+    conn: &Connection,
+    filesystem_name: &str,
+    dest_filesystem_name: &str,
+    user: &str,
+    name: &str,
+) -> Result<(), Error> {
+    conn.execute(
+        "UPDATE tags
+            SET filesystem = ?1
+            WHERE filesystem = ?2
+                AND user = ?3
+                AND name = ?4",
+        (dest_filesystem_name, filesystem_name, user, name),
+    )?;
+    Ok(())
+}
+
+/// Moves the tags of a workspace along with it when it is renamed or
+/// changes owner
+pub fn rename(
+    conn: &Connection,
+    filesystem_name: &str,
+    (user, name): (&str, &str),
+    (new_user, new_name): (&str, &str),
+) -> Result<(), Error> {
+    conn.execute(
+        "UPDATE tags
+            SET user = ?1,
+                name = ?2
+            WHERE filesystem = ?3
+                AND user = ?4
+                AND name = ?5",
+        (new_user, new_name, filesystem_name, user, name),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! Taking a workspace's data off the system and bringing it back
+//!
+//! Tarballs are compressed with zstd, so they can be unpacked anywhere with
+//! GNU tar, without the need for ZFS.
+
+use crate::{dry_run, error::Error, privileges};
+use std::{
+    fs::File,
+    os::unix::process::CommandExt,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+/// Writes the data below `mountpoint` to `out`
+///
+/// tar runs as the user `uid`, so files of other members of a shared
+/// workspace are only exported if that user could have read them anyway.
+pub fn export(mountpoint: &Path, out: impl Into<Stdio>, uid: u32, gid: u32) -> Result<(), Error> {
+    create(mountpoint, out, Command::new("tar").uid(uid).gid(gid))
+}
+
+/// Writes all data below `mountpoint` to `out`, reading it as root
+///
+/// Only meant for archiving a workspace as a whole.
+pub fn export_as_root(mountpoint: &Path, out: impl Into<Stdio>) -> Result<(), Error> {
+    create(
+        mountpoint,
+        out,
+        privileges::as_root(&mut Command::new("tar")),
+    )
+}
+
+fn create(mountpoint: &Path, out: impl Into<Stdio>, tar: &mut Command) -> Result<(), Error> {
+    if dry_run::skip(format_args!("export of {}", mountpoint.display())) {
+        return Ok(());
+    }
+    let status = tar
+        .arg("-C")
+        .arg(mountpoint)
+        .args(["--zstd", "-cf", "-", "."])
+        .stdout(out)
+        .status()?;
+    if !status.success() {
+        return Err(Error::Command(format!("tar exited with {}", status)));
+    }
+    Ok(())
+}
+
+/// Unpacks the tarball read from `file` into `mountpoint`
+///
+/// tar runs as the workspace's owner, so the files belong to them and links
+/// within the tarball cannot be used to write outside the workspace.
+pub fn import(file: File, mountpoint: &Path, uid: u32, gid: u32) -> Result<(), Error> {
+    if dry_run::skip(format_args!("import into {}", mountpoint.display())) {
+        return Ok(());
+    }
+    let status = Command::new("tar")
+        .arg("-C")
+        .arg(mountpoint)
+        .args(["--zstd", "--no-same-owner", "-xf", "-"])
+        .stdin(file)
+        .uid(uid)
+        .gid(gid)
+        .status()?;
+    if !status.success() {
+        return Err(Error::Command(format!("tar exited with {}", status)));
+    }
+    Ok(())
+}
@@ -0,0 +1,50 @@
+//! Formatting of sizes for display
+
+const UNITS: [&str; 6] = ["K", "M", "G", "T", "P", "E"];
+
+/// Formats a number of bytes as e.g. `900.0M` or `1.5T`, or verbatim if `exact`
+pub fn format_size(bytes: u64, exact: bool) -> String {
+    if exact || bytes < 1024 {
+        return bytes.to_string();
+    }
+    let mut size = bytes as f64 / 1024.;
+    let mut unit = 0;
+    // round first so that e.g. 1023.96K is shown as 1.0M rather than 1024.0K
+    while (size * 10.).round() >= 1024. * 10. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_small_sizes_verbatim() {
+        assert_eq!(format_size(0, false), "0");
+        assert_eq!(format_size(1023, false), "1023");
+    }
+
+    #[test]
+    fn formats_sizes_with_units() {
+        assert_eq!(format_size(1024, false), "1.0K");
+        assert_eq!(format_size(1536 << 30, false), "1.5T");
+        assert_eq!(format_size(900 << 20, false), "900.0M");
+        // rounds up into the next unit rather than showing 1024.0K
+        assert_eq!(format_size((1 << 20) - 1, false), "1.0M");
+    }
+
+    #[test]
+    fn formats_largest_sizes_in_exbibytes() {
+        assert_eq!(format_size(1 << 60, false), "1.0E");
+        assert_eq!(format_size(u64::MAX, false), "16.0E");
+    }
+
+    #[test]
+    fn formats_exact_sizes_verbatim() {
+        assert_eq!(format_size(1 << 40, true), "1099511627776");
+        assert_eq!(format_size(u64::MAX, true), u64::MAX.to_string());
+    }
+}
@@ -1,87 +1,571 @@
+use crate::{dry_run, privileges, units};
+use serde::Serialize;
 use std::{
-    io,
-    process::{self, Command},
+    collections::HashMap,
+    ffi::OsStr,
+    fmt,
+    io::{self, BufRead, BufReader, IsTerminal, Read},
+    os::unix::{ffi::OsStrExt, process::ExitStatusExt},
+    path::PathBuf,
+    process::{self, Command, Stdio},
     str::FromStr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
 };
 
+/// Subcommands which change volumes and are therefore skipped in dry runs
+const MODIFYING_SUBCOMMANDS: [&str; 12] = [
+    "create", "destroy", "rename", "snapshot", "set", "inherit", "mount", "unmount", "rollback",
+    "promote", "hold", "release",
+];
+
+/// Number of zfs invocations so far
+static CALL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Milliseconds after which a zfs invocation is killed
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(60_000);
+
+/// Interval at which running zfs invocations are checked for completion
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Sets the time after which hung zfs invocations are killed
+pub fn set_timeout(timeout: Duration) {
+    TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Returns the number of zfs invocations made by this process so far
+pub fn call_count() -> usize {
+    CALL_COUNT.load(Ordering::Relaxed)
+}
+
+/// Creates a zfs command, counting the invocation
+fn zfs() -> Command {
+    CALL_COUNT.fetch_add(1, Ordering::Relaxed);
+    let mut command = Command::new("zfs");
+    privileges::as_root(&mut command);
+    command
+}
+
+/// Creates a zpool command, counting the invocation
+fn zpool() -> Command {
+    CALL_COUNT.fetch_add(1, Ordering::Relaxed);
+    let mut command = Command::new("zpool");
+    privileges::as_root(&mut command);
+    command
+}
+
 #[derive(Debug)]
 pub enum Error {
     /// An error occurring while running a command
     Command(io::Error),
     /// The ZFS invocation completed, but returned a non-zero code
-    ZfsStatus(process::ExitStatus),
+    ///
+    /// Contains what zfs wrote to stderr, e.g. that a dataset is busy.
+    ZfsStatus(process::ExitStatus, String),
     /// Error while parsing ZFS's output
-    PropertyParse(Box<dyn std::error::Error>),
+    PropertyParse(Box<dyn std::error::Error + Send + Sync>),
+    /// The ZFS invocation did not finish in time and was killed
+    Timeout(Duration),
 }
 
-/// Creates a new ZFS volume
-pub fn create(volume: &str) -> Result<(), Error> {
-    let status = Command::new("zfs")
-        .args(["create", "-p", volume])
-        .status()
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Command(e) => write!(f, "could not run zfs: {}", e),
+            Error::ZfsStatus(status, stderr) if stderr.is_empty() => {
+                write!(f, "zfs exited with {}", status)
+            }
+            Error::ZfsStatus(status, stderr) => write!(f, "zfs exited with {}: {}", status, stderr),
+            Error::PropertyParse(e) => write!(f, "could not parse zfs output: {}", e),
+            Error::Timeout(timeout) => {
+                write!(f, "zfs did not finish within {}s", timeout.as_secs())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Reads a child's output pipe to its end in the background
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        // a broken pipe just leaves us with less output
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/// Formats a number of seconds left as e.g. `1h05m` or `42s`
+fn format_eta(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+/// Shows the progress `zfs send -Pv` writes to `pipe` on the terminal
+///
+/// Returns the other lines written to `pipe`, such as error messages.
+fn report_progress(pipe: impl Read + Send + 'static, label: String) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let show = io::stderr().is_terminal();
+        let start = Instant::now();
+        let mut total = None;
+        let mut shown = false;
+        let mut other = vec![];
+        for line in BufReader::new(pipe).split(b'\n') {
+            // a broken pipe just leaves us with less output
+            let Ok(line) = line else {
+                break;
+            };
+            let text = String::from_utf8_lossy(&line);
+            let fields: Vec<&str> = text.split('\t').collect();
+            match fields[..] {
+                // the estimated size of the stream
+                ["full" | "incremental" | "size", .., size] if size.parse::<u64>().is_ok() => {
+                    total = size.parse::<u64>().ok();
+                }
+                // the bytes sent so far, each second
+                [time, sent, _] if time.contains(':') && sent.parse::<u64>().is_ok() => {
+                    if !show {
+                        continue;
+                    }
+                    let sent: u64 = sent.parse().unwrap();
+                    let rate = (sent as f64 / start.elapsed().as_secs_f64()) as u64;
+                    let mut status = format!("{}: {}", label, units::format_size(sent, false));
+                    if let Some(total) = total.filter(|total| *total > 0) {
+                        status += &format!(
+                            " of {} ({}%)",
+                            units::format_size(total, false),
+                            (sent * 100 / total).min(100)
+                        );
+                    }
+                    status += &format!(" at {}/s", units::format_size(rate, false));
+                    if let Some(total) = total.filter(|total| *total > sent && rate > 0) {
+                        status += &format!(", {} left", format_eta((total - sent) / rate));
+                    }
+                    eprint!("\r\x1b[K{}", status);
+                    shown = true;
+                }
+                _ => {
+                    other.extend_from_slice(&line);
+                    other.push(b'\n');
+                }
+            }
+        }
+        if shown {
+            eprintln!();
+        }
+        other
+    })
+}
+
+/// Runs a zfs command, capturing its output
+///
+/// Fails if zfs returns a non-zero code, in which case the error contains its
+/// stderr.  If zfs does not finish within the timeout, e.g. because its pool
+/// is suspended, it is killed.
+fn run(command: &mut Command) -> Result<process::Output, Error> {
+    let args: Vec<_> = command
+        .get_args()
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect();
+    if MODIFYING_SUBCOMMANDS.contains(&args[0].as_str())
+        && dry_run::skip(format_args!("zfs {}", args.join(" ")))
+    {
+        return Ok(process::Output {
+            status: process::ExitStatus::from_raw(0),
+            stdout: vec![],
+            stderr: vec![],
+        });
+    }
+    let start = Instant::now();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
         .map_err(Error::Command)?;
-    match status.success() {
-        true => Ok(()),
-        false => Err(Error::ZfsStatus(status)),
+    let stdout = read_in_background(child.stdout.take().unwrap());
+    let stderr = read_in_background(child.stderr.take().unwrap());
+
+    let timeout = Duration::from_millis(TIMEOUT_MS.load(Ordering::Relaxed));
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(Error::Command)? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            tracing::warn!(?args, ?timeout, "zfs timed out");
+            return Err(Error::Timeout(timeout));
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    let output = process::Output {
+        status,
+        stdout: stdout.join().unwrap(),
+        stderr: stderr.join().unwrap(),
+    };
+    tracing::debug!(?args, duration = ?start.elapsed(), %status, "zfs");
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Err(Error::ZfsStatus(output.status, stderr));
     }
+    Ok(output)
+}
+
+/// Creates a new ZFS volume
+pub fn create(volume: &str) -> Result<(), Error> {
+    run(zfs().args(["create", "-p", volume])).map(|_| ())
 }
 
 /// Destroys a ZFS volume
-pub fn destroy(volume: &str) -> Result<(), Error> {
-    let status = Command::new("zfs")
-        .args(["destroy", "-r", volume])
-        .status()
-        .map_err(Error::Command)?;
-    match status.success() {
-        true => Ok(()),
-        false => Err(Error::ZfsStatus(status)),
+///
+/// If `recursive` is set, the volume's snapshots are destroyed as well.
+/// Otherwise, destroying a volume with snapshots fails.
+pub fn destroy(volume: &str, recursive: bool) -> Result<(), Error> {
+    let mut command = zfs();
+    command.arg("destroy");
+    if recursive {
+        command.arg("-r");
     }
+    run(command.arg(volume)).map(|_| ())
+}
+
+/// Mounts a ZFS volume
+pub fn mount(volume: &str) -> Result<(), Error> {
+    run(zfs().args(["mount", volume])).map(|_| ())
+}
+
+/// Unmounts a ZFS volume
+///
+/// If `force` is set, the volume is unmounted even if it is still in use.
+pub fn unmount(volume: &str, force: bool) -> Result<(), Error> {
+    let mut command = zfs();
+    command.arg("unmount");
+    if force {
+        command.arg("-f");
+    }
+    run(command.arg(volume)).map(|_| ())
+}
+
+/// Whether a ZFS volume is currently mounted
+pub fn is_mounted(volume: &str) -> Result<bool, Error> {
+    Ok(get_property::<String>(volume, "mounted")? == "yes")
 }
 
 /// Renames a ZFS volume
 pub fn rename(src_volume: &str, dest_volume: &str) -> Result<(), Error> {
-    let status = Command::new("zfs")
-        .args(["rename", src_volume, dest_volume])
-        .status()
+    run(zfs().args(["rename", "-p", src_volume, dest_volume])).map(|_| ())
+}
+
+/// Takes a snapshot of a ZFS volume
+pub fn snapshot(volume: &str, snapshot: &str) -> Result<(), Error> {
+    run(zfs().args(["snapshot", &format!("{}@{}", volume, snapshot)])).map(|_| ())
+}
+
+/// Places a hold on a snapshot, which keeps it from being destroyed
+pub fn hold(volume: &str, snapshot: &str, tag: &str) -> Result<(), Error> {
+    run(zfs().args(["hold", tag, &format!("{}@{}", volume, snapshot)])).map(|_| ())
+}
+
+/// Releases a hold placed on a snapshot by [`hold`]
+pub fn release(volume: &str, snapshot: &str, tag: &str) -> Result<(), Error> {
+    run(zfs().args(["release", tag, &format!("{}@{}", volume, snapshot)])).map(|_| ())
+}
+
+/// Lists the names of a volume's snapshots, oldest first
+pub fn list_snapshots(volume: &str) -> Result<Vec<String>, Error> {
+    let output = run(zfs().args([
+        "list", "-H", "-o", "name", "-t", "snapshot", "-s", "creation", "-d", "1", volume,
+    ]))?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once('@'))
+        .map(|(_, snapshot)| snapshot.to_string())
+        .collect())
+}
+
+/// Writes a replication stream of a snapshot to `out`
+///
+/// As streams may take arbitrarily long to write, this is not subject to the
+/// zfs timeout.  Their progress is shown if running on a terminal.
+pub fn send(volume: &str, snapshot: &str, out: impl Into<Stdio>) -> Result<(), Error> {
+    let snapshot = format!("{}@{}", volume, snapshot);
+    let args = ["send", "-Pv", &snapshot];
+    let start = Instant::now();
+    let mut send = zfs()
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(out)
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(Error::Command)?;
+    let stderr = report_progress(send.stderr.take().unwrap(), format!("Sending {}", snapshot));
+    let status = send.wait().map_err(Error::Command)?;
+    tracing::debug!(?args, duration = ?start.elapsed(), %status, "zfs");
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr.join().unwrap())
+            .trim()
+            .to_string();
+        return Err(Error::ZfsStatus(status, stderr));
+    }
+    Ok(())
+}
+
+/// The token to resume an interrupted [`replicate`] into `volume` with, if any
+pub fn resume_token(volume: &str) -> Result<Option<String>, Error> {
+    if get_property::<String>(volume, "type").is_err() {
+        return Ok(None);
+    }
+    let token: String = get_property(volume, "receive_resume_token")?;
+    Ok((token != "-").then_some(token))
+}
+
+/// Copies a snapshot of a volume to a new, unmounted volume
+///
+/// Like [`send`], this is not subject to the zfs timeout and shows its
+/// progress on the terminal.  If the copy is interrupted, `dest_volume` is
+/// left with a [`resume_token`], and calling this again picks up where the
+/// copy stopped.
+pub fn replicate(volume: &str, snapshot: &str, dest_volume: &str) -> Result<(), Error> {
+    let snapshot = format!("{}@{}", volume, snapshot);
+    if dry_run::skip(format_args!(
+        "zfs send {} | zfs receive -s -u {}",
+        snapshot, dest_volume
+    )) {
+        return Ok(());
+    }
+    let send_args = match resume_token(dest_volume)? {
+        Some(token) => vec![
+            "send".to_string(),
+            "-Pv".to_string(),
+            "-t".to_string(),
+            token,
+        ],
+        None => vec!["send".to_string(), "-Pv".to_string(), snapshot.clone()],
+    };
+    let start = Instant::now();
+    let mut send = zfs()
+        .args(&send_args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
         .map_err(Error::Command)?;
-    match status.success() {
-        true => Ok(()),
-        false => Err(Error::ZfsStatus(status)),
+    let send_stderr = report_progress(
+        send.stderr.take().unwrap(),
+        format!("Copying {} to {}", snapshot, dest_volume),
+    );
+    let receive = zfs()
+        .args(["receive", "-s", "-u", dest_volume])
+        .stdin(send.stdout.take().unwrap())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output();
+    // reap send even if receive could not be started, which closed the pipe
+    let send_status = send.wait().map_err(Error::Command)?;
+    let receive = receive.map_err(Error::Command)?;
+    tracing::debug!(
+        %snapshot,
+        dest_volume,
+        duration = ?start.elapsed(),
+        %send_status,
+        receive_status = %receive.status,
+        "zfs replicate"
+    );
+    if !send_status.success() {
+        let stderr = String::from_utf8_lossy(&send_stderr.join().unwrap())
+            .trim()
+            .to_string();
+        return Err(Error::ZfsStatus(send_status, stderr));
+    }
+    if !receive.status.success() {
+        let stderr = String::from_utf8_lossy(&receive.stderr).trim().to_string();
+        return Err(Error::ZfsStatus(receive.status, stderr));
     }
+    Ok(())
+}
+
+/// A change to a file between a snapshot and the current state of a volume
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Change {
+    Added { path: String },
+    Modified { path: String },
+    Removed { path: String },
+    Renamed { path: String, new_path: String },
+}
+
+/// Lists the changes made to a volume since one of its snapshots
+pub fn diff(volume: &str, snapshot: &str) -> Result<Vec<Change>, Error> {
+    let output = run(zfs().args(["diff", "-H", &format!("{}@{}", volume, snapshot), volume]))?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields[..] {
+                ["+", path] => Ok(Change::Added {
+                    path: path.to_string(),
+                }),
+                ["M", path] => Ok(Change::Modified {
+                    path: path.to_string(),
+                }),
+                ["-", path] => Ok(Change::Removed {
+                    path: path.to_string(),
+                }),
+                ["R", path, new_path] => Ok(Change::Renamed {
+                    path: path.to_string(),
+                    new_path: new_path.to_string(),
+                }),
+                _ => Err(Error::PropertyParse(
+                    format!("unexpected zfs diff output `{}`", line).into(),
+                )),
+            }
+        })
+        .collect()
 }
 
 /// Retrieves a ZFS property
 pub fn get_property<F: FromStr>(volume: &str, property: &str) -> Result<F, Error>
 where
-    <F as FromStr>::Err: std::error::Error + 'static,
+    <F as FromStr>::Err: std::error::Error + Send + Sync + 'static,
 {
-    let output = Command::new("zfs")
-        .args([
-            "get", "-Hp", // make zfs output easily parsable
-            "-o", "value", // output only desired value
-            property, volume,
-        ])
-        .output()
-        .map_err(Error::Command)?;
-    if !output.status.success() {
-        return Err(Error::ZfsStatus(output.status));
-    }
-    let mut info_line = String::from_utf8(output.stdout).unwrap();
+    let output = run(zfs().args([
+        "get", "-Hp", // make zfs output easily parsable
+        "-o", "value", // output only desired value
+        property, volume,
+    ]))?;
+    let mut info_line =
+        String::from_utf8(output.stdout).map_err(|e| Error::PropertyParse(Box::new(e)))?;
     info_line.pop(); // remove trailing newline
     info_line
         .parse()
         .map_err(|e| Error::PropertyParse(Box::new(e)))
 }
 
+/// Retrieves the health of the pool containing a volume, e.g. `ONLINE` or `DEGRADED`
+pub fn pool_health(volume: &str) -> Result<String, Error> {
+    let pool = volume.split('/').next().unwrap_or(volume);
+    let output = run(zpool().args(["list", "-H", "-o", "health", pool]))?;
+    let health = String::from_utf8(output.stdout).map_err(|e| Error::PropertyParse(Box::new(e)))?;
+    Ok(health.trim_end().to_string())
+}
+
+/// Retrieves a ZFS property holding a path, such as `mountpoint`
+///
+/// Unlike [`get_property`], this does not require the path to be valid UTF-8.
+pub fn get_path_property(volume: &str, property: &str) -> Result<PathBuf, Error> {
+    let output = run(zfs().args(["get", "-Hp", "-o", "value", property, volume]))?;
+    Ok(parse_path(&output.stdout))
+}
+
+/// Turns the raw output of `zfs get` into a path, keeping non-UTF-8 bytes
+fn parse_path(stdout: &[u8]) -> PathBuf {
+    let info_line = stdout.strip_suffix(b"\n").unwrap_or(stdout);
+    PathBuf::from(OsStr::from_bytes(info_line))
+}
+
+/// Properties of a dataset, as retrieved by [`list_datasets`]
+#[derive(Debug)]
+pub struct Dataset {
+    pub referenced: usize,
+    pub mountpoint: PathBuf,
+    /// Space used by the dataset's snapshots, unknown if taken from a cache
+    pub usedbysnapshots: Option<usize>,
+    /// Compression ratio, e.g. `1.50`, unknown if taken from a cache
+    pub compressratio: Option<String>,
+}
+
+/// Retrieves the properties of a volume and all its descendants at once
+///
+/// This is much faster than calling [`get_property`] for each dataset.
+pub fn list_datasets(root: &str) -> Result<HashMap<String, Dataset>, Error> {
+    let output = run(zfs().args([
+        "list",
+        "-Hp",
+        "-o",
+        "name,referenced,usedbysnapshots,compressratio,mountpoint",
+        "-r",
+        root,
+    ]))?;
+    parse_dataset_list(&output.stdout)
+}
+
+/// Parses the output of the `zfs list` run by [`list_datasets`]
+fn parse_dataset_list(stdout: &[u8]) -> Result<HashMap<String, Dataset>, Error> {
+    stdout
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let fields: Vec<&[u8]> = line.splitn(5, |&b| b == b'\t').collect();
+            let [name, referenced, usedbysnapshots, compressratio, mountpoint] = fields[..] else {
+                return Err(Error::PropertyParse(
+                    format!(
+                        "unexpected zfs list output `{}`",
+                        String::from_utf8_lossy(line)
+                    )
+                    .into(),
+                ));
+            };
+            let name =
+                String::from_utf8(name.to_vec()).map_err(|e| Error::PropertyParse(Box::new(e)))?;
+            let referenced = String::from_utf8_lossy(referenced)
+                .parse()
+                .map_err(|e| Error::PropertyParse(Box::new(e)))?;
+            let usedbysnapshots = String::from_utf8_lossy(usedbysnapshots)
+                .parse()
+                .map_err(|e| Error::PropertyParse(Box::new(e)))?;
+            let dataset = Dataset {
+                referenced,
+                mountpoint: PathBuf::from(OsStr::from_bytes(mountpoint)),
+                usedbysnapshots: Some(usedbysnapshots),
+                compressratio: Some(String::from_utf8_lossy(compressratio).into_owned()),
+            };
+            Ok((name, dataset))
+        })
+        .collect()
+}
+
 /// Sets a ZFS property
 pub fn set_property(volume: &str, property: &str, value: &str) -> Result<(), Error> {
-    let status: process::ExitStatus = Command::new("zfs")
-        .args(["set", &format!("{}={}", property, value), volume])
-        .status()
-        .map_err(Error::Command)?;
+    run(zfs().args(["set", &format!("{}={}", property, value), volume])).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_keeps_non_utf8_bytes() {
+        let path = parse_path(b"/mnt/bulk/\xffuser/ws\n");
+        assert_eq!(path.as_os_str().as_bytes(), b"/mnt/bulk/\xffuser/ws");
+        assert_eq!(parse_path(b"/mnt/ws"), PathBuf::from("/mnt/ws"));
+    }
+
+    #[test]
+    fn dataset_list_keeps_non_utf8_mountpoints() {
+        let datasets = parse_dataset_list(
+            b"pool/ws/u/a\t1024\t0\t1.50\t/mnt/\xff/a\npool/ws/u/b\t0\t0\t1.00\t-\n",
+        )
+        .unwrap();
+        let a = &datasets["pool/ws/u/a"];
+        assert_eq!(a.referenced, 1024);
+        assert_eq!(a.mountpoint.as_os_str().as_bytes(), b"/mnt/\xff/a");
+        assert_eq!(a.compressratio.as_deref(), Some("1.50"));
+        assert_eq!(datasets.len(), 2);
+    }
 
-    match status.success() {
-        true => Ok(()),
-        false => Err(Error::ZfsStatus(status)),
+    #[test]
+    fn dataset_list_rejects_malformed_output() {
+        assert!(parse_dataset_list(b"pool/ws\t1024\n").is_err());
+        assert!(parse_dataset_list(b"pool/ws\tmany\t0\t1.00\t/mnt\n").is_err());
+        assert!(parse_dataset_list(b"pool/\xff\t0\t0\t1.00\t/mnt\n").is_err());
+        assert!(parse_dataset_list(b"").unwrap().is_empty());
     }
 }